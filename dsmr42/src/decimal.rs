@@ -0,0 +1,185 @@
+use core::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    KWh,
+    KW,
+    V,
+    A,
+    M3,
+    GJ,
+    S,
+}
+
+impl Unit {
+    pub fn parse(unit: &str) -> Option<Self> {
+        match unit {
+            "kWh" => Some(Unit::KWh),
+            "kW" => Some(Unit::KW),
+            "V" => Some(Unit::V),
+            "A" => Some(Unit::A),
+            "m3" => Some(Unit::M3),
+            "GJ" => Some(Unit::GJ),
+            "s" => Some(Unit::S),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unit::KWh => "kWh",
+            Unit::KW => "kW",
+            Unit::V => "V",
+            Unit::A => "A",
+            Unit::M3 => "m3",
+            Unit::GJ => "GJ",
+            Unit::S => "s",
+        }
+    }
+}
+
+/// A fixed-point number as sent by the meter, such as `004589.123*kWh`,
+/// which is stored as a `value` of 4589123 with a `scale` of 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decimal {
+    pub value: i64,
+    /// Number of decimals.
+    pub scale: u8,
+    /// `None` if the value has no unit, or one that we don't recognise.
+    pub unit: Option<Unit>,
+}
+
+impl Decimal {
+    pub const fn new(value: i64, scale: u8, unit: Option<Unit>) -> Self {
+        Self { value, scale, unit }
+    }
+
+    /// Parses a COSEM value such as `-00123.45*m3`. The scale is taken from
+    /// the number of decimals.
+    pub fn parse(cosem: &str) -> Option<Self> {
+        let mut parts = cosem.splitn(2, '*');
+        let number = parts.next()?;
+        let unit = parts.next().and_then(Unit::parse);
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(number) => (true, number),
+            None => (false, number),
+        };
+        let mut parts = number.splitn(2, '.');
+        let integer = parts.next()?;
+        let fraction = parts.next().unwrap_or("");
+        if integer.is_empty() || fraction.len() > u8::MAX as usize {
+            return None;
+        }
+        let mut value: i64 = 0;
+        for c in integer.chars().chain(fraction.chars()) {
+            value = value.checked_mul(10)?.checked_add(c.to_digit(10)? as i64)?;
+        }
+        let value = if negative { -value } else { value };
+        Some(Self::new(value, fraction.len() as u8, unit))
+    }
+
+    /// Returns the value with `scale` decimals. Superfluous decimals are
+    /// truncated.
+    pub fn rescale(&self, scale: u8) -> i64 {
+        if scale >= self.scale {
+            let factor = 10i64.saturating_pow((scale - self.scale) as u32);
+            self.value.saturating_mul(factor)
+        } else {
+            match 10i64.checked_pow((self.scale - scale) as u32) {
+                Some(divisor) => self.value / divisor,
+                None => 0,
+            }
+        }
+    }
+
+    /// Returns the amount of energy in Wh, if this is an amount of energy.
+    pub fn wh(&self) -> Option<i64> {
+        match self.unit {
+            Some(Unit::KWh) => Some(self.rescale(3)),
+            _ => None,
+        }
+    }
+
+    /// Returns the power in W, if this is power.
+    pub fn w(&self) -> Option<i64> {
+        match self.unit {
+            Some(Unit::KW) => Some(self.rescale(3)),
+            _ => None,
+        }
+    }
+
+    /// Returns the power in mW, if this is power.
+    pub fn mw(&self) -> Option<i64> {
+        match self.unit {
+            Some(Unit::KW) => Some(self.rescale(6)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let value = self.value.unsigned_abs();
+        match 10u64.checked_pow(self.scale as u32) {
+            Some(1) => write!(f, "{}{}", sign, value)?,
+            Some(divisor) => write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                value / divisor,
+                value % divisor,
+                width = self.scale as usize
+            )?,
+            // Anything this small rounds to zero anyway.
+            None => write!(f, "0")?,
+        }
+        match self.unit {
+            Some(unit) => write!(f, " {}", unit.as_str()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn decimal_parses() {
+        assert_eq!(
+            Some(Decimal::new(12345, 3, Some(Unit::M3))),
+            Decimal::parse("00012.345*m3")
+        );
+        assert_eq!(
+            Some(Decimal::new(-5, 0, Some(Unit::KW))),
+            Decimal::parse("-5*kW")
+        );
+        assert_eq!(Some(Decimal::new(42, 1, None)), Decimal::parse("4.2"));
+        assert_eq!(None, Decimal::parse("12a.3"));
+        assert_eq!(None, Decimal::parse("*kW"));
+    }
+
+    #[test]
+    fn decimal_rescales() {
+        let energy = Decimal::new(4589123, 3, Some(Unit::KWh));
+        assert_eq!(4589, energy.rescale(0));
+        assert_eq!(45891230, energy.rescale(4));
+        assert_eq!(Some(4589123), energy.wh());
+        assert_eq!(None, energy.w());
+        let power = Decimal::new(329, 3, Some(Unit::KW));
+        assert_eq!(Some(329), power.w());
+        assert_eq!(Some(329000), power.mw());
+    }
+
+    #[test]
+    fn decimal_displays() {
+        assert_eq!(
+            "4589.023 kWh",
+            Decimal::new(4589023, 3, Some(Unit::KWh)).to_string()
+        );
+        assert_eq!("-0.5", Decimal::new(-5, 1, None).to_string());
+        assert_eq!("2 A", Decimal::new(2, 0, Some(Unit::A)).to_string());
+    }
+}
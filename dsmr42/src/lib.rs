@@ -1,12 +1,15 @@
 #![allow(unused)]
 #![no_std]
 
+mod decimal;
+
 use core::{
     fmt::{Display, Write},
     num::ParseIntError,
 };
 
 use arrayvec::{ArrayString, ArrayVec};
+pub use decimal::{Decimal, Unit};
 use nom::{
     branch::alt,
     bytes::streaming::{tag, take, take_until, take_while1, take_while_m_n},
@@ -21,17 +24,73 @@ use nom::{
     Compare, IResult, InputLength, InputTake, Parser,
 };
 
-const MAX_COSEM_PER_LINE: usize = 16;
+// Long enough for the eMUCS peak history, which takes three values per month.
+const MAX_COSEM_PER_LINE: usize = 48;
 const MAX_LINES_PER_TELEGRAM: usize = 32;
+// Long enough for the eMUCS peak history of 13 months.
+const MAX_LINE_LEN: usize = 768;
+// Equipment identifiers are sent hex-encoded, taking up to 96 characters.
+const MAX_EQUIPMENT_ID_LEN: usize = 48;
+// eMUCS meters keep the peaks of the last 13 months.
+pub const MAX_PEAK_HISTORY: usize = 13;
+
+pub type EquipmentId = ArrayString<[u8; MAX_EQUIPMENT_ID_LEN]>;
+
+/// An additional OBIS code for the parser to recognise.
+///
+/// `parse` receives the COSEM values of the line, and returns the value that
+/// should be passed on as `Line::Custom`. If it returns `None`, the line is
+/// passed on as `Line::UnknownObis`.
+#[derive(Clone, Copy)]
+pub struct CustomObis {
+    pub obis: [u8; 6],
+    pub parse: fn(&[&str]) -> Option<Decimal>,
+}
+
+/// Meter generations that need to be told apart while parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// DSMR 2.2 and 3.0, which don't send their version and have no CRC.
+    Dsmr2,
+    Dsmr4,
+    Dsmr5,
+}
 
 #[derive(Debug)]
 pub struct Telegram {
     pub device_id: ArrayString<[u8; 32]>,
     pub lines: ArrayVec<[Line; MAX_LINES_PER_TELEGRAM]>,
-    pub crc: u16,
+    /// `None` for DSMR 2.2 and 3.0 telegrams.
+    pub crc: Option<u16>,
 }
 
 impl Telegram {
+    /// Detects the DSMR version from the version line, which was introduced
+    /// in DSMR 4.0.
+    pub fn version(&self) -> Version {
+        let version = self.lines.iter().find_map(|line| match line {
+            Line::Version(version) => Some(*version),
+            // Belgian eMUCS meters send their own version instead, and are
+            // based on DSMR 5.
+            Line::EmucsVersion(_) => Some(50),
+            _ => None,
+        });
+        match version {
+            Some(version) if version >= 50 => Version::Dsmr5,
+            Some(_) => Version::Dsmr4,
+            None => Version::Dsmr2,
+        }
+    }
+
+    /// Returns the type of the device on M-Bus `channel`, if the meter
+    /// reported it. DSMR 2.2 and 3.0 meters don't.
+    pub fn mbus_device(&self, channel: u8) -> Option<MbusDevice> {
+        self.lines.iter().find_map(|line| match line {
+            Line::MbusDeviceType(c, device) if *c == channel => Some(*device),
+            _ => None,
+        })
+    }
+
     pub fn serialize<W: Write>(&self, writer: &mut W) {
         // Poor man's JSON
         write!(writer, "{{");
@@ -48,24 +107,38 @@ impl Telegram {
                     write!(
                         writer,
                         "{}\"tariff_{}_consumed\": {}",
-                        separator, tariff, power
+                        separator,
+                        tariff,
+                        power.rescale(3)
                     );
                 }
                 Line::Produced(tariff, power) => {
                     write!(
                         writer,
                         "{}\"tariff_{}_produced\": {}",
-                        separator, tariff, power
+                        separator,
+                        tariff,
+                        power.rescale(3)
                     );
                 }
                 Line::ActiveTariff(tariff) => {
                     write!(writer, "{}\"active_tariff\": {}", separator, tariff);
                 }
                 Line::TotalConsuming(power) => {
-                    write!(writer, "{}\"total_consuming\": {}", separator, power);
+                    write!(
+                        writer,
+                        "{}\"total_consuming\": {}",
+                        separator,
+                        power.rescale(3)
+                    );
                 }
                 Line::TotalProducing(power) => {
-                    write!(writer, "{}\"total_producing\": {}", separator, power);
+                    write!(
+                        writer,
+                        "{}\"total_producing\": {}",
+                        separator,
+                        power.rescale(3)
+                    );
                 }
                 Line::PowerFailures(count) => {
                     write!(writer, "{}\"power_failures\": {}", separator, count);
@@ -80,13 +153,63 @@ impl Telegram {
                     write!(writer, "{}\"voltage_swells\": {}", separator, count);
                 }
                 Line::Current(phase, current) => {
-                    write!(writer, "{}\"{}_current\": {}", separator, phase, current);
+                    write!(
+                        writer,
+                        "{}\"{}_current\": {}",
+                        separator,
+                        phase,
+                        current.rescale(0)
+                    );
                 }
                 Line::Consuming(phase, power) => {
-                    write!(writer, "{}\"{}_consuming\": {}", separator, phase, power);
+                    write!(
+                        writer,
+                        "{}\"{}_consuming\": {}",
+                        separator,
+                        phase,
+                        power.rescale(3)
+                    );
                 }
                 Line::Producing(phase, power) => {
-                    write!(writer, "{}\"{}_producing\": {}", separator, phase, power);
+                    write!(
+                        writer,
+                        "{}\"{}_producing\": {}",
+                        separator,
+                        phase,
+                        power.rescale(3)
+                    );
+                }
+                Line::AverageDemand(demand) => {
+                    write!(
+                        writer,
+                        "{}\"average_demand\": {}",
+                        separator,
+                        demand.rescale(3)
+                    );
+                }
+                Line::MaxDemand(_, demand) => {
+                    write!(writer, "{}\"max_demand\": {}", separator, demand.rescale(3));
+                }
+                Line::Voltage(phase, voltage) => {
+                    write!(
+                        writer,
+                        "{}\"{}_voltage\": {}",
+                        separator,
+                        phase,
+                        voltage.rescale(1)
+                    );
+                }
+                Line::MbusReading(channel, ts, reading) => {
+                    let key = MbusKey(self.mbus_device(*channel), *channel);
+                    write!(
+                        writer,
+                        "{}\"{}_consumed\": {}, \"{}_timestamp\": \"{}\"",
+                        separator,
+                        key,
+                        reading.rescale(3),
+                        key,
+                        ts
+                    );
                 }
                 _ => {
                     // Do not write unknown lines
@@ -104,15 +227,44 @@ pub struct RawLine<'a> {
     cosem: ArrayVec<[&'a str; MAX_COSEM_PER_LINE]>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Timestamp {
-    year: u16,
-    month: u8,
-    day: u8,
-    hour: u8,
-    minute: u8,
-    second: u8,
-    dst: bool,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// `None` if the meter did not say, which DSMR 2.2 and 3.0 meters don't.
+    pub dst: Option<bool>,
+}
+
+impl Timestamp {
+    /// Converts the timestamp to seconds since the Unix epoch. Meter clocks
+    /// run on Dutch and Belgian time, so this assumes CET or CEST, and CET if
+    /// the meter did not say.
+    pub fn unix_time(&self) -> i64 {
+        // Days since 1970-01-01, counting years from March so that the leap
+        // day comes last.
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            _ => (self.year as i64, self.month as i64 - 3),
+        };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let offset = match self.dst {
+            Some(true) => 2 * 3600,
+            _ => 3600,
+        };
+        days * 86_400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+            - offset
+    }
 }
 
 impl Display for Timestamp {
@@ -122,15 +274,15 @@ impl Display for Timestamp {
             "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
             self.year, self.month, self.day, self.hour, self.minute, self.second
         )?;
-        if self.dst {
-            write!(f, "+02:00")
-        } else {
-            write!(f, "+01:00")
+        match self.dst {
+            Some(true) => write!(f, "+02:00"),
+            Some(false) => write!(f, "+01:00"),
+            None => Ok(()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Phase {
     L1,
     L2,
@@ -147,24 +299,85 @@ impl Display for Phase {
     }
 }
 
+/// The type of a device connected over M-Bus, as defined by EN 13757-3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbusDevice {
+    Gas,
+    Heat,
+    WarmWater,
+    Water,
+    Cooling,
+    Other(u8),
+}
+
+impl From<u8> for MbusDevice {
+    fn from(device_type: u8) -> Self {
+        match device_type {
+            3 => MbusDevice::Gas,
+            4 => MbusDevice::Heat,
+            6 => MbusDevice::WarmWater,
+            7 => MbusDevice::Water,
+            10 => MbusDevice::Cooling,
+            other => MbusDevice::Other(other),
+        }
+    }
+}
+
+// Prefix of the keys that M-Bus readings are serialized under.
+struct MbusKey(Option<MbusDevice>, u8);
+
+impl Display for MbusKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MbusKey(Some(MbusDevice::Gas), _) => write!(f, "gas"),
+            MbusKey(Some(MbusDevice::Heat), _) => write!(f, "heat"),
+            MbusKey(Some(MbusDevice::WarmWater), _) => write!(f, "warm_water"),
+            MbusKey(Some(MbusDevice::Water), _) => write!(f, "water"),
+            MbusKey(Some(MbusDevice::Cooling), _) => write!(f, "cooling"),
+            // Older meters don't report the device type, but only support a
+            // gas meter on the first channel.
+            MbusKey(None, 1) => write!(f, "gas"),
+            MbusKey(_, channel) => write!(f, "mbus_{}", channel),
+        }
+    }
+}
+
+/// The highest average demand in a month, as kept by eMUCS meters.
+#[derive(Debug, Clone, Copy)]
+pub struct MonthlyPeak {
+    /// When the peak occurred.
+    pub at: Timestamp,
+    /// Average demand in W over the quarter hour of the peak.
+    pub demand: u32,
+}
+
 #[derive(Debug)]
 pub enum Line {
     Version(u8),
+    EmucsVersion(u32),
     Timestamp(Timestamp), // YYYY, MM, DD, HH, MM, SS
-    EquipmentId,          // ID is not passed in for now, it's too unwieldy
-    PowerFailureLog,      // Same here
-    Consumed(u8, u32),    // tariff, Wh
-    Produced(u8, u32),    // tariff, Wh
+    EquipmentId(EquipmentId),
+    PowerFailureLog,       // The log is not passed in for now, it's too unwieldy
+    Consumed(u8, Decimal), // tariff, kWh
+    Produced(u8, Decimal), // tariff, kWh
     ActiveTariff(u8),
-    TotalConsuming(u32),    // W
-    TotalProducing(u32),    // W
-    PowerFailures(u32),     // count
-    LongPowerFailures(u32), // count
-    VoltageSags(u32),       // count
-    VoltageSwells(u32),     // count
-    Current(Phase, u32),    // phase number, A
-    Consuming(Phase, u32),  // phase number, A
-    Producing(Phase, u32),  // phase number, A
+    TotalConsuming(Decimal),       // kW
+    TotalProducing(Decimal),       // kW
+    PowerFailures(u32),            // count
+    LongPowerFailures(u32),        // count
+    VoltageSags(u32),              // count
+    VoltageSwells(u32),            // count
+    Voltage(Phase, Decimal),       // phase number, V
+    Current(Phase, Decimal),       // phase number, A
+    Consuming(Phase, Decimal),     // phase number, kW
+    Producing(Phase, Decimal),     // phase number, kW
+    Custom([u8; 6], Decimal),      // value returned by `CustomObis::parse`
+    AverageDemand(Decimal),        // kW, over the current quarter hour
+    MaxDemand(Timestamp, Decimal), // time of peak, kW, in the current month
+    PeakHistory(ArrayVec<[MonthlyPeak; MAX_PEAK_HISTORY]>),
+    MbusDeviceType(u8, MbusDevice),      // M-Bus channel, device type
+    MbusEquipmentId(u8, EquipmentId),    // M-Bus channel, ID
+    MbusReading(u8, Timestamp, Decimal), // M-Bus channel, time of reading, value
     UnknownObis([u8; 6]),
 }
 
@@ -179,10 +392,21 @@ pub enum TelegramParseError {
     CrcMismatch(CrcMismatch),
     InvalidUtf8,
     Incomplete,
+    /// The telegram has no CRC, but its version requires one.
+    MissingCrc,
     ParseError(usize, nom::error::ErrorKind),
 }
 
 pub fn parse(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
+    parse_with(input, &[])
+}
+
+/// Like `parse`, but also recognises the OBIS codes in `custom`. Codes that
+/// the parser already knows are never passed to a custom parser.
+pub fn parse_with(
+    input: &[u8],
+    custom: &[CustomObis],
+) -> (usize, Result<Telegram, TelegramParseError>) {
     let input_str = match core::str::from_utf8(input) {
         Ok(res) => res,
         Err(err) => {
@@ -196,19 +420,24 @@ pub fn parse(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
         }
     };
     let line_buffer = ArrayVec::<[Line; MAX_LINES_PER_TELEGRAM]>::new();
-    match telegram(input_str, line_buffer) {
+    match telegram_with(input_str, line_buffer, custom) {
         Ok((remaining, telegram)) => {
             let telegram_length = input_str.len() - remaining.len();
 
-            let crc = crc16(&input[..telegram_length - 6]);
-
-            let res = if telegram.crc != crc {
-                Err(TelegramParseError::CrcMismatch(CrcMismatch {
-                    calculated: crc,
-                    read: telegram.crc,
-                }))
-            } else {
-                Ok(telegram)
+            let res = match telegram.crc {
+                Some(read) => {
+                    // CRC (4 bytes) and final CRLF (2 bytes)
+                    let crc = crc16(&input[..telegram_length - 6]);
+                    if read != crc {
+                        Err(TelegramParseError::CrcMismatch(CrcMismatch {
+                            calculated: crc,
+                            read,
+                        }))
+                    } else {
+                        Ok(telegram)
+                    }
+                }
+                None => check_missing_crc(telegram),
             };
 
             (input_str.len() - remaining.len(), res)
@@ -221,9 +450,186 @@ pub fn parse(input: &[u8]) -> (usize, Result<Telegram, TelegramParseError>) {
     }
 }
 
-fn telegram<'a>(
+/// Parses telegrams line by line as they are received, so the telegram never
+/// needs to be buffered in its entirety.
+pub struct TelegramParser<'c> {
+    custom: &'c [CustomObis],
+    line: ArrayVec<[u8; MAX_LINE_LEN]>,
+    // Whether `line` ends in a CRLF, but may still be continued.
+    line_complete: bool,
+    telegram: Option<Telegram>,
+    crc: u16,
+}
+
+impl<'c> TelegramParser<'c> {
+    /// `custom` lists additional OBIS codes to recognise, see `parse_with`.
+    pub fn new(custom: &'c [CustomObis]) -> Self {
+        Self {
+            custom,
+            line: ArrayVec::new(),
+            line_complete: false,
+            telegram: None,
+            crc: 0,
+        }
+    }
+
+    /// Feeds received data into the parser. Returns the number of bytes that
+    /// were consumed, and the result of a telegram if one was completed.
+    /// Feeding stops right after a telegram is completed, so any remaining
+    /// data should be fed again.
+    ///
+    /// Data that precedes the start of a telegram is skipped.
+    pub fn feed(&mut self, data: &[u8]) -> (usize, Option<Result<Telegram, TelegramParseError>>) {
+        for (i, &byte) in data.iter().enumerate() {
+            if self.line_complete {
+                self.line_complete = false;
+                // DSMR 2.2 and 3.0 meters send the gas meter reading on a
+                // line of its own, which we treat as part of the previous one.
+                if byte != b'(' {
+                    let res = self.end_line();
+                    self.line.clear();
+                    if res.is_some() {
+                        // This byte belongs to the next line.
+                        return (i, res);
+                    }
+                }
+            }
+            if byte == b'/' {
+                // The start of a new telegram, so whatever came before it on
+                // this line belongs to a telegram that was cut off.
+                self.line.clear();
+            }
+            if self.line.try_push(byte).is_err() {
+                self.line.clear();
+                if self.telegram.take().is_some() {
+                    let err = TelegramParseError::ParseError(0, nom::error::ErrorKind::TooLarge);
+                    return (i + 1, Some(Err(err)));
+                }
+                continue;
+            }
+            if byte == b'\n' {
+                // The header and the end of the telegram are never continued,
+                // so we handle them right away.
+                if !self.line.starts_with(b"/") && !self.line.starts_with(b"!") {
+                    self.line_complete = true;
+                    continue;
+                }
+                let res = self.end_line();
+                self.line.clear();
+                if res.is_some() {
+                    return (i + 1, res);
+                }
+            }
+        }
+        (data.len(), None)
+    }
+
+    /// Discards the telegram that is currently being parsed, if any.
+    pub fn reset(&mut self) {
+        self.line.clear();
+        self.line_complete = false;
+        self.telegram = None;
+    }
+
+    fn end_line(&mut self) -> Option<Result<Telegram, TelegramParseError>> {
+        let line = match core::str::from_utf8(&self.line) {
+            Ok(line) => line,
+            Err(_) => return self.abort(TelegramParseError::InvalidUtf8),
+        };
+        if let Some(header) = line.strip_prefix('/') {
+            // A telegram that is still in progress was cut off.
+            let cut_off = self
+                .telegram
+                .take()
+                .map(|_| Err(TelegramParseError::Incomplete));
+            let device_id = header.trim_end_matches("\r\n");
+            let device_id = match ArrayString::from(device_id) {
+                Ok(device_id) => device_id,
+                Err(_) => {
+                    let err = TelegramParseError::ParseError(1, nom::error::ErrorKind::TooLarge);
+                    return cut_off.or(Some(Err(err)));
+                }
+            };
+            self.crc = crc16_update(0, line.as_bytes());
+            self.telegram = Some(Telegram {
+                device_id,
+                lines: ArrayVec::new(),
+                crc: None,
+            });
+            return cut_off;
+        }
+        if line.starts_with('!') {
+            let mut telegram = self.telegram.take()?;
+            let calculated = crc16_update(self.crc, b"!");
+            return Some(match crc(line) {
+                Ok((_, Some(read))) if read == calculated => {
+                    telegram.crc = Some(read);
+                    Ok(telegram)
+                }
+                Ok((_, Some(read))) => Err(TelegramParseError::CrcMismatch(CrcMismatch {
+                    calculated,
+                    read,
+                })),
+                Ok((_, None)) => check_missing_crc(telegram),
+                Err(err) => Err(line_error(line, err)),
+            });
+        }
+        let telegram = self.telegram.as_mut()?;
+        self.crc = crc16_update(self.crc, line.as_bytes());
+        if line == "\r\n" {
+            // The header is followed by an empty line.
+            return None;
+        }
+        match line_with(line, self.custom) {
+            Ok((_, parsed)) => {
+                if telegram.lines.try_push(parsed).is_err() {
+                    let err = TelegramParseError::ParseError(0, nom::error::ErrorKind::TooLarge);
+                    return self.abort(err);
+                }
+                None
+            }
+            Err(err) => {
+                let err = line_error(line, err);
+                self.abort(err)
+            }
+        }
+    }
+
+    fn abort(&mut self, err: TelegramParseError) -> Option<Result<Telegram, TelegramParseError>> {
+        self.telegram.take().map(|_| Err(err))
+    }
+}
+
+fn check_missing_crc(telegram: Telegram) -> Result<Telegram, TelegramParseError> {
+    match telegram.version() {
+        Version::Dsmr2 => Ok(telegram),
+        _ => Err(TelegramParseError::MissingCrc),
+    }
+}
+
+fn line_error(line: &str, err: nom::Err<nom::error::Error<&str>>) -> TelegramParseError {
+    match err {
+        // The line is complete, so there is no more data to wait for.
+        nom::Err::Incomplete(_) => {
+            TelegramParseError::ParseError(line.len(), nom::error::ErrorKind::Complete)
+        }
+        nom::Err::Failure(err) | nom::Err::Error(err) => {
+            TelegramParseError::ParseError(line.len() - err.input.len(), err.code)
+        }
+    }
+}
+
+fn telegram(
+    input: &str,
+    line_buffer: ArrayVec<[Line; MAX_LINES_PER_TELEGRAM]>,
+) -> IResult<&str, Telegram> {
+    telegram_with(input, line_buffer, &[])
+}
+
+fn telegram_with<'a>(
     input: &'a str,
     mut line_buffer: ArrayVec<[Line; MAX_LINES_PER_TELEGRAM]>,
+    custom: &[CustomObis],
 ) -> IResult<&'a str, Telegram> {
     let (input, device_id) = device_id(input)?;
 
@@ -234,7 +640,7 @@ fn telegram<'a>(
         })
     })?;
 
-    let crc_val: u16;
+    let crc_val: Option<u16>;
     let mut next_input = input;
     loop {
         if let (inp, Some(crc)) = opt(crc)(next_input)? {
@@ -242,7 +648,7 @@ fn telegram<'a>(
             next_input = inp;
             break;
         }
-        match line(next_input) {
+        match line_with(next_input, custom) {
             Ok((i, o)) => {
                 next_input = i;
                 line_buffer.try_push(o).map_err(|_| {
@@ -272,16 +678,26 @@ fn device_id(input: &str) -> IResult<&str, &str> {
     delimited(tag("/"), take_until("\r\n"), pair(crlf, crlf))(input)
 }
 
-fn crc(input: &str) -> IResult<&str, u16> {
-    let (next_input, crc) = delimited(tag("!"), hex_digit1, crlf)(input)?;
+fn crc(input: &str) -> IResult<&str, Option<u16>> {
+    let hex_digits = take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit());
+    let (next_input, crc) = delimited(tag("!"), opt(hex_digits), crlf)(input)?;
+    let crc = match crc {
+        Some(crc) => crc,
+        // DSMR 2.2 and 3.0 telegrams have no CRC.
+        None => return Ok((next_input, None)),
+    };
 
     let mut crc_hex = [0u8; 2];
-    decode_hex(&crc, &mut crc_hex[..]).map_err(nom::Err::Error)?;
+    decode_hex(crc, &mut crc_hex[..]).map_err(nom::Err::Error)?;
     let crc = ((crc_hex[0] as u16) << 8) | crc_hex[1] as u16;
-    Ok((next_input, crc))
+    Ok((next_input, Some(crc)))
 }
 
 fn line(input: &str) -> IResult<&str, Line> {
+    line_with(input, &[])
+}
+
+fn line_with<'a>(input: &'a str, custom: &[CustomObis]) -> IResult<&'a str, Line> {
     fn map_cosem<'a, T, F>(
         val: Option<&&'a str>,
         func: F,
@@ -298,46 +714,77 @@ fn line(input: &str) -> IResult<&str, Line> {
         let (_, res) = func(cosem)?;
         Ok(res)
     };
-    let (input, raw) = raw_line(input)?;
+    let (mut input, raw) = raw_line(input)?;
 
     let line = match raw.obis {
-        [1, 3, 0, 2, 8, 255] => Line::Version(map_cosem(raw.cosem.get(0), u8_complete(2))?),
-        [0, 0, 1, 0, 0, 255] => Line::Timestamp(map_cosem(raw.cosem.get(0), timestamp)?),
-        [0, 0, 96, 1, 1, 255] => Line::EquipmentId,
-        [1, 0, 1, 8, tariff, 255] => {
-            Line::Consumed(tariff, map_cosem(raw.cosem.get(0), fixed_point(6, 3))?)
-        }
-        [1, 0, 2, 8, tariff, 255] => {
-            Line::Produced(tariff, map_cosem(raw.cosem.get(0), fixed_point(6, 3))?)
-        }
-        [0, 0, 96, 14, 0, 255] => Line::ActiveTariff(map_cosem(raw.cosem.get(0), u8_complete(4))?),
-        [1, 0, 1, 7, 0, 255] => {
-            Line::TotalConsuming(map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
-        }
-        [1, 0, 2, 7, 0, 255] => {
-            Line::TotalProducing(map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
-        }
+        [1, 3, 0, 2, 8, 255] => Line::Version(map_cosem(raw.cosem.first(), u8_complete(2))?),
+        [0, 0, 96, 1, 4, 255] => Line::EmucsVersion(map_cosem(raw.cosem.first(), u32_complete(5))?),
+        [1, 0, 1, 4, 0, 255] => Line::AverageDemand(map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 1, 6, 0, 255] => Line::MaxDemand(
+            map_cosem(raw.cosem.first(), timestamp)?,
+            map_cosem(raw.cosem.get(1), decimal)?,
+        ),
+        [0, 0, 98, 1, 0, 255] => Line::PeakHistory(peak_history(&raw.cosem)?),
+        [0, 0, 1, 0, 0, 255] => Line::Timestamp(map_cosem(raw.cosem.first(), timestamp)?),
+        [0, 0, 96, 1, 1, 255] => Line::EquipmentId(map_cosem(raw.cosem.first(), equipment_id)?),
+        [1, 0, 1, 8, tariff, 255] => Line::Consumed(tariff, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 2, 8, tariff, 255] => Line::Produced(tariff, map_cosem(raw.cosem.first(), decimal)?),
+        [0, 0, 96, 14, 0, 255] => Line::ActiveTariff(map_cosem(raw.cosem.first(), u8_complete(4))?),
+        [1, 0, 1, 7, 0, 255] => Line::TotalConsuming(map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 2, 7, 0, 255] => Line::TotalProducing(map_cosem(raw.cosem.first(), decimal)?),
         [0, 0, 96, 7, 21, 255] => {
-            Line::PowerFailures(map_cosem(raw.cosem.get(0), u32_complete(5))?)
+            Line::PowerFailures(map_cosem(raw.cosem.first(), u32_complete(5))?)
         }
         [0, 0, 96, 7, 9, 255] => {
-            Line::LongPowerFailures(map_cosem(raw.cosem.get(0), u32_complete(5))?)
+            Line::LongPowerFailures(map_cosem(raw.cosem.first(), u32_complete(5))?)
         }
         [1, 0, 99, 97, 0, 255] => Line::PowerFailureLog,
-        [1, 0, 32, 32, 0, 255] => Line::VoltageSags(map_cosem(raw.cosem.get(0), u32_complete(5))?),
+        [1, 0, 32, 32, 0, 255] => Line::VoltageSags(map_cosem(raw.cosem.first(), u32_complete(5))?),
         [1, 0, 32, 36, 0, 255] => {
-            Line::VoltageSwells(map_cosem(raw.cosem.get(0), u32_complete(5))?)
+            Line::VoltageSwells(map_cosem(raw.cosem.first(), u32_complete(5))?)
         }
-        [1, 0, 31, 7, 0, 255] => {
-            Line::Current(Phase::L1, map_cosem(raw.cosem.get(0), u32_complete(3))?)
-        }
-        [1, 0, 21, 7, 0, 255] => {
-            Line::Producing(Phase::L1, map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
+        [1, 0, 32, 7, 0, 255] => Line::Voltage(Phase::L1, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 52, 7, 0, 255] => Line::Voltage(Phase::L2, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 72, 7, 0, 255] => Line::Voltage(Phase::L3, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 31, 7, 0, 255] => Line::Current(Phase::L1, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 51, 7, 0, 255] => Line::Current(Phase::L2, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 71, 7, 0, 255] => Line::Current(Phase::L3, map_cosem(raw.cosem.first(), decimal)?),
+        // Group C 21, 41 and 61 are +P (delivered to the client), 22, 42 and 62 are -P.
+        [1, 0, 21, 7, 0, 255] => Line::Consuming(Phase::L1, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 41, 7, 0, 255] => Line::Consuming(Phase::L2, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 61, 7, 0, 255] => Line::Consuming(Phase::L3, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 22, 7, 0, 255] => Line::Producing(Phase::L1, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 42, 7, 0, 255] => Line::Producing(Phase::L2, map_cosem(raw.cosem.first(), decimal)?),
+        [1, 0, 62, 7, 0, 255] => Line::Producing(Phase::L3, map_cosem(raw.cosem.first(), decimal)?),
+        // Up to four M-Bus devices can be connected to the meter, and each
+        // one gets its own channel in group B.
+        [0, channel @ 1..=4, 24, 1, 0, 255] => Line::MbusDeviceType(
+            channel,
+            MbusDevice::from(map_cosem(raw.cosem.first(), u8_value)?),
+        ),
+        [0, channel @ 1..=4, 96, 1, 0, 255] => {
+            Line::MbusEquipmentId(channel, map_cosem(raw.cosem.first(), equipment_id)?)
         }
-        [1, 0, 22, 7, 0, 255] => {
-            Line::Consuming(Phase::L1, map_cosem(raw.cosem.get(0), fixed_point(2, 3))?)
+        [0, channel @ 1..=4, 24, 3, 0, 255] => {
+            // DSMR 2.2 and 3.0 meters send the reading on the next line, and
+            // its unit as the last value of this one.
+            let (next_input, reading) = terminated(cosem::<nom::error::Error<_>>(), crlf)(input)?;
+            input = next_input;
+            let mut reading = map_cosem(Some(&reading), decimal)?;
+            reading.unit = raw.cosem.last().and_then(|unit| Unit::parse(unit));
+            Line::MbusReading(channel, map_cosem(raw.cosem.first(), timestamp)?, reading)
         }
-        obis => Line::UnknownObis(obis),
+        [0, channel @ 1..=4, 24, 2, 1, 255] => Line::MbusReading(
+            channel,
+            map_cosem(raw.cosem.first(), timestamp)?,
+            map_cosem(raw.cosem.get(1), decimal)?,
+        ),
+        obis => custom
+            .iter()
+            .find(|c| c.obis == obis)
+            .and_then(|c| (c.parse)(&raw.cosem))
+            .map(|value| Line::Custom(obis, value))
+            .unwrap_or(Line::UnknownObis(obis)),
     };
     Ok((input, line))
 }
@@ -349,7 +796,10 @@ fn timestamp(input: &str) -> IResult<&str, Timestamp> {
     let (input, hour) = u8_complete(2)(input)?;
     let (input, minute) = u8_complete(2)(input)?;
     let (input, second) = u8_complete(2)(input)?;
-    let (input, dst) = alt((char('S'), char('W')))(input)?;
+    let (input, dst) = opt(alt((
+        character::complete::char('S'),
+        character::complete::char('W'),
+    )))(input)?;
 
     Ok((
         input,
@@ -360,12 +810,59 @@ fn timestamp(input: &str) -> IResult<&str, Timestamp> {
             hour,
             minute,
             second,
-            dst: dst == 'S',
+            dst: dst.map(|dst| dst == 'S'),
         },
     ))
 }
 
-fn raw_line(input: &str) -> IResult<&str, RawLine> {
+fn equipment_id(input: &str) -> IResult<&str, EquipmentId> {
+    let err = |code| nom::Err::Error(nom::error::Error { input, code });
+    if !input.len().is_multiple_of(2) {
+        return Err(err(nom::error::ErrorKind::HexDigit));
+    }
+    if input.len() > 2 * MAX_EQUIPMENT_ID_LEN {
+        return Err(err(nom::error::ErrorKind::TooLarge));
+    }
+    let mut decoded = [0u8; MAX_EQUIPMENT_ID_LEN];
+    let decoded = &mut decoded[..input.len() / 2];
+    decode_hex(input, decoded).map_err(nom::Err::Error)?;
+    let id = core::str::from_utf8(decoded).map_err(|_| err(nom::error::ErrorKind::Char))?;
+    // Cannot fail, we made sure the ID fits.
+    let id = ArrayString::from(id).unwrap();
+    Ok(("", id))
+}
+
+/// Parses the eMUCS peak history, which starts with the number of months and
+/// the OBIS codes of the values that follow, followed by the start of the
+/// month, the time of the peak and the peak itself for every month.
+fn peak_history<'a>(
+    cosem: &[&'a str],
+) -> Result<ArrayVec<[MonthlyPeak; MAX_PEAK_HISTORY]>, nom::Err<nom::error::Error<&'a str>>> {
+    let err = |input: &'a str, code| nom::Err::Error(nom::error::Error { input, code });
+    let count = cosem
+        .first()
+        .and_then(|count| count.parse::<usize>().ok())
+        // Keeps the slice below in bounds, and from overflowing.
+        .filter(|count| *count <= MAX_PEAK_HISTORY);
+    let entries = count
+        .and_then(|count| cosem.get(3..3 + 3 * count))
+        .ok_or_else(|| err("", nom::error::ErrorKind::Count))?;
+    let mut peaks = ArrayVec::new();
+    for entry in entries.chunks(3) {
+        let (_, at) = timestamp(entry[1])?;
+        let (_, demand) = decimal(entry[2])?;
+        let peak = MonthlyPeak {
+            at,
+            demand: demand.rescale(3) as u32,
+        };
+        peaks
+            .try_push(peak)
+            .map_err(|_| err(entry[0], nom::error::ErrorKind::TooLarge))?;
+    }
+    Ok(peaks)
+}
+
+fn raw_line(input: &str) -> IResult<&str, RawLine<'_>> {
     let (mut input, obis) = obis_code(input)?;
 
     let mut cosem_arr = ArrayVec::<[&str; MAX_COSEM_PER_LINE]>::new();
@@ -422,12 +919,17 @@ fn u8(input: &str) -> IResult<&str, u8> {
     map_res(digit1, |s: &str| s.parse())(input)
 }
 
+// Like `u8`, but of a whole value, which isn't followed by more digits.
+fn u8_value(input: &str) -> IResult<&str, u8> {
+    map_res(nom::character::complete::digit1, |s: &str| s.parse())(input)
+}
+
 fn u8_complete<'a, E>(digits: usize) -> impl FnMut(&'a str) -> IResult<&str, u8, E>
 where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError>,
 {
     map_res(
-        nom::bytes::complete::take_while_m_n(digits, digits, |c: char| c.is_digit(10)),
+        nom::bytes::complete::take_while_m_n(digits, digits, |c: char| c.is_ascii_digit()),
         |s: &str| s.parse(),
     )
 }
@@ -437,32 +939,21 @@ where
     E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError>,
 {
     map_res(
-        nom::bytes::complete::take_while_m_n(digits, digits, |c: char| c.is_digit(10)),
+        nom::bytes::complete::take_while_m_n(digits, digits, |c: char| c.is_ascii_digit()),
         |s: &str| s.parse(),
     )
 }
 
-fn fixed_point<'a, E>(
-    digits: usize,
-    decimals: usize,
-) -> impl FnMut(&'a str) -> IResult<&str, u32, E>
-where
-    E: ParseError<&'a str> + FromExternalError<&'a str, ParseIntError>,
-{
-    let integer = map_res(
-        terminated(
-            take_while_m_n(digits, digits, |c: char| c.is_digit(10)),
-            tag("."),
-        ),
-        |s: &str| s.parse(),
-    );
-    let fractional = map_res(
-        take_while_m_n(decimals, decimals, |c: char| c.is_digit(10)),
-        |s: &str| s.parse(),
-    );
-    map_res(integer.and(fractional), move |res: (u32, u32)| {
-        Ok(res.0 * 10u32.pow(decimals as u32) + res.1)
-    })
+/// Parses a number followed by an optional unit. Meter generations differ
+/// in how many digits they send, so any number of digits is accepted.
+fn decimal(input: &str) -> IResult<&str, Decimal> {
+    match Decimal::parse(input) {
+        Some(decimal) => Ok(("", decimal)),
+        None => Err(nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Digit,
+        })),
+    }
 }
 
 fn decode_hex<'a>(data: &'a str, out: &mut [u8]) -> Result<(), nom::error::Error<&'a str>> {
@@ -488,8 +979,12 @@ fn decode_hex<'a>(data: &'a str, out: &mut [u8]) -> Result<(), nom::error::Error
     Ok(())
 }
 
-fn crc16(data: &[u8]) -> u16 {
-    let mut crc = 0u16;
+/// The CRC-16/ARC of `data`, which DSMR telegrams end with.
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0, data)
+}
+
+fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
     for byte in data {
         crc ^= *byte as u16;
         for _ in 0..8 {
@@ -582,6 +1077,26 @@ mod tests {
     1-0:22.7.0(00.000*kW)\r\n\
     !6130\r\n";
 
+    const DSMR22_TELEGRAM: &[u8] = b"/ISk5\\2MT382-1000\r\n\r\n\
+    0-0:96.1.1(4B384547303034303436333935353037)\r\n\
+    1-0:1.8.1(12345.678*kWh)\r\n\
+    1-0:1.8.2(12345.678*kWh)\r\n\
+    1-0:2.8.1(12345.678*kWh)\r\n\
+    1-0:2.8.2(12345.678*kWh)\r\n\
+    0-0:96.14.0(0002)\r\n\
+    1-0:1.7.0(0001.19*kW)\r\n\
+    1-0:2.7.0(0000.00*kW)\r\n\
+    0-0:17.0.0(0999.00*kW)\r\n\
+    0-0:96.3.10(1)\r\n\
+    0-0:96.13.1()\r\n\
+    0-0:96.13.0()\r\n\
+    0-1:24.1.0(3)\r\n\
+    0-1:96.1.0(3232323241424344313233343536373839)\r\n\
+    0-1:24.3.0(090212160000)(00)(60)(1)(0-1:24.2.1)(m3)\r\n\
+    (00001.001)\r\n\
+    0-1:24.4.0(1)\r\n\
+    !\r\n";
+
     #[test]
     fn test_serialize() {
         let (read, res) = parse(EXAMPLE_TELEGRAM);
@@ -599,6 +1114,59 @@ mod tests {
         println!("{:?}", res);
     }
 
+    #[test]
+    fn version_is_detected() {
+        let (_, res) = parse(EXAMPLE_TELEGRAM);
+        assert_eq!(Version::Dsmr4, res.unwrap().version());
+        let (_, res) = parse(DSMR22_TELEGRAM);
+        assert_eq!(Version::Dsmr2, res.unwrap().version());
+    }
+
+    #[test]
+    fn dsmr22_telegram_parses() {
+        let (read, res) = parse(DSMR22_TELEGRAM);
+        let telegram = res.unwrap();
+        assert_eq!(DSMR22_TELEGRAM.len(), read);
+        assert_eq!(None, telegram.crc);
+        let gas = telegram.lines.iter().find_map(|line| match line {
+            Line::MbusReading(channel, ts, volume) => Some((*channel, *ts, *volume)),
+            _ => None,
+        });
+        let (channel, ts, volume) = gas.unwrap();
+        assert_eq!(1, channel);
+        assert_eq!(2009, ts.year);
+        assert_eq!(None, ts.dst);
+        assert_eq!(Decimal::new(1001, 3, Some(Unit::M3)), volume);
+        assert_eq!(Some(MbusDevice::Gas), telegram.mbus_device(1));
+    }
+
+    #[test]
+    fn dsmr22_telegram_parses_incrementally() {
+        let mut parser = TelegramParser::new(&[]);
+        let mut input = DSMR22_TELEGRAM;
+        let mut telegram = None;
+        while !input.is_empty() && telegram.is_none() {
+            let (read, res) = parser.feed(&input[..input.len().min(5)]);
+            telegram = res;
+            input = &input[read..];
+        }
+        let telegram = telegram.unwrap().unwrap();
+        assert_eq!(16, telegram.lines.len());
+        assert!(telegram
+            .lines
+            .iter()
+            .any(|line| matches!(line, Line::MbusReading(1, _, _))));
+    }
+
+    #[test]
+    fn missing_crc_is_rejected_for_dsmr4() {
+        let telegram = b"/XMX5LGBBFFB231237741\r\n\r\n1-3:0.2.8(42)\r\n!\r\n";
+        match parse(telegram) {
+            (_, Err(TelegramParseError::MissingCrc)) => {}
+            other => panic!("Expected missing CRC but got {:?}", other),
+        }
+    }
+
     #[test]
     fn two_telegrams_parse_successively() {
         let (read1, res) = parse(TWO_TELEGRAMS);
@@ -623,6 +1191,85 @@ mod tests {
         }
     }
 
+    #[test]
+    fn telegram_parses_incrementally() {
+        let mut parser = TelegramParser::new(&[]);
+        let mut input = TWO_TELEGRAMS;
+        let mut telegrams = 0;
+        // Feed the data in small chunks, as if it was coming in over the UART.
+        while !input.is_empty() {
+            let chunk = &input[..input.len().min(7)];
+            let (read, res) = parser.feed(chunk);
+            if let Some(res) = res {
+                let telegram = res.unwrap();
+                assert_eq!("XMX5LGBBFFB231237741", telegram.device_id.as_str());
+                assert_eq!(20, telegram.lines.len());
+                assert_eq!(Some(0x6130), telegram.crc);
+                telegrams += 1;
+            }
+            input = &input[read..];
+        }
+        assert_eq!(2, telegrams);
+    }
+
+    #[test]
+    fn incremental_parser_skips_garbage() {
+        let mut parser = TelegramParser::new(&[]);
+        let (read, res) = parser.feed(b"0.000*kW)\r\n!1234\r\n");
+        assert!(res.is_none());
+        let (read, res) = parser.feed(EXAMPLE_TELEGRAM);
+        assert_eq!(EXAMPLE_TELEGRAM.len(), read);
+        res.unwrap().unwrap();
+    }
+
+    #[test]
+    fn incremental_parser_rejects_crc_mismatch() {
+        let mut parser = TelegramParser::new(&[]);
+        let mut telegram = std::vec::Vec::from(EXAMPLE_TELEGRAM);
+        // Change the active tariff.
+        let pos = telegram.windows(5).position(|w| w == b"0001)").unwrap();
+        telegram[pos + 3] = b'2';
+        match parser.feed(&telegram) {
+            (_, Some(Err(TelegramParseError::CrcMismatch(mismatch)))) => {
+                assert_eq!(0x6130, mismatch.read)
+            }
+            other => panic!("Expected CRC mismatch but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn incremental_parser_survives_fuzzed_telegrams() {
+        // Inputs that the fuzz target of the meter reader found to panic.
+        let inputs: [&[u8]; 2] = [
+            b"/ISk5\\2MT382-1000\r\n\r\n1-0:1.8.1(12345.678*kWh)\r\n!1\r\n",
+            b"/XMX5LGBBFFB231215493\r\n\r\n1-3:0.2.8(50)\r\n\
+            0-0:98.1.0(9999999999999999999)(1-0:1.6.0)(1-0:1.6.0)\
+            (200501000000S)(200423192538S)(03.695*kW)\r\n!\r\n",
+        ];
+        for input in inputs.iter() {
+            let mut parser = TelegramParser::new(&[]);
+            let mut input = *input;
+            while !input.is_empty() {
+                let (read, res) = parser.feed(input);
+                assert!(read > 0 || res.is_some(), "parser stalled");
+                input = &input[read..];
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_parser_reports_cut_off_telegram() {
+        let mut parser = TelegramParser::new(&[]);
+        let (read, res) = parser.feed(&EXAMPLE_TELEGRAM[..100]);
+        assert!(res.is_none());
+        let read = match parser.feed(EXAMPLE_TELEGRAM) {
+            (read, Some(Err(TelegramParseError::Incomplete))) => read,
+            other => panic!("Expected incomplete but got {:?}", other),
+        };
+        let (_, res) = parser.feed(&EXAMPLE_TELEGRAM[read..]);
+        res.unwrap().unwrap();
+    }
+
     #[test]
     fn simple_telegram_parses() {
         let mut line_buffer = ArrayVec::<[_; 32]>::new();
@@ -633,7 +1280,7 @@ mod tests {
         let (rem, tel) = res.unwrap();
         assert_eq!("XMX1000", tel.device_id.as_str());
         assert_eq!(2, tel.lines.len());
-        assert_eq!(65535, tel.crc);
+        assert_eq!(Some(65535), tel.crc);
     }
 
     #[test]
@@ -646,6 +1293,181 @@ mod tests {
         }
     }
 
+    #[test]
+    fn equipment_id_line_parses() {
+        let res: TestResult<Line> = line("0-0:96.1.1(4530303034303031383434303034323134)\r\n");
+        let (rem, line) = res.unwrap();
+        match line {
+            Line::EquipmentId(id) => assert_eq!("E0004001844004214", id.as_str()),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn voltage_line_parses() {
+        let res: TestResult<Line> = line("1-0:52.7.0(229.8*V)\r\n");
+        let (rem, line) = res.unwrap();
+        match line {
+            Line::Voltage(Phase::L2, voltage) => {
+                assert_eq!(Decimal::new(2298, 1, Some(Unit::V)), voltage)
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn phase_power_lines_parse() {
+        let res: TestResult<Line> = line("1-0:21.7.0(00.329*kW)\r\n");
+        match res.unwrap().1 {
+            Line::Consuming(Phase::L1, power) => assert_eq!(Some(329), power.w()),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+        let res: TestResult<Line> = line("1-0:62.7.0(01.250*kW)\r\n");
+        match res.unwrap().1 {
+            Line::Producing(Phase::L3, power) => assert_eq!(Some(1250), power.w()),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn emucs_lines_parse() {
+        let res: TestResult<Line> = line("1-0:1.4.0(02.351*kW)\r\n");
+        match res.unwrap().1 {
+            Line::AverageDemand(demand) => assert_eq!(Some(2351), demand.w()),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+        let res: TestResult<Line> = line("1-0:1.6.0(200509134558S)(02.589*kW)\r\n");
+        match res.unwrap().1 {
+            Line::MaxDemand(at, demand) => {
+                assert_eq!(9, at.day);
+                assert_eq!(Some(2589), demand.w());
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+        let res: TestResult<Line> = line("0-0:96.1.4(50217)\r\n");
+        match res.unwrap().1 {
+            Line::EmucsVersion(version) => assert_eq!(50217, version),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn emucs_peak_history_parses() {
+        let res: TestResult<Line> = line(
+            "0-0:98.1.0(2)(1-0:1.6.0)(1-0:1.6.0)\
+            (200501000000S)(200423192538S)(03.695*kW)\
+            (200601000000S)(200510200500S)(04.436*kW)\r\n",
+        );
+        match res.unwrap().1 {
+            Line::PeakHistory(peaks) => {
+                assert_eq!(2, peaks.len());
+                assert_eq!(4, peaks[0].at.month);
+                assert_eq!(3695, peaks[0].demand);
+                assert_eq!(4436, peaks[1].demand);
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn emucs_peak_history_with_huge_count_fails() {
+        let res: TestResult<Line> =
+            line("0-0:98.1.0(9999999999999999999)(1-0:1.6.0)(1-0:1.6.0)\r\n");
+        match res.unwrap_err() {
+            Err::Error(t) => {}
+            _ => panic!("Expected parse error"),
+        }
+    }
+
+    #[test]
+    fn gas_line_parses() {
+        let res: TestResult<Line> = line("0-1:24.2.1(101209110000W)(12785.123*m3)\r\n");
+        let (rem, line) = res.unwrap();
+        match line {
+            Line::MbusReading(channel, ts, volume) => {
+                assert_eq!(1, channel);
+                assert_eq!(2010, ts.year);
+                assert_eq!(11, ts.hour);
+                assert_eq!(Decimal::new(12785123, 3, Some(Unit::M3)), volume);
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn timestamp_converts_to_unix_time() {
+        let res: TestResult<Timestamp> = timestamp("101209113020W");
+        assert_eq!(1291890620, res.unwrap().1.unix_time());
+        let res: TestResult<Timestamp> = timestamp("190301140000S");
+        assert_eq!(1551441600, res.unwrap().1.unix_time());
+    }
+
+    #[test]
+    fn mbus_device_type_line_parses() {
+        let res: TestResult<Line> = line("0-2:24.1.0(007)\r\n");
+        let (rem, line) = res.unwrap();
+        match line {
+            Line::MbusDeviceType(channel, device) => {
+                assert_eq!(2, channel);
+                assert_eq!(MbusDevice::Water, device);
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn mbus_readings_serialize_by_device_type() {
+        let telegram = b"/ISk5\\2MT382-1000\r\n\r\n\
+        0-1:24.1.0(003)\r\n\
+        0-1:24.2.1(101209110000W)(12785.123*m3)\r\n\
+        0-2:24.1.0(007)\r\n\
+        0-2:24.2.1(101209110000W)(00042.001*m3)\r\n\
+        0-3:24.2.1(101209110000W)(00001.500*GJ)\r\n\
+        !\r\n";
+        let (_, res) = parse(telegram);
+        let mut s = String::new();
+        res.unwrap().serialize(&mut s);
+        assert!(s.contains("\"gas_consumed\": 12785123"));
+        assert!(s.contains("\"water_consumed\": 42001"));
+        assert!(s.contains("\"mbus_3_consumed\": 1500"));
+    }
+
+    #[test]
+    fn unknown_obis_line_parses() {
+        let res: TestResult<Line> = line("0-0:96.13.0()\r\n");
+        let (rem, line) = res.unwrap();
+        match line {
+            Line::UnknownObis(obis) => assert_eq!([0, 0, 96, 13, 0, 255], obis),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
+    #[test]
+    fn custom_obis_line_parses() {
+        fn water(cosem: &[&str]) -> Option<Decimal> {
+            Decimal::parse(cosem.get(1)?)
+        }
+        const CUSTOM: &[CustomObis] = &[CustomObis {
+            obis: [0, 2, 24, 2, 3, 255],
+            parse: water,
+        }];
+        let res: TestResult<Line> =
+            line_with("0-2:24.2.3(200208153500W)(00012.345*m3)\r\n", CUSTOM);
+        match res.unwrap().1 {
+            Line::Custom(obis, value) => {
+                assert_eq!([0, 2, 24, 2, 3, 255], obis);
+                assert_eq!(Decimal::new(12345, 3, Some(Unit::M3)), value);
+            }
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+        // Without a registered parser, the line is still accepted.
+        let res: TestResult<Line> = line("0-2:24.2.3(200208153500W)(00012.345*m3)\r\n");
+        match res.unwrap().1 {
+            Line::UnknownObis(obis) => assert_eq!([0, 2, 24, 2, 3, 255], obis),
+            var => panic!("Unexpected enum variant: {:?}", var),
+        }
+    }
+
     #[test]
     fn single_value_raw_line_parses() {
         let res: TestResult<RawLine> = raw_line("0-0:96.14.0(0002)\r\n");
@@ -708,9 +1530,25 @@ mod tests {
 
     #[test]
     fn crc_parses() {
-        let res: TestResult<u16> = crc("!FE01\r\n");
+        let res: TestResult<Option<u16>> = crc("!FE01\r\n");
+        let (rem, crc) = res.unwrap();
+        assert_eq!(Some(65025), crc);
+    }
+
+    #[test]
+    fn short_crc_fails() {
+        let res: TestResult<Option<u16>> = crc("!1\r\n");
+        match res.unwrap_err() {
+            Err::Error(t) => {}
+            _ => panic!("Expected parse error"),
+        }
+    }
+
+    #[test]
+    fn missing_crc_parses() {
+        let res: TestResult<Option<u16>> = crc("!\r\n");
         let (rem, crc) = res.unwrap();
-        assert_eq!(65025, crc);
+        assert_eq!(None, crc);
     }
 
     #[test]
@@ -2,4 +2,7 @@
 # ^ This is just a stupid trick to get this script to run on both Linux and Windows
 
 cargo objcopy --release -- -O ihex teensy-test.hex
-
+cargo objcopy --release -- -O binary meter-reader.bin
+# With IMAGE_PUBLIC_KEY set, sign the image, and flash meter-reader-signed.hex:
+# python3 sign-image.py signing-key.pem meter-reader.bin meter-reader.bin meter-reader-signed.hex
+python3 ota-image.py meter-reader.bin meter-reader.ota
@@ -0,0 +1,43 @@
+//! Generates the constants that `build_info` embeds in the firmware: the git
+//! revision it was built from, and when.
+
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let revision = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(
+            || "unknown".to_string(),
+            |revision| revision.trim().to_string(),
+        );
+    // Reproducible builds set this instead.
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("build_info.rs");
+    fs::write(
+        out,
+        format!(
+            "const GIT_REVISION: &str = {:?};\nconst BUILT_AT: i64 = {};\n",
+            revision, built_at
+        ),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,109 @@
+//! Feeds arbitrary bytes through the framing and the parser, in chunks of
+//! arbitrary sizes, the way the firmware receives them from the meter. They
+//! may not panic, whatever comes in, nor take more bytes than they were
+//! given, nor allocate: everything they keep is of a fixed size.
+//!
+//! The first byte of an input is the size of the chunks, the rest is what the
+//! meter sends. Run from `meter-reader`, starting from the seeds: a real
+//! telegram, and the inputs that were found to panic before.
+//!
+//! ```text
+//! mkdir -p fuzz/corpus/telegram
+//! cp fuzz/seeds/telegram/* fuzz/corpus/telegram
+//! cargo +nightly fuzz run telegram
+//! ```
+
+#![no_main]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libfuzzer_sys::fuzz_target;
+use meter_reader::{dsmr::Parser, framing::DSMR_FRAMING, ring_buffer::RingBuffer};
+
+// `DSMR_READ_BUF_SZ` of the firmware.
+const READ_BUF_SZ: usize = 256;
+// Frames are only found once they are in the buffer as a whole.
+const FRAME_BUF_SZ: usize = 2048;
+const MAX_CHUNK: usize = 64;
+
+// Counts allocations, so that the harness can check that there are none.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk, data) = match data.split_first() {
+        Some((first, data)) => (*first as usize % MAX_CHUNK + 1, data),
+        None => return,
+    };
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+
+    // As the main loop does: the parser takes what is in the read buffer.
+    let mut buffer = RingBuffer::<READ_BUF_SZ>::new();
+    let mut parser = Parser::new(&[]);
+    // As `DsmrUart::read_frame` does, for meters that are read by frame.
+    let mut frames = RingBuffer::<FRAME_BUF_SZ>::new();
+    let mut frame_parser = Parser::new(&[]);
+
+    for chunk in data.chunks(chunk) {
+        for byte in chunk {
+            if !buffer.push(*byte) {
+                buffer.clear();
+                parser.reset();
+                buffer.push(*byte);
+            }
+            if !frames.push(*byte) {
+                frames.clear();
+                frames.push(*byte);
+            }
+        }
+
+        while !buffer.is_empty() {
+            let received = buffer.peek();
+            let (consumed, telegram) = parser.feed(received);
+            assert!(consumed <= received.len());
+            assert!(consumed > 0 || telegram.is_some(), "parser stalled");
+            buffer.consume(consumed);
+        }
+
+        let received = frames.make_contiguous();
+        let (consumed, frame) = DSMR_FRAMING.scan(received);
+        assert!(consumed <= received.len());
+        if let Some(frame) = frame {
+            assert!(frame.starts_with(b"/"));
+            assert!(frame.len() <= consumed);
+            let mut rest = frame;
+            while !rest.is_empty() {
+                let (consumed, telegram) = frame_parser.feed(rest);
+                assert!(consumed <= rest.len());
+                assert!(consumed > 0 || telegram.is_some(), "parser stalled");
+                rest = &rest[consumed..];
+            }
+            frame_parser.reset();
+        }
+        frames.consume(consumed);
+    }
+
+    assert_eq!(
+        allocations,
+        ALLOCATIONS.load(Ordering::Relaxed),
+        "framing or parser allocated"
+    );
+});
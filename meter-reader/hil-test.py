@@ -0,0 +1,215 @@
+#!/usr/bin/env python3
+"""Runs the hardware-in-the-loop tests: flashes `hil-tests` onto the Teensy,
+sends telegrams into the RX pin of the DSMR UART from a USB serial adapter,
+and checks what the board logs over USB of each. See `src/bin/hil-tests.rs`.
+
+Connect the TX pin of a 3.3 V USB serial adapter to pin 15 of the Teensy, and
+their grounds. Needs pyserial, cargo-binutils and teensy_loader_cli.
+
+Usage: hil-test.py [--no-flash] <teensy port> <adapter port>
+"""
+
+import subprocess
+import sys
+import time
+
+import serial
+
+BAUD = 115200
+HEX = "hil-tests.hex"
+# The board waits 5 s for the host when it starts.
+READY_TIMEOUT_S = 15
+# Per expected line, from when the last byte was sent.
+LINE_TIMEOUT_S = 3
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0xA001 if crc & 1 else crc >> 1
+    return crc
+
+
+def telegram(delivered=(4436791, 4234483), returned=(0, 0), power=(329, 0)):
+    """A DSMR 4.2 telegram, of registers in Wh and powers in W."""
+    lines = [
+        "/XMX5LGBBFFB231237741",
+        "",
+        "1-3:0.2.8(42)",
+        "0-0:1.0.0(200208153516W)",
+        "0-0:96.1.1(4530303034303031383434303034323134)",
+    ]
+    for tariff in range(2):
+        lines.append("1-0:1.8.%d(%s*kWh)" % (tariff + 1, fixed(delivered[tariff], 6)))
+        lines.append("1-0:2.8.%d(%s*kWh)" % (tariff + 1, fixed(returned[tariff], 6)))
+    lines += [
+        "0-0:96.14.0(0001)",
+        "1-0:1.7.0(%s*kW)" % fixed(power[0], 2),
+        "1-0:2.7.0(%s*kW)" % fixed(power[1], 2),
+        "0-0:96.7.21(00002)",
+        "0-0:96.7.9(00003)",
+        "1-0:99.97.0(0)(0-0:96.7.19)",
+        "1-0:32.32.0(00000)",
+        "1-0:32.36.0(00000)",
+        "0-0:96.13.1()",
+        "0-0:96.13.0()",
+        "1-0:31.7.0(002*A)",
+        "1-0:21.7.0(%s*kW)" % fixed(power[0], 2),
+        "1-0:22.7.0(%s*kW)" % fixed(power[1], 2),
+        "!",
+    ]
+    body = "\r\n".join(lines).encode()
+    return body + b"%04X\r\n" % crc16(body)
+
+
+def fixed(thousandths, digits):
+    return "%0*d.%03d" % (digits, thousandths // 1000, thousandths % 1000)
+
+
+def reported(delivered=(4436791, 4234483), returned=(0, 0), power=(329, 0)):
+    """What the board reports of a `telegram` of the same values."""
+
+    def decimal(thousandths, unit):
+        return "%d.%03d %s" % (thousandths // 1000, thousandths % 1000, unit)
+
+    values = [
+        ("delivered1", decimal(delivered[0], "kWh")),
+        ("delivered2", decimal(delivered[1], "kWh")),
+        ("returned1", decimal(returned[0], "kWh")),
+        ("returned2", decimal(returned[1], "kWh")),
+        ("power_delivered", decimal(power[0], "kW")),
+        ("power_returned", decimal(power[1], "kW")),
+    ]
+    return "ok " + "; ".join("%s=%s" % value for value in values)
+
+
+def with_bad_crc(data):
+    crc = data[-6:-2]
+    bad = b"%04X" % (int(crc, 16) ^ 1)
+    return data[:-6] + bad + data[-2:]
+
+
+# Each sends chunks, with a pause in s after each, and expects lines of the
+# board, without their `HIL ` prefix, in order. An expected line that ends in
+# `*` matches any line that starts with the rest.
+CASES = [
+    ("telegram", [(telegram(), 0)], [reported()]),
+    (
+        "byte by byte",
+        [(bytes([byte]), 0.001) for byte in telegram(power=(1234, 0))],
+        [reported(power=(1234, 0))],
+    ),
+    (
+        "back to back",
+        [
+            (
+                b"".join(
+                    telegram(delivered=(4436791 + i, 4234483)) for i in range(5)
+                ),
+                0,
+            )
+        ],
+        [reported(delivered=(4436791 + i, 4234483)) for i in range(5)],
+    ),
+    (
+        "garbage first",
+        [(b"\x00\xff\r\n12345)(*kW\r\n" * 8 + telegram(), 0)],
+        [reported()],
+    ),
+    (
+        "returned power",
+        [(telegram(returned=(12, 3400), power=(0, 2750)), 0)],
+        [reported(returned=(12, 3400), power=(0, 2750))],
+    ),
+    ("CRC mismatch", [(with_bad_crc(telegram()), 0)], ["err CrcMismatch*"]),
+    (
+        "cut off",
+        [(telegram()[:200], 0.1), (telegram(), 0)],
+        ["err Incomplete*", reported()],
+    ),
+]
+
+
+def read_line(port, timeout):
+    """The next `HIL` line of the board, without its prefix, or `None`."""
+    deadline = time.monotonic() + timeout
+    while time.monotonic() < deadline:
+        line = port.readline().decode(errors="replace").strip()
+        if "HIL " in line:
+            line = line[line.index("HIL ") + 4 :]
+            if line.startswith("panic"):
+                raise RuntimeError("Board panicked: " + line)
+            return line
+    return None
+
+
+def matches(expected, line):
+    if expected.endswith("*"):
+        return line.startswith(expected[:-1])
+    return line == expected
+
+
+def run(name, chunks, expected, board, adapter):
+    board.reset_input_buffer()
+    for chunk, pause in chunks:
+        adapter.write(chunk)
+        adapter.flush()
+        time.sleep(pause)
+    for want in expected:
+        line = read_line(board, LINE_TIMEOUT_S)
+        if line is None:
+            print("FAIL %s: expected %r, got nothing" % (name, want))
+            return False
+        if not matches(want, line):
+            print("FAIL %s: expected %r, got %r" % (name, want, line))
+            return False
+    # Anything more, such as an overflow, is a failure as well.
+    line = read_line(board, 0.5)
+    if line is not None:
+        print("FAIL %s: unexpected %r" % (name, line))
+        return False
+    print("PASS %s" % name)
+    return True
+
+
+def flash():
+    subprocess.run(
+        ["cargo", "objcopy", "--release", "--bin", "hil-tests", "--", "-O", "ihex", HEX],
+        check=True,
+    )
+    subprocess.run(["teensy_loader_cli", "--mcu=TEENSY40", "-w", "-v", HEX], check=True)
+
+
+def open_board(path):
+    # The port comes back once the board has started.
+    deadline = time.monotonic() + READY_TIMEOUT_S
+    while True:
+        try:
+            return serial.Serial(path, timeout=0.1)
+        except serial.SerialException:
+            if time.monotonic() > deadline:
+                raise
+            time.sleep(0.5)
+
+
+def main(args):
+    no_flash = "--no-flash" in args
+    args = [arg for arg in args if arg != "--no-flash"]
+    if len(args) != 2:
+        sys.exit(__doc__)
+    if not no_flash:
+        flash()
+    board = open_board(args[0])
+    adapter = serial.Serial(args[1], BAUD)
+    ready = read_line(board, READY_TIMEOUT_S)
+    if ready != "ready":
+        sys.exit("Board did not get ready: %r" % ready)
+    passed = [run(name, chunks, expected, board, adapter) for name, chunks, expected in CASES]
+    print("%d of %d passed" % (sum(passed), len(passed)))
+    sys.exit(0 if all(passed) else 1)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])
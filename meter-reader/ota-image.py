@@ -0,0 +1,15 @@
+#!/usr/bin/env python3
+"""Appends the trailer that `ota` expects to a binary image of the firmware.
+
+Usage: ota-image.py <image.bin> <update.ota>
+"""
+
+import struct
+import sys
+import zlib
+
+MAGIC = b"P1FW"
+
+image = open(sys.argv[1], "rb").read()
+trailer = MAGIC + struct.pack("<II", len(image), zlib.crc32(image))
+open(sys.argv[2], "wb").write(image + trailer)
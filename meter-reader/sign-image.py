@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Appends the signature block that `signature` expects to a binary image of
+the firmware, and writes the signed image as Intel HEX for the Teensy Loader.
+
+The key is an Ed25519 private key in PEM, as made by
+`openssl genpkey -algorithm ed25519 -out signing-key.pem`.
+
+Usage: sign-image.py <key.pem> <image.bin> <signed.bin> <signed.hex>
+       sign-image.py --public-key <key.pem>
+"""
+
+import struct
+import sys
+
+from cryptography.hazmat.primitives.serialization import (
+    Encoding,
+    PublicFormat,
+    load_pem_private_key,
+)
+
+MAGIC = b"P1SG"
+# Where the flash is mapped, which the image starts at.
+FLASH_BASE = 0x60000000
+
+
+def load_key(path):
+    return load_pem_private_key(open(path, "rb").read(), password=None)
+
+
+def hex_record(kind, address, data):
+    record = struct.pack(">BHB", len(data), address & 0xFFFF, kind) + data
+    checksum = -sum(record) & 0xFF
+    return ":" + (record + bytes([checksum])).hex().upper() + "\n"
+
+
+def write_hex(path, image):
+    with open(path, "w") as out:
+        upper = None
+        for offset in range(0, len(image), 16):
+            address = FLASH_BASE + offset
+            if address >> 16 != upper:
+                upper = address >> 16
+                out.write(hex_record(4, 0, struct.pack(">H", upper)))
+            out.write(hex_record(0, address, image[offset : offset + 16]))
+        out.write(hex_record(1, 0, b""))
+
+
+if sys.argv[1] == "--public-key":
+    public_key = load_key(sys.argv[2]).public_key()
+    raw = public_key.public_bytes(Encoding.Raw, PublicFormat.Raw)
+    print("Some([" + ", ".join("0x%02X" % byte for byte in raw) + "])")
+    sys.exit()
+
+key = load_key(sys.argv[1])
+image = open(sys.argv[2], "rb").read()
+signed = image + MAGIC + key.sign(image)
+open(sys.argv[3], "wb").write(signed)
+write_hex(sys.argv[4], signed)
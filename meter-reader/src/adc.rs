@@ -0,0 +1,366 @@
+//! Sampling analog inputs on ADC1, for CT clamps or the photodiode of a pulse
+//! reader, on pin 22 and pin 23, which it shares with CAN.
+//!
+//! PIT channel 0 triggers ADC_ETC at `sample_hz`, through XBAR1, and ADC_ETC
+//! converts every input in a chain, with the hardware averaging of the ADC.
+//! The DMA moves the results of each chain into a block, so that the CPU
+//! only sees whole blocks: the mean of each input, or the RMS around it for
+//! alternating currents, is worked out once per block, scaled to its unit,
+//! and added to every reading as the value of its OBIS code.
+//!
+//! The ADC is calibrated once, when it starts.
+
+use core::ptr;
+
+use dsmr42::{Decimal, Unit};
+use teensy4_bsp::hal::iomuxc::ad_b1::{AD_B1_08, AD_B1_09};
+
+use crate::{
+    dma::Channel,
+    dsmr::Reading,
+    telemetry::{Kind, Label, Metric, Source, Telemetry, Value},
+};
+
+/// The DMAMUX request source of ADC_ETC.
+pub const DMA_SOURCE: u32 = 119;
+/// The inputs that there are pins for.
+pub const MAX_INPUTS: usize = 2;
+// Samples per block, which at 2 kHz is five cycles of 50 Hz mains.
+const BLOCK_SZ: usize = 200;
+
+const ADC1: usize = 0x400C_4000;
+const HC0: usize = ADC1;
+const CFG: usize = ADC1 + 0x44;
+const GC: usize = ADC1 + 0x48;
+const GS: usize = ADC1 + 0x4C;
+// ADACK, 12 bits, a long sample time for sources with a high impedance, and
+// the high speed configuration that ADACK wants.
+const CFG_BASE: u32 = 0b11 | 0b10 << 2 | 1 << 4 | 0b11 << 8 | 1 << 10;
+const CFG_ADTRG: u32 = 1 << 13;
+const CFG_AVGS_SHIFT: u32 = 14;
+const GC_ADACKEN: u32 = 1 << 0;
+const GC_AVGE: u32 = 1 << 5;
+const GC_CAL: u32 = 1 << 7;
+const GS_CALF: u32 = 1 << 1;
+// Takes the channel from ADC_ETC.
+const HC_ADCH_EXTERNAL: u32 = 0x10;
+
+const ADC_ETC: usize = 0x403B_0000;
+const ETC_CTRL: usize = ADC_ETC;
+const ETC_TRIG0_CTRL: usize = ADC_ETC + 0x10;
+const ETC_TRIG0_COUNTER: usize = ADC_ETC + 0x14;
+const ETC_TRIG0_CHAIN_1_0: usize = ADC_ETC + 0x18;
+const ETC_TRIG0_RESULT_1_0: usize = ADC_ETC + 0x28;
+const ETC_DMA_CTRL: usize = ADC_ETC + 0x0C;
+const CTRL_SOFTRST: u32 = 1 << 31;
+// ADC2 is left to the touch screen controller, which isn't used.
+const CTRL_TSC_BYPASS: u32 = 1 << 30;
+// Pulses the DMA request, since the DMA can't clear the flag behind it.
+const CTRL_DMA_MODE_SEL: u32 = 1 << 29;
+const CTRL_TRIG0_ENABLE: u32 = 1 << 0;
+const TRIG_CHAIN_SHIFT: u32 = 8;
+const DMA_CTRL_TRIG0_ENABLE: u32 = 1 << 0;
+// Per link of the chain, in half of TRIG0_CHAIN_1_0: the channel, HC0 of
+// ADC1 as the trigger, and back to back with the link before.
+const CHAIN_HWTS_HC0: u32 = 1 << 4;
+const CHAIN_B2B: u32 = 1 << 12;
+// Done0, at the end of the chain, is what requests the DMA.
+const CHAIN_IE_DONE0: u32 = 0b01 << 13;
+
+// Routes the trigger of PIT channel 0 to trigger 0 of ADC_ETC.
+const XBAR1_SEL51: usize = 0x403B_C066;
+const XBAR_IN_PIT_TRIGGER0: u16 = 56;
+
+const PIT: usize = 0x4008_4000;
+const PIT_MCR: usize = PIT;
+const PIT_LDVAL0: usize = PIT + 0x100;
+const PIT_TCTRL0: usize = PIT + 0x108;
+const TCTRL_TEN: u32 = 1 << 0;
+// The peripheral clock, which the `Clock` sets to the crystal.
+const PIT_CLK_HZ: u32 = 24_000_000;
+
+// Gates the clocks of the PIT and ADC1, with CG6 and CG8, and of XBAR1, with
+// CG11.
+const CCM_CCGR1: usize = 0x400F_C06C;
+const CCGR1_PIT_ADC1: u32 = 0b11 << 12 | 0b11 << 16;
+const CCM_CCGR2: usize = 0x400F_C070;
+const CCGR2_XBAR1: u32 = 0b11 << 22;
+
+// The pads of pins 22 and 23, without the keeper, which would skew what is
+// measured.
+const PAD_P22: usize = 0x401F_830C;
+const PAD_P23: usize = 0x401F_8310;
+const PAD_ANALOG: u32 = 0;
+// Register reads before giving up on the calibration.
+const MAX_POLLS: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdcPin {
+    /// Channel 13 of ADC1.
+    P22,
+    /// Channel 14 of ADC1.
+    P23,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Measure {
+    Mean,
+    /// Around the mean, for the output of a CT clamp, which is biased to
+    /// half of the reference.
+    Rms,
+}
+
+/// The conversions that the ADC averages for each sample. Each conversion
+/// takes about 3 µs, and those of every input must fit between samples.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Averaging {
+    Of4,
+    Of8,
+    Of16,
+    Of32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdcInput {
+    /// What is measured, such as `heat_pump`, in lower snake case, for its
+    /// metric.
+    pub name: &'static str,
+    /// The code that the value is added to readings under, which should be
+    /// one that the meter doesn't send, such as one with a C of 128 or more.
+    pub obis: [u8; 6],
+    pub pin: AdcPin,
+    pub measure: Measure,
+    /// In the unit per count, of which there are 4096 to 3.3 V.
+    pub gain: f32,
+    /// Added to means, in the unit.
+    pub offset: f32,
+    /// Decimals that the value is rounded to.
+    pub scale: u8,
+    pub unit: Option<Unit>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdcConfig {
+    pub sample_hz: u32,
+    pub averaging: Averaging,
+    pub inputs: &'static [AdcInput],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdcError {
+    Calibration,
+    /// The PIT can't count down to this rate, in Hz.
+    SampleRate(u32),
+}
+
+pub struct Adc {
+    _pins: (AD_B1_08, AD_B1_09),
+    dma: Channel,
+    config: AdcConfig,
+    // For the DMA, which writes here: a result of ADC_ETC per sample, with
+    // the first input in the low half.
+    block: [u32; BLOCK_SZ],
+    started: bool,
+    values: [Option<Decimal>; MAX_INPUTS],
+}
+
+impl Adc {
+    /// Calibrates ADC1 and starts sampling, with `dma`, a channel for
+    /// `DMA_SOURCE`. Takes the first `MAX_INPUTS` of `config.inputs`.
+    pub fn init(
+        p22: AD_B1_08,
+        p23: AD_B1_09,
+        dma: Channel,
+        mut config: AdcConfig,
+    ) -> Result<Self, AdcError> {
+        let sample_hz = config.sample_hz;
+        if sample_hz == 0 || sample_hz > PIT_CLK_HZ {
+            return Err(AdcError::SampleRate(sample_hz));
+        }
+        config.inputs = &config.inputs[..config.inputs.len().min(MAX_INPUTS)];
+        unsafe {
+            write32(CCM_CCGR1, read32(CCM_CCGR1) | CCGR1_PIT_ADC1);
+            write32(CCM_CCGR2, read32(CCM_CCGR2) | CCGR2_XBAR1);
+            write32(PAD_P22, PAD_ANALOG);
+            write32(PAD_P23, PAD_ANALOG);
+
+            // Calibrated with software triggers, and averaging as much as
+            // the ADC can, as the reference manual advises.
+            write32(CFG, CFG_BASE | 0b11 << CFG_AVGS_SHIFT);
+            write32(GC, GC_ADACKEN | GC_AVGE | GC_CAL);
+            wait_until(|| read32(GC) & GC_CAL == 0)?;
+            if read32(GS) & GS_CALF != 0 {
+                write32(GS, GS_CALF);
+                return Err(AdcError::Calibration);
+            }
+            let avgs = match config.averaging {
+                Averaging::Of4 => 0b00,
+                Averaging::Of8 => 0b01,
+                Averaging::Of16 => 0b10,
+                Averaging::Of32 => 0b11,
+            };
+            write32(CFG, CFG_BASE | CFG_ADTRG | avgs << CFG_AVGS_SHIFT);
+            write32(GC, GC_ADACKEN | GC_AVGE);
+            write32(HC0, HC_ADCH_EXTERNAL);
+
+            write32(ETC_CTRL, read32(ETC_CTRL) & !CTRL_SOFTRST);
+            let links = config.inputs.len().max(1) as u32;
+            write32(ETC_TRIG0_CTRL, (links - 1) << TRIG_CHAIN_SHIFT);
+            write32(ETC_TRIG0_COUNTER, 0);
+            let mut chain = 0;
+            for (i, input) in config.inputs.iter().enumerate() {
+                let channel = match input.pin {
+                    AdcPin::P22 => 13,
+                    AdcPin::P23 => 14,
+                };
+                let mut link = channel | CHAIN_HWTS_HC0 | CHAIN_B2B;
+                if i + 1 == config.inputs.len() {
+                    link |= CHAIN_IE_DONE0;
+                }
+                chain |= link << (16 * i);
+            }
+            write32(ETC_TRIG0_CHAIN_1_0, chain);
+            write32(ETC_DMA_CTRL, DMA_CTRL_TRIG0_ENABLE);
+            write32(
+                ETC_CTRL,
+                CTRL_TSC_BYPASS | CTRL_DMA_MODE_SEL | CTRL_TRIG0_ENABLE,
+            );
+
+            let select = ptr::read_volatile(XBAR1_SEL51 as *const u16);
+            ptr::write_volatile(
+                XBAR1_SEL51 as *mut u16,
+                select & 0x00FF | XBAR_IN_PIT_TRIGGER0 << 8,
+            );
+
+            write32(PIT_MCR, 0);
+            write32(PIT_LDVAL0, PIT_CLK_HZ / sample_hz - 1);
+            write32(PIT_TCTRL0, TCTRL_TEN);
+        }
+        log::info!(
+            "Sampling {} analog inputs at {} Hz",
+            config.inputs.len(),
+            sample_hz
+        );
+        Ok(Self {
+            _pins: (p22, p23),
+            dma,
+            config,
+            block: [0; BLOCK_SZ],
+            started: false,
+            values: [None; MAX_INPUTS],
+        })
+    }
+
+    /// The values of the inputs, in the order of `inputs`, as of the last
+    /// block.
+    pub fn values(&self) -> &[Option<Decimal>] {
+        &self.values[..self.config.inputs.len()]
+    }
+
+    /// Adds the values of the inputs to `reading`, as custom values.
+    pub fn append_to(&self, reading: &mut Reading) {
+        for (input, value) in self.config.inputs.iter().zip(self.values()) {
+            if let Some(value) = value {
+                if reading.custom.try_push((input.obis, *value)).is_err() {
+                    log::warn!("No room for analog input {:?}", input.obis);
+                }
+            }
+        }
+    }
+
+    /// Registers the value of each input.
+    pub fn register(&self, telemetry: &mut Telemetry) {
+        for input in self.config.inputs {
+            telemetry.register(Metric {
+                source: Source::Analog,
+                name: "input",
+                label: Some(Label::Name(input.name)),
+                unit: input.unit.map(Into::into),
+                kind: Kind::Gauge,
+                help: "Value of an analog input",
+                value: Value::Custom(input.obis),
+            });
+        }
+    }
+
+    /// Works out the values once a block is complete, and starts the next.
+    pub fn poll(&mut self) {
+        if self.started {
+            if self.dma.has_failed() {
+                log::warn!("ADC DMA transfer failed");
+            } else if self.dma.is_done() {
+                self.update_values();
+            } else {
+                return;
+            }
+        }
+        // Only once the block is where it stays.
+        self.dma.read(ETC_TRIG0_RESULT_1_0, &mut self.block);
+        self.started = true;
+    }
+
+    fn update_values(&mut self) {
+        let n = BLOCK_SZ as u64;
+        for (i, (input, value)) in self.config.inputs.iter().zip(&mut self.values).enumerate() {
+            let (sum, squares) = self
+                .block
+                .iter()
+                .fold((0u64, 0u64), |(sum, squares), result| {
+                    let count = (result >> (16 * i) & 0xFFF) as u64;
+                    (sum + count, squares + count * count)
+                });
+            let counts = match input.measure {
+                Measure::Mean => sum as f32 / n as f32,
+                // The variance, times n², and its root, to a thousandth of a
+                // count.
+                Measure::Rms => {
+                    let variance = (n * squares - sum * sum) * 1_000_000;
+                    isqrt(variance) as f32 / (1_000 * n) as f32
+                }
+            };
+            let offset = match input.measure {
+                Measure::Mean => input.offset,
+                Measure::Rms => 0.0,
+            };
+            let scaled = (counts * input.gain + offset) * 10i64.pow(input.scale as u32) as f32;
+            let rounding = if scaled < 0.0 { -0.5 } else { 0.5 };
+            *value = Some(Decimal::new(
+                (scaled + rounding) as i64,
+                input.scale,
+                input.unit,
+            ));
+        }
+    }
+}
+
+fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+    // Newton's method, from above.
+    let mut root = value;
+    let mut next = (root + value / root) / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+fn wait_until<F: Fn() -> bool>(done: F) -> Result<(), AdcError> {
+    for _ in 0..MAX_POLLS {
+        if done() {
+            return Ok(());
+        }
+    }
+    Err(AdcError::Calibration)
+}
+
+unsafe fn read32(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write32(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,135 @@
+use teensy4_bsp::hal::{iomuxc::prelude::consts, uart::Parity};
+
+use crate::uart::{DsmrUart, DsmrUartError, StopBits};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baud: u32,
+    pub parity: Option<Parity>,
+    pub stop_bits: StopBits,
+}
+
+/// DSMR 4 and 5 meters use 115200 8N1, DSMR 2.2 meters use 9600 7E1.
+pub const DSMR_LINE_SETTINGS: &[LineSettings] = &[
+    LineSettings {
+        baud: 115200,
+        parity: None,
+        stop_bits: StopBits::One,
+    },
+    LineSettings {
+        baud: 9600,
+        parity: Some(Parity::Even),
+        stop_bits: StopBits::One,
+    },
+];
+
+/// Recognises the identification header that starts every DSMR telegram:
+/// a `/`, followed by a three-letter manufacturer code.
+pub fn dsmr_header(buffer: &[u8]) -> bool {
+    buffer
+        .windows(4)
+        .any(|w| w[0] == b'/' && w[1..].iter().all(|c| c.is_ascii_alphabetic()))
+}
+
+/// Cycles through a list of line settings until one of them produces data
+/// that is recognised as valid, and then locks onto it.
+pub struct AutoBaud<'a> {
+    candidates: &'a [LineSettings],
+    recognise: fn(&[u8]) -> bool,
+    attempt_timeout: i64,
+    current: usize,
+    attempt_start: Option<i64>,
+    locked: bool,
+}
+
+impl<'a> AutoBaud<'a> {
+    /// `attempt_timeout` is the number of milliseconds to wait for valid data
+    /// before moving on to the next candidate. It should exceed the interval
+    /// at which the meter sends telegrams.
+    pub fn new(
+        candidates: &'a [LineSettings],
+        recognise: fn(&[u8]) -> bool,
+        attempt_timeout: i64,
+    ) -> Self {
+        assert!(!candidates.is_empty(), "No line settings to try");
+        Self {
+            candidates,
+            recognise,
+            attempt_timeout,
+            current: 0,
+            attempt_start: None,
+            locked: false,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns the settings that are currently being tried, or that have been
+    /// locked onto.
+    pub fn settings(&self) -> LineSettings {
+        self.candidates[self.current]
+    }
+
+    /// Polls the UART and decides whether to lock onto the current settings
+    /// or move on to the next ones. Returns the settings once locked.
+    pub fn poll<M: consts::Unsigned, const N: usize>(
+        &mut self,
+        uart: &mut DsmrUart<M, N>,
+        now: i64,
+    ) -> Option<LineSettings> {
+        if self.locked {
+            return Some(self.settings());
+        }
+        let attempt_start = match self.attempt_start {
+            Some(start) => start,
+            None => {
+                self.apply(uart);
+                self.attempt_start = Some(now);
+                now
+            }
+        };
+        let result = uart.poll();
+        match &result {
+            // The buffer may fill up before the header comes in, so we keep
+            // looking rather than giving up right away.
+            Ok(()) | Err(DsmrUartError::BufferFull) => {}
+            Err(err) => {
+                log::debug!("Autobaud rejecting {:?}: {:?}", self.settings(), err);
+                self.next();
+                return None;
+            }
+        }
+        let (first, second) = uart.split_read();
+        if (self.recognise)(first) || (self.recognise)(second) {
+            log::info!("Autobaud locked onto {:?}", self.settings());
+            self.locked = true;
+            return Some(self.settings());
+        }
+        if result.is_err() {
+            uart.clear();
+        }
+        if now - attempt_start > self.attempt_timeout {
+            log::debug!("Autobaud timed out on {:?}", self.settings());
+            self.next();
+        }
+        None
+    }
+
+    fn next(&mut self) {
+        self.current = (self.current + 1) % self.candidates.len();
+        self.attempt_start = None;
+    }
+
+    fn apply<M: consts::Unsigned, const N: usize>(&self, uart: &mut DsmrUart<M, N>) {
+        let settings = self.settings();
+        log::debug!("Autobaud trying {:?}", settings);
+        if let Err(err) = uart.set_baud(settings.baud) {
+            log::warn!("Autobaud could not apply {:?}: {:?}", settings, err);
+        }
+        uart.set_parity(settings.parity);
+        uart.set_stop_bits(settings.stop_bits);
+        uart.clear();
+    }
+}
@@ -0,0 +1,124 @@
+//! Firmware for testing the reception of telegrams on the board itself, since
+//! the LPUART, its FIFO and its interrupts can't be tested on the host.
+//! `hil-test.py` flashes it, sends telegrams into the RX pin of the DSMR UART,
+//! pin 15, from a USB serial adapter, and checks what it logs over USB, a
+//! line for each of these:
+//!
+//! - `HIL ready`, once it is listening.
+//! - `HIL ok <values>`, of a telegram that was parsed, with the registers and
+//!   the power of its reading, as `name=value`, separated by `; `.
+//! - `HIL err <error>`, of a telegram that was not.
+//! - `HIL overflow`, when the read buffer was full.
+//! - `HIL uart-error <error>`, when the UART reported one.
+
+#![no_std]
+#![no_main]
+
+use core::{fmt::Write, panic::PanicInfo};
+
+use arrayvec::ArrayString;
+use dsmr42::Decimal;
+use meter_reader::{
+    clock::Clock,
+    dsmr::{Parser, Reading},
+    uart::{DsmrUart, DsmrUartError},
+};
+use teensy4_bsp::{
+    hal::ccm::{self, PLL1},
+    t40, usb,
+    usb::LoggingConfig,
+    SysTick,
+};
+
+// That of the default configuration of the firmware.
+const BAUD: u32 = 115_200;
+// `DSMR_READ_BUF_SZ` of the firmware.
+const READ_BUF_SZ: usize = 256;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let mut per = teensy4_bsp::Peripherals::take().unwrap();
+    let core_per = cortex_m::Peripherals::take().unwrap();
+    let mut systick = SysTick::new(core_per.SYST);
+    let _ = usb::init(
+        &systick,
+        LoggingConfig {
+            max_level: log::LevelFilter::Info,
+            filters: &[],
+        },
+    )
+    .unwrap();
+    // Wait a bit for the host to catch up.
+    systick.delay(5000);
+
+    per.ccm
+        .pll1
+        .set_arm_clock(PLL1::ARM_HZ, &mut per.ccm.handle, &mut per.dcdc);
+    let mut clock = Clock::init(per.ccm.perclk, &mut per.ccm.handle, per.gpt2);
+    let uarts = per.uart.clock(
+        &mut per.ccm.handle,
+        ccm::uart::ClockSelect::OSC,
+        ccm::uart::PrescalarSelect::DIVIDE_1,
+    );
+    let pins = t40::into_pins(per.iomuxc);
+    let uart = uarts
+        .uart2
+        .init(pins.p14, pins.p15, BAUD)
+        .unwrap_or_else(|err| panic!("Failed to configure UART: {:?}", err));
+    // The USB serial adapter sends what an inverter would.
+    let mut uart = DsmrUart::<_, READ_BUF_SZ>::new(uart, false);
+    if let Err(err) = uart.self_test(&mut clock) {
+        panic!("UART self test failed: {:?}", err);
+    }
+    let mut parser = Parser::new(&[]);
+    log::info!("HIL ready");
+
+    loop {
+        match uart.poll_at(clock.millis()) {
+            Ok(()) => {}
+            Err(DsmrUartError::BufferFull) => {
+                log::info!("HIL overflow");
+                uart.clear();
+                parser.reset();
+            }
+            Err(err) => log::info!("HIL uart-error {:?}", err),
+        }
+        let (consumed, telegram) = parser.feed(uart.peek());
+        uart.consume(consumed);
+        match telegram {
+            Some(Ok((_, reading))) => report(&reading),
+            Some(Err(err)) => log::info!("HIL err {:?}", err),
+            None => {}
+        }
+    }
+}
+
+// Logs the values of `reading` that `hil-test.py` checks, on one line, with
+// `-` for those that are missing.
+fn report(reading: &Reading) {
+    let values: [(&str, Option<Decimal>); 6] = [
+        ("delivered1", reading.delivered[0]),
+        ("delivered2", reading.delivered[1]),
+        ("returned1", reading.returned[0]),
+        ("returned2", reading.returned[1]),
+        ("power_delivered", reading.power_delivered),
+        ("power_returned", reading.power_returned),
+    ];
+    let mut line = ArrayString::<[u8; 256]>::new();
+    for (i, (name, value)) in values.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "; " };
+        let _ = match value {
+            Some(value) => write!(line, "{}{}={}", separator, name, value),
+            None => write!(line, "{}{}=-", separator, name),
+        };
+    }
+    log::info!("HIL ok {}", line);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    log::error!("HIL panic: {}", info);
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
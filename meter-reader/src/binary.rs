@@ -0,0 +1,234 @@
+//! Compact binary encoding of readings, for uplinks where every byte counts.
+//!
+//! An encoded reading starts with the schema version byte, currently
+//! `SCHEMA_VERSION`, followed by a varint bitmask of the fields that are
+//! present, followed by those fields in order of their bit:
+//!
+//! | bit   | field                                                     |
+//! |-------|-----------------------------------------------------------|
+//! | 0     | protocol: `u8`, 2, 4 or 5                                 |
+//! | 1     | version: `u8`                                             |
+//! | 2     | timestamp                                                 |
+//! | 3     | equipment ID: varint length, then ASCII                   |
+//! | 4     | tariff: `u8`                                              |
+//! | 5-6   | energy delivered in tariff 1 and 2: decimal               |
+//! | 7-8   | energy returned in tariff 1 and 2: decimal                |
+//! | 9-10  | power delivered and returned: decimal                     |
+//! | 11-22 | voltage, current, power delivered and power returned for  |
+//! |       | L1, then L2, then L3: decimal                             |
+//! | 23    | average demand: decimal                                   |
+//! | 24    | max demand: timestamp, then decimal                       |
+//! | 25    | peak history: varint count, then timestamp and varint W   |
+//! |       | for every peak                                            |
+//! | 26    | M-Bus devices: varint count, then an M-Bus device for     |
+//! |       | every device                                              |
+//! | 27    | custom values: varint count, then 6 bytes of OBIS code    |
+//! |       | and a decimal for every value                             |
+//! | 28    | received at: signed varint of ms since the Unix epoch     |
+//!
+//! Varints are LEB128, and signed values are zigzag encoded first, as in
+//! Protocol Buffers. A timestamp is a signed varint of seconds since the Unix
+//! epoch. A decimal is a signed varint value, a `u8` scale and a `u8` unit,
+//! which is one of `UNITS`, or 0 if there is none.
+//!
+//! An M-Bus device is a `u8` channel, a `u8` device type as defined by
+//! EN 13757-3 or 0 if unknown, and a `u8` bitmask of the fields that follow:
+//! the equipment ID (bit 0) and the reading (bit 1), which is a timestamp
+//! followed by a decimal.
+//!
+//! New fields will only ever be added after the existing ones, using the next
+//! free bit, so that decoders can skip fields they don't know about as long
+//! as they are the last ones. Any other change increments the schema version.
+
+use dsmr42::{Decimal, MbusDevice, Timestamp, Unit, Version};
+
+use crate::dsmr::{MbusReading, Reading};
+
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// The units that decimals can have, in the order of their code, starting at
+/// 1.
+pub const UNITS: [Unit; 7] = [
+    Unit::KWh,
+    Unit::KW,
+    Unit::V,
+    Unit::A,
+    Unit::M3,
+    Unit::GJ,
+    Unit::S,
+];
+
+#[derive(Debug)]
+pub struct BufferTooSmall;
+
+struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn u8(&mut self, value: u8) -> Result<(), BufferTooSmall> {
+        let slot = self.buf.get_mut(self.len).ok_or(BufferTooSmall)?;
+        *slot = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), BufferTooSmall> {
+        self.varint(bytes.len() as u64)?;
+        let slot = self
+            .buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(BufferTooSmall)?;
+        slot.copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn varint(&mut self, mut value: u64) -> Result<(), BufferTooSmall> {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                return self.u8(byte);
+            }
+            self.u8(byte | 0x80)?;
+        }
+    }
+
+    fn signed(&mut self, value: i64) -> Result<(), BufferTooSmall> {
+        self.varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    fn timestamp(&mut self, timestamp: Timestamp) -> Result<(), BufferTooSmall> {
+        self.signed(timestamp.unix_time())
+    }
+
+    fn decimal(&mut self, decimal: Decimal) -> Result<(), BufferTooSmall> {
+        self.signed(decimal.value)?;
+        self.u8(decimal.scale)?;
+        let unit = decimal
+            .unit
+            .and_then(|unit| UNITS.iter().position(|u| *u == unit))
+            .map_or(0, |i| i as u8 + 1);
+        self.u8(unit)
+    }
+
+    fn mbus(&mut self, channel: u8, mbus: &MbusReading) -> Result<(), BufferTooSmall> {
+        self.u8(channel)?;
+        self.u8(match mbus.device {
+            Some(MbusDevice::Gas) => 3,
+            Some(MbusDevice::Heat) => 4,
+            Some(MbusDevice::WarmWater) => 6,
+            Some(MbusDevice::Water) => 7,
+            Some(MbusDevice::Cooling) => 10,
+            Some(MbusDevice::Other(device_type)) => device_type,
+            None => 0,
+        })?;
+        let present = mbus.equipment_id.is_some() as u8 | (mbus.reading.is_some() as u8) << 1;
+        self.u8(present)?;
+        if let Some(id) = mbus.equipment_id {
+            self.bytes(id.as_bytes())?;
+        }
+        if let Some((timestamp, value)) = mbus.reading {
+            self.timestamp(timestamp)?;
+            self.decimal(value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes `reading` into `buf`, returning the number of bytes written.
+pub fn encode_reading(buf: &mut [u8], reading: &Reading) -> Result<usize, BufferTooSmall> {
+    let phases = reading.phases.iter().flat_map(|phase| {
+        [
+            phase.voltage,
+            phase.current,
+            phase.power_delivered,
+            phase.power_returned,
+        ]
+    });
+    // Decimals in the order of their bit, starting at bit 5.
+    let decimals = reading
+        .delivered
+        .iter()
+        .chain(reading.returned.iter())
+        .copied()
+        .chain([reading.power_delivered, reading.power_returned])
+        .chain(phases)
+        .chain([reading.average_demand]);
+
+    let mut present = reading.protocol.is_some() as u32
+        | (reading.version.is_some() as u32) << 1
+        | (reading.timestamp.is_some() as u32) << 2
+        | (reading.equipment_id.is_some() as u32) << 3
+        | (reading.tariff.is_some() as u32) << 4
+        | (reading.max_demand.is_some() as u32) << 24
+        | (!reading.peak_history.is_empty() as u32) << 25
+        | (reading.mbus.iter().any(Option::is_some) as u32) << 26
+        | (!reading.custom.is_empty() as u32) << 27
+        | (reading.received_at.is_some() as u32) << 28;
+    for (bit, decimal) in decimals.clone().enumerate() {
+        present |= (decimal.is_some() as u32) << (bit + 5);
+    }
+
+    let mut encoder = Encoder { buf, len: 0 };
+    encoder.u8(SCHEMA_VERSION)?;
+    encoder.varint(present as u64)?;
+    if let Some(protocol) = reading.protocol {
+        encoder.u8(match protocol {
+            Version::Dsmr2 => 2,
+            Version::Dsmr4 => 4,
+            Version::Dsmr5 => 5,
+        })?;
+    }
+    if let Some(version) = reading.version {
+        encoder.u8(version)?;
+    }
+    if let Some(timestamp) = reading.timestamp {
+        encoder.timestamp(timestamp)?;
+    }
+    if let Some(id) = reading.equipment_id {
+        encoder.bytes(id.as_bytes())?;
+    }
+    if let Some(tariff) = reading.tariff {
+        encoder.u8(tariff)?;
+    }
+    for decimal in decimals.flatten() {
+        encoder.decimal(decimal)?;
+    }
+    if let Some((at, demand)) = reading.max_demand {
+        encoder.timestamp(at)?;
+        encoder.decimal(demand)?;
+    }
+    if !reading.peak_history.is_empty() {
+        encoder.varint(reading.peak_history.len() as u64)?;
+        for peak in reading.peak_history.iter() {
+            encoder.timestamp(peak.at)?;
+            encoder.varint(peak.demand as u64)?;
+        }
+    }
+    if reading.mbus.iter().any(Option::is_some) {
+        let devices = reading.mbus.iter().flatten().count();
+        encoder.varint(devices as u64)?;
+        for (i, mbus) in reading.mbus.iter().enumerate() {
+            if let Some(mbus) = mbus {
+                // Channels are numbered from 1.
+                encoder.mbus(i as u8 + 1, mbus)?;
+            }
+        }
+    }
+    if !reading.custom.is_empty() {
+        encoder.varint(reading.custom.len() as u64)?;
+        for (obis, value) in reading.custom.iter() {
+            for byte in obis {
+                encoder.u8(*byte)?;
+            }
+            encoder.decimal(*value)?;
+        }
+    }
+    if let Some(received_at) = reading.received_at {
+        encoder.signed(received_at)?;
+    }
+    Ok(encoder.len)
+}
@@ -0,0 +1,146 @@
+//! What firmware is running: its version, the git revision and time it was
+//! built from, and which optional subsystems it was built with.
+//!
+//! `BUILD_INFO` is kept in `.rodata.build_info`, where the linker can't drop
+//! it, and starts with the magic `P1BI`, so that it can also be found in an
+//! image without running it.
+
+use core::fmt::{self, Display, Write};
+
+use crate::{json::JsonWriter, sntp::DateTime};
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+const MAGIC: [u8; 4] = *b"P1BI";
+const VERSION_SZ: usize = 16;
+const REVISION_SZ: usize = 32;
+
+// The optional subsystems, as configured in `main`, and their bits in
+// `features`.
+const FEATURES: [(&str, bool); 9] = [
+    ("syslog", crate::SYSLOG_CONFIG.is_some()),
+    ("sd_card", crate::SD_CARD),
+    ("datalog", crate::DATALOG),
+    ("console", crate::CONSOLE_BAUD.is_some()),
+    ("watchdog", crate::WATCHDOG_TIMEOUT_MS.is_some()),
+    ("autobaud", crate::DSMR_AUTOBAUD),
+    ("ota", crate::OTA_CONFIG.is_some()),
+    ("http_system_commands", crate::HTTP_SYSTEM_COMMANDS),
+    ("signed", crate::IMAGE_PUBLIC_KEY.is_some()),
+];
+
+#[used]
+#[link_section = ".rodata.build_info"]
+pub static BUILD_INFO: BuildInfo = BuildInfo {
+    magic: MAGIC,
+    version: padded(env!("CARGO_PKG_VERSION")),
+    revision: padded(GIT_REVISION),
+    built_at: BUILT_AT,
+    features: features(),
+};
+
+/// Strings are padded with zeroes, and truncated if they don't fit.
+#[repr(C)]
+pub struct BuildInfo {
+    // Only looked for in the image.
+    #[allow(dead_code)]
+    magic: [u8; 4],
+    version: [u8; VERSION_SZ],
+    revision: [u8; REVISION_SZ],
+    /// Unix time in seconds.
+    built_at: i64,
+    features: u32,
+}
+
+impl BuildInfo {
+    pub fn version(&self) -> &str {
+        unpadded(&self.version)
+    }
+
+    /// The output of `git describe --dirty`, or `unknown`.
+    pub fn revision(&self) -> &str {
+        unpadded(&self.revision)
+    }
+
+    pub fn built_at(&self) -> DateTime {
+        DateTime::from_unix_millis(self.built_at * 1000)
+    }
+
+    /// The names of the optional subsystems that are enabled.
+    pub fn features(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let all: &'static [(&str, bool)] = &FEATURES;
+        all.iter()
+            .enumerate()
+            .filter(move |(i, _)| self.features & 1 << i != 0)
+            .map(|(_, (name, _))| *name)
+    }
+
+    /// Writes this as a single JSON object.
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        let mut json = JsonWriter::new(writer);
+        json.begin_object()?;
+        json.key("version")?;
+        json.string(self.version())?;
+        json.key("revision")?;
+        json.string(self.revision())?;
+        json.key("built_at")?;
+        json.string(self.built_at())?;
+        json.key("features")?;
+        json.begin_array()?;
+        for feature in self.features() {
+            json.string(feature)?;
+        }
+        json.end_array()?;
+        json.end_object()
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "meter-reader {} ({}, built {})",
+            self.version(),
+            self.revision(),
+            self.built_at()
+        )?;
+        let mut features = self.features();
+        if let Some(first) = features.next() {
+            write!(f, " with {}", first)?;
+            for feature in features {
+                write!(f, ", {}", feature)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const fn features() -> u32 {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < FEATURES.len() {
+        if FEATURES[i].1 {
+            bits |= 1 << i;
+        }
+        i += 1;
+    }
+    bits
+}
+
+const fn padded<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut padded = [0; N];
+    let mut i = 0;
+    while i < bytes.len() && i < N {
+        padded[i] = bytes[i];
+        i += 1;
+    }
+    padded
+}
+
+fn unpadded(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    // A truncated revision may end halfway through a character, but
+    // revisions are ASCII.
+    core::str::from_utf8(&bytes[..len]).unwrap_or("?")
+}
@@ -0,0 +1,154 @@
+//! A pushbutton that connects a pin to ground, told apart by how long it is
+//! held.
+//!
+//! The GPIO latches every edge in its interrupt status register, so that a
+//! press is seen even if it is shorter than a main loop iteration, and so
+//! that `Idle` can wake on it. The level is only accepted once it has been
+//! stable for `DEBOUNCE_MS`, since the contacts bounce.
+
+use core::ptr;
+
+use teensy4_bsp::hal::{
+    gpio::{Input, GPIO},
+    iomuxc::{
+        self, gpio::Pin, prelude::consts::Unsigned, Config, Hysteresis, PullKeep, PullKeepSelect,
+        PullUpDown, IOMUX,
+    },
+};
+
+const DEBOUNCE_MS: i64 = 20;
+const LONG_PRESS_MS: i64 = 3_000;
+const VERY_LONG_PRESS_MS: i64 = 10_000;
+// Logged while the button is held, so that it can be told when to let go.
+const ANNOUNCEMENTS: [(i64, &str); 2] = [
+    (LONG_PRESS_MS, "reset the config"),
+    (VERY_LONG_PRESS_MS, "enter the bootloader"),
+];
+
+// Of GPIO1 to 4, which are the ones with interrupts.
+const GPIO_BASES: [usize; 4] = [0x401B_8000, 0x401B_C000, 0x401C_0000, 0x401C_4000];
+const PSR: usize = 0x08;
+const ISR: usize = 0x18;
+const EDGE_SEL: usize = 0x1C;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Press {
+    Short,
+    /// At least 3 s.
+    Long,
+    /// At least 10 s.
+    VeryLong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Released,
+    /// `announced` counts the `ANNOUNCEMENTS` that were logged.
+    Pressed {
+        since: i64,
+        announced: usize,
+    },
+}
+
+pub struct Button<P> {
+    _pin: GPIO<P, Input>,
+    base: usize,
+    mask: u32,
+    state: State,
+    // The level that was read last, and since when.
+    level: bool,
+    level_since: i64,
+}
+
+impl<P: Pin + IOMUX> Button<P> {
+    /// Pulls `pin` up, so the button only needs to connect it to ground.
+    /// It must be on GPIO1 to 4, which is where pins start out.
+    pub fn new(mut pin: P) -> Self {
+        let config = Config::zero()
+            .set_hysteresis(Hysteresis::Enabled)
+            .set_pull_keep(PullKeep::Enabled)
+            .set_pull_keep_select(PullKeepSelect::Pull)
+            .set_pullupdown(PullUpDown::Pullup22k);
+        iomuxc::configure(&mut pin, config);
+        let base = GPIO_BASES[P::Module::USIZE - 1];
+        let mask = 1 << P::Offset::USIZE;
+        unsafe {
+            // Latch both edges.
+            write(base + EDGE_SEL, read(base + EDGE_SEL) | mask);
+            write(base + ISR, mask);
+        }
+        Self {
+            _pin: GPIO::new(pin),
+            base,
+            mask,
+            state: State::Released,
+            level: false,
+            level_since: 0,
+        }
+    }
+
+    /// The GPIO module, from 1, and the bit of the pin, for `Idle`.
+    pub fn gpio(&self) -> (usize, usize) {
+        (P::Module::USIZE, P::Offset::USIZE)
+    }
+
+    /// Returns a press once the button is released.
+    pub fn poll(&mut self, now: i64) -> Option<Press> {
+        let edge = unsafe { read(self.base + ISR) } & self.mask != 0;
+        if edge {
+            unsafe { write(self.base + ISR, self.mask) };
+        }
+        let pressed = unsafe { read(self.base + PSR) } & self.mask == 0;
+        if edge || pressed != self.level {
+            // Wait for the bouncing to stop.
+            self.level = pressed;
+            self.level_since = now;
+            return None;
+        }
+        if now - self.level_since < DEBOUNCE_MS {
+            return None;
+        }
+
+        match (self.state, pressed) {
+            (State::Released, true) => {
+                self.state = State::Pressed {
+                    since: now,
+                    announced: 0,
+                };
+                None
+            }
+            (State::Pressed { since, announced }, true) => {
+                if let Some((threshold, action)) = ANNOUNCEMENTS.get(announced) {
+                    if now - since >= *threshold {
+                        log::info!("Release the button now to {}", action);
+                        self.state = State::Pressed {
+                            since,
+                            announced: announced + 1,
+                        };
+                    }
+                }
+                None
+            }
+            (State::Pressed { since, .. }, false) => {
+                self.state = State::Released;
+                let held = now - since;
+                Some(if held >= VERY_LONG_PRESS_MS {
+                    Press::VeryLong
+                } else if held >= LONG_PRESS_MS {
+                    Press::Long
+                } else {
+                    Press::Short
+                })
+            }
+            (State::Released, false) => None,
+        }
+    }
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
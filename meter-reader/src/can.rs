@@ -0,0 +1,237 @@
+//! Readings as CAN frames, for inverters, battery systems and displays that
+//! take the grid power from the bus, such as those in Victron installations.
+//!
+//! Which values go into which frames is set by `CanConfig::frames`: each
+//! frame has an ID, a length, and signals that put a value at an offset, as
+//! an integer of a given width and byte order, divided by a divisor. The
+//! frames are queued with every reading, at most every `interval_ms`, and
+//! sent as message buffers come free.
+//!
+//! A value that the reading doesn't have is sent with all of its bits set,
+//! which J1939 and NMEA 2000 read as not available.
+
+mod flexcan;
+
+use arrayvec::ArrayVec;
+use teensy4_bsp::hal::iomuxc::ad_b1::{AD_B1_08, AD_B1_09};
+
+use crate::dsmr::Reading;
+
+use self::flexcan::{BusState, FlexCan, Frame};
+pub use self::flexcan::{CanError, Id};
+
+// Frames wait here while the message buffers are taken.
+const TX_QUEUE_SZ: usize = 32;
+
+/// A value of a reading, in integer units.
+// `CAN_FRAMES` may only use some of these, as of `Width`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    /// In W.
+    PowerDelivered,
+    PowerReturned,
+    /// Delivered minus returned, which is negative while returning.
+    PowerNet,
+    /// Over every tariff, in Wh.
+    EnergyDelivered,
+    EnergyReturned,
+    /// Of a phase, from 0, in mV.
+    Voltage(usize),
+    /// Of a phase, from 0, in mA.
+    Current(usize),
+    /// Of a phase, from 0, delivered minus returned, in W.
+    PhasePowerNet(usize),
+    /// The last reading of an M-Bus device, by channel from 1, in
+    /// thousandths of its unit.
+    Mbus(usize),
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signal {
+    pub quantity: Quantity,
+    /// The first byte of the value in the frame.
+    pub offset: usize,
+    pub width: Width,
+    pub big_endian: bool,
+    /// The value is divided by this, so that 10 sends a voltage in
+    /// hundredths of a volt. Values out of the range of `width` are clamped.
+    pub divisor: i64,
+}
+
+impl Signal {
+    /// A little-endian signal.
+    pub const fn new(quantity: Quantity, offset: usize, width: Width, divisor: i64) -> Self {
+        Self {
+            quantity,
+            offset,
+            width,
+            big_endian: false,
+            divisor,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameMap {
+    pub id: Id,
+    /// In bytes, up to 8.
+    pub len: u8,
+    pub signals: &'static [Signal],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CanConfig {
+    /// In bit/s, such as 250_000 or 500_000.
+    pub bit_rate: u32,
+    pub interval_ms: i64,
+    pub frames: &'static [FrameMap],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CanStats {
+    pub sent: u32,
+    /// Frames that didn't fit in the queue, since the bus was too busy, or
+    /// nothing acknowledged them.
+    pub dropped: u32,
+    /// Times that the controller went bus off.
+    pub bus_off: u32,
+    pub tx_errors: u8,
+    pub rx_errors: u8,
+}
+
+pub struct CanOutput {
+    can: FlexCan,
+    config: CanConfig,
+    queue: ArrayVec<[Frame; TX_QUEUE_SZ]>,
+    next_at: i64,
+    bus_state: BusState,
+    stats: CanStats,
+}
+
+impl CanOutput {
+    /// Starts FlexCAN1 on pins 22 and 23, which are `tx` and `rx`.
+    pub fn init(tx: AD_B1_08, rx: AD_B1_09, config: CanConfig) -> Result<Self, CanError> {
+        Ok(Self {
+            can: FlexCan::init(tx, rx, config.bit_rate)?,
+            config,
+            queue: ArrayVec::new(),
+            next_at: 0,
+            bus_state: BusState::ErrorActive,
+            stats: CanStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> CanStats {
+        let (tx_errors, rx_errors) = self.can.error_counters();
+        CanStats {
+            tx_errors,
+            rx_errors,
+            ..self.stats
+        }
+    }
+
+    /// Queues the frames of `reading`, unless the last ones were queued less
+    /// than `interval_ms` ago.
+    pub fn queue_reading(&mut self, now: i64, reading: &Reading) {
+        if now < self.next_at {
+            return;
+        }
+        self.next_at = now + self.config.interval_ms;
+        for map in self.config.frames {
+            let frame = encode_frame(map, reading);
+            if self.queue.try_push(frame).is_err() {
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    pub fn poll(&mut self) {
+        self.stats.sent += self.can.take_sent();
+        if self.can.take_bus_off() {
+            self.stats.bus_off += 1;
+        }
+        let bus_state = self.can.bus_state();
+        if bus_state != self.bus_state {
+            match bus_state {
+                BusState::ErrorActive => log::info!("CAN bus is back"),
+                _ => log::warn!("CAN controller is {:?}", bus_state),
+            }
+            self.bus_state = bus_state;
+        }
+        while let Some(frame) = self.queue.first() {
+            if !self.can.transmit(frame) {
+                break;
+            }
+            self.queue.remove(0);
+        }
+    }
+}
+
+fn encode_frame(map: &FrameMap, reading: &Reading) -> Frame {
+    let mut frame = Frame {
+        id: map.id,
+        len: map.len.min(8),
+        data: [0; 8],
+    };
+    for signal in map.signals {
+        let (size, min, max) = match signal.width {
+            Width::U8 => (1, 0, u8::MAX as i64),
+            Width::I8 => (1, i8::MIN as i64, i8::MAX as i64),
+            Width::U16 => (2, 0, u16::MAX as i64),
+            Width::I16 => (2, i16::MIN as i64, i16::MAX as i64),
+            Width::U32 => (4, 0, u32::MAX as i64),
+            Width::I32 => (4, i32::MIN as i64, i32::MAX as i64),
+        };
+        let bytes = match frame.data.get_mut(signal.offset..signal.offset + size) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let raw = match value(signal.quantity, reading) {
+            Some(value) => (value / signal.divisor.max(1)).clamp(min, max) as u32,
+            None => u32::MAX,
+        };
+        let raw = raw.to_le_bytes();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let at = if signal.big_endian { size - 1 - i } else { i };
+            *byte = raw[at];
+        }
+    }
+    frame
+}
+
+fn value(quantity: Quantity, reading: &Reading) -> Option<i64> {
+    let total = |registers: &[Option<dsmr42::Decimal>]| {
+        registers
+            .iter()
+            .try_fold(0, |sum, register| Some(sum + register.as_ref()?.wh()?))
+    };
+    match quantity {
+        Quantity::PowerDelivered => reading.power_delivered?.w(),
+        Quantity::PowerReturned => reading.power_returned?.w(),
+        Quantity::PowerNet => Some(reading.power_delivered?.w()? - reading.power_returned?.w()?),
+        Quantity::EnergyDelivered => total(&reading.delivered),
+        Quantity::EnergyReturned => total(&reading.returned),
+        Quantity::Voltage(phase) => Some(reading.phases.get(phase)?.voltage?.rescale(3)),
+        Quantity::Current(phase) => Some(reading.phases.get(phase)?.current?.rescale(3)),
+        Quantity::PhasePowerNet(phase) => {
+            let phase = reading.phases.get(phase)?;
+            Some(phase.power_delivered?.w()? - phase.power_returned?.w()?)
+        }
+        Quantity::Mbus(channel) => {
+            let mbus = reading.mbus.get(channel.checked_sub(1)?)?.as_ref()?;
+            Some(mbus.reading?.1.rescale(3))
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Just enough of FlexCAN1 to send classic CAN frames, on pin 22 (TX) and
+//! pin 23 (RX), which go to a transceiver such as an SN65HVD230.
+//!
+//! Frames are sent from a few message buffers, which the controller sends
+//! in the order of their IDs, as arbitration would. Nothing is received:
+//! the other message buffers stay inactive. The clock of the CAN engine is
+//! the 24 MHz oscillator.
+
+use core::ptr;
+
+use teensy4_bsp::hal::iomuxc::ad_b1::{AD_B1_08, AD_B1_09};
+
+const FLEXCAN1: usize = 0x401D_0000;
+const MCR: usize = FLEXCAN1;
+const CTRL1: usize = FLEXCAN1 + 0x04;
+const ECR: usize = FLEXCAN1 + 0x1C;
+const ESR1: usize = FLEXCAN1 + 0x20;
+const IFLAG1: usize = FLEXCAN1 + 0x30;
+const CTRL2: usize = FLEXCAN1 + 0x34;
+// 16 bytes each: the control and status word, the ID, and 8 data bytes.
+const MB: usize = FLEXCAN1 + 0x80;
+const RXIMR: usize = FLEXCAN1 + 0x880;
+
+const MCR_MDIS: u32 = 1 << 31;
+const MCR_FRZ: u32 = 1 << 30;
+const MCR_HALT: u32 = 1 << 28;
+const MCR_NOTRDY: u32 = 1 << 27;
+const MCR_SOFTRST: u32 = 1 << 25;
+const MCR_FRZACK: u32 = 1 << 24;
+const MCR_LPMACK: u32 = 1 << 20;
+// Don't receive our own frames.
+const MCR_SRXDIS: u32 = 1 << 17;
+const MCR_IRMQ: u32 = 1 << 16;
+const MCR_AEN: u32 = 1 << 12;
+const MCR_MAXMB_MASK: u32 = 0x7F;
+// Arbitrate between message buffers by ID, not by number.
+const CTRL2_EACEN: u32 = 1 << 16;
+const ESR1_BOFFINT: u32 = 1 << 2;
+const ESR1_ERRINT: u32 = 1 << 1;
+const ESR1_FLTCONF_SHIFT: u32 = 4;
+
+const CS_CODE_SHIFT: u32 = 24;
+const CS_SRR: u32 = 1 << 22;
+const CS_IDE: u32 = 1 << 21;
+const CS_DLC_SHIFT: u32 = 16;
+const CODE_TX_INACTIVE: u32 = 0b1000;
+const CODE_TX_DATA: u32 = 0b1100;
+const ID_STD_SHIFT: u32 = 18;
+
+// Message buffer 0 stays inactive as a workaround for erratum ERR005829,
+// which could otherwise keep a frame from being sent. The ones after it send.
+const TX_MAILBOXES: usize = 4;
+const MAILBOXES: usize = 1 + TX_MAILBOXES;
+// Register reads before giving up on a change of mode.
+const MAX_POLLS: usize = 100_000;
+
+// Gates the clocks of FlexCAN1 and its bit timing, with CG7 and CG8.
+const CCM_CCGR0: usize = 0x400F_C068;
+const CG7_CG8: u32 = 0b1111 << 14;
+// The CAN clock root, which is set to the oscillator, undivided.
+const CCM_CSCMR2: usize = 0x400F_C020;
+const CAN_CLK_MASK: u32 = 0xFF << 2;
+const CAN_CLK_SEL_OSC: u32 = 0b01 << 8;
+const CAN_CLK_HZ: u32 = 24_000_000;
+
+// The pads of pins 22 and 23, and where FlexCAN1 takes RX from.
+const MUX_TX: usize = 0x401F_811C;
+const MUX_RX: usize = 0x401F_8120;
+const PAD_TX: usize = 0x401F_830C;
+const PAD_RX: usize = 0x401F_8310;
+const MUX_FLEXCAN: u32 = 0x12;
+// Pull-up and keeper, medium speed, a drive strength of R0/6.
+const PAD_CONFIG: u32 = 0x10B0;
+const RX_SELECT_INPUT: usize = 0x401F_844C;
+const RX_SELECT_AD_B1_09: u32 = 2;
+
+// The time quanta of a bit, of which sync takes 1, and the segments the rest.
+const MIN_QUANTA: u32 = 8;
+const MAX_QUANTA: u32 = 25;
+const MAX_PRESCALER: u32 = 256;
+// Of the propagation segment and phase segment 1.
+const MAX_SEGMENT: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanError {
+    /// The 24 MHz clock can't be divided into this bit rate, in bit/s.
+    BitRate(u32),
+    /// The controller didn't leave or enter freeze mode.
+    Timeout,
+}
+
+/// The state of the controller on the bus, from the error counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusState {
+    ErrorActive,
+    /// Either error counter is past 127, so it no longer flags errors.
+    ErrorPassive,
+    /// The transmit error counter went past 255, so the controller keeps off
+    /// the bus until it has seen 128 times 11 recessive bits.
+    BusOff,
+}
+
+// `CAN_FRAMES` may only use standard IDs.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Id {
+    Standard(u16),
+    Extended(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub id: Id,
+    pub len: u8,
+    pub data: [u8; 8],
+}
+
+pub struct FlexCan {
+    _pins: (AD_B1_08, AD_B1_09),
+}
+
+impl FlexCan {
+    /// Starts FlexCAN1 at `bit_rate`, in bit/s, sampling at about 87.5% of
+    /// each bit, as CANopen and DeviceNet do.
+    pub fn init(tx: AD_B1_08, rx: AD_B1_09, bit_rate: u32) -> Result<Self, CanError> {
+        let timing = bit_timing(bit_rate).ok_or(CanError::BitRate(bit_rate))?;
+        unsafe {
+            write(CCM_CCGR0, read(CCM_CCGR0) & !CG7_CG8);
+            write(
+                CCM_CSCMR2,
+                read(CCM_CSCMR2) & !CAN_CLK_MASK | CAN_CLK_SEL_OSC,
+            );
+            write(CCM_CCGR0, read(CCM_CCGR0) | CG7_CG8);
+
+            write(MUX_TX, MUX_FLEXCAN);
+            write(PAD_TX, PAD_CONFIG);
+            write(MUX_RX, MUX_FLEXCAN);
+            write(PAD_RX, PAD_CONFIG);
+            write(RX_SELECT_INPUT, RX_SELECT_AD_B1_09);
+
+            write(MCR, read(MCR) & !MCR_MDIS);
+            wait_until(|| read(MCR) & MCR_LPMACK == 0)?;
+            write(MCR, read(MCR) | MCR_SOFTRST);
+            wait_until(|| read(MCR) & MCR_SOFTRST == 0)?;
+            // Only configurable in freeze mode, which a soft reset enters.
+            write(MCR, read(MCR) | MCR_FRZ | MCR_HALT);
+            wait_until(|| read(MCR) & MCR_FRZACK != 0)?;
+
+            let mcr = read(MCR) & !MCR_MAXMB_MASK;
+            write(
+                MCR,
+                mcr | MCR_SRXDIS | MCR_IRMQ | MCR_AEN | (MAILBOXES as u32 - 1),
+            );
+            write(CTRL1, timing);
+            write(CTRL2, read(CTRL2) | CTRL2_EACEN);
+            for mailbox in 0..MAILBOXES {
+                let (cs, id) = (mailbox_word(mailbox, 0), mailbox_word(mailbox, 1));
+                let code = if mailbox == 0 { 0 } else { CODE_TX_INACTIVE };
+                write(cs, code << CS_CODE_SHIFT);
+                write(id, 0);
+                write(RXIMR + 4 * mailbox, 0);
+            }
+            write(IFLAG1, u32::MAX);
+
+            write(MCR, read(MCR) & !(MCR_FRZ | MCR_HALT));
+            wait_until(|| read(MCR) & (MCR_FRZACK | MCR_NOTRDY) == 0)?;
+        }
+        log::info!("CAN bus started at {} bit/s", bit_rate);
+        Ok(Self { _pins: (tx, rx) })
+    }
+
+    /// Puts `frame` in a free message buffer, and returns whether there was
+    /// one.
+    pub fn transmit(&mut self, frame: &Frame) -> bool {
+        let mailbox = match (1..MAILBOXES).find(|mailbox| unsafe {
+            read(mailbox_word(*mailbox, 0)) >> CS_CODE_SHIFT & 0xF == CODE_TX_INACTIVE
+        }) {
+            Some(mailbox) => mailbox,
+            None => return false,
+        };
+        let word = |at: usize| {
+            u32::from_be_bytes([
+                frame.data[at],
+                frame.data[at + 1],
+                frame.data[at + 2],
+                frame.data[at + 3],
+            ])
+        };
+        let (id, ide) = match frame.id {
+            Id::Standard(id) => ((id as u32 & 0x7FF) << ID_STD_SHIFT, 0),
+            Id::Extended(id) => (id & 0x1FFF_FFFF, CS_IDE | CS_SRR),
+        };
+        let len = frame.len.min(8) as u32;
+        unsafe {
+            write(IFLAG1, 1 << mailbox);
+            write(mailbox_word(mailbox, 1), id);
+            write(mailbox_word(mailbox, 2), word(0));
+            write(mailbox_word(mailbox, 3), word(4));
+            write(
+                mailbox_word(mailbox, 0),
+                CODE_TX_DATA << CS_CODE_SHIFT | ide | len << CS_DLC_SHIFT,
+            );
+            // As the workaround for ERR005829 asks.
+            write(mailbox_word(0, 0), 0);
+            write(mailbox_word(0, 0), 0);
+        }
+        true
+    }
+
+    /// Returns the frames that were sent since the last call, which must be
+    /// before `transmit` reuses their message buffers.
+    pub fn take_sent(&mut self) -> u32 {
+        let flags = unsafe { read(IFLAG1) } & (((1 << MAILBOXES) - 1) & !1);
+        unsafe { write(IFLAG1, flags) };
+        flags.count_ones()
+    }
+
+    /// Whether the controller went bus off since the last call.
+    pub fn take_bus_off(&mut self) -> bool {
+        let esr1 = unsafe { read(ESR1) };
+        unsafe { write(ESR1, esr1 & (ESR1_BOFFINT | ESR1_ERRINT)) };
+        esr1 & ESR1_BOFFINT != 0
+    }
+
+    pub fn bus_state(&self) -> BusState {
+        match unsafe { read(ESR1) } >> ESR1_FLTCONF_SHIFT & 0b11 {
+            0b00 => BusState::ErrorActive,
+            0b01 => BusState::ErrorPassive,
+            _ => BusState::BusOff,
+        }
+    }
+
+    /// The transmit and receive error counters.
+    pub fn error_counters(&self) -> (u8, u8) {
+        let ecr = unsafe { read(ECR) };
+        (ecr as u8, (ecr >> 8) as u8)
+    }
+}
+
+// CTRL1 for `bit_rate`, with as many time quanta per bit as will divide the
+// clock and fit the segments, and phase segment 2 taking the last eighth.
+fn bit_timing(bit_rate: u32) -> Option<u32> {
+    (MIN_QUANTA..=MAX_QUANTA).rev().find_map(|quanta| {
+        if bit_rate == 0 || CAN_CLK_HZ % (bit_rate * quanta) != 0 {
+            return None;
+        }
+        let prescaler = CAN_CLK_HZ / (bit_rate * quanta);
+        let pseg2 = (quanta / 8).max(2);
+        let segments = quanta - 1 - pseg2;
+        let propseg = (segments / 2).min(MAX_SEGMENT);
+        let pseg1 = segments - propseg;
+        if prescaler > MAX_PRESCALER || pseg1 > MAX_SEGMENT {
+            return None;
+        }
+        let rjw = pseg2.min(4);
+        Some(
+            (prescaler - 1) << 24
+                | (rjw - 1) << 22
+                | (pseg1 - 1) << 19
+                | (pseg2 - 1) << 16
+                | (propseg - 1),
+        )
+    })
+}
+
+fn mailbox_word(mailbox: usize, word: usize) -> usize {
+    MB + 16 * mailbox + 4 * word
+}
+
+fn wait_until<F: Fn() -> bool>(done: F) -> Result<(), CanError> {
+    for _ in 0..MAX_POLLS {
+        if done() {
+            return Ok(());
+        }
+    }
+    Err(CanError::Timeout)
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,78 @@
+//! Sensors of the temperature and relative humidity around the meter reader,
+//! such as in the meter cupboard, which are added to every reading.
+
+use dsmr42::Decimal;
+
+use crate::{
+    dsmr::Reading,
+    telemetry::{Kind, Label, Metric, Source, Telemetry, Unit, Value},
+};
+
+pub mod dht22;
+pub mod sht3x;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClimateConfig {
+    /// Where the sensor is, such as `cupboard`, in lower snake case, for its
+    /// metrics.
+    pub name: &'static str,
+    pub interval_ms: i64,
+    /// The codes that the values are added to readings under, in °C and %.
+    pub temperature_obis: [u8; 6],
+    pub humidity_obis: [u8; 6],
+}
+
+/// A measurement.
+#[derive(Clone, Copy, Debug)]
+pub struct Climate {
+    pub temperature: Decimal,
+    pub humidity: Decimal,
+}
+
+// Adds the values of `climate` to `reading`, as custom values.
+fn append_to(config: &ClimateConfig, climate: Option<Climate>, reading: &mut Reading) {
+    let climate = match climate {
+        Some(climate) => climate,
+        None => return,
+    };
+    for (obis, value) in [
+        (config.temperature_obis, climate.temperature),
+        (config.humidity_obis, climate.humidity),
+    ]
+    .iter()
+    {
+        if reading.custom.try_push((*obis, *value)).is_err() {
+            log::warn!("No room for climate value {:?}", obis);
+        }
+    }
+}
+
+// Registers the temperature and the humidity of `config`.
+fn register(config: &ClimateConfig, telemetry: &mut Telemetry) {
+    for (name, unit, help, obis) in [
+        (
+            "temperature",
+            Unit::Celsius,
+            "Temperature",
+            config.temperature_obis,
+        ),
+        (
+            "humidity",
+            Unit::Percent,
+            "Relative humidity",
+            config.humidity_obis,
+        ),
+    ]
+    .iter()
+    {
+        telemetry.register(Metric {
+            source: Source::Sensor,
+            name: *name,
+            label: Some(Label::Name(config.name)),
+            unit: Some(*unit),
+            kind: Kind::Gauge,
+            help: *help,
+            value: Value::Custom(*obis),
+        });
+    }
+}
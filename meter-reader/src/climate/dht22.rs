@@ -0,0 +1,244 @@
+//! A DHT22, or AM2302, on pin 37, with a pull-up of 10 kΩ to 3.3 V, which
+//! most modules have on board.
+//!
+//! A measurement starts with the pin held low for a millisecond. The sensor
+//! then answers with a high pulse of 80 µs, then sends 40 bits, each a low
+//! pulse of 50 µs followed by a high pulse of 26 µs for a 0, or 70 µs for a
+//! 1. Rather than by timing a loop, the edges are timed by the input capture
+//! of submodule 0 of FlexPWM1, which pin 37 is channel A of, so that a bit
+//! is still read right if the loop is late to see its edges. A 1 is a high
+//! pulse longer than 5/8 of that of the answer, so that the rate of the
+//! counter, which changes along with the core clock, doesn't matter.
+//!
+//! Measuring takes about 6 ms, which blocks. The sensor can't be read more
+//! often than every 2 s.
+
+use core::ptr;
+
+use cortex_m::{interrupt, peripheral::DWT};
+use dsmr42::Decimal;
+use teensy4_bsp::hal::iomuxc::sd_b0::SD_B0_00;
+
+use super::{Climate, ClimateConfig};
+use crate::{cpu_clock, dsmr::Reading, telemetry::Telemetry};
+
+pub const MIN_INTERVAL_MS: i64 = 2_000;
+
+const FLEXPWM1: usize = 0x403D_C000;
+// Of submodule 0.
+const SM0_INIT: usize = FLEXPWM1 + 0x02;
+const SM0_CTRL: usize = FLEXPWM1 + 0x06;
+const SM0_VAL1: usize = FLEXPWM1 + 0x0E;
+const SM0_STS: usize = FLEXPWM1 + 0x24;
+const SM0_CAPTCTRLA: usize = FLEXPWM1 + 0x34;
+const SM0_CVAL2: usize = FLEXPWM1 + 0x48;
+const SM0_CVAL3: usize = FLEXPWM1 + 0x4C;
+const MCTRL: usize = FLEXPWM1 + 0x188;
+
+// The counter runs free over 16 bits, at a 32nd of the IPG clock, which is
+// about 14 ms per turn at 600 MHz.
+const CTRL_FULL: u16 = 1 << 10;
+const CTRL_PRSC_DIV32: u16 = 0b101 << 4;
+const MCTRL_LDOK0: u16 = 1 << 0;
+const MCTRL_RUN0: u16 = 1 << 8;
+// Capture A0 on falling edges, into CVAL2, and A1 on rising ones, into
+// CVAL3, continuously.
+const CAPTCTRLA_ARMA: u16 = 1 << 0;
+const CAPTCTRLA_EDGA0_FALLING: u16 = 0b01 << 2;
+const CAPTCTRLA_EDGA1_RISING: u16 = 0b10 << 4;
+const STS_CFA0: u16 = 1 << 10;
+const STS_CFA1: u16 = 1 << 11;
+
+// Gates the clock of FlexPWM1, with CG8.
+const CCM_CCGR4: usize = 0x400F_C078;
+const CG8: u32 = 0b11 << 16;
+
+// The pad of pin 37, which is GPIO3 12 as GPIO, and the input of channel A
+// of submodule 0 of FlexPWM1 as that, once the daisy chain selects it.
+const MUX: usize = 0x401F_81BC;
+const PAD: usize = 0x401F_83AC;
+const PWMA0_SELECT_INPUT: usize = 0x401F_8458;
+const MUX_FLEXPWM: u32 = 1;
+const MUX_GPIO: u32 = 5;
+const SELECT_SD_B0_00: u32 = 0;
+// Hysteresis, and a pull-up of 22 kΩ.
+const PAD_CONFIG: u32 = 1 << 16 | 0b11 << 14 | 1 << 13 | 1 << 12;
+const GPIO3_GDIR: usize = 0x401C_0004;
+const GPIO3_DR_CLEAR: usize = 0x401C_0088;
+const PIN: u32 = 1 << 12;
+
+const START_US: u32 = 1_100;
+// For the answer, all bits, and the low pulse after the last.
+const TIMEOUT_US: u32 = 10_000;
+// After the last bit, at the most.
+const QUIET_US: u32 = 200;
+// The answer, and the bits.
+const HIGH_PULSES: usize = 1 + 40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dht22Error {
+    /// Fewer edges than a measurement has came in time.
+    Timeout,
+    Checksum,
+}
+
+pub struct Dht22 {
+    _pin: SD_B0_00,
+    config: ClimateConfig,
+    next_at: i64,
+    climate: Option<Climate>,
+}
+
+impl Dht22 {
+    /// Measures every `interval_ms` of `config`, though not more often than
+    /// `MIN_INTERVAL_MS`.
+    pub fn new(pin: SD_B0_00, config: ClimateConfig) -> Self {
+        unsafe {
+            write32(CCM_CCGR4, read32(CCM_CCGR4) | CG8);
+            write32(PAD, PAD_CONFIG);
+            write32(PWMA0_SELECT_INPUT, SELECT_SD_B0_00);
+            write32(MUX, MUX_FLEXPWM);
+            write32(GPIO3_DR_CLEAR, PIN);
+
+            write16(SM0_CTRL, CTRL_FULL | CTRL_PRSC_DIV32);
+            write16(SM0_INIT, 0);
+            write16(SM0_VAL1, 0xFFFF);
+            write16(
+                SM0_CAPTCTRLA,
+                CAPTCTRLA_EDGA0_FALLING | CAPTCTRLA_EDGA1_RISING | CAPTCTRLA_ARMA,
+            );
+            write16(MCTRL, read16(MCTRL) | MCTRL_LDOK0 | MCTRL_RUN0);
+        }
+        Self {
+            _pin: pin,
+            config,
+            next_at: 0,
+            climate: None,
+        }
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        if now < self.next_at {
+            return;
+        }
+        self.next_at = now + self.config.interval_ms.max(MIN_INTERVAL_MS);
+        self.climate = match measure() {
+            Ok(climate) => Some(climate),
+            Err(err) => {
+                log::warn!("Failed to read the DHT22: {:?}", err);
+                None
+            }
+        };
+    }
+
+    pub fn register(&self, telemetry: &mut Telemetry) {
+        super::register(&self.config, telemetry);
+    }
+
+    /// Adds the last measurement, if it succeeded, to `reading`.
+    pub fn append_to(&self, reading: &mut Reading) {
+        super::append_to(&self.config, self.climate, reading);
+    }
+}
+
+fn measure() -> Result<Climate, Dht22Error> {
+    unsafe {
+        write32(MUX, MUX_GPIO);
+        write32(GPIO3_GDIR, read32(GPIO3_GDIR) | PIN);
+    }
+    delay_us(START_US);
+    // With the high of the release, before the answer, if the capture saw
+    // its rising edge.
+    let mut widths = [0u16; HIGH_PULSES + 1];
+    let captured = interrupt::free(|_| unsafe {
+        write16(SM0_STS, STS_CFA0 | STS_CFA1);
+        // Releases the pin, which the pull-up takes high, to the capture.
+        write32(GPIO3_GDIR, read32(GPIO3_GDIR) & !PIN);
+        write32(MUX, MUX_FLEXPWM);
+
+        let cycles_per_us = cpu_clock::hz() / 1_000_000;
+        let since = DWT::get_cycle_count();
+        let mut fell_at_cycle = since;
+        let mut rose_at = None;
+        let mut pulses = 0;
+        while pulses < widths.len() {
+            let cycle = DWT::get_cycle_count();
+            if cycle.wrapping_sub(since) > TIMEOUT_US * cycles_per_us
+                || (pulses >= HIGH_PULSES
+                    && cycle.wrapping_sub(fell_at_cycle) > QUIET_US * cycles_per_us)
+            {
+                break;
+            }
+            let status = read16(SM0_STS);
+            // Edges of either kind are at least 26 µs apart, so this sees
+            // a rising edge before the falling one after it.
+            if status & STS_CFA1 != 0 {
+                write16(SM0_STS, STS_CFA1);
+                rose_at = Some(read16(SM0_CVAL3));
+            }
+            if status & STS_CFA0 != 0 {
+                write16(SM0_STS, STS_CFA0);
+                let fell_at = read16(SM0_CVAL2);
+                fell_at_cycle = cycle;
+                if let Some(rose_at) = rose_at.take() {
+                    widths[pulses] = fell_at.wrapping_sub(rose_at);
+                    pulses += 1;
+                }
+            }
+        }
+        pulses
+    });
+    if captured < HIGH_PULSES {
+        return Err(Dht22Error::Timeout);
+    }
+    // The last bit is followed by a low pulse and the release, which don't
+    // fall again, so the last pulses are the answer and the bits.
+    let widths = &widths[captured - HIGH_PULSES..captured];
+    let threshold = widths[0] as u32 * 5 / 8;
+    let mut bytes = [0u8; 5];
+    for (i, width) in widths[1..].iter().enumerate() {
+        if *width as u32 > threshold {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    let sum = bytes[..4]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if sum != bytes[4] {
+        return Err(Dht22Error::Checksum);
+    }
+    let humidity = u16::from_be_bytes([bytes[0], bytes[1]]) as i64;
+    // In tenths, as a sign and a magnitude.
+    let magnitude = u16::from_be_bytes([bytes[2] & 0x7F, bytes[3]]) as i64;
+    let temperature = if bytes[2] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    };
+    Ok(Climate {
+        temperature: Decimal::new(temperature, 1, None),
+        humidity: Decimal::new(humidity, 1, None),
+    })
+}
+
+fn delay_us(us: u32) {
+    let since = DWT::get_cycle_count();
+    let cycles = us * (cpu_clock::hz() / 1_000_000);
+    while DWT::get_cycle_count().wrapping_sub(since) < cycles {}
+}
+
+unsafe fn read16(register: usize) -> u16 {
+    ptr::read_volatile(register as *const u16)
+}
+
+unsafe fn write16(register: usize, value: u16) {
+    ptr::write_volatile(register as *mut u16, value)
+}
+
+unsafe fn read32(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write32(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,116 @@
+//! An SHT30, SHT31 or SHT35, on the same I2C bus as the display.
+//!
+//! Every `interval_ms`, a single shot measurement is started, without clock
+//! stretching, so that the bus stays free for the display while it takes,
+//! and read once it is done. Each value comes with a CRC.
+
+use dsmr42::Decimal;
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use super::{Climate, ClimateConfig};
+use crate::{dsmr::Reading, i2c::I2c, telemetry::Telemetry};
+
+/// With ADDR low, or 0x45 with it high.
+pub const DEFAULT_ADDRESS: u8 = 0x44;
+
+// A single shot at high repeatability, which takes at most 15.5 ms.
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const MEASUREMENT_MS: i64 = 16;
+// The temperature and the humidity, each followed by its CRC.
+const RESULT_SZ: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle { next_at: i64 },
+    Measuring { done_at: i64 },
+}
+
+pub struct Sht3x {
+    address: u8,
+    config: ClimateConfig,
+    state: State,
+    climate: Option<Climate>,
+}
+
+impl Sht3x {
+    pub fn new(address: u8, config: ClimateConfig) -> Self {
+        Self {
+            address,
+            config,
+            state: State::Idle { next_at: 0 },
+            climate: None,
+        }
+    }
+
+    /// Starts a measurement when it is time to, and reads it once it is
+    /// done, waiting for the display to finish sending if it is.
+    pub fn poll(&mut self, now: i64, i2c: &mut I2c) {
+        match self.state {
+            State::Idle { next_at } if now >= next_at => match i2c.write(self.address, &MEASURE) {
+                Ok(()) => {
+                    self.state = State::Measuring {
+                        done_at: now + MEASUREMENT_MS,
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Failed to start an SHT3x measurement: {:?}", err);
+                    self.climate = None;
+                    self.state = State::Idle {
+                        next_at: now + self.config.interval_ms,
+                    };
+                }
+            },
+            State::Measuring { done_at } if now >= done_at => {
+                let mut result = [0; RESULT_SZ];
+                self.climate = match i2c.read(self.address, &mut result) {
+                    Ok(()) => decode(&result),
+                    Err(err) => {
+                        log::warn!("Failed to read an SHT3x measurement: {:?}", err);
+                        None
+                    }
+                };
+                self.state = State::Idle {
+                    next_at: now + self.config.interval_ms,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    pub fn register(&self, telemetry: &mut Telemetry) {
+        super::register(&self.config, telemetry);
+    }
+
+    /// Adds the last measurement, if it succeeded, to `reading`.
+    pub fn append_to(&self, reading: &mut Reading) {
+        super::append_to(&self.config, self.climate, reading);
+    }
+}
+
+fn decode(result: &[u8; RESULT_SZ]) -> Option<Climate> {
+    if crc8(&result[0..2]) != result[2] || crc8(&result[3..5]) != result[5] {
+        log::warn!("SHT3x measurement failed its CRC: {:02X?}", result);
+        return None;
+    }
+    let temperature = u16::from_be_bytes([result[0], result[1]]) as i64;
+    let humidity = u16::from_be_bytes([result[3], result[4]]) as i64;
+    // To the hundredth, of -45 to 130 °C and 0 to 100%.
+    Some(Climate {
+        temperature: Decimal::new(-4_500 + 17_500 * temperature / 65_535, 2, None),
+        humidity: Decimal::new(10_000 * humidity / 65_535, 2, None),
+    })
+}
+
+// With the polynomial x^8 + x^5 + x^4 + 1, most significant bit first, from
+// 0xFF.
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0xFF, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
@@ -1,9 +1,31 @@
+use core::ptr;
+
 use smoltcp::time::Instant;
 use teensy4_bsp::hal::{
-    ccm::{self, perclk, IPGFrequency},
-    gpt::{self, Mode, GPT},
+    ccm::{self, perclk},
+    gpt::{self, GPT},
 };
 
+/// The rate at which `ticks` counts.
+pub const TICKS_PER_MS: u32 = 8000;
+
+// The `Clock` runs on GPT2, which counts the 24 MHz crystal rather than the
+// peripheral clock, since that changes along with the core clock.
+const GPT2: usize = 0x401F_0000;
+const GPT_CR: usize = GPT2;
+const GPT_PR: usize = GPT2 + 0x04;
+const GPT_SR: usize = GPT2 + 0x08;
+const CR_EN: u32 = 1 << 0;
+const CR_ENMOD: u32 = 1 << 1;
+const CR_WAITEN: u32 = 1 << 3;
+const CR_CLKSRC_24M: u32 = 0b101 << 6;
+const CR_FRR: u32 = 1 << 9;
+const CR_EN_24M: u32 = 1 << 10;
+const CR_SWR: u32 = 1 << 15;
+// 24 MHz divided by 3.
+const PR_PRESCALER24M: u32 = 2 << 12;
+const SR_ALL: u32 = 0x3F;
+
 pub struct Clock {
     gpt: GPT,
     rollover_count: u32,
@@ -12,19 +34,31 @@ pub struct Clock {
 impl Clock {
     pub fn init(
         perclk: perclk::Multiplexer,
-        ipg: IPGFrequency,
         handle: &mut ccm::Handle,
         gpt: gpt::Unclocked,
     ) -> Self {
-        let mut clk_cfg =
-            perclk.configure(handle, perclk::PODF::DIVIDE_10, perclk::CLKSEL::IPG(ipg));
+        // The crystal as well, for the PIT that paces the ADC.
+        let mut clk_cfg = perclk.configure(handle, perclk::PODF::DIVIDE_1, perclk::CLKSEL::OSC);
 
-        let mut gpt = gpt.clock(&mut clk_cfg);
-        gpt.set_mode(Mode::FreeRunning);
-        gpt.set_enable(true);
+        // Only for the clock gate; the rest of the configuration is ours.
+        let gpt = gpt.clock(&mut clk_cfg);
+        unsafe {
+            // Changing the clock source takes a reset, with the GPT disabled.
+            write(GPT_CR, 0);
+            write(GPT_CR, CR_SWR);
+            while read(GPT_CR) & CR_SWR != 0 {}
+            write(GPT_PR, PR_PRESCALER24M);
+            // Free running, and also counting while the core sleeps.
+            write(
+                GPT_CR,
+                CR_EN_24M | CR_CLKSRC_24M | CR_FRR | CR_WAITEN | CR_ENMOD,
+            );
+            write(GPT_SR, SR_ALL);
+            write(GPT_CR, read(GPT_CR) | CR_EN);
+        }
         log::debug!(
             "GPT rolls over in {} seconds",
-            (gpt.clock_period() * u32::max_value()).as_secs()
+            u32::max_value() / (TICKS_PER_MS * 1000)
         );
         Self {
             gpt,
@@ -46,10 +80,18 @@ impl Clock {
             log::debug!("Clock rolled over to {}", self.rollover_count);
         }
         let total_ticks = (self.rollover_count as i64) << 32 | self.gpt.count() as i64;
-        total_ticks / 7500
+        total_ticks / TICKS_PER_MS as i64
     }
 
     pub fn instant(&mut self) -> Instant {
         Instant::from_millis(self.millis())
     }
 }
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
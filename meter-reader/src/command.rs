@@ -0,0 +1,374 @@
+//! The commands of the console, and how they are parsed from the lines that
+//! are entered.
+
+use arrayvec::ArrayString;
+use core::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+use log::LevelFilter;
+
+use crate::{
+    config::{Config, IpConfig},
+    rules::{Quantity, Rule, MAX_RULES},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    ShowTelegram,
+    ShowConfig,
+    ShowCrash,
+    ShowVersion,
+    Stats(Option<Subsystem>),
+    Set(Setting),
+    ShowLog,
+    /// The level of a module, `None` for the default. `None` as the level
+    /// resets the module to the default.
+    Log(Option<ModuleName>, Option<LevelFilter>),
+    Reboot,
+    Bootloader,
+    Update,
+}
+
+pub type ModuleName = ArrayString<[u8; 32]>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Uart,
+    Parse,
+    Diag,
+    Tasks,
+    Idle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setting {
+    DsmrBaud(u32),
+    Ip(IpConfig),
+    MqttHost([u8; 4]),
+    MqttPort(u16),
+    ReportInterval(u32),
+    /// From 0.
+    Rule(usize, Option<Rule>),
+}
+
+impl Setting {
+    /// Changes the setting in `config`.
+    pub fn apply(self, config: &mut Config) {
+        match self {
+            Setting::DsmrBaud(baud) => config.dsmr_baud = baud,
+            Setting::Ip(ip_config) => config.ip_config = ip_config,
+            Setting::MqttHost(addr) => config.mqtt_broker_addr = addr,
+            Setting::MqttPort(port) => config.mqtt_broker_port = port,
+            Setting::ReportInterval(interval) => config.report_interval_ms = interval,
+            Setting::Rule(index, rule) => config.rules[index] = rule,
+        }
+    }
+}
+
+/// Parses a line entered in the console, or says what is wrong with it.
+pub fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next(), words.next(), words.next()) {
+        (Some("help"), None, _) => Command::Help,
+        (Some("show"), Some("telegram"), None) => Command::ShowTelegram,
+        (Some("show"), Some("config"), None) => Command::ShowConfig,
+        (Some("show"), Some("crash"), None) => Command::ShowCrash,
+        (Some("show"), Some("version"), None) => Command::ShowVersion,
+        (Some("stats"), None, _) => Command::Stats(None),
+        (Some("stats"), Some("uart"), None) => Command::Stats(Some(Subsystem::Uart)),
+        (Some("stats"), Some("parse"), None) => Command::Stats(Some(Subsystem::Parse)),
+        (Some("stats"), Some("diag"), None) => Command::Stats(Some(Subsystem::Diag)),
+        (Some("stats"), Some("tasks"), None) => Command::Stats(Some(Subsystem::Tasks)),
+        (Some("stats"), Some("idle"), None) => Command::Stats(Some(Subsystem::Idle)),
+        (Some("set"), Some(key), Some(value)) => {
+            Command::Set(parse_setting(key, value, words.next())?)
+        }
+        (Some("log"), None, _) => Command::ShowLog,
+        (Some("log"), Some(module), Some(level)) => {
+            let module = match module {
+                "default" => None,
+                _ => Some(ModuleName::from(module).map_err(|_| "Module name too long")?),
+            };
+            let level = match level {
+                "reset" => None,
+                _ => Some(LevelFilter::from_str(level).map_err(|_| "Unknown log level")?),
+            };
+            Command::Log(module, level)
+        }
+        (Some("reboot"), None, _) => Command::Reboot,
+        (Some("bootloader"), None, _) => Command::Bootloader,
+        (Some("update"), None, _) => Command::Update,
+        _ => return Err("Unknown command"),
+    };
+    if words.next().is_some() {
+        return Err("Too many arguments");
+    }
+    Ok(command)
+}
+
+// `extra` is the gateway of a static IP configuration.
+fn parse_setting(key: &str, value: &str, extra: Option<&str>) -> Result<Setting, &'static str> {
+    if extra.is_some() && key != "ip" {
+        return Err("Too many arguments");
+    }
+    let setting = match key {
+        "dsmr.baud" => Setting::DsmrBaud(value.parse().map_err(|_| "Invalid baud rate")?),
+        "ip" if value == "dhcp" && extra.is_none() => Setting::Ip(IpConfig::Dhcp),
+        "ip" => {
+            let mut parts = value.splitn(2, '/');
+            let address = parts.next().and_then(parse_ipv4);
+            let prefix_len = parts.next().and_then(|len| len.parse().ok());
+            let gateway = extra.and_then(parse_ipv4);
+            match (address, prefix_len, gateway) {
+                (Some(address), Some(prefix_len), Some(gateway)) if prefix_len <= 32 => {
+                    Setting::Ip(IpConfig::Static {
+                        address,
+                        prefix_len,
+                        gateway,
+                    })
+                }
+                _ => return Err("Expected `dhcp` or `<address>/<prefix length> <gateway>`"),
+            }
+        }
+        "mqtt.host" => Setting::MqttHost(parse_ipv4(value).ok_or("Invalid IPv4 address")?),
+        "mqtt.port" => Setting::MqttPort(value.parse().map_err(|_| "Invalid port")?),
+        "report.interval" => {
+            Setting::ReportInterval(value.parse().map_err(|_| "Invalid interval")?)
+        }
+        _ if key.starts_with("rule.") => {
+            let index = key["rule.".len()..]
+                .parse::<usize>()
+                .ok()
+                .filter(|n| (1..=MAX_RULES).contains(n))
+                .ok_or("Unknown rule")?;
+            let rule =
+                match value {
+                    "none" => None,
+                    _ => Some(parse_rule(value).ok_or(
+                        "Expected `none` or `<relay>:<quantity><op><threshold>:<seconds>`",
+                    )?),
+                };
+            Setting::Rule(index - 1, rule)
+        }
+        _ => return Err("Unknown setting"),
+    };
+    Ok(setting)
+}
+
+// Such as `1:returned>1000:300`.
+fn parse_rule(value: &str) -> Option<Rule> {
+    let mut parts = value.split(':');
+    let relay = parts.next()?.parse().ok()?;
+    let condition = parts.next()?;
+    let delay_s = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let at = condition.find(['>', '<'])?;
+    let quantity = *Quantity::ALL
+        .iter()
+        .find(|quantity| quantity.name() == &condition[..at])?;
+    Some(Rule {
+        relay,
+        quantity,
+        above: condition[at..].starts_with('>'),
+        threshold: condition[at + 1..].parse().ok()?,
+        delay_s,
+    })
+}
+
+fn parse_ipv4(value: &str) -> Option<[u8; 4]> {
+    let mut addr = [0; 4];
+    let mut parts = value.split('.');
+    for octet in addr.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    Some(addr).filter(|_| parts.next().is_none())
+}
+
+/// Writes `config` as `set` takes it, a setting per line.
+pub fn write_config<W: Write>(writer: &mut W, config: &Config) -> fmt::Result {
+    let host = config.mqtt_broker_addr;
+    write!(writer, "dsmr.baud {}\r\n", config.dsmr_baud)?;
+    match config.ip_config {
+        IpConfig::Dhcp => writer.write_str("ip dhcp\r\n")?,
+        IpConfig::Static {
+            address: a,
+            prefix_len,
+            gateway: g,
+        } => write!(
+            writer,
+            "ip {}.{}.{}.{}/{} {}.{}.{}.{}\r\n",
+            a[0], a[1], a[2], a[3], prefix_len, g[0], g[1], g[2], g[3]
+        )?,
+    }
+    write!(
+        writer,
+        "mqtt.host {}.{}.{}.{}\r\n",
+        host[0], host[1], host[2], host[3]
+    )?;
+    write!(writer, "mqtt.port {}\r\n", config.mqtt_broker_port)?;
+    write!(writer, "report.interval {}\r\n", config.report_interval_ms)?;
+    for (i, rule) in config.rules.iter().enumerate() {
+        match rule {
+            Some(rule) => write!(
+                writer,
+                "rule.{} {}:{}{}{}:{}\r\n",
+                i + 1,
+                rule.relay,
+                rule.quantity.name(),
+                if rule.above { '>' } else { '<' },
+                rule.threshold,
+                rule.delay_s
+            )?,
+            None => write!(writer, "rule.{} none\r\n", i + 1)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    const RULE: Rule = Rule {
+        relay: 1,
+        quantity: Quantity::PowerReturned,
+        above: true,
+        threshold: 1000,
+        delay_s: 300,
+    };
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Ok(Command::Help), parse(" help "));
+        assert_eq!(
+            Ok(Command::Stats(Some(Subsystem::Tasks))),
+            parse("stats tasks")
+        );
+        assert_eq!(
+            Ok(Command::Log(None, Some(LevelFilter::Debug))),
+            parse("log default debug")
+        );
+        assert_eq!(
+            Ok(Command::Log(Some(ModuleName::from("mqtt").unwrap()), None)),
+            parse("log mqtt reset")
+        );
+        assert_eq!(Err("Unknown command"), parse("show everything"));
+        assert_eq!(Err("Too many arguments"), parse("set mqtt.port 1883 1884"));
+        assert_eq!(Err("Unknown log level"), parse("log mqtt loud"));
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        assert_eq!(
+            Ok(Setting::DsmrBaud(9600)),
+            parse_setting("dsmr.baud", "9600", None)
+        );
+        assert_eq!(
+            Ok(Setting::MqttHost([10, 0, 0, 2])),
+            parse_setting("mqtt.host", "10.0.0.2", None)
+        );
+        assert_eq!(
+            Err("Invalid IPv4 address"),
+            parse_setting("mqtt.host", "10.0.0.256", None)
+        );
+        assert_eq!(
+            Err("Invalid IPv4 address"),
+            parse_setting("mqtt.host", "10.0.0.2.1", None)
+        );
+        assert_eq!(
+            Err("Unknown setting"),
+            parse_setting("mqtt.user", "me", None)
+        );
+    }
+
+    #[test]
+    fn ip_setting_is_dhcp_or_static() {
+        assert_eq!(
+            Ok(Setting::Ip(IpConfig::Dhcp)),
+            parse_setting("ip", "dhcp", None)
+        );
+        let ip_config = IpConfig::Static {
+            address: [192, 168, 1, 20],
+            prefix_len: 24,
+            gateway: [192, 168, 1, 1],
+        };
+        assert_eq!(
+            Ok(Setting::Ip(ip_config)),
+            parse_setting("ip", "192.168.1.20/24", Some("192.168.1.1"))
+        );
+        for (value, gateway) in [
+            ("192.168.1.20/24", None),
+            ("192.168.1.20/33", Some("192.168.1.1")),
+            ("192.168.1.20", Some("192.168.1.1")),
+        ]
+        .iter()
+        {
+            assert!(parse_setting("ip", value, *gateway).is_err());
+        }
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        assert_eq!(
+            Ok(Setting::Rule(0, Some(RULE))),
+            parse_setting("rule.1", "1:returned>1000:300", None)
+        );
+        let rule = Rule {
+            relay: 2,
+            quantity: Quantity::PowerNet,
+            above: false,
+            threshold: -500,
+            delay_s: 0,
+        };
+        assert_eq!(Some(rule), parse_rule("2:net<-500:0"));
+        assert_eq!(
+            Ok(Setting::Rule(3, None)),
+            parse_setting("rule.4", "none", None)
+        );
+        assert_eq!(Err("Unknown rule"), parse_setting("rule.0", "none", None));
+        assert_eq!(Err("Unknown rule"), parse_setting("rule.5", "none", None));
+        for value in &["1:returned=1000:300", "1:solar>1000:300", "1:returned>1000"] {
+            assert_eq!(None, parse_rule(value));
+        }
+    }
+
+    #[test]
+    fn written_config_is_parsed_back() {
+        let mut config = Config {
+            dsmr_baud: 115_200,
+            ip_config: IpConfig::Static {
+                address: [192, 168, 1, 20],
+                prefix_len: 24,
+                gateway: [192, 168, 1, 1],
+            },
+            mqtt_broker_addr: [10, 0, 0, 2],
+            mqtt_broker_port: 1883,
+            report_interval_ms: 10_000,
+            rules: [None; MAX_RULES],
+        };
+        config.rules[0] = Some(RULE);
+        let mut written = String::new();
+        write_config(&mut written, &config).unwrap();
+
+        let mut parsed = Config {
+            dsmr_baud: 0,
+            ip_config: IpConfig::Dhcp,
+            mqtt_broker_addr: [0; 4],
+            mqtt_broker_port: 0,
+            report_interval_ms: 0,
+            rules: [Some(RULE); MAX_RULES],
+        };
+        for line in written.split_terminator("\r\n") {
+            match parse(&std::format!("set {}", line)) {
+                Ok(Command::Set(setting)) => setting.apply(&mut parsed),
+                other => panic!("{:?} for {:?}", other, line),
+            }
+        }
+        assert_eq!(config, parsed);
+    }
+}
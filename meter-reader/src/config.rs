@@ -0,0 +1,198 @@
+//! Settings that can be changed without reflashing, and how they are
+//! encoded to be stored in flash by the firmware's `ConfigStore`.
+//!
+//! The settings of version 1, from before the relay rules, still decode,
+//! without rules.
+
+use crate::rules::{Quantity, Rule, MAX_RULES};
+
+/// Incremented whenever the encoding of the settings changes. Settings of an
+/// unknown version are ignored.
+pub const SCHEMA_VERSION: u8 = 2;
+const V1_SETTINGS_SZ: usize = 24;
+// The relay, the quantity and comparison, the threshold, and the delay.
+const RULE_SZ: usize = 8;
+/// Of the settings of `SCHEMA_VERSION`, as they are encoded.
+pub const SETTINGS_SZ: usize = V1_SETTINGS_SZ + RULE_SZ * MAX_RULES;
+const NO_RULE: u8 = 0xFF;
+const RULE_ABOVE: u8 = 1 << 7;
+
+/// How the interface gets its IPv4 address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpConfig {
+    Dhcp,
+    Static {
+        address: [u8; 4],
+        prefix_len: u8,
+        gateway: [u8; 4],
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// The baud rate of the P1 port, if it isn't detected automatically.
+    pub dsmr_baud: u32,
+    pub ip_config: IpConfig,
+    pub mqtt_broker_addr: [u8; 4],
+    pub mqtt_broker_port: u16,
+    /// How often readings are sampled for InfluxDB, in ms.
+    pub report_interval_ms: u32,
+    /// Of the relays.
+    pub rules: [Option<Rule>; MAX_RULES],
+}
+
+impl Config {
+    /// As of `SCHEMA_VERSION`.
+    pub fn encode(&self) -> [u8; SETTINGS_SZ] {
+        let mut buf = [0; SETTINGS_SZ];
+        buf[0..4].copy_from_slice(&self.dsmr_baud.to_le_bytes());
+        if let IpConfig::Static {
+            address,
+            prefix_len,
+            gateway,
+        } = self.ip_config
+        {
+            buf[4] = 1;
+            buf[5..9].copy_from_slice(&address);
+            buf[9] = prefix_len;
+            buf[10..14].copy_from_slice(&gateway);
+        }
+        buf[14..18].copy_from_slice(&self.mqtt_broker_addr);
+        buf[18..20].copy_from_slice(&self.mqtt_broker_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.report_interval_ms.to_le_bytes());
+        for (rule, buf) in self
+            .rules
+            .iter()
+            .zip(buf[V1_SETTINGS_SZ..].chunks_exact_mut(RULE_SZ))
+        {
+            match rule {
+                Some(rule) => {
+                    buf[0] = rule.relay;
+                    buf[1] = rule.quantity as u8 | if rule.above { RULE_ABOVE } else { 0 };
+                    buf[2..6].copy_from_slice(&rule.threshold.to_le_bytes());
+                    buf[6..8].copy_from_slice(&rule.delay_s.to_le_bytes());
+                }
+                None => buf[0] = NO_RULE,
+            }
+        }
+        buf
+    }
+
+    /// Returns `None` if `buf` isn't valid settings of `version`.
+    pub fn decode(version: u8, buf: &[u8]) -> Option<Self> {
+        match (version, buf.len()) {
+            (1, V1_SETTINGS_SZ) | (SCHEMA_VERSION, SETTINGS_SZ) => {}
+            _ => return None,
+        }
+        let u32_at =
+            |at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let addr_at = |at: usize| [buf[at], buf[at + 1], buf[at + 2], buf[at + 3]];
+        let ip_config = match buf[4] {
+            0 => IpConfig::Dhcp,
+            1 if buf[9] <= 32 => IpConfig::Static {
+                address: addr_at(5),
+                prefix_len: buf[9],
+                gateway: addr_at(10),
+            },
+            _ => return None,
+        };
+        let mut rules = [None; MAX_RULES];
+        for (rule, buf) in rules
+            .iter_mut()
+            .zip(buf[V1_SETTINGS_SZ..].chunks_exact(RULE_SZ))
+        {
+            if buf[0] == NO_RULE {
+                continue;
+            }
+            let quantity = *Quantity::ALL.get((buf[1] & !RULE_ABOVE) as usize)?;
+            *rule = Some(Rule {
+                relay: buf[0],
+                quantity,
+                above: buf[1] & RULE_ABOVE != 0,
+                threshold: i32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]),
+                delay_s: u16::from_le_bytes([buf[6], buf[7]]),
+            });
+        }
+        Some(Self {
+            dsmr_baud: u32_at(0),
+            ip_config,
+            mqtt_broker_addr: addr_at(14),
+            mqtt_broker_port: u16::from_le_bytes([buf[18], buf[19]]),
+            report_interval_ms: u32_at(20),
+            rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut rules = [None; MAX_RULES];
+        rules[1] = Some(Rule {
+            relay: 2,
+            quantity: Quantity::PowerNet,
+            above: false,
+            threshold: -1500,
+            delay_s: 300,
+        });
+        Config {
+            dsmr_baud: 115_200,
+            ip_config: IpConfig::Static {
+                address: [192, 168, 1, 20],
+                prefix_len: 24,
+                gateway: [192, 168, 1, 1],
+            },
+            mqtt_broker_addr: [10, 0, 0, 2],
+            mqtt_broker_port: 1883,
+            report_interval_ms: 10_000,
+            rules,
+        }
+    }
+
+    #[test]
+    fn encoded_config_decodes() {
+        let config = config();
+        assert_eq!(
+            Some(config),
+            Config::decode(SCHEMA_VERSION, &config.encode())
+        );
+        let config = Config {
+            ip_config: IpConfig::Dhcp,
+            rules: [None; MAX_RULES],
+            ..config
+        };
+        assert_eq!(
+            Some(config),
+            Config::decode(SCHEMA_VERSION, &config.encode())
+        );
+    }
+
+    #[test]
+    fn version_1_decodes_without_rules() {
+        let config = config();
+        let buf = config.encode();
+        let expected = Config {
+            rules: [None; MAX_RULES],
+            ..config
+        };
+        assert_eq!(Some(expected), Config::decode(1, &buf[..V1_SETTINGS_SZ]));
+    }
+
+    #[test]
+    fn invalid_settings_are_ignored() {
+        let buf = config().encode();
+        // Of a version that is too new, or of the wrong length.
+        assert_eq!(None, Config::decode(SCHEMA_VERSION + 1, &buf));
+        assert_eq!(None, Config::decode(SCHEMA_VERSION, &buf[..V1_SETTINGS_SZ]));
+        assert_eq!(None, Config::decode(1, &buf));
+
+        let mut prefix_too_long = buf;
+        prefix_too_long[9] = 33;
+        assert_eq!(None, Config::decode(SCHEMA_VERSION, &prefix_too_long));
+        let mut unknown_quantity = buf;
+        unknown_quantity[V1_SETTINGS_SZ + RULE_SZ + 1] = 0x7F;
+        assert_eq!(None, Config::decode(SCHEMA_VERSION, &unknown_quantity));
+    }
+}
@@ -0,0 +1,121 @@
+//! Settings that can be changed without reflashing, stored in flash.
+//!
+//! Like Teensyduino's EEPROM emulation, every save appends a new record to
+//! one of two sectors, and the other sector is only erased once the first is
+//! full. A record is its schema version, the length of its settings, a `u32`
+//! generation that is one more than that of the record saved before it, the
+//! settings, encoded as in `config`, and the CRC-16 of all of these, each
+//! little-endian. The valid record with the highest generation wins.
+//!
+//! Changes take effect after a reboot.
+
+use crate::{
+    config::{Config, SCHEMA_VERSION, SETTINGS_SZ},
+    flash::{Flash, SECTOR_SZ},
+};
+
+const RECORD_HEADER_SZ: usize = 6;
+const RECORD_SZ: usize = RECORD_HEADER_SZ + SETTINGS_SZ + 2;
+const ERASED: u8 = 0xFF;
+
+pub struct ConfigStore {
+    sectors: [usize; 2],
+    /// The sector that the latest record is in, and where the next one goes.
+    active: usize,
+    offset: usize,
+    generation: u32,
+    config: Config,
+}
+
+impl ConfigStore {
+    /// Loads the latest saved config from `sectors`, or `defaults` if none
+    /// was ever saved.
+    pub fn load(flash: &Flash, sectors: [usize; 2], defaults: Config) -> Self {
+        let mut store = Self {
+            sectors,
+            active: 0,
+            offset: SECTOR_SZ,
+            generation: 0,
+            config: defaults,
+        };
+        let mut found = false;
+        let mut ends = [0; 2];
+        for (index, sector) in sectors.iter().enumerate() {
+            let mut offset = 0;
+            while offset + RECORD_HEADER_SZ <= SECTOR_SZ {
+                let header = flash.read(*sector, offset, RECORD_HEADER_SZ);
+                let len = RECORD_HEADER_SZ + header[1] as usize + 2;
+                if header[0] == ERASED || offset + len > SECTOR_SZ {
+                    break;
+                }
+                let record = flash.read(*sector, offset, len);
+                offset += len;
+                let generation = u32::from_le_bytes([record[2], record[3], record[4], record[5]]);
+                let crc = u16::from_le_bytes([record[len - 2], record[len - 1]]);
+                if dsmr42::crc16(&record[..len - 2]) != crc
+                    || (found && generation <= store.generation)
+                {
+                    continue;
+                }
+                let settings = &record[RECORD_HEADER_SZ..len - 2];
+                if let Some(config) = Config::decode(record[0], settings) {
+                    found = true;
+                    store.generation = generation;
+                    store.config = config;
+                    store.active = index;
+                }
+            }
+            ends[index] = offset;
+        }
+        if found {
+            store.offset = ends[store.active];
+            log::info!(
+                "Loaded config generation {}: {:?}",
+                store.generation,
+                store.config
+            );
+        } else {
+            log::info!("No saved config, using defaults");
+        }
+        store
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Saves `config`, unless it is what was saved before.
+    pub fn save(&mut self, flash: &mut Flash, config: Config) {
+        if config == self.config && self.generation > 0 {
+            return;
+        }
+        let generation = self.generation.wrapping_add(1);
+        let mut record = [0; RECORD_SZ];
+        record[0] = SCHEMA_VERSION;
+        record[1] = SETTINGS_SZ as u8;
+        record[2..6].copy_from_slice(&generation.to_le_bytes());
+        record[RECORD_HEADER_SZ..RECORD_SZ - 2].copy_from_slice(&config.encode());
+        let crc = dsmr42::crc16(&record[..RECORD_SZ - 2]);
+        record[RECORD_SZ - 2..].copy_from_slice(&crc.to_le_bytes());
+
+        // Space that isn't erased was left behind by an interrupted save.
+        let sector = self.sectors[self.active];
+        let fits = self.offset + RECORD_SZ <= SECTOR_SZ
+            && flash
+                .read(sector, self.offset, RECORD_SZ)
+                .iter()
+                .all(|byte| *byte == ERASED);
+        if !fits {
+            // The latest record stays in the current sector until the new one
+            // has been written to the other.
+            self.active = 1 - self.active;
+            self.offset = 0;
+            flash.erase(self.sectors[self.active]);
+        }
+        flash.program(self.sectors[self.active], self.offset, &record);
+        self.offset += RECORD_SZ;
+        self.generation = generation;
+        self.config = config;
+        log::info!("Saved config generation {}", generation);
+    }
+}
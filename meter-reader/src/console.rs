@@ -0,0 +1,305 @@
+//! Line-based shell on a serial port, for configuring and inspecting the
+//! meter reader in the field.
+
+use arrayvec::ArrayString;
+use core::fmt::{self, Write};
+use embedded_hal::serial;
+
+use crate::{
+    build_info::BUILD_INFO,
+    command::{self, Command, Subsystem},
+    config_store::ConfigStore,
+    dsmr::Reading,
+    flash::Flash,
+    json, log_filter,
+    metrics::{self, Diagnostics},
+    panic::Crash,
+    ring_buffer::RingBuffer,
+    scheduler::TaskStats,
+    system,
+    telemetry::Telemetry,
+    watchdog::Task,
+};
+
+const LINE_SZ: usize = 128;
+// Large enough for the metrics, which are the longest output. Output that
+// doesn't fit is dropped.
+const OUTPUT_SZ: usize = 4096;
+const PROMPT: &str = "> ";
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+const HELP: &str = "\
+show telegram        the latest reading as JSON\r
+show config          the saved settings\r
+show crash           the crash before the last reset, if any\r
+show version         the version of the firmware, and what it was built with\r
+stats [uart|parse|diag|tasks|idle]\r
+                     diagnostics\r
+log                  the log level of every module\r
+log <module> <level> change the log level of a module, or `default`, until\r
+                     the next reset, `reset` returns it to the default\r
+set <key> <value>    change and save a setting, keys as in `show config`\r
+set rule.<n> <relay>:<quantity><op><threshold>:<seconds>\r
+                     a relay rule, such as `1:returned>1000:300`, with\r
+                     `delivered`, `returned`, `net` or `tariff` as the quantity\r
+                     and `>` or `<` as op, or `none`\r
+reboot               restart, which applies changed settings\r
+bootloader           restart into the bootloader, to be reflashed over USB\r
+update               download a firmware update, to be installed at the next\r
+                     reboot\r
+";
+
+/// Serves these commands:
+///
+/// - `show telegram`: the latest reading as JSON
+/// - `show config`: the saved settings
+/// - `show crash`: the crash that happened before the last reset, if any
+/// - `show version`: the `BuildInfo` of the firmware
+/// - `stats`: all diagnostics as Prometheus metrics, or those of the UART,
+///   the parser, the stack and main loop, the tasks of the `Scheduler` or
+///   sleeping with `stats uart`, `stats parse`, `stats diag`, `stats tasks`
+///   and `stats idle`
+/// - `set <key> <value>`: changes and saves a setting
+/// - `log [<module> <level>]`: shows or changes the log levels of
+///   `log_filter`
+/// - `reboot`
+/// - `bootloader`: restarts into the bootloader, to be reflashed over USB
+/// - `update`: asks for a firmware update, see `take_update_request`
+pub struct Console<S> {
+    serial: S,
+    line: ArrayString<[u8; LINE_SZ]>,
+    // Whether the line was too long, and is ignored until it ends.
+    overflowed: bool,
+    last_byte: u8,
+    output: Output,
+    latest: Option<Reading>,
+    diagnostics: Diagnostics,
+    last_crash: Option<Crash>,
+    update_requested: bool,
+}
+
+impl<S> Console<S>
+where
+    S: serial::Read<u8> + serial::Write<u8>,
+{
+    pub fn new(serial: S) -> Self {
+        let mut console = Self {
+            serial,
+            line: ArrayString::new(),
+            overflowed: false,
+            last_byte: 0,
+            output: Output(RingBuffer::new()),
+            latest: None,
+            diagnostics: Diagnostics::default(),
+            last_crash: None,
+            update_requested: false,
+        };
+        let _ = write!(
+            console.output,
+            "\r\nMeter reader console, type `help`\r\n{}",
+            PROMPT
+        );
+        console
+    }
+
+    pub fn update(&mut self, reading: &Reading) {
+        self.latest = Some(reading.clone());
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.diagnostics = diagnostics;
+    }
+
+    pub fn set_last_crash(&mut self, crash: Option<Crash>) {
+        self.last_crash = crash;
+    }
+
+    /// Whether `update` was entered since the last call.
+    pub fn take_update_request(&mut self) -> bool {
+        core::mem::replace(&mut self.update_requested, false)
+    }
+
+    /// Handles the input received since the last call, and sends as much of
+    /// the output as the serial port takes.
+    pub fn poll(&mut self, flash: &mut Flash, config: &mut ConfigStore) {
+        while let Ok(byte) = self.serial.read() {
+            self.receive(byte, flash, config);
+        }
+        while let Some(byte) = self.output.0.peek().first().copied() {
+            if self.serial.write(byte).is_err() {
+                break;
+            }
+            self.output.0.consume(1);
+        }
+    }
+
+    fn receive(&mut self, byte: u8, flash: &mut Flash, config: &mut ConfigStore) {
+        let last = self.last_byte;
+        self.last_byte = byte;
+        match byte {
+            // Terminals end lines with either, or both.
+            b'\n' if last == b'\r' => {}
+            b'\r' | b'\n' => {
+                let _ = self.output.write_str("\r\n");
+                if self.overflowed {
+                    let _ = write!(self.output, "Lines are at most {} characters\r\n", LINE_SZ);
+                } else if !self.line.trim().is_empty() {
+                    let line = self.line;
+                    self.execute(&line, flash, config);
+                }
+                self.line.clear();
+                self.overflowed = false;
+                let _ = self.output.write_str(PROMPT);
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    let _ = self.output.write_str("\x08 \x08");
+                }
+            }
+            b' '..=b'~' => {
+                if self.line.try_push(byte as char).is_ok() {
+                    self.output.0.push(byte);
+                } else {
+                    self.overflowed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self, line: &str, flash: &mut Flash, store: &mut ConfigStore) {
+        let command = match command::parse(line) {
+            Ok(command) => command,
+            Err(err) => {
+                let _ = write!(self.output, "{}, type `help`\r\n", err);
+                return;
+            }
+        };
+        log::info!("Console command: {:?}", command);
+        let out = &mut self.output;
+        let written = match command {
+            Command::Help => out.write_str(HELP),
+            Command::ShowTelegram => match &self.latest {
+                Some(reading) => {
+                    json::write_reading(out, reading).and_then(|()| out.write_str("\r\n"))
+                }
+                None => out.write_str("No telegram received yet\r\n"),
+            },
+            Command::ShowConfig => command::write_config(out, &store.config()),
+            Command::ShowCrash => match &self.last_crash {
+                Some(crash) => write!(out, "{}\r\n", crash),
+                None => out.write_str("No crash before the last reset\r\n"),
+            },
+            Command::ShowVersion => write!(out, "{}\r\n", BUILD_INFO),
+            Command::Stats(None) => {
+                // Only the metrics of the meter reader itself.
+                let telemetry = Telemetry::new();
+                let sample = telemetry.sample(None, self.diagnostics.reader_state());
+                metrics::write_metrics(
+                    &mut Crlf(out),
+                    &telemetry,
+                    &sample,
+                    &[],
+                    &[],
+                    &self.diagnostics,
+                )
+            }
+            Command::Stats(Some(Subsystem::Uart)) => {
+                write!(Crlf(out), "{:#?}\n", self.diagnostics.uart)
+            }
+            Command::Stats(Some(Subsystem::Parse)) => {
+                write!(Crlf(out), "{:#?}\n", self.diagnostics.parse)
+            }
+            Command::Stats(Some(Subsystem::Diag)) => {
+                write!(Crlf(out), "{:#?}\n", self.diagnostics.diag)
+            }
+            Command::Stats(Some(Subsystem::Tasks)) => write_tasks(out, &self.diagnostics.tasks),
+            Command::Stats(Some(Subsystem::Idle)) => {
+                write!(Crlf(out), "{:#?}\n", self.diagnostics.idle)
+            }
+            Command::Set(setting) => {
+                let mut config = store.config();
+                setting.apply(&mut config);
+                store.save(flash, config);
+                out.write_str("Saved, `reboot` to apply\r\n")
+            }
+            Command::ShowLog => {
+                let mut written = Ok(());
+                log_filter::for_each(|module, level| {
+                    written = written.and_then(|()| {
+                        write!(out, "{} {}\r\n", module.unwrap_or("default"), level)
+                    });
+                });
+                written
+            }
+            Command::Log(None, Some(level)) => {
+                log_filter::set_default(level);
+                Ok(())
+            }
+            Command::Log(None, None) => out.write_str("The default level can't be reset\r\n"),
+            Command::Log(Some(module), Some(level)) => match log_filter::set(&module, level) {
+                Ok(()) => Ok(()),
+                Err(err) => write!(out, "Failed to set log level: {:?}\r\n", err),
+            },
+            Command::Log(Some(module), None) => {
+                log_filter::reset(&module);
+                Ok(())
+            }
+            Command::Reboot => system::reboot(),
+            Command::Bootloader => system::enter_bootloader(),
+            Command::Update => {
+                self.update_requested = true;
+                out.write_str("Downloading the update, follow the log for progress\r\n")
+            }
+        };
+        if written.is_err() {
+            let _ = self.output.write_str("\r\n(output truncated)\r\n");
+        }
+    }
+}
+
+fn write_tasks<W: Write>(writer: &mut W, tasks: &[TaskStats]) -> fmt::Result {
+    writer.write_str("task        runs  average us  max us  budget us  overruns\r\n")?;
+    for (task, stats) in Task::ALL.iter().zip(tasks) {
+        write!(
+            writer,
+            "{:<8} {:>7} {:>11} {:>7} {:>10} {:>9}\r\n",
+            task.name(),
+            stats.runs,
+            stats.average_us,
+            stats.max_us,
+            stats.budget_us,
+            stats.overruns
+        )?;
+    }
+    Ok(())
+}
+
+struct Output(RingBuffer<OUTPUT_SZ>);
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if !self.0.push(byte) {
+                return Err(fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Terminals need a carriage return before every line feed.
+struct Crlf<'a, W>(&'a mut W);
+
+impl<'a, W: Write> Write for Crlf<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str("\r\n")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Lowering the core clock while there is little to do, and raising it again
+//! when the network gets busy.
+//!
+//! Reading a telegram a second takes a fraction of what the core can do at
+//! 600 MHz, so most of the time it runs at `idle_hz`, at a lower voltage.
+//! Only the core, AHB and IPG clocks change: the UARTs and the `Clock` count
+//! the 24 MHz crystal, and SPI runs from PLL2, so baud rates and time keep
+//! going as they were.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use teensy4_bsp::hal::{
+    ccm::{Handle, PLL1},
+    dcdc::DCDC,
+};
+
+static HZ: AtomicU32 = AtomicU32::new(PLL1::ARM_HZ);
+
+/// The frequency the core runs at.
+pub fn hz() -> u32 {
+    HZ.load(Ordering::Relaxed)
+}
+
+pub struct CpuClock {
+    pll1: PLL1,
+    handle: Handle,
+    dcdc: DCDC,
+    idle_hz: u32,
+    idle_after_ms: i64,
+    last_busy: i64,
+    throttled: bool,
+}
+
+impl CpuClock {
+    /// Takes over the core clock, which must run at `PLL1::ARM_HZ`, and lowers
+    /// it to `idle_hz` once nothing has been busy for `idle_after_ms`.
+    pub fn new(pll1: PLL1, handle: Handle, dcdc: DCDC, idle_hz: u32, idle_after_ms: i64) -> Self {
+        Self {
+            pll1,
+            handle,
+            dcdc,
+            idle_hz,
+            idle_after_ms,
+            last_busy: 0,
+            throttled: false,
+        }
+    }
+
+    /// Keeps the clock at `idle_hz`, even when busy, while `throttled`, such
+    /// as when the die is too hot.
+    pub fn throttle(&mut self, throttled: bool) {
+        if throttled != self.throttled {
+            self.throttled = throttled;
+            if throttled {
+                self.set(self.idle_hz);
+            }
+        }
+    }
+
+    /// Raises the clock right away if `busy`, and lowers it once it hasn't
+    /// been busy for a while.
+    pub fn poll(&mut self, now: i64, busy: bool) {
+        if busy && !self.throttled {
+            self.last_busy = now;
+            self.set(PLL1::ARM_HZ);
+        } else if now - self.last_busy >= self.idle_after_ms {
+            self.set(self.idle_hz);
+        }
+    }
+
+    fn set(&mut self, hz: u32) {
+        if hz == HZ.load(Ordering::Relaxed) {
+            return;
+        }
+        // Also keeps IPG within its limit, and changes the core voltage to
+        // suit.
+        self.pll1
+            .set_arm_clock(hz, &mut self.handle, &mut self.dcdc);
+        HZ.store(hz, Ordering::Relaxed);
+        log::debug!("Core clock set to {} MHz", hz / 1_000_000);
+    }
+}
@@ -0,0 +1,322 @@
+//! Circular log of readings in flash, for when the broker can't be reached.
+//! Logged readings are replayed once it can be reached again.
+//!
+//! The log takes up a range of sectors, which are written one after the
+//! other, so that they all wear equally. Once the last one is full, the
+//! oldest is erased, dropping any readings in it that were never replayed.
+//!
+//! A sector starts with `MAGIC` and its `u32` sequence number, which is one
+//! more than that of the sector written before it. Records follow, each its
+//! length as a `u16`, the CRC-16 of its data, a state byte and the reading in
+//! the encoding of `binary`. All of these are little-endian. The state is
+//! `0xFF` as programmed, and cleared once the reading has been replayed, which
+//! the flash allows without erasing it. A length of `0xFFFF` marks the end of
+//! the sector.
+
+use core::ops::Range;
+
+use crate::{
+    binary,
+    dsmr::Reading,
+    flash::{Flash, SECTOR_SZ},
+};
+
+/// Readings that encode to more than this are not logged.
+pub const MAX_RECORD_SZ: usize = 512;
+// "P1LG"
+const MAGIC: u32 = 0x474C_3150;
+const SECTOR_HEADER_SZ: usize = 8;
+const RECORD_HEADER_SZ: usize = 5;
+const FREE: u16 = 0xFFFF;
+const PENDING: u8 = 0xFF;
+const REPLAYED: u8 = 0x00;
+
+/// Where a record is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    sector: usize,
+    // The sector's sequence number, which tells whether the record is still
+    // there.
+    sequence: u32,
+    offset: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Record {
+    len: usize,
+    state: u8,
+    intact: bool,
+}
+
+impl Position {
+    fn after(self, record: Record) -> Self {
+        Self {
+            offset: self.offset + RECORD_HEADER_SZ + record.len,
+            ..self
+        }
+    }
+}
+
+pub struct DataLog {
+    sectors: Range<usize>,
+    /// Where the next record goes.
+    head: Position,
+    /// There are no pending records before this.
+    tail: Position,
+    pending: u32,
+    dropped: u32,
+    interval: i64,
+    last_recorded_at: Option<i64>,
+}
+
+impl DataLog {
+    /// Picks up the log in `sectors` where it was left, or starts a new one.
+    /// Readings are logged at most once per `interval`, in ms.
+    pub fn mount(flash: &mut Flash, sectors: Range<usize>, interval: i64) -> Self {
+        assert!(sectors.len() >= 2);
+        let newest = sectors
+            .clone()
+            .filter_map(|sector| sequence(flash, sector).map(|sequence| (sequence, sector)))
+            .max();
+        let mut log = Self {
+            head: Position {
+                sector: sectors.start,
+                sequence: 0,
+                offset: SECTOR_SZ,
+            },
+            tail: Position {
+                sector: sectors.start,
+                sequence: 0,
+                offset: SECTOR_SZ,
+            },
+            sectors,
+            pending: 0,
+            dropped: 0,
+            interval,
+            last_recorded_at: None,
+        };
+        let (sequence, sector) = match newest {
+            Some(newest) => newest,
+            None => {
+                log::info!("Starting a new data log in flash");
+                log.start_sector(flash);
+                log.tail = log.head;
+                return log;
+            }
+        };
+        let mut head = Position {
+            sector,
+            sequence,
+            offset: SECTOR_HEADER_SZ,
+        };
+        while let Some(record) = record_at(flash, head) {
+            head = head.after(record);
+        }
+        log.head = head;
+
+        // The oldest sector is the first one after the newest that is in use.
+        let mut oldest = log.next(sector);
+        while sequence(flash, oldest).is_none() {
+            oldest = log.next(oldest);
+        }
+        log.tail = Position {
+            sector: oldest,
+            sequence: sequence(flash, oldest).unwrap_or(0),
+            offset: SECTOR_HEADER_SZ,
+        };
+        log.pending = log
+            .sectors
+            .clone()
+            .filter(|sector| sequence(flash, *sector).is_some())
+            .map(|sector| pending_in(flash, sector))
+            .sum();
+        log::info!("Mounted data log with {} pending readings", log.pending);
+        log
+    }
+
+    /// Logs `reading`, received at `now`, our own time, unless the previous
+    /// reading was logged less than an interval ago.
+    ///
+    /// This can take up to one sector erase, so it should be called right
+    /// after a telegram has been received, when there is time until the next
+    /// one.
+    pub fn record(&mut self, flash: &mut Flash, now: i64, reading: &Reading) {
+        if let Some(last) = self.last_recorded_at {
+            if now - last < self.interval {
+                return;
+            }
+        }
+        self.last_recorded_at = Some(now);
+        let mut buf = [0; RECORD_HEADER_SZ + MAX_RECORD_SZ];
+        let len = match binary::encode_reading(&mut buf[RECORD_HEADER_SZ..], reading) {
+            Ok(len) => len,
+            Err(_) => {
+                log::warn!("Reading does not fit in {} bytes", MAX_RECORD_SZ);
+                return;
+            }
+        };
+        let crc = dsmr42::crc16(&buf[RECORD_HEADER_SZ..RECORD_HEADER_SZ + len]);
+        buf[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[2..4].copy_from_slice(&crc.to_le_bytes());
+        buf[4] = PENDING;
+        let record = &buf[..RECORD_HEADER_SZ + len];
+
+        // Space that isn't erased was left behind by an interrupted write.
+        let head = self.head;
+        let fits = head.offset + record.len() <= SECTOR_SZ
+            && flash
+                .read(head.sector, head.offset, record.len())
+                .iter()
+                .all(|byte| *byte == 0xFF);
+        if !fits {
+            self.start_sector(flash);
+        }
+        flash.program(self.head.sector, self.head.offset, record);
+        self.head.offset += record.len();
+        self.pending = self.pending.saturating_add(1);
+    }
+
+    /// The oldest reading that has not been replayed, if any, in the encoding
+    /// of `binary`.
+    pub fn next_pending<'f>(&mut self, flash: &'f Flash) -> Option<(Position, &'f [u8])> {
+        while let Some((position, record)) = self.scan(flash, self.tail) {
+            self.tail = position;
+            if record.state == PENDING && record.intact {
+                let data = flash.read(
+                    position.sector,
+                    position.offset + RECORD_HEADER_SZ,
+                    record.len,
+                );
+                return Some((position, data));
+            }
+            self.tail = position.after(record);
+        }
+        self.tail = self.head;
+        None
+    }
+
+    /// Records that the reading at `position` has been replayed.
+    pub fn mark_replayed(&mut self, flash: &mut Flash, position: Position) {
+        // The sector may have been erased and reused in the meantime.
+        if sequence(flash, position.sector) != Some(position.sequence) {
+            return;
+        }
+        flash.program(position.sector, position.offset + 4, &[REPLAYED]);
+        self.pending = self.pending.saturating_sub(1);
+    }
+
+    /// The number of readings that have not been replayed.
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    /// The number of readings that were erased before they were replayed.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // The first record at or after `position`, up to the head.
+    fn scan(&self, flash: &Flash, mut position: Position) -> Option<(Position, Record)> {
+        loop {
+            if position.sector == self.head.sector && position.offset >= self.head.offset {
+                return None;
+            }
+            if let Some(record) = record_at(flash, position) {
+                return Some((position, record));
+            }
+            let sector = self.next(position.sector);
+            position = Position {
+                sector,
+                sequence: sequence(flash, sector).unwrap_or(0),
+                offset: SECTOR_HEADER_SZ,
+            };
+        }
+    }
+
+    // Erases the sector after the head, and continues there.
+    fn start_sector(&mut self, flash: &mut Flash) {
+        let sector = self.next(self.head.sector);
+        if sequence(flash, sector).is_some() {
+            let lost = pending_in(flash, sector);
+            if lost > 0 {
+                self.dropped = self.dropped.saturating_add(lost);
+                self.pending = self.pending.saturating_sub(lost);
+                log::warn!(
+                    "Data log is full, dropped {} readings ({} so far)",
+                    lost,
+                    self.dropped
+                );
+            }
+        }
+        if self.tail.sector == sector {
+            let oldest = self.next(sector);
+            self.tail = Position {
+                sector: oldest,
+                sequence: sequence(flash, oldest).unwrap_or(0),
+                offset: SECTOR_HEADER_SZ,
+            };
+        }
+        let sequence = self.head.sequence.wrapping_add(1);
+        let mut header = [0; SECTOR_HEADER_SZ];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        flash.erase(sector);
+        flash.program(sector, 0, &header);
+        self.head = Position {
+            sector,
+            sequence,
+            offset: SECTOR_HEADER_SZ,
+        };
+    }
+
+    fn next(&self, sector: usize) -> usize {
+        if sector + 1 == self.sectors.end {
+            self.sectors.start
+        } else {
+            sector + 1
+        }
+    }
+}
+
+// The sequence number of `sector`, if it is part of the log.
+fn sequence(flash: &Flash, sector: usize) -> Option<u32> {
+    let header = flash.read(sector, 0, SECTOR_HEADER_SZ);
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Some(sequence).filter(|_| magic == MAGIC)
+}
+
+fn record_at(flash: &Flash, position: Position) -> Option<Record> {
+    if position.offset + RECORD_HEADER_SZ > SECTOR_SZ {
+        return None;
+    }
+    let header = flash.read(position.sector, position.offset, RECORD_HEADER_SZ);
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let len = len as usize;
+    if len == FREE as usize || position.offset + RECORD_HEADER_SZ + len > SECTOR_SZ {
+        return None;
+    }
+    let crc = u16::from_le_bytes([header[2], header[3]]);
+    let data = flash.read(position.sector, position.offset + RECORD_HEADER_SZ, len);
+    Some(Record {
+        len,
+        state: header[4],
+        intact: dsmr42::crc16(data) == crc,
+    })
+}
+
+fn pending_in(flash: &Flash, sector: usize) -> u32 {
+    let mut position = Position {
+        sector,
+        sequence: 0,
+        offset: SECTOR_HEADER_SZ,
+    };
+    let mut pending = 0;
+    while let Some(record) = record_at(flash, position) {
+        if record.state == PENDING && record.intact {
+            pending += 1;
+        }
+        position = position.after(record);
+    }
+    pending
+}
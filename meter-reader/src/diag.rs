@@ -0,0 +1,125 @@
+//! Measures how much of the stack has been used, and how long main loop
+//! iterations take, so that buffers can be sized with some confidence.
+//!
+//! There is no heap, so the stack is the only memory whose use varies. It is
+//! painted with a pattern at boot, and the deepest word that no longer holds
+//! the pattern marks the highest the stack has grown. The stack grows down
+//! from `_stack_start` towards the end of `.bss`, which is how teensy4-rt
+//! lays out DTCM.
+
+use core::ptr;
+
+use cortex_m::{interrupt, register::msp};
+
+use crate::clock::TICKS_PER_MS;
+
+const PAINT: u32 = 0xCCCC_CCCC;
+// Left alone below the stack pointer while painting, for the painting itself.
+const PAINT_MARGIN: usize = 256;
+// Scanning the whole stack takes a while, so it isn't done every iteration.
+const STACK_CHECK_INTERVAL_MS: u32 = 1_000;
+// Iteration times are averaged over roughly this many iterations.
+const AVERAGE_SHIFT: u32 = 4;
+
+extern "C" {
+    static __ebss: u32;
+    static _stack_start: u32;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiagStats {
+    /// Bytes between the end of `.bss` and the top of the stack.
+    pub stack_size: usize,
+    /// The most the stack has grown, in bytes.
+    pub stack_used: usize,
+    pub loop_iterations: u32,
+    /// The average duration of recent main loop iterations.
+    pub loop_average_us: u32,
+    /// The longest main loop iteration since boot.
+    pub loop_max_us: u32,
+}
+
+pub struct Diag {
+    stats: DiagStats,
+    // The deepest word that isn't painted anymore.
+    stack_low: usize,
+    last_iteration: Option<u32>,
+    // In ticks, scaled up by `AVERAGE_SHIFT`.
+    average: u32,
+    since_stack_check: u32,
+}
+
+impl Diag {
+    /// Paints the unused part of the stack. This should happen first thing
+    /// in `main`, so that little of it is in use yet.
+    pub fn paint() -> Self {
+        let (bottom, top) = stack_bounds();
+        interrupt::free(|_| {
+            // An interrupt would use the stack below this frame.
+            let end = msp::read() as usize - PAINT_MARGIN;
+            for word in (bottom..end).step_by(4) {
+                unsafe { ptr::write_volatile(word as *mut u32, PAINT) };
+            }
+        });
+        let mut diag = Self {
+            stats: DiagStats {
+                stack_size: top - bottom,
+                ..DiagStats::default()
+            },
+            stack_low: top,
+            last_iteration: None,
+            average: 0,
+            since_stack_check: 0,
+        };
+        diag.check_stack();
+        diag
+    }
+
+    /// Marks the start of a main loop iteration at `ticks` of the `Clock`.
+    pub fn on_iteration(&mut self, ticks: u32) {
+        let last = self.last_iteration.replace(ticks);
+        let elapsed = match last {
+            Some(last) => ticks.wrapping_sub(last),
+            None => return,
+        };
+        self.stats.loop_iterations = self.stats.loop_iterations.wrapping_add(1);
+        self.stats.loop_max_us = self.stats.loop_max_us.max(ticks_to_us(elapsed));
+        self.average = self.average - (self.average >> AVERAGE_SHIFT) + elapsed;
+        self.stats.loop_average_us = ticks_to_us(self.average >> AVERAGE_SHIFT);
+
+        self.since_stack_check = self.since_stack_check.saturating_add(elapsed);
+        if self.since_stack_check >= STACK_CHECK_INTERVAL_MS * TICKS_PER_MS {
+            self.since_stack_check = 0;
+            self.check_stack();
+        }
+    }
+
+    pub fn stats(&self) -> DiagStats {
+        self.stats
+    }
+
+    /// Updates `stack_used`, which otherwise happens every
+    /// `STACK_CHECK_INTERVAL_MS`.
+    pub fn check_stack(&mut self) {
+        let (bottom, top) = stack_bounds();
+        // Everything below the previous mark was still painted then.
+        self.stack_low = (bottom..self.stack_low)
+            .step_by(4)
+            .find(|word| unsafe { ptr::read_volatile(*word as *const u32) } != PAINT)
+            .unwrap_or(self.stack_low);
+        self.stats.stack_used = top - self.stack_low;
+    }
+}
+
+fn stack_bounds() -> (usize, usize) {
+    unsafe {
+        (
+            &__ebss as *const u32 as usize,
+            &_stack_start as *const u32 as usize,
+        )
+    }
+}
+
+fn ticks_to_us(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / TICKS_PER_MS as u64) as u32
+}
@@ -0,0 +1,289 @@
+//! A small OLED of 128 by 64 pixels, on an SSD1306 or SH1106 controller over
+//! I2C, that shows the power, the energy used today and whether the broker
+//! can be reached.
+//!
+//! The frame is drawn in memory, and sent to the display a page per `poll`,
+//! by DMA, so that the main loop goes on while it is sent. The bus is shared
+//! with other devices, so it is passed in rather than kept. Both controllers
+//! are driven in page addressing mode, which is the only one that the SH1106
+//! has.
+
+use core::fmt::Write as _;
+
+use arrayvec::ArrayString;
+use embedded_hal::blocking::i2c::Write;
+
+use crate::{dsmr::Reading, i2c::I2c};
+
+const WIDTH: usize = 128;
+// Rows of 8 pixels, each a byte per column, with the top pixel in bit 0.
+const PAGES: usize = 8;
+const GLYPH_WIDTH: usize = 5;
+
+const CONTROL_COMMANDS: u8 = 0x00;
+// Followed by a single command, and another control byte.
+const CONTROL_COMMAND: u8 = 0x80;
+const CONTROL_DATA: u8 = 0x40;
+// Sets the page and column, then the data of the page.
+const PAGE_WRITE_SZ: usize = 7 + WIDTH;
+const SET_PAGE: u8 = 0xB0;
+const SET_COLUMN_LOW: u8 = 0x00;
+const SET_COLUMN_HIGH: u8 = 0x10;
+// Display off, clock, multiplex ratio of 64, no display offset, start line 0,
+// segments and COM remapped so the image is upright, alternative COM pins,
+// contrast, pre-charge, VCOMH, show RAM, not inverted.
+const INIT: &[u8] = &[
+    0xAE, 0xD5, 0x80, 0xA8, 0x3F, 0xD3, 0x00, 0x40, 0xA1, 0xC8, 0xDA, 0x12, 0x81, 0xCF, 0xD9, 0xF1,
+    0xDB, 0x40, 0xA4, 0xA6,
+];
+const CHARGE_PUMP_ON: &[u8] = &[0x8D, 0x14];
+const DISPLAY_ON: u8 = 0xAF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Controller {
+    Ssd1306,
+    /// Has 132 columns, of which the middle 128 are shown.
+    Sh1106,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayConfig {
+    pub address: u8,
+    pub controller: Controller,
+    /// How often the frame is redrawn, if anything changed.
+    pub refresh_ms: i64,
+}
+
+pub struct Display {
+    config: DisplayConfig,
+    frame: [[u8; WIDTH]; PAGES],
+    // The page that is sent next, while sending a frame.
+    sending: Option<usize>,
+    drawn_at: i64,
+    dirty: bool,
+    power_w: Option<i64>,
+    // The meter's date, and the energy delivered at the first telegram of
+    // that day, in Wh.
+    day: Option<(u16, u8, u8)>,
+    day_start_wh: i64,
+    today_wh: Option<i64>,
+    network_up: bool,
+}
+
+impl Display {
+    /// Turns the display on, which blocks for the few commands it takes.
+    pub fn init(i2c: &mut I2c, config: DisplayConfig) -> Self {
+        let mut commands = [0; 24];
+        commands[0] = CONTROL_COMMANDS;
+        let mut len = 1;
+        for command in INIT.iter().chain(match config.controller {
+            Controller::Ssd1306 => CHARGE_PUMP_ON,
+            Controller::Sh1106 => &[],
+        }) {
+            commands[len] = *command;
+            len += 1;
+        }
+        commands[len] = DISPLAY_ON;
+        if let Err(err) = i2c.write(config.address, &commands[..len + 1]) {
+            log::warn!("Failed to initialise display: {:?}", err);
+        }
+        Self {
+            config,
+            frame: [[0; WIDTH]; PAGES],
+            sending: None,
+            drawn_at: i64::min_value(),
+            dirty: true,
+            power_w: None,
+            day: None,
+            day_start_wh: 0,
+            today_wh: None,
+            network_up: false,
+        }
+    }
+
+    pub fn update(&mut self, reading: &Reading) {
+        let delivered = |reading: &Reading| {
+            reading
+                .delivered
+                .iter()
+                .map(|energy| energy.and_then(|energy| energy.wh()))
+                .sum::<Option<i64>>()
+        };
+        let power = match (reading.power_delivered, reading.power_returned) {
+            (Some(delivered), returned) => delivered
+                .w()
+                .map(|w| w - returned.and_then(|returned| returned.w()).unwrap_or(0)),
+            (None, _) => None,
+        };
+        self.power_w = power;
+        if let (Some(timestamp), Some(delivered)) = (reading.timestamp, delivered(reading)) {
+            let day = (timestamp.year, timestamp.month, timestamp.day);
+            if self.day != Some(day) {
+                self.day = Some(day);
+                self.day_start_wh = delivered;
+            }
+            self.today_wh = Some(delivered - self.day_start_wh);
+        }
+        self.dirty = true;
+    }
+
+    pub fn set_network_up(&mut self, up: bool) {
+        if up != self.network_up {
+            self.network_up = up;
+            self.dirty = true;
+        }
+    }
+
+    /// Redraws the frame when it is time to, and sends the next page of it
+    /// once the previous one has been sent.
+    pub fn poll(&mut self, now: i64, i2c: &mut I2c) {
+        match i2c.poll() {
+            Ok(()) => {}
+            Err(nb::Error::WouldBlock) => return,
+            Err(nb::Error::Other(err)) => log::warn!("Failed to send to display: {:?}", err),
+        }
+        match self.sending {
+            None if self.dirty && now - self.drawn_at >= self.config.refresh_ms => {
+                self.draw();
+                self.drawn_at = now;
+                self.dirty = false;
+                self.sending = Some(0);
+            }
+            None => {}
+            Some(page) => {
+                self.send(page, i2c);
+                self.sending = Some(page + 1).filter(|page| *page < PAGES);
+            }
+        }
+    }
+
+    fn send(&mut self, page: usize, i2c: &mut I2c) {
+        let offset = match self.config.controller {
+            Controller::Ssd1306 => 0,
+            Controller::Sh1106 => 2,
+        };
+        let mut write = [0; PAGE_WRITE_SZ];
+        write[..7].copy_from_slice(&[
+            CONTROL_COMMAND,
+            SET_PAGE | page as u8,
+            CONTROL_COMMAND,
+            SET_COLUMN_LOW | (offset & 0x0F),
+            CONTROL_COMMAND,
+            SET_COLUMN_HIGH | (offset >> 4),
+            CONTROL_DATA,
+        ]);
+        write[7..].copy_from_slice(&self.frame[page]);
+        if let Err(err) = i2c.start_write(self.config.address, &write) {
+            log::warn!("Failed to send to display: {:?}", err);
+        }
+    }
+
+    fn draw(&mut self) {
+        self.frame = [[0; WIDTH]; PAGES];
+        let mut line = ArrayString::<[u8; 21]>::new();
+        self.text(0, 0, 1, "POWER");
+        // Always fits.
+        let _ = match self.power_w {
+            Some(power) => write!(line, "{} W", power),
+            None => line.write_str("-"),
+        };
+        self.text(1, 0, 2, &line);
+
+        line.clear();
+        self.text(4, 0, 1, "TODAY");
+        let _ = match self.today_wh {
+            Some(wh) => write!(line, "{}.{:03} KWH", wh / 1000, wh % 1000),
+            None => line.write_str("-"),
+        };
+        self.text(5, 0, 1, &line);
+
+        let network = if self.network_up {
+            "BROKER OK"
+        } else {
+            "BROKER DOWN"
+        };
+        self.text(7, 0, 1, network);
+    }
+
+    // Draws `text` from the top of `page`, at `column`, with glyphs `scale`
+    // times as large, 1 or 2. Characters that don't fit are left out.
+    fn text(&mut self, page: usize, column: usize, scale: usize, text: &str) {
+        let mut x = column;
+        for c in text.chars() {
+            if x + GLYPH_WIDTH * scale > WIDTH {
+                break;
+            }
+            for bits in glyph(c).iter() {
+                for _ in 0..scale {
+                    match scale {
+                        1 => self.frame[page][x] = *bits,
+                        _ => {
+                            let doubled = double(*bits);
+                            self.frame[page][x] = doubled as u8;
+                            self.frame[page + 1][x] = (doubled >> 8) as u8;
+                        }
+                    }
+                    x += 1;
+                }
+            }
+            // The space between glyphs.
+            x += scale;
+        }
+    }
+}
+
+// Doubles every bit, which makes a glyph twice as tall.
+fn double(bits: u8) -> u16 {
+    (0..8).fold(0, |doubled, bit| match bits & 1 << bit {
+        0 => doubled,
+        _ => doubled | 0b11 << (2 * bit),
+    })
+}
+
+// A 5 by 7 font for upper case letters, digits and a few symbols. Lower case
+// letters are drawn as upper case.
+fn glyph(c: char) -> [u8; GLYPH_WIDTH] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x3E, 0x51, 0x49, 0x45, 0x3E],
+        '1' => [0x00, 0x42, 0x7F, 0x40, 0x00],
+        '2' => [0x42, 0x61, 0x51, 0x49, 0x46],
+        '3' => [0x21, 0x41, 0x45, 0x4B, 0x31],
+        '4' => [0x18, 0x14, 0x12, 0x7F, 0x10],
+        '5' => [0x27, 0x45, 0x45, 0x45, 0x39],
+        '6' => [0x3C, 0x4A, 0x49, 0x49, 0x30],
+        '7' => [0x01, 0x71, 0x09, 0x05, 0x03],
+        '8' => [0x36, 0x49, 0x49, 0x49, 0x36],
+        '9' => [0x06, 0x49, 0x49, 0x29, 0x1E],
+        'A' => [0x7E, 0x11, 0x11, 0x11, 0x7E],
+        'B' => [0x7F, 0x49, 0x49, 0x49, 0x36],
+        'C' => [0x3E, 0x41, 0x41, 0x41, 0x22],
+        'D' => [0x7F, 0x41, 0x41, 0x22, 0x1C],
+        'E' => [0x7F, 0x49, 0x49, 0x49, 0x41],
+        'F' => [0x7F, 0x09, 0x09, 0x09, 0x01],
+        'G' => [0x3E, 0x41, 0x49, 0x49, 0x7A],
+        'H' => [0x7F, 0x08, 0x08, 0x08, 0x7F],
+        'I' => [0x00, 0x41, 0x7F, 0x41, 0x00],
+        'J' => [0x20, 0x40, 0x41, 0x3F, 0x01],
+        'K' => [0x7F, 0x08, 0x14, 0x22, 0x41],
+        'L' => [0x7F, 0x40, 0x40, 0x40, 0x40],
+        'M' => [0x7F, 0x02, 0x0C, 0x02, 0x7F],
+        'N' => [0x7F, 0x04, 0x08, 0x10, 0x7F],
+        'O' => [0x3E, 0x41, 0x41, 0x41, 0x3E],
+        'P' => [0x7F, 0x09, 0x09, 0x09, 0x06],
+        'Q' => [0x3E, 0x41, 0x51, 0x21, 0x5E],
+        'R' => [0x7F, 0x09, 0x19, 0x29, 0x46],
+        'S' => [0x46, 0x49, 0x49, 0x49, 0x31],
+        'T' => [0x01, 0x01, 0x7F, 0x01, 0x01],
+        'U' => [0x3F, 0x40, 0x40, 0x40, 0x3F],
+        'V' => [0x1F, 0x20, 0x40, 0x20, 0x1F],
+        'W' => [0x3F, 0x40, 0x38, 0x40, 0x3F],
+        'X' => [0x63, 0x14, 0x08, 0x14, 0x63],
+        'Y' => [0x07, 0x08, 0x70, 0x08, 0x07],
+        'Z' => [0x61, 0x51, 0x49, 0x45, 0x43],
+        '-' => [0x08, 0x08, 0x08, 0x08, 0x08],
+        '.' => [0x00, 0x60, 0x60, 0x00, 0x00],
+        ':' => [0x00, 0x36, 0x36, 0x00, 0x00],
+        '/' => [0x20, 0x10, 0x08, 0x04, 0x02],
+        _ => [0x00; GLYPH_WIDTH],
+    }
+}
@@ -0,0 +1,160 @@
+//! Just enough of the eDMA to move a buffer to or from a peripheral register,
+//! a word per request of the peripheral, while the main loop goes on.
+//!
+//! Buffers must stay where they are until the transfer is done, and must be
+//! in DTCM, where the stack and statics are, since it isn't cached: the DMA
+//! doesn't see the data cache.
+
+use core::{mem, ptr};
+
+use cortex_m::asm;
+
+const DMA: usize = 0x400E_8000;
+const ERR: usize = DMA + 0x2C;
+const CERQ: usize = DMA + 0x1A;
+const SERQ: usize = DMA + 0x1B;
+const CDNE: usize = DMA + 0x1C;
+const CERR: usize = DMA + 0x1E;
+// The transfer control descriptors, 32 bytes per channel.
+const TCD: usize = DMA + 0x1000;
+const TCD_SADDR: usize = 0x00;
+const TCD_SOFF: usize = 0x04;
+const TCD_ATTR: usize = 0x06;
+const TCD_NBYTES: usize = 0x08;
+const TCD_SLAST: usize = 0x0C;
+const TCD_DADDR: usize = 0x10;
+const TCD_DOFF: usize = 0x14;
+const TCD_CITER: usize = 0x16;
+const TCD_DLAST_SGA: usize = 0x18;
+const TCD_CSR: usize = 0x1C;
+const TCD_BITER: usize = 0x1E;
+// Stop taking requests once the last word has moved.
+const CSR_DREQ: u16 = 1 << 3;
+const CSR_DONE: u16 = 1 << 7;
+// Connects a request source to a channel.
+const DMAMUX: usize = 0x400E_C000;
+const CHCFG_ENBL: u32 = 1 << 31;
+// Gates the clock of the eDMA, with the bits of CG3.
+const CCM_CCGR5: usize = 0x400F_C07C;
+const CG3: u32 = 0b11 << 6;
+// Per transfer.
+const MAX_WORDS: usize = 0x7FFF;
+
+pub struct Channel {
+    n: usize,
+}
+
+impl Channel {
+    /// Takes channel `n`, from 0 to 31, for the requests of `source`, a
+    /// request source of the DMAMUX.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may use the channel.
+    pub unsafe fn new(n: usize, source: u32) -> Self {
+        assert!(n < 32);
+        write32(CCM_CCGR5, read32(CCM_CCGR5) | CG3);
+        write32(DMAMUX + 4 * n, 0);
+        write32(DMAMUX + 4 * n, CHCFG_ENBL | source);
+        Self { n }
+    }
+
+    /// Moves `buffer` to `register`, a word per request.
+    pub fn write<T: Copy>(&mut self, buffer: &[T], register: usize) {
+        let size = mem::size_of::<T>();
+        self.start(
+            buffer.as_ptr() as usize,
+            size as i16,
+            register,
+            0,
+            size,
+            buffer.len(),
+        );
+    }
+
+    /// Moves words from `register` into `buffer`, a word per request.
+    pub fn read<T: Copy>(&mut self, register: usize, buffer: &mut [T]) {
+        let size = mem::size_of::<T>();
+        self.start(
+            register,
+            0,
+            buffer.as_mut_ptr() as usize,
+            size as i16,
+            size,
+            buffer.len(),
+        );
+    }
+
+    /// Whether the last word of the transfer has moved.
+    pub fn is_done(&self) -> bool {
+        unsafe { read16(self.tcd(TCD_CSR)) & CSR_DONE != 0 }
+    }
+
+    /// Whether the transfer stopped on a bus error, or a bad descriptor.
+    pub fn has_failed(&self) -> bool {
+        unsafe { read32(ERR) & 1 << self.n != 0 }
+    }
+
+    /// Stops the transfer, if any, and forgets how it ended.
+    pub fn stop(&mut self) {
+        unsafe {
+            ptr::write_volatile(CERQ as *mut u8, self.n as u8);
+            ptr::write_volatile(CDNE as *mut u8, self.n as u8);
+            ptr::write_volatile(CERR as *mut u8, self.n as u8);
+        }
+    }
+
+    fn start(
+        &mut self,
+        source: usize,
+        source_offset: i16,
+        destination: usize,
+        destination_offset: i16,
+        size: usize,
+        words: usize,
+    ) {
+        assert!(words >= 1 && words <= MAX_WORDS);
+        let size_code = match size {
+            1 => 0,
+            2 => 1,
+            _ => 2,
+        };
+        self.stop();
+        unsafe {
+            write32(self.tcd(TCD_SADDR), source as u32);
+            write16(self.tcd(TCD_SOFF), source_offset as u16);
+            write16(self.tcd(TCD_ATTR), size_code << 8 | size_code);
+            write32(self.tcd(TCD_NBYTES), size as u32);
+            write32(self.tcd(TCD_SLAST), 0);
+            write32(self.tcd(TCD_DADDR), destination as u32);
+            write16(self.tcd(TCD_DOFF), destination_offset as u16);
+            write16(self.tcd(TCD_CITER), words as u16);
+            write32(self.tcd(TCD_DLAST_SGA), 0);
+            write16(self.tcd(TCD_CSR), CSR_DREQ);
+            write16(self.tcd(TCD_BITER), words as u16);
+            // The buffer must be written before the DMA reads it.
+            asm::dsb();
+            ptr::write_volatile(SERQ as *mut u8, self.n as u8);
+        }
+    }
+
+    fn tcd(&self, register: usize) -> usize {
+        TCD + 32 * self.n + register
+    }
+}
+
+unsafe fn read16(register: usize) -> u16 {
+    ptr::read_volatile(register as *const u16)
+}
+
+unsafe fn write16(register: usize, value: u16) {
+    ptr::write_volatile(register as *mut u16, value)
+}
+
+unsafe fn read32(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write32(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,211 @@
+//! Full-duplex transfers on an LPSPI by DMA, for devices that move more than
+//! a few bytes at a time, such as external flash, an Ethernet controller or a
+//! LoRa radio.
+//!
+//! Like `I2c`, the bytes to send are copied into a buffer that the driver
+//! owns, so `start_transfer` returns right away, and `poll` tells when the
+//! transfer is done, after which `received` has the bytes that were read.
+//! One channel moves bytes into the transmit FIFO, and another moves them out
+//! of the receive FIFO. Chip select is left to the device driver, which
+//! drives it as a GPIO, as for the ENC28J60 and the SD card.
+
+use core::ptr;
+
+use cortex_m::peripheral::DWT;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use teensy4_bsp::hal::{iomuxc::prelude::consts::Unsigned, spi::SPI};
+
+use crate::{cpu_clock, dma::Channel};
+
+/// The DMAMUX request sources of LPSPI1 to 4, for receiving and sending.
+pub const DMA_SOURCES: [(u32, u32); 4] = [(13, 14), (77, 78), (15, 16), (79, 80)];
+// The most bytes that a transfer takes.
+const MAX_TRANSFER_SZ: usize = 512;
+
+const LPSPI_BASES: [usize; 4] = [0x4039_4000, 0x4039_8000, 0x4039_C000, 0x403A_0000];
+const CR: usize = 0x10;
+const SR: usize = 0x14;
+const DER: usize = 0x1C;
+const FCR: usize = 0x58;
+const TCR: usize = 0x60;
+const TDR: usize = 0x64;
+const RDR: usize = 0x74;
+const CR_MEN: u32 = 1 << 0;
+const CR_RTF: u32 = 1 << 8;
+const CR_RRF: u32 = 1 << 9;
+const SR_TEF: u32 = 1 << 11;
+const SR_REF: u32 = 1 << 12;
+// The flags that are cleared by writing ones.
+const SR_FLAGS: u32 = 0x3F << 8;
+const DER_TDDE: u32 = 1 << 0;
+const DER_RDDE: u32 = 1 << 1;
+// Ask for a byte as soon as there is room in the transmit FIFO of 16 words,
+// and move each byte out of the receive FIFO as soon as it arrives.
+const FCR_WATERMARKS: u32 = 15;
+const TCR_FRAMESZ: u32 = 0xFFF;
+const TCR_TXMSK: u32 = 1 << 18;
+const TCR_RXMSK: u32 = 1 << 19;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpiError {
+    /// A transfer is still going.
+    Busy,
+    /// More than `MAX_TRANSFER_SZ` bytes.
+    TooLong,
+    /// The receive FIFO overflowed, or the transmit FIFO ran empty.
+    Fifo,
+    /// The transfer didn't finish in time.
+    Timeout,
+    Dma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    // In cycles of the DWT.
+    Transferring { since: u32 },
+}
+
+pub struct DmaSpi<M> {
+    // Only kept so that nothing else can use the LPSPI, or its pins.
+    _spi: SPI<M>,
+    base: usize,
+    rx_dma: Channel,
+    tx_dma: Channel,
+    tx: [u8; MAX_TRANSFER_SZ],
+    rx: [u8; MAX_TRANSFER_SZ],
+    // The length of the last transfer.
+    len: usize,
+    state: State,
+    timeout_us: u32,
+}
+
+impl<M: Unsigned> DmaSpi<M> {
+    /// Takes `spi`, which has its pins, clock speed and mode set, and
+    /// `rx_dma` and `tx_dma`, for the `DMA_SOURCES` of its module. Transfers
+    /// fail once they take longer than `timeout_us`.
+    pub fn new(spi: SPI<M>, rx_dma: Channel, tx_dma: Channel, timeout_us: u32) -> Self {
+        let base = LPSPI_BASES[M::USIZE - 1];
+        unsafe {
+            // The watermarks can only be set while the module is disabled.
+            write(base + CR, read(base + CR) & !CR_MEN);
+            write(base + FCR, FCR_WATERMARKS);
+            write(base + CR, read(base + CR) | CR_MEN);
+            // Bytes, both ways, keeping the clock polarity and phase.
+            write(
+                base + TCR,
+                read(base + TCR) & !(TCR_FRAMESZ | TCR_TXMSK | TCR_RXMSK) | 7,
+            );
+        }
+        Self {
+            _spi: spi,
+            base,
+            rx_dma,
+            tx_dma,
+            tx: [0; MAX_TRANSFER_SZ],
+            rx: [0; MAX_TRANSFER_SZ],
+            len: 0,
+            state: State::Idle,
+            timeout_us,
+        }
+    }
+
+    /// Starts sending `bytes`, while reading as many. `poll` returns how it
+    /// went.
+    pub fn start_transfer(&mut self, bytes: &[u8]) -> Result<(), SpiError> {
+        if self.state != State::Idle {
+            return Err(SpiError::Busy);
+        }
+        if bytes.is_empty() || bytes.len() > MAX_TRANSFER_SZ {
+            return Err(SpiError::TooLong);
+        }
+        self.len = bytes.len();
+        self.tx[..self.len].copy_from_slice(bytes);
+        unsafe { write(self.base + SR, SR_FLAGS) };
+        // The receiving channel must be ready before the first byte is sent.
+        self.rx_dma.read(self.base + RDR, &mut self.rx[..self.len]);
+        self.tx_dma.write(&self.tx[..self.len], self.base + TDR);
+        unsafe { write(self.base + DER, DER_TDDE | DER_RDDE) };
+        self.state = State::Transferring {
+            since: DWT::get_cycle_count(),
+        };
+        Ok(())
+    }
+
+    /// Whether the transfer that `start_transfer` started is done. Also `Ok`
+    /// if there is none.
+    pub fn poll(&mut self) -> nb::Result<(), SpiError> {
+        let since = match self.state {
+            State::Idle => return Ok(()),
+            State::Transferring { since } => since,
+        };
+        let status = unsafe { read(self.base + SR) };
+        let elapsed_us = DWT::get_cycle_count().wrapping_sub(since) / (cpu_clock::hz() / 1_000_000);
+        let result = if status & (SR_TEF | SR_REF) != 0 {
+            Err(SpiError::Fifo)
+        } else if self.rx_dma.has_failed() || self.tx_dma.has_failed() {
+            Err(SpiError::Dma)
+        } else if self.rx_dma.is_done() {
+            // The last byte has been read, so it has also been sent.
+            Ok(())
+        } else if elapsed_us > self.timeout_us {
+            Err(SpiError::Timeout)
+        } else {
+            return Err(nb::Error::WouldBlock);
+        };
+        self.state = State::Idle;
+        unsafe { write(self.base + DER, 0) };
+        self.rx_dma.stop();
+        self.tx_dma.stop();
+        if result.is_err() {
+            self.len = 0;
+            // Throw away what is left in the FIFOs.
+            unsafe { write(self.base + CR, read(self.base + CR) | CR_RTF | CR_RRF) };
+        }
+        result.map_err(nb::Error::Other)
+    }
+
+    /// The bytes that were read during the last transfer that finished.
+    pub fn received(&self) -> &[u8] {
+        &self.rx[..self.len]
+    }
+}
+
+impl<M: Unsigned> Transfer<u8> for DmaSpi<M> {
+    type Error = SpiError;
+
+    /// Sends `words` and replaces them with the bytes that were read, in
+    /// transfers of up to `MAX_TRANSFER_SZ` bytes, waiting until they are
+    /// done.
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SpiError> {
+        nb::block!(self.poll())?;
+        for chunk in words.chunks_mut(MAX_TRANSFER_SZ) {
+            self.start_transfer(chunk)?;
+            nb::block!(self.poll())?;
+            chunk.copy_from_slice(self.received());
+        }
+        Ok(words)
+    }
+}
+
+impl<M: Unsigned> Write<u8> for DmaSpi<M> {
+    type Error = SpiError;
+
+    /// Sends `words`, and waits until they are sent.
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        nb::block!(self.poll())?;
+        for chunk in words.chunks(MAX_TRANSFER_SZ) {
+            self.start_transfer(chunk)?;
+            nb::block!(self.poll())?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
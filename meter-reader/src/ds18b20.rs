@@ -0,0 +1,220 @@
+//! DS18B20 temperature sensors on a 1-Wire bus, such as one in the meter
+//! cupboard and one strapped to the boiler, each powered from 3.3 V rather
+//! than parasitically.
+//!
+//! The sensors are found with a search, at start-up and whenever the bus
+//! stops answering. Every `interval_ms`, all of them start a conversion at
+//! once, and `CONVERSION_MS` later, one sensor is read per poll, so that a
+//! poll blocks for about 10 ms, rather than that for every sensor. The
+//! temperatures are added to every reading under the OBIS codes of their
+//! `Ds18b20Sensor`, in °C.
+
+use arrayvec::ArrayVec;
+use dsmr42::Decimal;
+
+use crate::{
+    dsmr::Reading,
+    onewire::{self, OneWire, OneWireError, Rom},
+    telemetry::{Kind, Label, Metric, Source, Telemetry, Unit, Value},
+};
+
+pub const MAX_SENSORS: usize = 4;
+
+const FAMILY_CODE: u8 = 0x28;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+// At the default resolution of 12 bits.
+const CONVERSION_MS: i64 = 750;
+const SCRATCHPAD_SZ: usize = 9;
+// 85 °C, which the scratchpad holds from power-up until a conversion, so
+// which a sensor that browned out returns. It is taken to be that, rather
+// than a temperature.
+const POWER_ON_VALUE: i16 = 0x0550;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ds18b20Sensor {
+    /// Where the sensor is, such as `boiler`, in lower snake case, for its
+    /// metric.
+    pub name: &'static str,
+    /// As logged when the sensors are found, or `None` for the next sensor
+    /// that no other `Ds18b20Sensor` names, in the order of their ROMs.
+    pub rom: Option<Rom>,
+    /// The code that the temperature is added to readings under, such as
+    /// 0-1:128.41.0.
+    pub obis: [u8; 6],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ds18b20Config {
+    pub sensors: &'static [Ds18b20Sensor],
+    pub interval_ms: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle { next_at: i64 },
+    Converting { done_at: i64 },
+    // The index of the next sensor to read.
+    Reading(usize),
+}
+
+struct Sensor {
+    rom: Rom,
+    obis: [u8; 6],
+    // In sixteenths of a degree.
+    temperature: Option<i16>,
+}
+
+pub struct Thermometers {
+    bus: OneWire,
+    config: Ds18b20Config,
+    sensors: ArrayVec<[Sensor; MAX_SENSORS]>,
+    state: State,
+}
+
+impl Thermometers {
+    pub fn new(bus: OneWire, config: Ds18b20Config) -> Self {
+        Self {
+            bus,
+            config,
+            sensors: ArrayVec::new(),
+            state: State::Idle { next_at: 0 },
+        }
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        match self.state {
+            State::Idle { next_at } if now >= next_at => {
+                if self.sensors.is_empty() {
+                    self.find_sensors();
+                }
+                if self.sensors.is_empty() {
+                    self.state = State::Idle {
+                        next_at: now + self.config.interval_ms,
+                    };
+                    return;
+                }
+                self.state = match self.bus.select(None) {
+                    Ok(()) => {
+                        self.bus.write_byte(CONVERT_T);
+                        State::Converting {
+                            done_at: now + CONVERSION_MS,
+                        }
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to start a DS18B20 conversion: {:?}", err);
+                        self.sensors.clear();
+                        State::Idle {
+                            next_at: now + self.config.interval_ms,
+                        }
+                    }
+                };
+            }
+            State::Converting { done_at } if now >= done_at => self.state = State::Reading(0),
+            State::Reading(index) => {
+                let sensor = &mut self.sensors[index];
+                sensor.temperature = match read_temperature(&mut self.bus, &sensor.rom) {
+                    Ok(temperature) => Some(temperature).filter(|t| *t != POWER_ON_VALUE),
+                    Err(err) => {
+                        log::warn!("Failed to read DS18B20 {:02X?}: {:?}", sensor.rom, err);
+                        None
+                    }
+                };
+                self.state = if index + 1 < self.sensors.len() {
+                    State::Reading(index + 1)
+                } else {
+                    State::Idle {
+                        next_at: now + self.config.interval_ms,
+                    }
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds the temperature of each sensor that was read to `reading`, as
+    /// custom values.
+    pub fn append_to(&self, reading: &mut Reading) {
+        for sensor in &self.sensors {
+            if let Some(temperature) = sensor.temperature {
+                // A sixteenth is 0.0625.
+                let value = Decimal::new(temperature as i64 * 625, 4, None);
+                if reading.custom.try_push((sensor.obis, value)).is_err() {
+                    log::warn!("No room for DS18B20 {:?}", sensor.obis);
+                }
+            }
+        }
+    }
+
+    /// Registers the temperature of each sensor of the config, whether it
+    /// has been found or not.
+    pub fn register(&self, telemetry: &mut Telemetry) {
+        for sensor in self.config.sensors.iter().take(MAX_SENSORS) {
+            telemetry.register(Metric {
+                source: Source::Sensor,
+                name: "temperature",
+                label: Some(Label::Name(sensor.name)),
+                unit: Some(Unit::Celsius),
+                kind: Kind::Gauge,
+                help: "Temperature",
+                value: Value::Custom(sensor.obis),
+            });
+        }
+    }
+
+    // Matches the sensors on the bus to those of the config.
+    fn find_sensors(&mut self) {
+        let roms = match self.bus.search() {
+            Ok(roms) => roms,
+            Err(err) => {
+                log::warn!("Failed to search the 1-Wire bus: {:?}", err);
+                return;
+            }
+        };
+        let roms: ArrayVec<[Rom; onewire::MAX_DEVICES]> = roms
+            .into_iter()
+            .filter(|rom| rom[0] == FAMILY_CODE)
+            .collect();
+        for rom in &roms {
+            log::info!("Found DS18B20 {:02X?}", rom);
+        }
+        let mut taken = [false; onewire::MAX_DEVICES];
+        for configured in self.config.sensors.iter().take(MAX_SENSORS) {
+            let found = match configured.rom {
+                Some(rom) => roms.iter().position(|found| *found == rom),
+                None => (0..roms.len()).find(|i| {
+                    !taken[*i]
+                        && !self
+                            .config
+                            .sensors
+                            .iter()
+                            .any(|sensor| sensor.rom == Some(roms[*i]))
+                }),
+            };
+            match found {
+                Some(i) if !taken[i] => {
+                    taken[i] = true;
+                    self.sensors.push(Sensor {
+                        rom: roms[i],
+                        obis: configured.obis,
+                        temperature: None,
+                    });
+                }
+                _ => log::warn!("No DS18B20 for {:?}", configured.obis),
+            }
+        }
+    }
+}
+
+fn read_temperature(bus: &mut OneWire, rom: &Rom) -> Result<i16, OneWireError> {
+    bus.select(Some(rom))?;
+    bus.write_byte(READ_SCRATCHPAD);
+    let mut scratchpad = [0; SCRATCHPAD_SZ];
+    for byte in scratchpad.iter_mut() {
+        *byte = bus.read_byte();
+    }
+    if onewire::crc8(&scratchpad[..SCRATCHPAD_SZ - 1]) != scratchpad[SCRATCHPAD_SZ - 1] {
+        return Err(OneWireError::Crc);
+    }
+    Ok(i16::from_le_bytes([scratchpad[0], scratchpad[1]]))
+}
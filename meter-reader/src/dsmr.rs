@@ -0,0 +1,361 @@
+use arrayvec::ArrayVec;
+use dsmr42::{
+    CustomObis, Decimal, EquipmentId, Line, MbusDevice, MonthlyPeak, Phase, Telegram,
+    TelegramParseError, TelegramParser, Timestamp, Version, MAX_PEAK_HISTORY,
+};
+
+/// Number of tariffs that DSMR meters keep separate registers for.
+pub const TARIFFS: usize = 2;
+/// Number of devices that can be connected to a meter over M-Bus.
+pub const MBUS_CHANNELS: usize = 4;
+const MAX_CUSTOM_VALUES: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PhaseReading {
+    pub voltage: Option<Decimal>,
+    pub current: Option<Decimal>,
+    /// Instantaneous power delivered to the client.
+    pub power_delivered: Option<Decimal>,
+    /// Instantaneous power returned by the client.
+    pub power_returned: Option<Decimal>,
+}
+
+/// A device connected to the meter over M-Bus, such as a gas or water meter.
+/// Fields are `None` if the telegram did not include them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MbusReading {
+    pub device: Option<MbusDevice>,
+    pub equipment_id: Option<EquipmentId>,
+    /// The last reading of the device, and when it was taken, which is
+    /// usually not when the telegram was sent.
+    pub reading: Option<(Timestamp, Decimal)>,
+}
+
+/// Typed view of a P1 telegram. Fields are `None` if the telegram did not
+/// include them, since not every meter sends every value.
+#[derive(Clone, Debug, Default)]
+pub struct Reading {
+    pub protocol: Option<Version>,
+    pub version: Option<u8>,
+    pub timestamp: Option<Timestamp>,
+    pub equipment_id: Option<EquipmentId>,
+    /// Energy delivered to the client per tariff.
+    pub delivered: [Option<Decimal>; TARIFFS],
+    /// Energy returned by the client per tariff.
+    pub returned: [Option<Decimal>; TARIFFS],
+    pub tariff: Option<u8>,
+    /// Instantaneous power delivered to the client.
+    pub power_delivered: Option<Decimal>,
+    /// Instantaneous power returned by the client.
+    pub power_returned: Option<Decimal>,
+    pub phases: [PhaseReading; 3],
+    /// Average demand over the current quarter hour, sent by eMUCS meters.
+    pub average_demand: Option<Decimal>,
+    /// The peak of the average demand in the current month, and when it
+    /// occurred, sent by eMUCS meters.
+    pub max_demand: Option<(Timestamp, Decimal)>,
+    /// The peaks of previous months, sent by eMUCS meters.
+    pub peak_history: ArrayVec<[MonthlyPeak; MAX_PEAK_HISTORY]>,
+    /// Devices connected over M-Bus, by channel. Channels are numbered from
+    /// 1, so the first channel is at index 0.
+    pub mbus: [Option<MbusReading>; MBUS_CHANNELS],
+    /// Values for OBIS codes registered with the `Parser`.
+    pub custom: ArrayVec<[([u8; 6], Decimal); MAX_CUSTOM_VALUES]>,
+    /// When the telegram was received, in ms since the Unix epoch, if the
+    /// wall-clock time is known. Not part of the telegram, so it must be set
+    /// by the receiver.
+    pub received_at: Option<i64>,
+}
+
+impl Reading {
+    pub fn from_telegram(telegram: &Telegram) -> Self {
+        let mut reading = Self {
+            protocol: Some(telegram.version()),
+            ..Self::default()
+        };
+        for line in telegram.lines.iter() {
+            match *line {
+                Line::Version(version) => reading.version = Some(version),
+                Line::Timestamp(timestamp) => reading.timestamp = Some(timestamp),
+                Line::EquipmentId(id) => reading.equipment_id = Some(id),
+                Line::Consumed(tariff, energy) => {
+                    if let Some(slot) = tariff_slot(&mut reading.delivered, tariff) {
+                        *slot = Some(energy);
+                    }
+                }
+                Line::Produced(tariff, energy) => {
+                    if let Some(slot) = tariff_slot(&mut reading.returned, tariff) {
+                        *slot = Some(energy);
+                    }
+                }
+                Line::ActiveTariff(tariff) => reading.tariff = Some(tariff),
+                Line::TotalConsuming(power) => reading.power_delivered = Some(power),
+                Line::TotalProducing(power) => reading.power_returned = Some(power),
+                Line::Voltage(phase, voltage) => {
+                    reading.phase_mut(phase).voltage = Some(voltage);
+                }
+                Line::Current(phase, current) => {
+                    reading.phase_mut(phase).current = Some(current);
+                }
+                Line::Consuming(phase, power) => {
+                    reading.phase_mut(phase).power_delivered = Some(power);
+                }
+                Line::Producing(phase, power) => {
+                    reading.phase_mut(phase).power_returned = Some(power);
+                }
+                Line::AverageDemand(demand) => reading.average_demand = Some(demand),
+                Line::MaxDemand(at, demand) => reading.max_demand = Some((at, demand)),
+                Line::PeakHistory(ref peaks) => reading.peak_history = peaks.clone(),
+                Line::MbusDeviceType(channel, device) => {
+                    if let Some(mbus) = reading.mbus_mut(channel) {
+                        mbus.device = Some(device);
+                    }
+                }
+                Line::MbusEquipmentId(channel, id) => {
+                    if let Some(mbus) = reading.mbus_mut(channel) {
+                        mbus.equipment_id = Some(id);
+                    }
+                }
+                Line::MbusReading(channel, timestamp, value) => {
+                    if let Some(mbus) = reading.mbus_mut(channel) {
+                        mbus.reading = Some((timestamp, value));
+                    }
+                }
+                Line::Custom(obis, value) if reading.custom.try_push((obis, value)).is_err() => {
+                    log::warn!("Too many custom OBIS values, dropping {:?}", obis);
+                }
+                _ => {}
+            }
+        }
+        reading
+    }
+
+    /// Returns the gas meter, if one is connected. Meters that don't report
+    /// device types only support a gas meter on the first channel.
+    pub fn gas(&self) -> Option<&MbusReading> {
+        self.mbus_device(MbusDevice::Gas)
+            .or_else(|| self.mbus[0].as_ref().filter(|mbus| mbus.device.is_none()))
+    }
+
+    /// Returns the first device of type `device`.
+    pub fn mbus_device(&self, device: MbusDevice) -> Option<&MbusReading> {
+        self.mbus
+            .iter()
+            .flatten()
+            .find(|mbus| mbus.device == Some(device))
+    }
+
+    // Channels are numbered from 1.
+    fn mbus_mut(&mut self, channel: u8) -> Option<&mut MbusReading> {
+        let slot = self.mbus.get_mut((channel as usize).checked_sub(1)?)?;
+        Some(slot.get_or_insert_with(MbusReading::default))
+    }
+
+    fn phase_mut(&mut self, phase: Phase) -> &mut PhaseReading {
+        match phase {
+            Phase::L1 => &mut self.phases[0],
+            Phase::L2 => &mut self.phases[1],
+            Phase::L3 => &mut self.phases[2],
+        }
+    }
+}
+
+// Tariffs are numbered from 1.
+fn tariff_slot(
+    registers: &mut [Option<Decimal>; TARIFFS],
+    tariff: u8,
+) -> Option<&mut Option<Decimal>> {
+    registers.get_mut((tariff as usize).checked_sub(1)?)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParseStats {
+    pub parsed: u32,
+    /// Telegrams whose trailing CRC did not match their contents.
+    pub crc_mismatches: u32,
+    /// Telegrams that could not be parsed at all.
+    pub malformed: u32,
+}
+
+impl ParseStats {
+    pub fn rejected(&self) -> u32 {
+        self.crc_mismatches.saturating_add(self.malformed)
+    }
+}
+
+/// A telegram that was received, and the reading it contains, or why it was
+/// rejected.
+pub type ParseResult = Result<(Telegram, Reading), TelegramParseError>;
+
+/// Parses telegrams as they are received and keeps track of how many were
+/// rejected.
+pub struct Parser<'a> {
+    parser: TelegramParser<'a>,
+    stats: ParseStats,
+}
+
+impl<'a> Parser<'a> {
+    /// `custom` lists the OBIS codes to recognise on top of the standard
+    /// ones. Their values end up in `Reading::custom`.
+    pub fn new(custom: &'a [CustomObis]) -> Self {
+        Self {
+            parser: TelegramParser::new(custom),
+            stats: ParseStats::default(),
+        }
+    }
+
+    /// Feeds received data into the parser, returning the number of bytes
+    /// consumed. Once a telegram has been received, parsing stops and its
+    /// result is returned. Telegrams are only accepted if their CRC matches,
+    /// or if they are DSMR 2.2 or 3.0 telegrams, which have none.
+    pub fn feed(&mut self, data: &[u8]) -> (usize, Option<ParseResult>) {
+        let (consumed, telegram) = self.parser.feed(data);
+        (consumed, telegram.map(|telegram| self.record(telegram)))
+    }
+
+    /// Discards the telegram that is currently being received.
+    pub fn reset(&mut self) {
+        self.parser.reset();
+    }
+
+    fn record(&mut self, telegram: Result<Telegram, TelegramParseError>) -> ParseResult {
+        let counter = match telegram {
+            Ok(_) => &mut self.stats.parsed,
+            Err(TelegramParseError::CrcMismatch(_)) => &mut self.stats.crc_mismatches,
+            Err(_) => &mut self.stats.malformed,
+        };
+        *counter = counter.saturating_add(1);
+        let telegram = telegram?;
+        let reading = Reading::from_telegram(&telegram);
+        Ok((telegram, reading))
+    }
+
+    pub fn stats(&self) -> ParseStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = ParseStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+    use dsmr42::Unit;
+
+    // Has no CRC, so none needs to be computed for it.
+    const DSMR22_TELEGRAM: &[u8] = b"/ISk5\\2MT382-1000\r\n\r\n\
+    1-0:1.8.1(12345.678*kWh)\r\n\
+    1-0:1.7.0(0001.19*kW)\r\n\
+    !\r\n";
+
+    const TIMESTAMP: Timestamp = Timestamp {
+        year: 2020,
+        month: 2,
+        day: 8,
+        hour: 15,
+        minute: 35,
+        second: 6,
+        dst: Some(false),
+    };
+
+    fn kwh(value: i64) -> Decimal {
+        Decimal::new(value, 3, Some(Unit::KWh))
+    }
+
+    fn telegram<const N: usize>(lines: [Line; N]) -> Telegram {
+        let mut telegram = Telegram {
+            device_id: ArrayString::from("XMX5LGBBFFB231237741").unwrap(),
+            lines: ArrayVec::new(),
+            crc: Some(0),
+        };
+        for line in lines {
+            telegram.lines.push(line);
+        }
+        telegram
+    }
+
+    #[test]
+    fn reading_has_the_values_of_the_telegram() {
+        let power = Decimal::new(326, 3, Some(Unit::KW));
+        let reading = Reading::from_telegram(&telegram([
+            Line::Version(42),
+            Line::Timestamp(TIMESTAMP),
+            Line::Consumed(1, kwh(4436790)),
+            Line::Consumed(2, kwh(4234483)),
+            Line::Produced(2, kwh(1000)),
+            Line::ActiveTariff(1),
+            Line::TotalConsuming(power),
+            Line::Consuming(Phase::L2, power),
+        ]));
+        assert_eq!(Some(Version::Dsmr4), reading.protocol);
+        assert_eq!(Some(42), reading.version);
+        assert_eq!(Some(TIMESTAMP), reading.timestamp);
+        assert_eq!([Some(kwh(4436790)), Some(kwh(4234483))], reading.delivered);
+        assert_eq!([None, Some(kwh(1000))], reading.returned);
+        assert_eq!(Some(1), reading.tariff);
+        assert_eq!(Some(power), reading.power_delivered);
+        assert_eq!(None, reading.phases[0].power_delivered);
+        assert_eq!(Some(power), reading.phases[1].power_delivered);
+        assert_eq!(None, reading.received_at);
+    }
+
+    #[test]
+    fn reading_skips_unknown_tariffs_and_channels() {
+        let reading = Reading::from_telegram(&telegram([
+            Line::Consumed(0, kwh(1)),
+            Line::Consumed(3, kwh(1)),
+            Line::MbusDeviceType(0, MbusDevice::Gas),
+            Line::MbusReading(5, TIMESTAMP, kwh(1)),
+        ]));
+        assert_eq!([None, None], reading.delivered);
+        assert!(reading.mbus.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn gas_meter_is_found_by_device_type() {
+        let volume = Decimal::new(12785123, 3, Some(Unit::M3));
+        let reading = Reading::from_telegram(&telegram([
+            Line::MbusDeviceType(1, MbusDevice::Water),
+            Line::MbusDeviceType(2, MbusDevice::Gas),
+            Line::MbusReading(2, TIMESTAMP, volume),
+        ]));
+        let gas = reading.gas().unwrap();
+        assert_eq!(Some(MbusDevice::Gas), gas.device);
+        assert_eq!(Some((TIMESTAMP, volume)), gas.reading);
+    }
+
+    #[test]
+    fn gas_meter_is_on_the_first_channel_without_device_types() {
+        let volume = Decimal::new(1001, 3, Some(Unit::M3));
+        let reading = Reading::from_telegram(&telegram([Line::MbusReading(1, TIMESTAMP, volume)]));
+        assert_eq!(Some((TIMESTAMP, volume)), reading.gas().unwrap().reading);
+        assert!(reading.mbus_device(MbusDevice::Gas).is_none());
+    }
+
+    #[test]
+    fn parser_counts_telegrams() {
+        let mut parser = Parser::new(&[]);
+        let (read, res) = parser.feed(DSMR22_TELEGRAM);
+        assert_eq!(DSMR22_TELEGRAM.len(), read);
+        let (_, reading) = res.unwrap().unwrap();
+        assert_eq!(Some(Version::Dsmr2), reading.protocol);
+        assert_eq!([Some(kwh(12345678)), None], reading.delivered);
+
+        let (_, res) = parser.feed(b"/XMX5LGBBFFB231237741\r\n\r\n1-3:0.2.8(42)\r\n!0000\r\n");
+        assert!(matches!(res, Some(Err(TelegramParseError::CrcMismatch(_)))));
+        let (_, res) = parser.feed(b"/XMX5LGBBFFB231237741\r\n\r\n1-3:0.2.8(42)\r\n!\r\n");
+        assert!(matches!(res, Some(Err(TelegramParseError::MissingCrc))));
+
+        let stats = parser.stats();
+        assert_eq!(1, stats.parsed);
+        assert_eq!(1, stats.crc_mismatches);
+        assert_eq!(1, stats.malformed);
+        assert_eq!(2, stats.rejected());
+        parser.reset_stats();
+        assert_eq!(0, parser.stats().parsed);
+        assert_eq!(0, parser.stats().rejected());
+    }
+}
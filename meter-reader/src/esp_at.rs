@@ -0,0 +1,401 @@
+//! A Wi-Fi uplink through an ESP8266 or ESP32 running Espressif's AT
+//! firmware, on a serial port of its own, for where there is no Ethernet.
+//!
+//! The module keeps a single TCP connection to a collector, over which every
+//! reading is sent as a line of JSON, the same as `show telegram` prints.
+//! The module does the TCP itself, so this doesn't go through smoltcp, and
+//! the other clients stay on Ethernet.
+//!
+//! The serial port is polled like the console's, and everything is driven
+//! by `poll`, which never blocks. Every command has a timeout, after which
+//! the module is reset. The module may also reset by itself, or lose the
+//! access point or the connection, which it tells us about at any time, so
+//! each of those sends the state machine back to where it can recover from.
+
+use core::fmt::{self, Write as _};
+
+use arrayvec::ArrayVec;
+use embedded_hal::serial;
+
+use crate::{dsmr::Reading, json, ring_buffer::RingBuffer};
+
+const LINE_SZ: usize = 128;
+// Readings that wait for the connection. When it is full, new readings are
+// dropped.
+const TX_BUF_SZ: usize = 4096;
+const RX_BUF_SZ: usize = 1024;
+// Commands, and the data of a send. At least `MAX_SEND_SZ` plus a command.
+const OUTPUT_SZ: usize = 1536;
+// The most bytes per AT+CIPSEND.
+const MAX_SEND_SZ: usize = 1024;
+const COMMAND_TIMEOUT_MS: i64 = 2_000;
+// Joining an access point takes a few seconds, and up to 15 s.
+const JOIN_TIMEOUT_MS: i64 = 20_000;
+const CONNECT_TIMEOUT_MS: i64 = 10_000;
+const RESET_TIMEOUT_MS: i64 = 5_000;
+// How long to wait after a failure, before trying again.
+const RETRY_DELAY_MS: i64 = 5_000;
+// Run in order after a reset: no echo, station mode, a single connection.
+const SETUP: [&str; 3] = ["ATE0", "AT+CWMODE=1", "AT+CIPMUX=0"];
+
+#[derive(Clone, Copy, Debug)]
+pub struct WifiConfig {
+    pub ssid: &'static str,
+    pub password: &'static str,
+    /// The collector that the readings are sent to.
+    pub host: &'static str,
+    pub port: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WifiStats {
+    /// Times that the module was reset, or reset by itself.
+    pub resets: u32,
+    /// Readings that didn't fit while the connection was down.
+    pub dropped: u32,
+    pub bytes_sent: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Sent AT+RST, waiting for `ready`.
+    Resetting,
+    /// Sent the `SETUP` command at `step`.
+    Setup {
+        step: usize,
+    },
+    Joining,
+    Connecting,
+    Connected,
+    /// Sent AT+CIPSEND for `len` bytes, waiting for the prompt, and then for
+    /// the module to have sent them.
+    Sending {
+        len: usize,
+    },
+    /// Waiting until `until` to try `then` again.
+    Waiting {
+        until: i64,
+        then: Retry,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Retry {
+    Reset,
+    Join,
+    Connect,
+}
+
+// What the module tells us, a line at a time, apart from the prompt and
+// received data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    Ok,
+    Error,
+    Ready,
+    SendOk,
+    SendFail,
+    AlreadyConnected,
+    WifiDisconnected,
+    Closed,
+    Prompt,
+    Other,
+}
+
+impl Event {
+    fn parse(line: &[u8]) -> Self {
+        match line {
+            b"OK" => Event::Ok,
+            b"ERROR" | b"FAIL" => Event::Error,
+            b"ready" => Event::Ready,
+            b"SEND OK" => Event::SendOk,
+            b"SEND FAIL" => Event::SendFail,
+            b"ALREADY CONNECTED" => Event::AlreadyConnected,
+            b"WIFI DISCONNECT" => Event::WifiDisconnected,
+            b"CLOSED" => Event::Closed,
+            _ => Event::Other,
+        }
+    }
+}
+
+pub struct EspAt<S> {
+    serial: S,
+    config: WifiConfig,
+    state: State,
+    // When the command that is waited for was sent.
+    since: i64,
+    line: ArrayVec<[u8; LINE_SZ]>,
+    // Bytes of received data that are still to come, after `+IPD,<len>:`.
+    receiving: usize,
+    tx: RingBuffer<TX_BUF_SZ>,
+    rx: RingBuffer<RX_BUF_SZ>,
+    output: Output,
+    stats: WifiStats,
+}
+
+impl<S> EspAt<S>
+where
+    S: serial::Read<u8> + serial::Write<u8>,
+{
+    /// Resets the module, after which it joins the access point and
+    /// connects, as `poll` is called.
+    pub fn new(serial: S, config: WifiConfig, now: i64) -> Self {
+        let mut esp = Self {
+            serial,
+            config,
+            state: State::Resetting,
+            since: now,
+            line: ArrayVec::new(),
+            receiving: 0,
+            tx: RingBuffer::new(),
+            rx: RingBuffer::new(),
+            output: Output(RingBuffer::new()),
+            stats: WifiStats::default(),
+        };
+        esp.reset(now);
+        esp
+    }
+
+    pub fn stats(&self) -> WifiStats {
+        self.stats
+    }
+
+    /// Queues `reading`, which is sent once the connection is up.
+    pub fn send_reading(&mut self, reading: &Reading) {
+        let mut line = Line(ArrayVec::new());
+        if json::write_reading(&mut line, reading).is_err() || line.0.try_push(b'\n').is_err() {
+            log::warn!("Reading doesn't fit in a Wi-Fi send");
+            return;
+        }
+        if TX_BUF_SZ - self.tx.len() < line.0.len() {
+            self.stats.dropped = self.stats.dropped.saturating_add(1);
+            return;
+        }
+        for byte in line.0 {
+            self.tx.push(byte);
+        }
+    }
+
+    /// Data from the collector.
+    pub fn received(&self) -> &[u8] {
+        self.rx.peek()
+    }
+
+    pub fn consume(&mut self, count: usize) {
+        self.rx.consume(count);
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        while let Ok(byte) = self.serial.read() {
+            if let Some(event) = self.receive(byte) {
+                self.handle(now, event);
+            }
+        }
+        self.check_timeout(now);
+        match self.state {
+            State::Waiting { until, then } if now >= until => match then {
+                Retry::Reset => self.reset(now),
+                Retry::Join => self.join(now),
+                Retry::Connect => self.connect(now),
+            },
+            State::Connected if !self.tx.is_empty() => {
+                let len = self.tx.len().min(MAX_SEND_SZ);
+                self.command(now, format_args!("AT+CIPSEND={}", len));
+                self.state = State::Sending { len };
+            }
+            _ => {}
+        }
+        while let Some(byte) = self.output.0.peek().first().copied() {
+            if self.serial.write(byte).is_err() {
+                break;
+            }
+            self.output.0.consume(1);
+        }
+    }
+
+    // Returns an event once a line, or the prompt, is complete.
+    fn receive(&mut self, byte: u8) -> Option<Event> {
+        if self.receiving > 0 {
+            self.receiving -= 1;
+            if !self.rx.push(byte) {
+                log::warn!("Dropped data from the Wi-Fi connection");
+            }
+            return None;
+        }
+        match byte {
+            b'\n' => {
+                let event = Event::parse(self.line.strip_suffix(b"\r").unwrap_or(&self.line[..]));
+                self.line.clear();
+                Some(event)
+            }
+            // The prompt for the data of a send isn't followed by a newline.
+            b'>' if self.line.is_empty() => Some(Event::Prompt),
+            b':' if self.line.starts_with(b"+IPD,") => {
+                self.receiving = parse_len(&self.line[5..]).unwrap_or(0);
+                self.line.clear();
+                None
+            }
+            _ => {
+                // Long lines aren't anything that is waited for.
+                let _ = self.line.try_push(byte);
+                None
+            }
+        }
+    }
+
+    fn handle(&mut self, now: i64, event: Event) {
+        match (self.state, event) {
+            (_, Event::Other) => {}
+            (State::Resetting, Event::Ready) => self.setup(now, 0),
+            (_, Event::Ready) => {
+                log::warn!("Wi-Fi module reset itself");
+                self.stats.resets = self.stats.resets.saturating_add(1);
+                self.setup(now, 0);
+            }
+            (State::Setup { step }, Event::Ok) => self.setup(now, step + 1),
+            (State::Joining, Event::Ok) => {
+                log::info!("Joined Wi-Fi network {}", self.config.ssid);
+                self.connect(now);
+            }
+            (State::Connecting, Event::Ok) | (State::Connecting, Event::AlreadyConnected) => {
+                log::info!(
+                    "Connected to {}:{} over Wi-Fi",
+                    self.config.host,
+                    self.config.port
+                );
+                self.state = State::Connected;
+            }
+            (State::Sending { len }, Event::Prompt) => {
+                let (first, second) = self.tx.split_read();
+                for byte in first.iter().chain(second).take(len) {
+                    self.output.0.push(*byte);
+                }
+                self.since = now;
+            }
+            (State::Sending { len }, Event::SendOk) => {
+                self.tx.consume(len);
+                self.stats.bytes_sent = self.stats.bytes_sent.saturating_add(len as u32);
+                self.state = State::Connected;
+            }
+            (_, Event::WifiDisconnected) => {
+                log::warn!("Lost Wi-Fi network {}", self.config.ssid);
+                self.wait(now, Retry::Join);
+            }
+            (State::Connected, Event::Closed) | (State::Sending { .. }, Event::Closed) => {
+                log::warn!("Wi-Fi connection closed");
+                self.wait(now, Retry::Connect);
+            }
+            (State::Joining, Event::Error) => {
+                log::warn!("Failed to join Wi-Fi network {}", self.config.ssid);
+                self.wait(now, Retry::Join);
+            }
+            // The data that wasn't sent stays, and is sent again.
+            (State::Connecting, Event::Error)
+            | (State::Sending { .. }, Event::Error)
+            | (State::Sending { .. }, Event::SendFail) => {
+                log::warn!(
+                    "Failed to send to {}:{} over Wi-Fi",
+                    self.config.host,
+                    self.config.port
+                );
+                self.wait(now, Retry::Connect);
+            }
+            (State::Setup { step }, Event::Error) => {
+                log::warn!("Wi-Fi module rejected {}", SETUP[step]);
+                self.wait(now, Retry::Reset);
+            }
+            (state, event) => log::debug!("Ignoring {:?} from Wi-Fi module in {:?}", event, state),
+        }
+    }
+
+    fn check_timeout(&mut self, now: i64) {
+        let timeout_ms = match self.state {
+            State::Resetting => RESET_TIMEOUT_MS,
+            State::Joining => JOIN_TIMEOUT_MS,
+            State::Connecting => CONNECT_TIMEOUT_MS,
+            State::Setup { .. } | State::Sending { .. } => COMMAND_TIMEOUT_MS,
+            State::Connected | State::Waiting { .. } => return,
+        };
+        if now - self.since > timeout_ms {
+            log::warn!(
+                "Wi-Fi module didn't answer in {:?}, resetting it",
+                self.state
+            );
+            self.wait(now, Retry::Reset);
+        }
+    }
+
+    fn reset(&mut self, now: i64) {
+        self.stats.resets = self.stats.resets.saturating_add(1);
+        self.command(now, format_args!("AT+RST"));
+        self.state = State::Resetting;
+    }
+
+    fn setup(&mut self, now: i64, step: usize) {
+        match SETUP.get(step) {
+            Some(command) => {
+                self.command(now, format_args!("{}", command));
+                self.state = State::Setup { step };
+            }
+            None => self.join(now),
+        }
+    }
+
+    fn join(&mut self, now: i64) {
+        let WifiConfig { ssid, password, .. } = self.config;
+        self.command(now, format_args!("AT+CWJAP=\"{}\",\"{}\"", ssid, password));
+        self.state = State::Joining;
+    }
+
+    fn connect(&mut self, now: i64) {
+        let WifiConfig { host, port, .. } = self.config;
+        self.command(
+            now,
+            format_args!("AT+CIPSTART=\"TCP\",\"{}\",{}", host, port),
+        );
+        self.state = State::Connecting;
+    }
+
+    fn wait(&mut self, now: i64, then: Retry) {
+        self.receiving = 0;
+        self.state = State::Waiting {
+            until: now + RETRY_DELAY_MS,
+            then,
+        };
+    }
+
+    fn command(&mut self, now: i64, command: fmt::Arguments) {
+        // Anything that is left belongs to a command that was given up on.
+        self.output.0.clear();
+        self.line.clear();
+        let _ = write!(self.output, "{}\r\n", command);
+        self.since = now;
+    }
+}
+
+fn parse_len(digits: &[u8]) -> Option<usize> {
+    core::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+// Commands and data for the module. What doesn't fit is dropped.
+struct Output(RingBuffer<OUTPUT_SZ>);
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0.push(byte);
+        }
+        Ok(())
+    }
+}
+
+// A reading as JSON, which has to fit in a single send.
+struct Line(ArrayVec<[u8; MAX_SEND_SZ]>);
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0
+            .try_extend_from_slice(s.as_bytes())
+            .map_err(|_| fmt::Error)
+    }
+}
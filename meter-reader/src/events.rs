@@ -0,0 +1,169 @@
+use crate::dsmr::Reading;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The active tariff changed. `from` is `None` for the first telegram.
+    TariffChanged { from: Option<u8>, to: u8 },
+    /// The power delivered to the client rose above the threshold.
+    ThresholdExceeded { power: i64 },
+    /// The power delivered to the client dropped back below the threshold.
+    ThresholdCleared { power: i64 },
+    /// The client started returning power to the grid.
+    ReturnStarted { power: i64 },
+    /// The client stopped returning power to the grid.
+    ReturnStopped,
+}
+
+/// An event, as it is sent over the uplinks, which don't drop it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Alarm {
+    pub event: Event,
+    /// When the reading that it was found in was received, in ms since the
+    /// Unix epoch, if known.
+    pub at: Option<i64>,
+}
+
+/// Compares each reading to the previous one and reports what changed.
+pub struct EventDetector {
+    threshold: i64,
+    hysteresis: i64,
+    tariff: Option<u8>,
+    above_threshold: bool,
+    returning: bool,
+}
+
+impl EventDetector {
+    /// `threshold` is the delivered power in W above which
+    /// `ThresholdExceeded` is emitted. To avoid flapping, `ThresholdCleared`
+    /// is only emitted once the power drops `hysteresis` W below it.
+    pub fn new(threshold: i64, hysteresis: i64) -> Self {
+        Self {
+            threshold,
+            hysteresis,
+            tariff: None,
+            above_threshold: false,
+            returning: false,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: i64, hysteresis: i64) {
+        self.threshold = threshold;
+        self.hysteresis = hysteresis;
+    }
+
+    /// Checks `reading` for changes, calling `emit` for each event. Values
+    /// that are missing from the reading don't produce any events.
+    pub fn update<F: FnMut(Event)>(&mut self, reading: &Reading, mut emit: F) {
+        if let Some(tariff) = reading.tariff {
+            if self.tariff != Some(tariff) {
+                emit(Event::TariffChanged {
+                    from: self.tariff,
+                    to: tariff,
+                });
+                self.tariff = Some(tariff);
+            }
+        }
+
+        if let Some(power) = reading.power_delivered.and_then(|power| power.w()) {
+            if !self.above_threshold && power > self.threshold {
+                self.above_threshold = true;
+                emit(Event::ThresholdExceeded { power });
+            } else if self.above_threshold && power < self.threshold - self.hysteresis {
+                self.above_threshold = false;
+                emit(Event::ThresholdCleared { power });
+            }
+        }
+
+        if let Some(power) = reading.power_returned.and_then(|power| power.w()) {
+            if !self.returning && power > 0 {
+                self.returning = true;
+                emit(Event::ReturnStarted { power });
+            } else if self.returning && power == 0 {
+                self.returning = false;
+                emit(Event::ReturnStopped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dsmr42::{Decimal, Unit};
+    use std::vec::Vec;
+
+    const THRESHOLD: i64 = 3000;
+    const HYSTERESIS: i64 = 500;
+
+    fn reading(tariff: u8, delivered: i64, returned: i64) -> Reading {
+        Reading {
+            tariff: Some(tariff),
+            power_delivered: Some(Decimal::new(delivered, 3, Some(Unit::KW))),
+            power_returned: Some(Decimal::new(returned, 3, Some(Unit::KW))),
+            ..Reading::default()
+        }
+    }
+
+    fn update(detector: &mut EventDetector, reading: &Reading) -> Vec<Event> {
+        let mut events = Vec::new();
+        detector.update(reading, |event| events.push(event));
+        events
+    }
+
+    #[test]
+    fn first_reading_reports_the_tariff() {
+        let mut detector = EventDetector::new(THRESHOLD, HYSTERESIS);
+        let event = Event::TariffChanged { from: None, to: 1 };
+        assert_eq!(std::vec![event], update(&mut detector, &reading(1, 100, 0)));
+        assert_eq!(
+            Vec::<Event>::new(),
+            update(&mut detector, &reading(1, 200, 0))
+        );
+        let event = Event::TariffChanged {
+            from: Some(1),
+            to: 2,
+        };
+        assert_eq!(std::vec![event], update(&mut detector, &reading(2, 200, 0)));
+    }
+
+    #[test]
+    fn threshold_is_cleared_below_the_hysteresis() {
+        let mut detector = EventDetector::new(THRESHOLD, HYSTERESIS);
+        update(&mut detector, &reading(1, 1000, 0));
+        assert_eq!(
+            std::vec![Event::ThresholdExceeded { power: 3001 }],
+            update(&mut detector, &reading(1, 3001, 0))
+        );
+        // Dropping below the threshold, but not by the hysteresis, doesn't
+        // clear it, and rising above it again isn't reported again.
+        assert!(update(&mut detector, &reading(1, 2500, 0)).is_empty());
+        assert!(update(&mut detector, &reading(1, 3500, 0)).is_empty());
+        assert_eq!(
+            std::vec![Event::ThresholdCleared { power: 2499 }],
+            update(&mut detector, &reading(1, 2499, 0))
+        );
+        assert!(update(&mut detector, &reading(1, 3000, 0)).is_empty());
+    }
+
+    #[test]
+    fn returning_power_is_reported_once() {
+        let mut detector = EventDetector::new(THRESHOLD, HYSTERESIS);
+        update(&mut detector, &reading(1, 0, 0));
+        assert_eq!(
+            std::vec![Event::ReturnStarted { power: 800 }],
+            update(&mut detector, &reading(1, 0, 800))
+        );
+        assert!(update(&mut detector, &reading(1, 0, 1200)).is_empty());
+        assert_eq!(
+            std::vec![Event::ReturnStopped],
+            update(&mut detector, &reading(1, 100, 0))
+        );
+    }
+
+    #[test]
+    fn missing_values_are_ignored() {
+        let mut detector = EventDetector::new(THRESHOLD, HYSTERESIS);
+        update(&mut detector, &reading(1, 4000, 500));
+        assert!(update(&mut detector, &Reading::default()).is_empty());
+    }
+}
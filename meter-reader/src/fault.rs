@@ -0,0 +1,84 @@
+//! Records faults in the crash record of `panic`, and resets.
+//!
+//! MemManage, BusFault and UsageFault are left disabled, so that they
+//! escalate to a HardFault, which is the only handler that cortex-m-rt
+//! passes the stacked registers to. CFSR tells them apart.
+
+use core::fmt::{self, Display};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+
+use crate::panic::{self, Registers};
+
+const HFSR_VECTTBL: u32 = 1 << 1;
+const HFSR_FORCED: u32 = 1 << 30;
+
+// The fault status bits of CFSR.
+const FAULTS: [(u32, &str); 17] = [
+    (1 << 0, "MemManage: instruction access violation"),
+    (1 << 1, "MemManage: data access violation"),
+    (1 << 3, "MemManage: fault on exception return"),
+    (1 << 4, "MemManage: fault on exception entry"),
+    (1 << 5, "MemManage: fault on lazy FP state preservation"),
+    (1 << 8, "BusFault: instruction bus error"),
+    (1 << 9, "BusFault: precise data bus error"),
+    (1 << 10, "BusFault: imprecise data bus error"),
+    (1 << 11, "BusFault: fault on exception return"),
+    (1 << 12, "BusFault: fault on exception entry"),
+    (1 << 13, "BusFault: fault on lazy FP state preservation"),
+    (1 << 16, "UsageFault: undefined instruction"),
+    (1 << 17, "UsageFault: invalid state"),
+    (1 << 18, "UsageFault: invalid PC load on exception return"),
+    (1 << 19, "UsageFault: no coprocessor"),
+    (1 << 24, "UsageFault: unaligned access"),
+    (1 << 25, "UsageFault: division by zero"),
+];
+
+#[exception]
+fn HardFault(frame: &ExceptionFrame) -> ! {
+    let mut registers = Registers::capture();
+    registers.pc = frame.pc;
+    registers.lr = frame.lr;
+    registers.psr = frame.xpsr;
+    // Where the registers were stacked.
+    registers.sp = frame as *const ExceptionFrame as u32;
+    panic::record_crash(
+        format_args!(
+            "{}",
+            Description {
+                cfsr: registers.cfsr,
+                hfsr: registers.hfsr,
+            }
+        ),
+        registers,
+    );
+    // Nothing can be trusted anymore, not even the logger, so this doesn't
+    // wait for anything.
+    SCB::sys_reset();
+}
+
+struct Description {
+    cfsr: u32,
+    hfsr: u32,
+}
+
+impl Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HardFault")?;
+        if self.hfsr & HFSR_VECTTBL != 0 {
+            f.write_str(", vector table read failed")?;
+        }
+        if self.hfsr & HFSR_FORCED != 0 {
+            f.write_str(", escalated")?;
+        }
+        let mut faults = FAULTS.iter().filter(|(bit, _)| self.cfsr & bit != 0);
+        if let Some((_, first)) = faults.next() {
+            write!(f, " from {}", first)?;
+            for (_, fault) in faults {
+                write!(f, ", {}", fault)?;
+            }
+        }
+        Ok(())
+    }
+}
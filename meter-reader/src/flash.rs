@@ -0,0 +1,269 @@
+//! Erases and programs the QSPI flash that the firmware runs from, through IP
+//! commands of the FlexSPI controller, like Teensyduino's EEPROM emulation.
+//!
+//! Only the 60 KB that Teensyduino reserves for EEPROM emulation can be
+//! used freely. It lies between the program, which the linker script keeps
+//! out of the last 64 KB, and the recovery program in the very last sector.
+//! `ota` also writes to the area of the program itself.
+//!
+//! The flash can't be read while it is busy, so interrupts are disabled until
+//! an operation has finished. That relies on all code running from ITCM, as
+//! teensy4-rt arranges. Erasing a sector takes 45 ms, and up to 400 ms.
+
+use core::{
+    ptr, slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use cortex_m::{asm, interrupt};
+
+pub const SECTOR_SZ: usize = 4096;
+/// The number of sectors that can be used, numbered from 0.
+pub const SECTORS: usize = 15;
+/// The number of sectors of the program area, numbered from 0 at the start
+/// of the flash.
+pub const PROGRAM_SECTORS: usize = REGION_OFFSET / SECTOR_SZ;
+// Programming can't cross a page boundary.
+const PAGE_SZ: usize = 256;
+// Where the flash is mapped, and where the usable sectors start within it.
+const FLASH_BASE: usize = 0x6000_0000;
+const REGION_OFFSET: usize = 0x1F_0000;
+const CACHE_LINE_SZ: usize = 32;
+
+const FLEXSPI: usize = 0x402A_8000;
+const MCR0: usize = FLEXSPI;
+const INTR: usize = FLEXSPI + 0x14;
+const LUTKEY: usize = FLEXSPI + 0x18;
+const LUTCR: usize = FLEXSPI + 0x1C;
+const IPCR0: usize = FLEXSPI + 0xA0;
+const IPCR1: usize = FLEXSPI + 0xA4;
+const IPCMD: usize = FLEXSPI + 0xB0;
+const IPRXFCR: usize = FLEXSPI + 0xB8;
+const IPTXFCR: usize = FLEXSPI + 0xBC;
+const RFDR0: usize = FLEXSPI + 0x100;
+const TFDR0: usize = FLEXSPI + 0x180;
+// The last of the 16 LUT sequences, which the boot ROM leaves unused.
+const SEQ_ID: u32 = 15;
+const LUT_SEQ: usize = FLEXSPI + 0x200 + SEQ_ID as usize * 16;
+// Data cache invalidate by address.
+const SCB_DCIMVAC: usize = 0xE000_EF5C;
+
+const LUT_KEY: u32 = 0x5AF0_5AF0;
+const LUT_UNLOCK: u32 = 0x02;
+const MCR0_SWRESET: u32 = 0x01;
+const INTR_IPCMDDONE: u32 = 0x01;
+const INTR_IPTXWE: u32 = 0x40;
+const IPCMD_TRG: u32 = 0x01;
+const FIFO_CLEAR: u32 = 0x01;
+// Bytes that fit in the transmit FIFO below its watermark.
+const TX_WATERMARK_SZ: usize = 8;
+
+// LUT instructions and their number of pads.
+const CMD_SDR: u32 = 0x01;
+const RADDR_SDR: u32 = 0x02;
+const WRITE_SDR: u32 = 0x08;
+const READ_SDR: u32 = 0x09;
+const PADS_1: u32 = 0;
+const PADS_4: u32 = 2;
+
+// Commands of the W25Q16JV.
+const WRITE_ENABLE: u32 = 0x06;
+const READ_STATUS: u32 = 0x05;
+const QUAD_PAGE_PROGRAM: u32 = 0x32;
+const SECTOR_ERASE: u32 = 0x20;
+const STATUS_BUSY: u8 = 0x01;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The usable sectors of the flash. Erased bytes read as `0xFF`, and
+/// programming can only clear bits.
+pub struct Flash {
+    _private: (),
+}
+
+impl Flash {
+    /// Returns the flash the first time it is called, and `None` after.
+    pub fn take() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Self { _private: () })
+    }
+
+    /// The `len` bytes at `offset` in `sector`.
+    pub fn read(&self, sector: usize, offset: usize, len: usize) -> &[u8] {
+        read_at(address(sector, offset, len), len)
+    }
+
+    pub fn erase(&mut self, sector: usize) {
+        erase_at(address(sector, 0, SECTOR_SZ));
+    }
+
+    /// Programs `data` at `offset` in `sector`, clearing bits that are clear
+    /// in `data`.
+    pub fn program(&mut self, sector: usize, offset: usize, data: &[u8]) {
+        program_at(address(sector, offset, data.len()), data);
+    }
+
+    /// Like `read`, in `sector` of the program area.
+    pub fn read_program(&self, sector: usize, offset: usize, len: usize) -> &[u8] {
+        read_at(program_address(sector, offset, len), len)
+    }
+
+    /// Like `erase`, in `sector` of the program area.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may be read from the sector while the program is running,
+    /// unless it is no longer part of the program.
+    pub unsafe fn erase_program(&mut self, sector: usize) {
+        erase_at(program_address(sector, 0, SECTOR_SZ));
+    }
+
+    /// Like `program`, in `sector` of the program area.
+    ///
+    /// # Safety
+    ///
+    /// As for `erase_program`.
+    pub unsafe fn program_program(&mut self, sector: usize, offset: usize, data: &[u8]) {
+        program_at(program_address(sector, offset, data.len()), data);
+    }
+}
+
+// The address of `len` bytes at `offset` in `sector`, relative to the start
+// of the flash.
+fn address(sector: usize, offset: usize, len: usize) -> usize {
+    // Anything beyond the usable sectors would overwrite the firmware or the
+    // recovery program.
+    assert!(sector < SECTORS && offset + len <= SECTOR_SZ);
+    REGION_OFFSET + sector * SECTOR_SZ + offset
+}
+
+fn program_address(sector: usize, offset: usize, len: usize) -> usize {
+    assert!(sector < PROGRAM_SECTORS && offset + len <= SECTOR_SZ);
+    sector * SECTOR_SZ + offset
+}
+
+fn read_at(addr: usize, len: usize) -> &'static [u8] {
+    // The flash is memory-mapped, and can only change through `&mut Flash`,
+    // which the returned slice is bound to by the callers.
+    unsafe { slice::from_raw_parts((FLASH_BASE + addr) as *const u8, len) }
+}
+
+fn erase_at(addr: usize) {
+    interrupt::free(|_| unsafe {
+        write_enable();
+        set_sequence(
+            instr(CMD_SDR, PADS_1, SECTOR_ERASE) | instr(RADDR_SDR, PADS_1, 24) << 16,
+            0,
+        );
+        write(IPCR0, addr as u32);
+        write(IPCR1, SEQ_ID << 16);
+        run_command();
+        finish(addr, SECTOR_SZ);
+    });
+}
+
+fn program_at(mut addr: usize, mut data: &[u8]) {
+    while !data.is_empty() {
+        let len = data.len().min(PAGE_SZ - addr % PAGE_SZ);
+        interrupt::free(|_| unsafe { program_page(addr, &data[..len]) });
+        addr += len;
+        data = &data[len..];
+    }
+}
+
+unsafe fn program_page(addr: usize, data: &[u8]) {
+    write_enable();
+    set_sequence(
+        instr(CMD_SDR, PADS_1, QUAD_PAGE_PROGRAM) | instr(RADDR_SDR, PADS_1, 24) << 16,
+        instr(WRITE_SDR, PADS_4, 1),
+    );
+    write(IPTXFCR, FIFO_CLEAR);
+    write(IPCR0, addr as u32);
+    write(IPCR1, SEQ_ID << 16 | data.len() as u32);
+    write(IPCMD, IPCMD_TRG);
+    let mut chunks = data.chunks(TX_WATERMARK_SZ);
+    loop {
+        let intr = read(INTR);
+        if intr & INTR_IPCMDDONE != 0 {
+            break;
+        }
+        if intr & INTR_IPTXWE != 0 {
+            if let Some(chunk) = chunks.next() {
+                let mut bytes = [0xFF; TX_WATERMARK_SZ];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                for (i, word) in bytes.chunks(4).enumerate() {
+                    let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                    write(TFDR0 + i * 4, word);
+                }
+            }
+            write(INTR, INTR_IPTXWE);
+        }
+    }
+    write(INTR, INTR_IPCMDDONE | INTR_IPTXWE);
+    finish(addr, data.len());
+}
+
+unsafe fn write_enable() {
+    write(LUTKEY, LUT_KEY);
+    write(LUTCR, LUT_UNLOCK);
+    set_sequence(instr(CMD_SDR, PADS_1, WRITE_ENABLE), 0);
+    write(IPCR0, 0);
+    write(IPCR1, SEQ_ID << 16);
+    run_command();
+}
+
+// Waits until the flash is no longer busy, and makes sure that the changed
+// bytes are read from the flash again.
+unsafe fn finish(addr: usize, len: usize) {
+    set_sequence(
+        instr(CMD_SDR, PADS_1, READ_STATUS) | instr(READ_SDR, PADS_1, 1) << 16,
+        0,
+    );
+    loop {
+        write(IPRXFCR, FIFO_CLEAR);
+        write(IPCR0, 0);
+        write(IPCR1, SEQ_ID << 16 | 1);
+        run_command();
+        if ptr::read_volatile(RFDR0 as *const u8) & STATUS_BUSY == 0 {
+            break;
+        }
+    }
+    // Both the controller's read buffers and the data cache may hold the
+    // bytes as they were before.
+    write(MCR0, read(MCR0) | MCR0_SWRESET);
+    while read(MCR0) & MCR0_SWRESET != 0 {}
+    asm::dsb();
+    let start = (FLASH_BASE + addr) & !(CACHE_LINE_SZ - 1);
+    for line in (start..FLASH_BASE + addr + len).step_by(CACHE_LINE_SZ) {
+        write(SCB_DCIMVAC, line as u32);
+    }
+    asm::dsb();
+    asm::isb();
+}
+
+unsafe fn set_sequence(first: u32, second: u32) {
+    write(LUT_SEQ, first);
+    write(LUT_SEQ + 4, second);
+    write(LUT_SEQ + 8, 0);
+    write(LUT_SEQ + 12, 0);
+}
+
+unsafe fn run_command() {
+    write(IPCMD, IPCMD_TRG);
+    while read(INTR) & INTR_IPCMDDONE == 0 {}
+    write(INTR, INTR_IPCMDDONE);
+}
+
+fn instr(opcode: u32, pads: u32, operand: u32) -> u32 {
+    opcode << 10 | pads << 8 | operand
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,140 @@
+/// DSMR telegrams start with `/` and end with `!`, followed by a four-digit
+/// CRC and a CRLF. DSMR 2.2 and 3.0 telegrams have no CRC.
+pub const DSMR_FRAMING: Framing = Framing::new(b"/", b"!", b"\r\n");
+
+/// Describes how frames are delimited in a byte stream.
+///
+/// A frame starts with `start`, and ends with the first occurrence of
+/// `trailer_end` after the first occurrence of `end` that follows it, or
+/// right after `end` if `trailer_end` is empty. Neither marker may be empty.
+pub struct Framing {
+    start: &'static [u8],
+    end: &'static [u8],
+    trailer_end: &'static [u8],
+}
+
+impl Framing {
+    pub const fn new(start: &'static [u8], end: &'static [u8], trailer_end: &'static [u8]) -> Self {
+        Self {
+            start,
+            end,
+            trailer_end,
+        }
+    }
+
+    /// Looks for the first complete frame in `buffer`.
+    ///
+    /// Returns the number of bytes that may be consumed, which covers any
+    /// garbage preceding the frame and the frame itself, along with the frame
+    /// if one was found.
+    pub fn scan<'a>(&self, buffer: &'a [u8]) -> (usize, Option<&'a [u8]>) {
+        let mut frame_start = match find(buffer, self.start) {
+            Some(pos) => pos,
+            // Keep enough bytes around to detect a start marker that has only
+            // been partially received.
+            None => return (buffer.len().saturating_sub(self.start.len() - 1), None),
+        };
+        loop {
+            let body_start = frame_start + self.start.len();
+            let body = &buffer[body_start..];
+            let end = find(body, self.end);
+            // If a new frame starts before the current one ends, the current
+            // frame was cut off, so we skip to the new one.
+            if let Some(next_start) = find(body, self.start) {
+                if end.map(|end| next_start < end).unwrap_or(true) {
+                    frame_start = body_start + next_start;
+                    continue;
+                }
+            }
+            let trailer_start = match end {
+                Some(end) => body_start + end + self.end.len(),
+                None => return (frame_start, None),
+            };
+            if self.trailer_end.is_empty() {
+                return (trailer_start, Some(&buffer[frame_start..trailer_start]));
+            }
+            let trailer = &buffer[trailer_start..];
+            let trailer_end = find(trailer, self.trailer_end);
+            // Likewise, if the trailer was cut off.
+            if let Some(next_start) = find(trailer, self.start) {
+                if trailer_end.map(|end| next_start < end).unwrap_or(true) {
+                    frame_start = trailer_start + next_start;
+                    continue;
+                }
+            }
+            return match trailer_end {
+                Some(end) => {
+                    let frame_end = trailer_start + end + self.trailer_end.len();
+                    (frame_end, Some(&buffer[frame_start..frame_end]))
+                }
+                None => (frame_start, None),
+            };
+        }
+    }
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_is_found_after_garbage() {
+        let buffer = b"xx/body!ABCD\r\nrest";
+        assert_eq!(
+            (14, Some(&b"/body!ABCD\r\n"[..])),
+            DSMR_FRAMING.scan(buffer)
+        );
+    }
+
+    #[test]
+    fn incomplete_frame_is_kept() {
+        assert_eq!((2, None), DSMR_FRAMING.scan(b"xx/body!AB"));
+        assert_eq!((2, None), DSMR_FRAMING.scan(b"xx/body"));
+        assert_eq!((2, None), DSMR_FRAMING.scan(b"xx/body!ABCD\r"));
+    }
+
+    #[test]
+    fn frame_without_crc_is_found() {
+        // A DSMR 2.2 telegram, which has no CRC.
+        let buffer = b"/ISk5\\2MT382-1000\r\n\r\n1-0:1.8.1(12345.678*kWh)\r\n!\r\n/ISk5";
+        assert_eq!(
+            (
+                50,
+                Some(&b"/ISk5\\2MT382-1000\r\n\r\n1-0:1.8.1(12345.678*kWh)\r\n!\r\n"[..])
+            ),
+            DSMR_FRAMING.scan(buffer)
+        );
+    }
+
+    #[test]
+    fn frame_with_cut_off_trailer_is_skipped() {
+        let buffer = b"/cut!AB/body!ABCD\r\n";
+        assert_eq!(
+            (19, Some(&b"/body!ABCD\r\n"[..])),
+            DSMR_FRAMING.scan(buffer)
+        );
+    }
+
+    #[test]
+    fn cut_off_frame_is_skipped() {
+        let buffer = b"/cut/body!ABCD\r\n";
+        assert_eq!(
+            (16, Some(&b"/body!ABCD\r\n"[..])),
+            DSMR_FRAMING.scan(buffer)
+        );
+    }
+
+    #[test]
+    fn partial_start_marker_is_kept() {
+        let framing = Framing::new(b"<<", b">>", b"");
+        assert_eq!((3, None), framing.scan(b"abc<"));
+        assert_eq!((8, Some(&b"<<x>>"[..])), framing.scan(b"abc<<x>>"));
+    }
+}
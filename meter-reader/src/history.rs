@@ -0,0 +1,200 @@
+use crate::dsmr::Reading;
+
+/// Windows over which `History::aggregates` computes aggregates, in ms.
+pub const AGGREGATE_WINDOWS: [i64; 3] = [60_000, 5 * 60_000, 15 * 60_000];
+
+/// The net power of a single telegram: positive when power is delivered to
+/// the client, negative when it is returned.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// When the telegram was received, in ms.
+    pub at: i64,
+    /// Net power in W.
+    pub power: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aggregate {
+    pub min: i32,
+    pub max: i32,
+    pub average: i32,
+    pub samples: usize,
+}
+
+/// Keeps the net power of the last `N` telegrams.
+///
+/// Only a single sample is stored per telegram, so `N` can be made large
+/// enough to cover the longest aggregate window.
+pub struct History<const N: usize> {
+    samples: [Sample; N],
+    // Index of the oldest sample.
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> History<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample { at: 0, power: 0 }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a sample, overwriting the oldest one if the history is full.
+    pub fn push(&mut self, sample: Sample) {
+        if self.len < N {
+            self.samples[(self.head + self.len) % N] = sample;
+            self.len += 1;
+        } else {
+            self.samples[self.head] = sample;
+            self.head = (self.head + 1) % N;
+        }
+    }
+
+    /// Adds a sample for `reading`, if it includes the instantaneous power.
+    pub fn record(&mut self, at: i64, reading: &Reading) {
+        let delivered = reading.power_delivered.and_then(|power| power.w());
+        let returned = reading.power_returned.and_then(|power| power.w());
+        if delivered.is_none() && returned.is_none() {
+            return;
+        }
+        let power = delivered.unwrap_or(0) - returned.unwrap_or(0);
+        self.push(Sample {
+            at,
+            power: power as i32,
+        });
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        self.iter().next()
+    }
+
+    /// Returns the samples from newest to oldest.
+    pub fn iter(&self) -> impl Iterator<Item = Sample> + '_ {
+        (0..self.len)
+            .rev()
+            .map(move |i| self.samples[(self.head + i) % N])
+    }
+
+    /// Aggregates the samples received in the `window` ms up to `now`.
+    pub fn aggregate(&self, now: i64, window: i64) -> Option<Aggregate> {
+        let mut samples = self.iter().take_while(|sample| now - sample.at <= window);
+        let first = samples.next()?;
+        let mut aggregate = Aggregate {
+            min: first.power,
+            max: first.power,
+            average: 0,
+            samples: 1,
+        };
+        let mut sum = first.power as i64;
+        for sample in samples {
+            aggregate.min = aggregate.min.min(sample.power);
+            aggregate.max = aggregate.max.max(sample.power);
+            aggregate.samples += 1;
+            sum += sample.power as i64;
+        }
+        aggregate.average = (sum / aggregate.samples as i64) as i32;
+        Some(aggregate)
+    }
+
+    /// Aggregates the samples over each of the `AGGREGATE_WINDOWS`.
+    pub fn aggregates(&self, now: i64) -> [Option<Aggregate>; AGGREGATE_WINDOWS.len()] {
+        let mut aggregates = [None; AGGREGATE_WINDOWS.len()];
+        for (aggregate, &window) in aggregates.iter_mut().zip(AGGREGATE_WINDOWS.iter()) {
+            *aggregate = self.aggregate(now, window);
+        }
+        aggregates
+    }
+}
+
+impl<const N: usize> Default for History<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dsmr42::{Decimal, Unit};
+    use std::vec::Vec;
+
+    fn sample(at: i64, power: i32) -> Sample {
+        Sample { at, power }
+    }
+
+    fn powers<const N: usize>(history: &History<N>) -> Vec<i32> {
+        history.iter().map(|sample| sample.power).collect()
+    }
+
+    #[test]
+    fn oldest_samples_are_overwritten() {
+        let mut history = History::<3>::new();
+        for i in 0..5 {
+            history.push(sample(i * 1000, i as i32));
+        }
+        assert_eq!(3, history.len());
+        assert_eq!(std::vec![4, 3, 2], powers(&history));
+        assert_eq!(4, history.latest().unwrap().power);
+    }
+
+    #[test]
+    fn aggregate_wraps_around_the_ring() {
+        let mut history = History::<4>::new();
+        for (i, &power) in [100, -50, 300, 200, 400, 0].iter().enumerate() {
+            history.push(sample(i as i64 * 1000, power));
+        }
+        let aggregate = history.aggregate(5000, 60_000).unwrap();
+        assert_eq!(4, aggregate.samples);
+        assert_eq!(0, aggregate.min);
+        assert_eq!(400, aggregate.max);
+        assert_eq!(225, aggregate.average);
+    }
+
+    #[test]
+    fn aggregate_stops_at_the_window() {
+        let mut history = History::<8>::new();
+        history.push(sample(0, 1000));
+        history.push(sample(10_000, 200));
+        history.push(sample(20_000, 100));
+        // Samples exactly at the start of the window are included.
+        let aggregate = history.aggregate(30_000, 20_000).unwrap();
+        assert_eq!(2, aggregate.samples);
+        assert_eq!(100, aggregate.min);
+        assert_eq!(200, aggregate.max);
+        assert_eq!(150, aggregate.average);
+        assert!(history.aggregate(50_000, 20_000).is_none());
+    }
+
+    #[test]
+    fn empty_history_has_no_aggregates() {
+        let history = History::<4>::new();
+        assert!(history.latest().is_none());
+        assert!(history.aggregates(0).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn record_takes_the_net_power() {
+        let mut history = History::<4>::new();
+        history.record(0, &Reading::default());
+        assert!(history.is_empty());
+
+        let mut reading = Reading {
+            power_returned: Some(Decimal::new(1500, 3, Some(Unit::KW))),
+            ..Reading::default()
+        };
+        history.record(1000, &reading);
+        reading.power_delivered = Some(Decimal::new(2000, 3, Some(Unit::KW)));
+        history.record(2000, &reading);
+        assert_eq!(std::vec![500, -1500], powers(&history));
+    }
+}
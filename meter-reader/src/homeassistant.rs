@@ -0,0 +1,88 @@
+use core::fmt::{self, Write};
+
+use crate::{
+    build_info::BUILD_INFO,
+    json::JsonWriter,
+    mqtt::MqttConfig,
+    telemetry::{Kind, Metric, Unit},
+};
+
+/// Writes the topic that the discovery config of the sensor of `metric` is
+/// published to.
+pub fn write_topic<W: Write>(
+    writer: &mut W,
+    prefix: &str,
+    config: &MqttConfig,
+    metric: &Metric,
+) -> fmt::Result {
+    write!(
+        writer,
+        "{}/sensor/{}/{}/config",
+        prefix,
+        config.client_id,
+        metric.key()
+    )
+}
+
+/// Writes the discovery config of a sensor for `metric`, whose state is
+/// taken from the JSON published to the sample topic.
+pub fn write_config<W: Write>(writer: &mut W, config: &MqttConfig, metric: &Metric) -> fmt::Result {
+    let mut json = JsonWriter::new(writer);
+    json.begin_object()?;
+    json.key("name")?;
+    json.string(metric.title())?;
+    json.key("unique_id")?;
+    json.string(format_args!("{}_{}", config.client_id, metric.key()))?;
+    json.key("state_topic")?;
+    json.string(config.sample_topic)?;
+    json.key("value_template")?;
+    json.string(format_args!("{{{{ value_json.{} }}}}", metric.key()))?;
+    json.optional("unit_of_measurement", metric.unit, |json, unit| {
+        json.string(unit.symbol())
+    })?;
+    json.optional(
+        "device_class",
+        metric.unit.and_then(device_class),
+        JsonWriter::string,
+    )?;
+    json.key("state_class")?;
+    json.string(match metric.kind {
+        Kind::Counter => "total_increasing",
+        Kind::Gauge => "measurement",
+    })?;
+    // The status topic holds `online` and `offline`, which are the values
+    // Home Assistant expects by default.
+    json.key("availability_topic")?;
+    json.string(config.status_topic)?;
+    json.key("device")?;
+    json.begin_object()?;
+    json.key("identifiers")?;
+    json.begin_array()?;
+    json.string(config.client_id)?;
+    json.end_array()?;
+    json.key("name")?;
+    json.string("Smart meter")?;
+    json.key("model")?;
+    json.string("P1 meter reader")?;
+    json.key("sw_version")?;
+    json.string(format_args!(
+        "{} ({})",
+        BUILD_INFO.version(),
+        BUILD_INFO.revision()
+    ))?;
+    json.end_object()?;
+    json.end_object()
+}
+
+fn device_class(unit: Unit) -> Option<&'static str> {
+    match unit {
+        Unit::KWh => Some("energy"),
+        Unit::KW | Unit::W => Some("power"),
+        Unit::V => Some("voltage"),
+        Unit::A => Some("current"),
+        Unit::M3 => Some("gas"),
+        Unit::Celsius => Some("temperature"),
+        Unit::Percent => Some("humidity"),
+        Unit::GJ | Unit::Seconds => None,
+    }
+}
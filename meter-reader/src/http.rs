@@ -0,0 +1,344 @@
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, TcpSocket},
+};
+
+use crate::{
+    build_info::BUILD_INFO,
+    dsmr::Reading,
+    json,
+    metrics::{self, Diagnostics},
+    modbus::{MeterValues, MAX_DEVICES},
+    network::client::TcpClient,
+    p1,
+    random::Random,
+    system::SystemCommand,
+    telemetry::Telemetry,
+};
+
+const REQUEST_BUF_SZ: usize = 512;
+// The socket's send buffer, of 8 KiB, which the headers and body must fit in
+// together. It's also the size of the body's buffer, which arrayvec has no
+// smaller fitting size for.
+const RESPONSE_BUF_SZ: usize = 8192;
+const MAX_HEAD_SZ: usize = 256;
+// Large enough for the metrics, which are the largest response.
+const MAX_BODY_SZ: usize = RESPONSE_BUF_SZ - MAX_HEAD_SZ;
+
+type Body = ArrayString<[u8; RESPONSE_BUF_SZ]>;
+
+/// Serves the latest reading over HTTP/1.1, one connection at a time:
+///
+/// - `GET /api/telegram`: the latest reading as JSON
+/// - `GET /api/sample`: the values of every metric of the `Telemetry`, as of
+///   the latest reading, as a flat JSON object
+/// - `GET /metrics`: the metrics of the `Telemetry`, those of the meter
+///   also for the other P1 meters, with a `meter` label, and diagnostics as
+///   Prometheus metrics
+/// - `GET /health`: whether a telegram has been received, and the version of
+///   the firmware
+///
+/// With `system_commands`, which are not authenticated in any way:
+///
+/// - `POST /api/reboot`: restarts the meter reader
+/// - `POST /api/bootloader`: restarts into the bootloader, to be reflashed
+///   over USB
+pub struct HttpServer {
+    handle: Option<SocketHandle>,
+    port: u16,
+    system_commands: bool,
+    request: ArrayVec<[u8; REQUEST_BUF_SZ]>,
+    telemetry: Telemetry,
+    latest: Option<Reading>,
+    // The latest readings of the other P1 meters, by their names.
+    p1_meters: ArrayVec<[(&'static str, Reading); p1::MAX_METERS]>,
+    meters: ArrayVec<[MeterValues; MAX_DEVICES]>,
+    diagnostics: Diagnostics,
+    // Executed once the response to it has been sent.
+    pending_command: Option<SystemCommand>,
+}
+
+impl TcpClient for HttpServer {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<TcpSocket>,
+        _random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if let Some(command) = self.pending_command {
+            if socket.send_queue() == 0 || !socket.is_active() {
+                command.execute();
+            }
+            return;
+        }
+
+        if !socket.is_open() {
+            self.request.clear();
+            if let Err(err) = socket.listen(self.port) {
+                log::warn!("Failed to listen on port {}: {}", self.port, err);
+            }
+            return;
+        }
+
+        if socket.can_recv() {
+            let request = &mut self.request;
+            let received = socket.recv(|buf| {
+                let len = buf.len().min(request.capacity() - request.len());
+                request.extend(buf[..len].iter().copied());
+                (len, ())
+            });
+            if let Err(err) = received {
+                log::warn!("Failed to receive HTTP request: {}", err);
+            }
+        }
+
+        if socket.can_send() && !self.request.is_empty() {
+            let response = if let Some(end) = find_end_of_headers(&self.request) {
+                match self.system_command(&self.request[..end]) {
+                    Some(command) => {
+                        log::info!("{:?} requested over HTTP", command);
+                        self.pending_command = Some(command);
+                        Some(Response::accepted())
+                    }
+                    None => Some(self.respond(&self.request[..end])),
+                }
+            } else if self.request.is_full() {
+                Some(Response::error(431, "Request Header Fields Too Large"))
+            } else {
+                None
+            };
+            if let Some(response) = response {
+                response.send(&mut socket);
+                self.request.clear();
+                // Every response is sent with `Connection: close`.
+                socket.close();
+            }
+        }
+    }
+}
+
+impl HttpServer {
+    /// Serves samples of the metrics of `telemetry`.
+    pub fn new(port: u16, system_commands: bool, telemetry: Telemetry) -> Self {
+        Self {
+            handle: None,
+            port,
+            system_commands,
+            request: ArrayVec::new(),
+            telemetry,
+            latest: None,
+            p1_meters: ArrayVec::new(),
+            meters: ArrayVec::new(),
+            diagnostics: Diagnostics::default(),
+            pending_command: None,
+        }
+    }
+
+    /// Replaces the reading that is served.
+    pub fn update(&mut self, reading: &Reading) {
+        self.latest = Some(reading.clone());
+    }
+
+    /// Replaces the reading of the other P1 meter `name`.
+    pub fn update_p1_meter(&mut self, name: &'static str, reading: &Reading) {
+        match self.p1_meters.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, latest)) => *latest = reading.clone(),
+            None => {
+                let _ = self.p1_meters.try_push((name, reading.clone()));
+            }
+        }
+    }
+
+    /// Replaces the values of a Modbus meter, by its address.
+    pub fn update_meter(&mut self, values: MeterValues) {
+        match self.meters.iter_mut().find(|m| m.address == values.address) {
+            Some(meter) => *meter = values,
+            None => {
+                let _ = self.meters.try_push(values);
+            }
+        }
+    }
+
+    /// Replaces the diagnostics that are served along with the reading.
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.diagnostics = diagnostics;
+    }
+
+    fn system_command(&self, head: &[u8]) -> Option<SystemCommand> {
+        match request_line(head)? {
+            (b"POST", b"/api/reboot") if self.system_commands => Some(SystemCommand::Reboot),
+            (b"POST", b"/api/bootloader") if self.system_commands => {
+                Some(SystemCommand::EnterBootloader)
+            }
+            _ => None,
+        }
+    }
+
+    fn respond(&self, head: &[u8]) -> Response {
+        let (method, path) = match request_line(head) {
+            Some(request) => request,
+            None => return Response::error(400, "Bad Request"),
+        };
+        log::debug!(
+            "HTTP request: {} {}",
+            core::str::from_utf8(method).unwrap_or("?"),
+            core::str::from_utf8(path).unwrap_or("?")
+        );
+        if method != b"GET" {
+            return Response::error(405, "Method Not Allowed");
+        }
+        // Ignore the query string, if any.
+        let path = path.split(|c| *c == b'?').next().unwrap_or_default();
+        let mut response = Response::ok();
+        // Taken now, rather than along with the reading, so that the values
+        // of the meter reader itself are current.
+        let sample = self
+            .telemetry
+            .sample(self.latest.as_ref(), self.diagnostics.reader_state());
+        let written = match (path, &self.latest) {
+            (b"/health", Some(_)) => write!(response.body, "ok\n{}\n", BUILD_INFO),
+            (b"/health", None) => {
+                return Response::error(503, "Service Unavailable");
+            }
+            (b"/api/telegram", Some(reading)) => {
+                response.content_type = "application/json";
+                json::write_reading(&mut response.body, reading)
+            }
+            (b"/api/sample", Some(reading)) => {
+                response.content_type = "application/json";
+                json::write_sample(
+                    &mut response.body,
+                    &self.telemetry,
+                    &sample,
+                    reading.received_at,
+                )
+            }
+            (b"/metrics", _) => {
+                response.content_type = "text/plain; version=0.0.4";
+                let p1_meters: ArrayVec<[_; p1::MAX_METERS]> = self
+                    .p1_meters
+                    .iter()
+                    .map(|(name, reading)| {
+                        (
+                            *name,
+                            self.telemetry
+                                .sample(Some(reading), self.diagnostics.reader_state()),
+                        )
+                    })
+                    .collect();
+                metrics::write_metrics(
+                    &mut response.body,
+                    &self.telemetry,
+                    &sample,
+                    &p1_meters,
+                    &self.meters,
+                    &self.diagnostics,
+                )
+            }
+            (b"/api/telegram", None) | (b"/api/sample", None) => {
+                return Response::error(503, "Service Unavailable");
+            }
+            _ => return Response::error(404, "Not Found"),
+        };
+        match written {
+            Ok(()) if response.body.len() <= MAX_BODY_SZ => response,
+            _ => {
+                log::warn!("HTTP response does not fit in {} bytes", MAX_BODY_SZ);
+                Response::error(500, "Internal Server Error")
+            }
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Body,
+}
+
+impl Response {
+    fn ok() -> Self {
+        Self {
+            status: 200,
+            reason: "OK",
+            content_type: "text/plain",
+            body: Body::new(),
+        }
+    }
+
+    fn accepted() -> Self {
+        Self {
+            status: 202,
+            reason: "Accepted",
+            ..Self::ok()
+        }
+    }
+
+    fn error(status: u16, reason: &'static str) -> Self {
+        let mut body = Body::new();
+        // Always fits, the reasons are short.
+        let _ = writeln!(body, "{}", reason);
+        Self {
+            status,
+            reason,
+            content_type: "text/plain",
+            body,
+        }
+    }
+
+    fn send(&self, socket: &mut SocketRef<TcpSocket>) {
+        let mut head = ArrayString::<[u8; MAX_HEAD_SZ]>::new();
+        let _ = write!(
+            head,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        );
+        for part in [head.as_bytes(), self.body.as_bytes()] {
+            match socket.send_slice(part) {
+                Ok(sent) if sent == part.len() => {}
+                Ok(sent) => {
+                    log::warn!("HTTP response truncated after {} bytes", sent);
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("Failed to send HTTP response: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Returns the method and path of the request line, which is the only part
+// of the request head that is of interest.
+fn request_line(head: &[u8]) -> Option<(&[u8], &[u8])> {
+    let line = head.split(|c| *c == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|c| *c == b' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with(b"HTTP/1.") => {
+            Some((method, path))
+        }
+        _ => None,
+    }
+}
+
+// Returns the length of the request head, without the empty line that ends
+// it.
+fn find_end_of_headers(request: &[u8]) -> Option<usize> {
+    request.windows(4).position(|w| w == b"\r\n\r\n")
+}
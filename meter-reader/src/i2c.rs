@@ -0,0 +1,329 @@
+//! LPI2C1, with SCL on pin 19 and SDA on pin 18, for the display and other
+//! devices on the same bus.
+//!
+//! Writes are moved into the transmit FIFO by DMA, so `start_write` returns
+//! right away, and `poll` tells when the write is done. Reads are short, and
+//! go through the FIFO directly. Every transfer has a timeout, and when one
+//! fails while a device holds SDA low, which happens when it was reset in
+//! the middle of a byte, the bus is recovered by pulsing SCL until the
+//! device lets go, as the I2C specification describes.
+
+use core::ptr;
+
+use cortex_m::{asm, peripheral::DWT};
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use teensy4_bsp::hal::{i2c::I2C, iomuxc::prelude::consts};
+
+use crate::{cpu_clock, dma::Channel};
+
+/// The DMAMUX request source of LPI2C1.
+pub const DMA_SOURCE: u32 = 17;
+// The most bytes that `start_write` takes.
+const MAX_WRITE_SZ: usize = 256;
+// The most bytes a read takes, as a single receive command.
+const MAX_READ_SZ: usize = 256;
+
+const LPI2C1: usize = 0x403F_0000;
+const MCR: usize = LPI2C1 + 0x10;
+const MSR: usize = LPI2C1 + 0x14;
+const MDER: usize = LPI2C1 + 0x1C;
+const MFSR: usize = LPI2C1 + 0x5C;
+const MTDR: usize = LPI2C1 + 0x60;
+const MRDR: usize = LPI2C1 + 0x70;
+const MCR_MEN: u32 = 1 << 0;
+const MCR_RTF: u32 = 1 << 8;
+const MCR_RRF: u32 = 1 << 9;
+const MSR_SDF: u32 = 1 << 9;
+const MSR_NDF: u32 = 1 << 10;
+const MSR_ALF: u32 = 1 << 11;
+const MSR_FEF: u32 = 1 << 12;
+const MSR_PLTF: u32 = 1 << 13;
+// The flags that are cleared by writing ones.
+const MSR_FLAGS: u32 = 0x7F << 8;
+const MDER_TDDE: u32 = 1 << 0;
+const MRDR_RXEMPTY: u32 = 1 << 14;
+const TX_FIFO_SZ: u32 = 4;
+// Commands in MTDR, with the data in the low byte.
+const CMD_TRANSMIT: u16 = 0b000 << 8;
+const CMD_RECEIVE: u16 = 0b001 << 8;
+const CMD_STOP: u16 = 0b010 << 8;
+const CMD_START: u16 = 0b100 << 8;
+
+// The pads of pins 19 and 18, which are GPIO1 16 and 17 as GPIO.
+const MUX_SCL: usize = 0x401F_80FC;
+const MUX_SDA: usize = 0x401F_8100;
+const MUX_GPIO: u32 = 5;
+const GPIO1_DR: usize = 0x401B_8000;
+const GPIO1_GDIR: usize = 0x401B_8004;
+const GPIO1_PSR: usize = 0x401B_8008;
+const SCL: u32 = 1 << 16;
+const SDA: u32 = 1 << 17;
+// A device lets go of SDA within a byte and its acknowledgement.
+const RECOVERY_PULSES: usize = 9;
+// Half a period at 100 kHz.
+const RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum I2cError {
+    /// A write is still going.
+    Busy,
+    /// More than `MAX_WRITE_SZ` or `MAX_READ_SZ` bytes.
+    TooLong,
+    /// The device didn't acknowledge its address or a byte.
+    Nack,
+    ArbitrationLost,
+    /// The transfer didn't finish in time, or the bus stayed stuck.
+    Timeout,
+    Dma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    // In cycles of the DWT.
+    Writing { since: u32 },
+}
+
+pub struct I2c {
+    // Only kept so that nothing else can use LPI2C1, or its pins.
+    _i2c: I2C<consts::U1>,
+    dma: Channel,
+    // Commands for the transmit FIFO, which the DMA reads from.
+    words: [u16; MAX_WRITE_SZ + 2],
+    state: State,
+    timeout_us: u32,
+}
+
+impl I2c {
+    /// Takes `i2c`, which has its pins and clock speed set, and `dma`, for
+    /// `DMA_SOURCE`. Transfers fail once they take longer than `timeout_us`.
+    pub fn new(i2c: I2C<consts::U1>, dma: Channel, timeout_us: u32) -> Self {
+        let mut i2c = Self {
+            _i2c: i2c,
+            dma,
+            words: [0; MAX_WRITE_SZ + 2],
+            state: State::Idle,
+            timeout_us,
+        };
+        // A device may have been reset in the middle of a byte along with
+        // us.
+        i2c.abort();
+        i2c
+    }
+
+    /// Starts writing `bytes` to the device at `address`. `poll` returns how
+    /// it went.
+    pub fn start_write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        if self.state != State::Idle {
+            return Err(I2cError::Busy);
+        }
+        if bytes.len() > MAX_WRITE_SZ {
+            return Err(I2cError::TooLong);
+        }
+        let len = bytes.len() + 2;
+        self.words[0] = CMD_START | (address as u16) << 1;
+        for (word, byte) in self.words[1..].iter_mut().zip(bytes) {
+            *word = CMD_TRANSMIT | *byte as u16;
+        }
+        self.words[len - 1] = CMD_STOP;
+        unsafe {
+            write(MSR, MSR_FLAGS);
+            write(MDER, MDER_TDDE);
+        }
+        self.dma.write(&self.words[..len], MTDR);
+        self.state = State::Writing {
+            since: DWT::get_cycle_count(),
+        };
+        Ok(())
+    }
+
+    /// Whether the write that `start_write` started is done. Also `Ok` if
+    /// there is none.
+    pub fn poll(&mut self) -> nb::Result<(), I2cError> {
+        let since = match self.state {
+            State::Idle => return Ok(()),
+            State::Writing { since } => since,
+        };
+        let status = unsafe { read(MSR) };
+        let result = if let Some(err) = error(status) {
+            Err(err)
+        } else if self.dma.has_failed() {
+            Err(I2cError::Dma)
+        } else if self.dma.is_done() && status & MSR_SDF != 0 {
+            Ok(())
+        } else if self.elapsed_us(since) > self.timeout_us {
+            Err(I2cError::Timeout)
+        } else {
+            return Err(nb::Error::WouldBlock);
+        };
+        self.state = State::Idle;
+        unsafe { write(MDER, 0) };
+        self.dma.stop();
+        if result.is_err() {
+            self.abort();
+        }
+        result.map_err(nb::Error::Other)
+    }
+
+    // Throws away what is left in the FIFOs, and recovers the bus if SDA is
+    // stuck.
+    fn abort(&mut self) {
+        unsafe {
+            write(MCR, read(MCR) & !MCR_MEN);
+            write(MCR, read(MCR) | MCR_RTF | MCR_RRF);
+            write(MSR, MSR_FLAGS);
+            // The pads can be read while LPI2C1 has them.
+            if read(GPIO1_PSR) & SDA == 0 {
+                recover();
+            }
+            write(MCR, read(MCR) | MCR_MEN);
+        }
+    }
+
+    fn elapsed_us(&self, since: u32) -> u32 {
+        DWT::get_cycle_count().wrapping_sub(since) / (cpu_clock::hz() / 1_000_000)
+    }
+
+    // Pushes `word` into the transmit FIFO, waiting for room.
+    fn push(&mut self, word: u16, since: u32) -> Result<(), I2cError> {
+        loop {
+            let status = unsafe { read(MSR) };
+            if let Some(err) = error(status) {
+                return Err(err);
+            }
+            if unsafe { read(MFSR) } & 0x7 < TX_FIFO_SZ {
+                unsafe { write(MTDR, word as u32) };
+                return Ok(());
+            }
+            if self.elapsed_us(since) > self.timeout_us {
+                return Err(I2cError::Timeout);
+            }
+        }
+    }
+
+    // Without `bytes`, this only reads.
+    fn read_into(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        nb::block!(self.poll())?;
+        if bytes.len() > MAX_WRITE_SZ || buffer.is_empty() || buffer.len() > MAX_READ_SZ {
+            return Err(I2cError::TooLong);
+        }
+        let since = DWT::get_cycle_count();
+        unsafe { write(MSR, MSR_FLAGS) };
+        if !bytes.is_empty() {
+            self.push(CMD_START | (address as u16) << 1, since)?;
+            for byte in bytes {
+                self.push(CMD_TRANSMIT | *byte as u16, since)?;
+            }
+        }
+        self.push(CMD_START | (address as u16) << 1 | 1, since)?;
+        self.push(CMD_RECEIVE | (buffer.len() - 1) as u16, since)?;
+        self.push(CMD_STOP, since)?;
+        for byte in buffer.iter_mut() {
+            loop {
+                if let Some(err) = error(unsafe { read(MSR) }) {
+                    return Err(err);
+                }
+                let data = unsafe { read(MRDR) };
+                if data & MRDR_RXEMPTY == 0 {
+                    *byte = data as u8;
+                    break;
+                }
+                if self.elapsed_us(since) > self.timeout_us {
+                    return Err(I2cError::Timeout);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for I2c {
+    type Error = I2cError;
+
+    /// Writes `bytes`, and waits until they are sent.
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        nb::block!(self.poll())?;
+        self.start_write(address, bytes)?;
+        nb::block!(self.poll())
+    }
+}
+
+impl Read for I2c {
+    type Error = I2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.write_read(address, &[], buffer)
+    }
+}
+
+impl WriteRead for I2c {
+    type Error = I2cError;
+
+    /// Writes `bytes`, such as a register address, and reads `buffer` after
+    /// a repeated start.
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        let result = self.read_into(address, bytes, buffer);
+        if result.is_err() {
+            self.abort();
+        }
+        result
+    }
+}
+
+fn error(status: u32) -> Option<I2cError> {
+    if status & MSR_NDF != 0 {
+        Some(I2cError::Nack)
+    } else if status & MSR_ALF != 0 {
+        Some(I2cError::ArbitrationLost)
+    } else if status & (MSR_FEF | MSR_PLTF) != 0 {
+        Some(I2cError::Timeout)
+    } else {
+        None
+    }
+}
+
+// Takes the pads from LPI2C1, pulses SCL until SDA is released, and ends
+// with a stop condition. Lines are only ever pulled low, and otherwise left
+// to the pull-ups.
+unsafe fn recover() {
+    let mux = (read(MUX_SCL), read(MUX_SDA));
+    write(GPIO1_DR, read(GPIO1_DR) & !(SCL | SDA));
+    write(GPIO1_GDIR, read(GPIO1_GDIR) & !(SCL | SDA));
+    write(MUX_SCL, MUX_GPIO);
+    write(MUX_SDA, MUX_GPIO);
+
+    let half_period = || asm::delay(RECOVERY_HALF_PERIOD_US * (cpu_clock::hz() / 1_000_000));
+    let mut pulses = 0;
+    while read(GPIO1_PSR) & SDA == 0 && pulses < RECOVERY_PULSES {
+        write(GPIO1_GDIR, read(GPIO1_GDIR) | SCL);
+        half_period();
+        write(GPIO1_GDIR, read(GPIO1_GDIR) & !SCL);
+        half_period();
+        pulses += 1;
+    }
+    // A stop condition: SDA rises while SCL is high.
+    write(GPIO1_GDIR, read(GPIO1_GDIR) | SCL);
+    half_period();
+    write(GPIO1_GDIR, read(GPIO1_GDIR) | SDA);
+    half_period();
+    write(GPIO1_GDIR, read(GPIO1_GDIR) & !SCL);
+    half_period();
+    write(GPIO1_GDIR, read(GPIO1_GDIR) & !SDA);
+    half_period();
+
+    if read(GPIO1_PSR) & SDA == 0 {
+        log::warn!("I2C bus still stuck after {} clock pulses", pulses);
+    } else {
+        log::info!("Recovered the I2C bus with {} clock pulses", pulses);
+    }
+    write(MUX_SCL, mux.0);
+    write(MUX_SDA, mux.1);
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
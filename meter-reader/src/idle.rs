@@ -0,0 +1,214 @@
+//! Sleeping between main loop iterations, rather than spinning, until there
+//! is something to do.
+//!
+//! The core waits for an interrupt with `wfi`, with interrupts masked, so
+//! that the wake sources don't need handlers of their own: they are only
+//! enabled while sleeping, and cleared again on waking. Other interrupts,
+//! such as those of USB and the watchdog, wake the core as well, and run
+//! their handlers once it is awake.
+//!
+//! The wake sources are data on the UARTs given to `wake_on_uart`, edges
+//! that the GPIO pins given to `wake_on_gpio` latched, and output compare 1
+//! of GPT2, which bounds every sleep. GPT2 also runs the `Clock`, which only
+//! uses its counter.
+
+use core::ptr;
+
+use cortex_m::{asm, interrupt, peripheral::NVIC};
+use teensy4_bsp::hal::ral::{self, interrupt as Interrupt, lpuart};
+
+use crate::clock::{Clock, TICKS_PER_MS};
+
+const GPT2: usize = 0x401F_0000;
+const GPT_SR: usize = GPT2 + 0x08;
+const GPT_IR: usize = GPT2 + 0x0C;
+const GPT_OCR1: usize = GPT2 + 0x10;
+const GPT_OF1: u32 = 0x01;
+// Of GPIO1 to 4, which are the ones with interrupts.
+const GPIO_BASES: [usize; 4] = [0x401B_8000, 0x401B_C000, 0x401C_0000, 0x401C_4000];
+const GPIO_IMR: usize = 0x14;
+// How long the sleeping percentage is measured over.
+const WINDOW_MS: u32 = 10_000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdleStats {
+    pub sleeps: u32,
+    /// The time spent sleeping during the last `WINDOW_MS`.
+    pub sleeping_percent: u8,
+}
+
+pub struct Idle {
+    // The LPUARTs to wake on, by bit.
+    uarts: u8,
+    // The pins of GPIO1 to 4 to wake on, by bit.
+    gpios: [u32; 4],
+    stats: IdleStats,
+    window_start: Option<u32>,
+    slept: u32,
+}
+
+impl Idle {
+    pub fn new() -> Self {
+        Self {
+            uarts: 0,
+            gpios: [0; 4],
+            stats: IdleStats::default(),
+            window_start: None,
+            slept: 0,
+        }
+    }
+
+    /// Wakes up when LPUART `n` receives data, numbered from 1.
+    pub fn wake_on_uart(&mut self, n: usize) {
+        assert!((1..=8).contains(&n));
+        self.uarts |= 1 << (n - 1);
+    }
+
+    /// Wakes up when pin `bit` of GPIO `module`, numbered from 1, has
+    /// latched an edge in its interrupt status register, which is left for
+    /// its owner to clear.
+    pub fn wake_on_gpio(&mut self, module: usize, bit: usize) {
+        assert!((1..=4).contains(&module) && bit < 32);
+        self.gpios[module - 1] |= 1 << bit;
+    }
+
+    /// Sleeps until a wake source fires, for at most `max_ms`.
+    pub fn sleep(&mut self, clock: &Clock, max_ms: u32) {
+        let window_start = *self.window_start.get_or_insert(clock.ticks());
+        if max_ms > 0 {
+            let slept = interrupt::free(|_| unsafe { self.wait(clock, max_ms * TICKS_PER_MS) });
+            self.slept = self.slept.saturating_add(slept);
+            self.stats.sleeps = self.stats.sleeps.wrapping_add(1);
+        }
+
+        let elapsed = clock.ticks().wrapping_sub(window_start);
+        if elapsed >= WINDOW_MS * TICKS_PER_MS {
+            self.stats.sleeping_percent = (self.slept as u64 * 100 / elapsed as u64) as u8;
+            self.window_start = Some(clock.ticks());
+            self.slept = 0;
+        }
+    }
+
+    pub fn stats(&self) -> IdleStats {
+        self.stats
+    }
+
+    // Returns the ticks spent sleeping. Must run with interrupts masked, or
+    // the wake sources would run their handlers, which they don't have.
+    unsafe fn wait(&mut self, clock: &Clock, ticks: u32) -> u32 {
+        let start = clock.ticks();
+        write(GPT_OCR1, start.wrapping_add(ticks));
+        write(GPT_SR, GPT_OF1);
+        write(GPT_IR, read(GPT_IR) | GPT_OF1);
+        let gpt_enabled = enable(Interrupt::GPT2);
+
+        // Already pending if data was received since the last poll, in which
+        // case `wfi` returns right away.
+        let mut receive_interrupts = [None; 8];
+        for n in (1..=8).filter(|n| self.uarts & 1 << (n - 1) != 0) {
+            let (reg, interrupt) = lpuart(n);
+            let rie = ral::read_reg!(lpuart, reg, CTRL, RIE);
+            ral::modify_reg!(lpuart, reg, CTRL, RIE: 1);
+            receive_interrupts[n - 1] = Some((rie, enable(interrupt)));
+        }
+
+        let mut gpio_interrupts = [None; 4];
+        for (i, mask) in self
+            .gpios
+            .iter()
+            .enumerate()
+            .filter(|(_, mask)| **mask != 0)
+        {
+            let imr = GPIO_BASES[i] + GPIO_IMR;
+            let saved = read(imr);
+            write(imr, saved | mask);
+            let [low, high] = gpio(i + 1);
+            gpio_interrupts[i] = Some((saved, enable(low), enable(high)));
+        }
+
+        asm::dsb();
+        asm::wfi();
+
+        for (i, saved) in gpio_interrupts.iter().enumerate() {
+            if let Some((imr, low_enabled, high_enabled)) = *saved {
+                write(GPIO_BASES[i] + GPIO_IMR, imr);
+                let [low, high] = gpio(i + 1);
+                restore(low, low_enabled);
+                restore(high, high_enabled);
+            }
+        }
+
+        for (i, saved) in receive_interrupts.iter().enumerate() {
+            if let Some((rie, enabled)) = *saved {
+                let (reg, interrupt) = lpuart(i + 1);
+                ral::modify_reg!(lpuart, reg, CTRL, RIE: rie);
+                restore(interrupt, enabled);
+            }
+        }
+        write(GPT_IR, read(GPT_IR) & !GPT_OF1);
+        write(GPT_SR, GPT_OF1);
+        restore(Interrupt::GPT2, gpt_enabled);
+        clock.ticks().wrapping_sub(start)
+    }
+}
+
+// Unmasks `interrupt` in the NVIC, returning whether it already was.
+unsafe fn enable(interrupt: Interrupt) -> bool {
+    let enabled = NVIC::is_enabled(interrupt);
+    NVIC::unmask(interrupt);
+    enabled
+}
+
+// Masks `interrupt` again, and forgets that it fired, unless it `was_enabled`,
+// in which case its handler is left to run.
+fn restore(interrupt: Interrupt, was_enabled: bool) {
+    if !was_enabled {
+        NVIC::mask(interrupt);
+        NVIC::unpend(interrupt);
+    }
+}
+
+// The registers and interrupt of LPUART `n`. Only its RIE is changed, and
+// restored before its owner can see it.
+unsafe fn lpuart(n: usize) -> (lpuart::Instance, Interrupt) {
+    match n {
+        1 => (lpuart::LPUART1::steal(), Interrupt::LPUART1),
+        2 => (lpuart::LPUART2::steal(), Interrupt::LPUART2),
+        3 => (lpuart::LPUART3::steal(), Interrupt::LPUART3),
+        4 => (lpuart::LPUART4::steal(), Interrupt::LPUART4),
+        5 => (lpuart::LPUART5::steal(), Interrupt::LPUART5),
+        6 => (lpuart::LPUART6::steal(), Interrupt::LPUART6),
+        7 => (lpuart::LPUART7::steal(), Interrupt::LPUART7),
+        _ => (lpuart::LPUART8::steal(), Interrupt::LPUART8),
+    }
+}
+
+// The interrupts of pins 0 to 15 and 16 to 31 of GPIO `module`.
+fn gpio(module: usize) -> [Interrupt; 2] {
+    match module {
+        1 => [
+            Interrupt::GPIO1_Combined_0_15,
+            Interrupt::GPIO1_Combined_16_31,
+        ],
+        2 => [
+            Interrupt::GPIO2_Combined_0_15,
+            Interrupt::GPIO2_Combined_16_31,
+        ],
+        3 => [
+            Interrupt::GPIO3_Combined_0_15,
+            Interrupt::GPIO3_Combined_16_31,
+        ],
+        _ => [
+            Interrupt::GPIO4_Combined_0_15,
+            Interrupt::GPIO4_Combined_16_31,
+        ],
+    }
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
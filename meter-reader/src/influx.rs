@@ -0,0 +1,525 @@
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Write};
+use dsmr42::{Decimal, MbusDevice};
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, TcpSocket},
+    time::Duration,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    dsmr::Reading, network::client::TcpClient, network::stack, random::Random,
+    ring_buffer::RingBuffer,
+};
+
+// Holds the points that have not been written yet. A point takes about 400
+// bytes, so at one point every 10 seconds, this covers almost an hour.
+const QUEUE_SZ: usize = 131072;
+// The most that is written in a single request. The request must fit in the
+// socket's send buffer.
+const MAX_BATCH_SZ: usize = 4096;
+// The lines written for a single reading.
+const LINES_SZ: usize = 1024;
+const LINE_SZ: usize = 512;
+const STATUS_LINE_SZ: usize = 64;
+
+#[derive(Copy, Clone, Debug)]
+pub struct InfluxConfig {
+    pub server_addr: [u8; 4],
+    pub server_port: u16,
+    /// Path and query string of the write endpoint, such as
+    /// `/api/v2/write?org=home&bucket=meter&precision=ms` for InfluxDB 2, or
+    /// `/write?db=meter&precision=ms` for InfluxDB 1. Timestamps are written
+    /// in ms, so the precision must be `ms`.
+    pub path: &'static str,
+    /// Sent as `Authorization: Token <token>`, if set.
+    pub token: Option<&'static str>,
+    pub measurement: &'static str,
+    /// Readings received within this many ms of the last one that was
+    /// queued are skipped. DSMR 5 meters send a telegram every second, which
+    /// would fill the queue quickly.
+    pub sample_interval: i64,
+    /// Points are written as soon as this many are queued, or once
+    /// `flush_interval` ms have passed since the last write.
+    pub batch_size: usize,
+    pub flush_interval: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    /// Connecting to send the first `len` bytes of the queue, holding
+    /// `points` points.
+    Sending {
+        len: usize,
+        points: usize,
+    },
+    /// Waiting for the server to accept the points that were sent.
+    AwaitingResponse {
+        len: usize,
+        points: usize,
+    },
+}
+
+/// Writes readings to InfluxDB in line protocol. Points are queued in RAM
+/// until they have been accepted by the server, so that nothing is lost while
+/// the network or the server is down, up to the size of the queue. After
+/// that, the oldest points are dropped.
+pub struct InfluxClient {
+    handle: Option<SocketHandle>,
+    config: InfluxConfig,
+    state: State,
+    queue: RingBuffer<QUEUE_SZ>,
+    queued_points: usize,
+    status_line: ArrayVec<[u8; STATUS_LINE_SZ]>,
+    last_queued_at: Option<i64>,
+    last_attempt_at: Option<i64>,
+    last_attempt_failed: bool,
+    flush_due: bool,
+    dropped: u32,
+}
+
+impl TcpClient for InfluxClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<TcpSocket>,
+        random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if !socket.is_active() {
+            if self.state != State::Idle {
+                self.fail("connection closed before the server responded");
+            }
+            if self.flush_due {
+                self.try_connect(socket, random);
+            }
+            return;
+        }
+
+        match self.state {
+            State::Sending { len, points } if socket.may_send() => {
+                if self.send_request(&mut socket, len) {
+                    self.state = State::AwaitingResponse { len, points };
+                } else {
+                    socket.abort();
+                }
+            }
+            State::AwaitingResponse { len, points } if socket.can_recv() => {
+                let status_line = &mut self.status_line;
+                let received = socket.recv(|buf| {
+                    let len = buf.len().min(status_line.capacity() - status_line.len());
+                    status_line.extend(buf[..len].iter().copied());
+                    // Drain everything, only the status line is of interest.
+                    (buf.len(), ())
+                });
+                if let Err(err) = received {
+                    log::warn!("Failed to receive InfluxDB response: {}", err);
+                }
+                if let Some(status) = parse_status(&self.status_line) {
+                    self.handle_status(status, len, points);
+                    socket.close();
+                } else if self.status_line.is_full() {
+                    self.fail("malformed response");
+                    socket.abort();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl InfluxClient {
+    pub fn new(config: InfluxConfig) -> Self {
+        Self {
+            handle: None,
+            config,
+            state: State::Idle,
+            queue: RingBuffer::new(),
+            queued_points: 0,
+            status_line: ArrayVec::new(),
+            last_queued_at: None,
+            last_attempt_at: None,
+            last_attempt_failed: false,
+            flush_due: false,
+            dropped: 0,
+        }
+    }
+
+    /// Queues the points for `reading`, received at `now`, our own time.
+    ///
+    /// The reading's own timestamp is used if the wall-clock time at which
+    /// it was received is unknown. Readings with neither are not written,
+    /// since they would be timestamped by the server when they are finally
+    /// written, which can be much later.
+    pub fn queue_reading(&mut self, now: i64, reading: &Reading) {
+        if let Some(at) = self.last_queued_at {
+            if now - at < self.config.sample_interval {
+                return;
+            }
+        }
+        let time = reading
+            .received_at
+            .or_else(|| reading.timestamp.map(|t| t.unix_time() * 1000));
+        let time = match time {
+            Some(time) => time,
+            None => {
+                log::debug!("Not writing reading without a timestamp to InfluxDB");
+                return;
+            }
+        };
+        let mut lines = ArrayString::<[u8; LINES_SZ]>::new();
+        let points = match write_points(&mut lines, self.config.measurement, time, reading) {
+            Ok(points) => points,
+            Err(_) => {
+                log::warn!("InfluxDB points do not fit in {} bytes", LINES_SZ);
+                return;
+            }
+        };
+        while QUEUE_SZ - self.queue.len() < lines.len() {
+            self.drop_oldest_point();
+        }
+        for byte in lines.bytes() {
+            self.queue.push(byte);
+        }
+        self.queued_points += points;
+        self.last_queued_at = Some(now);
+
+        let interval_passed = self
+            .last_attempt_at
+            .map_or(true, |at| now - at >= self.config.flush_interval);
+        // After a failure, wait for the interval to pass before trying again.
+        let batch_full = self.queued_points >= self.config.batch_size && !self.last_attempt_failed;
+        if interval_passed || batch_full {
+            self.flush_due = true;
+            self.last_attempt_at = Some(now);
+        }
+    }
+
+    fn try_connect(&mut self, mut socket: SocketRef<TcpSocket>, random: &mut Random) {
+        self.flush_due = false;
+        let (len, points) = self.next_batch();
+        if points == 0 {
+            return;
+        }
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        let local = stack::generate_local_port(random);
+        let remote = IpAddress::Ipv4(Ipv4Address(self.config.server_addr));
+        let remote = IpEndpoint::new(remote, self.config.server_port);
+        log::debug!(
+            "Writing {} points to InfluxDB at {}, {} queued",
+            points,
+            remote,
+            self.queued_points
+        );
+        match socket.connect(remote, local) {
+            Ok(()) => {
+                self.status_line.clear();
+                self.state = State::Sending { len, points };
+            }
+            Err(err) => self.fail(err),
+        }
+    }
+
+    // Returns the length of the oldest points that fit in a single request,
+    // and their number.
+    fn next_batch(&self) -> (usize, usize) {
+        let (first, second) = self.queue.split_read();
+        let mut len = 0;
+        let mut points = 0;
+        for (i, byte) in first.iter().chain(second).enumerate().take(MAX_BATCH_SZ) {
+            if *byte == b'\n' {
+                len = i + 1;
+                points += 1;
+            }
+        }
+        (len, points)
+    }
+
+    // Returns whether the request was sent in full.
+    fn send_request(&mut self, socket: &mut SocketRef<TcpSocket>, len: usize) -> bool {
+        let mut head = ArrayString::<[u8; 512]>::new();
+        let written = write_head(&mut head, &self.config, len);
+        if written.is_err() || socket.send_capacity() - socket.send_queue() < head.len() + len {
+            self.fail("request does not fit in the send buffer");
+            return false;
+        }
+        let (first, second) = self.queue.split_read();
+        let body_first = &first[..len.min(first.len())];
+        let body_second = &second[..len - body_first.len()];
+        for part in [head.as_bytes(), body_first, body_second] {
+            match socket.send_slice(part) {
+                Ok(sent) if sent == part.len() => {}
+                Ok(_) => {
+                    self.fail("request truncated");
+                    return false;
+                }
+                Err(err) => {
+                    self.fail(err);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn handle_status(&mut self, status: u16, len: usize, points: usize) {
+        self.state = State::Idle;
+        match status {
+            200..=299 => {
+                log::debug!("InfluxDB accepted {} points", points);
+                self.remove(len, points);
+                self.last_attempt_failed = false;
+                // Write the backlog, if any, without waiting for the next
+                // reading.
+                self.flush_due = self.queued_points >= self.config.batch_size;
+            }
+            // Sending these points again will not help, except for these,
+            // which mean the server is overloaded.
+            400..=499 if status != 408 && status != 429 => {
+                log::warn!(
+                    "InfluxDB rejected {} points with status {}, dropping them",
+                    points,
+                    status
+                );
+                self.remove(len, points);
+                self.dropped = self.dropped.saturating_add(points as u32);
+                self.last_attempt_failed = false;
+            }
+            status => self.fail(format_args!("status {}", status)),
+        }
+    }
+
+    fn fail<D: fmt::Display>(&mut self, reason: D) {
+        log::warn!(
+            "Failed to write to InfluxDB: {}, {} points queued",
+            reason,
+            self.queued_points
+        );
+        self.state = State::Idle;
+        self.last_attempt_failed = true;
+    }
+
+    fn drop_oldest_point(&mut self) {
+        let (first, second) = self.queue.split_read();
+        let len = first
+            .iter()
+            .chain(second)
+            .position(|byte| *byte == b'\n')
+            .map_or(self.queue.len(), |i| i + 1);
+        // The oldest point is the first one of the request that is being
+        // written, if any, so it no longer needs to be removed afterwards.
+        match &mut self.state {
+            State::Sending {
+                len: sending,
+                points,
+            }
+            | State::AwaitingResponse {
+                len: sending,
+                points,
+            } => {
+                *sending = sending.saturating_sub(len);
+                *points = points.saturating_sub(1);
+            }
+            State::Idle => {}
+        }
+        self.remove(len, 1);
+        self.dropped = self.dropped.saturating_add(1);
+        log::warn!(
+            "InfluxDB queue full, dropped the oldest point ({} dropped so far)",
+            self.dropped
+        );
+    }
+
+    fn remove(&mut self, len: usize, points: usize) {
+        self.queue.consume(len);
+        self.queued_points = self.queued_points.saturating_sub(points);
+    }
+}
+
+fn write_head<W: Write>(writer: &mut W, config: &InfluxConfig, len: usize) -> fmt::Result {
+    write!(
+        writer,
+        "POST {} HTTP/1.1\r\nHost: {}.{}.{}.{}:{}\r\n",
+        config.path,
+        config.server_addr[0],
+        config.server_addr[1],
+        config.server_addr[2],
+        config.server_addr[3],
+        config.server_port
+    )?;
+    if let Some(token) = config.token {
+        write!(writer, "Authorization: Token {}\r\n", token)?;
+    }
+    write!(
+        writer,
+        "Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        len
+    )
+}
+
+// Returns the status code once the status line has been received in full.
+fn parse_status(response: &[u8]) -> Option<u16> {
+    let end = response.windows(2).position(|w| w == b"\r\n")?;
+    let mut parts = response[..end].split(|c| *c == b' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with(b"HTTP/1.") => {
+            core::str::from_utf8(status).ok()?.parse().ok()
+        }
+        // Never complete, so that the connection is eventually aborted.
+        _ => None,
+    }
+}
+
+/// Writes the points for `reading` in line protocol, returning how many were
+/// written. The electricity values are written as a single point at `time`,
+/// in Unix ms. Every M-Bus device gets a point of its own in
+/// `<measurement>_mbus`, at the time of its reading.
+pub fn write_points<W: Write>(
+    writer: &mut W,
+    measurement: &str,
+    time: i64,
+    reading: &Reading,
+) -> Result<usize, fmt::Error> {
+    let mut points = 0;
+    // Lines are only complete once it is known that they have fields.
+    let mut buf = ArrayString::<[u8; LINE_SZ]>::new();
+    let mut line = Line::new(&mut buf, measurement, reading)?;
+    for (i, energy) in reading.delivered.iter().enumerate() {
+        // Tariffs are numbered from 1.
+        line.decimal(format_args!("delivered_t{}_kwh", i + 1), *energy)?;
+    }
+    for (i, energy) in reading.returned.iter().enumerate() {
+        line.decimal(format_args!("returned_t{}_kwh", i + 1), *energy)?;
+    }
+    if let Some(tariff) = reading.tariff {
+        line.field(format_args!("tariff"), format_args!("{}i", tariff))?;
+    }
+    line.decimal(format_args!("power_delivered_kw"), reading.power_delivered)?;
+    line.decimal(format_args!("power_returned_kw"), reading.power_returned)?;
+    for (phase, reading) in PHASES.iter().zip(&reading.phases) {
+        line.decimal(format_args!("voltage_{}_v", phase), reading.voltage)?;
+        line.decimal(format_args!("current_{}_a", phase), reading.current)?;
+        line.decimal(
+            format_args!("power_delivered_{}_kw", phase),
+            reading.power_delivered,
+        )?;
+        line.decimal(
+            format_args!("power_returned_{}_kw", phase),
+            reading.power_returned,
+        )?;
+    }
+    line.decimal(format_args!("average_demand_kw"), reading.average_demand)?;
+    if line.finish(time)? {
+        writer.write_str(&buf)?;
+        points += 1;
+    }
+
+    for (i, mbus) in reading.mbus.iter().enumerate() {
+        let (timestamp, value) = match mbus.and_then(|mbus| mbus.reading) {
+            Some(reading) => reading,
+            None => continue,
+        };
+        let device = match mbus.and_then(|mbus| mbus.device) {
+            Some(MbusDevice::Gas) => "gas",
+            Some(MbusDevice::Heat) => "heat",
+            Some(MbusDevice::WarmWater) => "warm_water",
+            Some(MbusDevice::Water) => "water",
+            Some(MbusDevice::Cooling) => "cooling",
+            Some(MbusDevice::Other(_)) | None => "unknown",
+        };
+        buf.clear();
+        let mut line = Line::new(&mut buf, format_args!("{}_mbus", measurement), reading)?;
+        // Channels are numbered from 1.
+        line.tag("channel", i + 1)?;
+        line.tag("device", device)?;
+        if let Some(unit) = value.unit {
+            line.tag("unit", unit.as_str())?;
+        }
+        line.decimal(format_args!("value"), Some(value))?;
+        line.finish(timestamp.unix_time() * 1000)?;
+        writer.write_str(&buf)?;
+        points += 1;
+    }
+    Ok(points)
+}
+
+struct Line<'w, W: Write> {
+    writer: &'w mut W,
+    fields: usize,
+}
+
+impl<'w, W: Write> Line<'w, W> {
+    /// Starts the line, tagged with the meter's equipment ID if known.
+    fn new<M: fmt::Display>(
+        writer: &'w mut W,
+        measurement: M,
+        reading: &Reading,
+    ) -> Result<Self, fmt::Error> {
+        write!(Escaper(writer, b", "), "{}", measurement)?;
+        let mut line = Self { writer, fields: 0 };
+        if let Some(id) = reading.equipment_id {
+            line.tag("equipment_id", id)?;
+        }
+        Ok(line)
+    }
+
+    fn tag<D: fmt::Display>(&mut self, key: &str, value: D) -> fmt::Result {
+        write!(self.writer, ",{}=", key)?;
+        write!(Escaper(self.writer, b", ="), "{}", value)
+    }
+
+    fn field(&mut self, key: fmt::Arguments<'_>, value: fmt::Arguments<'_>) -> fmt::Result {
+        let separator = if self.fields == 0 { ' ' } else { ',' };
+        self.fields += 1;
+        write!(self.writer, "{}{}={}", separator, key, value)
+    }
+
+    fn decimal(&mut self, key: fmt::Arguments<'_>, value: Option<Decimal>) -> fmt::Result {
+        match value {
+            // Without a unit, decimals are formatted as plain numbers.
+            Some(value) => self.field(
+                key,
+                format_args!("{}", Decimal::new(value.value, value.scale, None)),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Ends the line, returning `false` if it had no fields. Such a line is
+    /// not a valid point, so the caller must discard the output.
+    fn finish(self, time: i64) -> Result<bool, fmt::Error> {
+        if self.fields == 0 {
+            return Ok(false);
+        }
+        writeln!(self.writer, " {}", time)?;
+        Ok(true)
+    }
+}
+
+// Escapes the given characters with a backslash, as line protocol requires
+// in measurements and tag values.
+struct Escaper<'w, W: Write>(&'w mut W, &'static [u8]);
+
+impl<'w, W: Write> Write for Escaper<'w, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c.is_ascii() && self.1.contains(&(c as u8)) {
+                self.0.write_char('\\')?;
+            }
+            self.0.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+const PHASES: [&str; 3] = ["l1", "l2", "l3"];
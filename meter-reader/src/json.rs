@@ -0,0 +1,344 @@
+use core::fmt::{self, Display, Write};
+
+use dsmr42::{Decimal, MbusDevice, Timestamp, Version};
+
+use crate::{
+    dsmr::{MbusReading, PhaseReading, Reading},
+    events::{Alarm, Event},
+    telemetry::{Sample, Telemetry},
+};
+
+/// Writes JSON to `W`, inserting separators where needed. Callers are
+/// responsible for balancing objects and arrays.
+pub struct JsonWriter<'w, W: Write> {
+    writer: &'w mut W,
+    // Whether nothing has been written to the current object or array yet.
+    first: bool,
+    // Whether a key has just been written, which means the next value
+    // belongs to it.
+    after_key: bool,
+}
+
+impl<'w, W: Write> JsonWriter<'w, W> {
+    pub fn new(writer: &'w mut W) -> Self {
+        Self {
+            writer,
+            first: true,
+            after_key: false,
+        }
+    }
+
+    pub fn begin_object(&mut self) -> fmt::Result {
+        self.element()?;
+        self.first = true;
+        self.writer.write_char('{')
+    }
+
+    pub fn end_object(&mut self) -> fmt::Result {
+        self.first = false;
+        self.writer.write_char('}')
+    }
+
+    pub fn begin_array(&mut self) -> fmt::Result {
+        self.element()?;
+        self.first = true;
+        self.writer.write_char('[')
+    }
+
+    pub fn end_array(&mut self) -> fmt::Result {
+        self.first = false;
+        self.writer.write_char(']')
+    }
+
+    pub fn key<D: Display>(&mut self, key: D) -> fmt::Result {
+        self.element()?;
+        self.write_string(key)?;
+        self.writer.write_char(':')?;
+        self.after_key = true;
+        Ok(())
+    }
+
+    pub fn null(&mut self) -> fmt::Result {
+        self.element()?;
+        self.writer.write_str("null")
+    }
+
+    /// Writes `value` verbatim, so it must format as a valid JSON number.
+    pub fn number<D: Display>(&mut self, value: D) -> fmt::Result {
+        self.element()?;
+        write!(self.writer, "{}", value)
+    }
+
+    /// Writes `value` as a string, escaping it where needed.
+    pub fn string<D: Display>(&mut self, value: D) -> fmt::Result {
+        self.element()?;
+        self.write_string(value)
+    }
+
+    pub fn decimal(&mut self, value: Decimal) -> fmt::Result {
+        // Without a unit, decimals are formatted as plain numbers.
+        self.number(Decimal::new(value.value, value.scale, None))
+    }
+
+    /// Writes a key and its value, or nothing at all if `value` is `None`.
+    pub fn optional<T, F>(&mut self, key: &str, value: Option<T>, write: F) -> fmt::Result
+    where
+        F: FnOnce(&mut Self, T) -> fmt::Result,
+    {
+        match value {
+            Some(value) => {
+                self.key(key)?;
+                write(self, value)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn element(&mut self) -> fmt::Result {
+        if self.after_key {
+            self.after_key = false;
+        } else if self.first {
+            self.first = false;
+        } else {
+            self.writer.write_char(',')?;
+        }
+        Ok(())
+    }
+
+    fn write_string<D: Display>(&mut self, value: D) -> fmt::Result {
+        self.writer.write_char('"')?;
+        write!(Escaper(&mut *self.writer), "{}", value)?;
+        self.writer.write_char('"')
+    }
+}
+
+// Escapes everything written to it for use in a JSON string.
+struct Escaper<'a, W: Write>(&'a mut W);
+
+impl<'a, W: Write> Write for Escaper<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `reading` as a single JSON object. Values that are missing from
+/// the reading are left out, except within arrays, where they are `null`.
+pub fn write_reading<W: Write>(writer: &mut W, reading: &Reading) -> fmt::Result {
+    let mut json = JsonWriter::new(writer);
+    json.begin_object()?;
+    json.optional("protocol", reading.protocol, |json, protocol| {
+        json.string(match protocol {
+            Version::Dsmr2 => "dsmr2",
+            Version::Dsmr4 => "dsmr4",
+            Version::Dsmr5 => "dsmr5",
+        })
+    })?;
+    json.optional("version", reading.version, JsonWriter::number)?;
+    json.optional("timestamp", reading.timestamp, JsonWriter::string)?;
+    json.optional("received_at_ms", reading.received_at, JsonWriter::number)?;
+    json.optional("equipment_id", reading.equipment_id, JsonWriter::string)?;
+    json.optional("tariff", reading.tariff, JsonWriter::number)?;
+    json.key("delivered_kwh")?;
+    write_registers(&mut json, &reading.delivered)?;
+    json.key("returned_kwh")?;
+    write_registers(&mut json, &reading.returned)?;
+    json.optional(
+        "power_delivered_kw",
+        reading.power_delivered,
+        JsonWriter::decimal,
+    )?;
+    json.optional(
+        "power_returned_kw",
+        reading.power_returned,
+        JsonWriter::decimal,
+    )?;
+    json.key("phases")?;
+    json.begin_array()?;
+    for phase in reading.phases.iter() {
+        write_phase(&mut json, phase)?;
+    }
+    json.end_array()?;
+    json.optional(
+        "average_demand_kw",
+        reading.average_demand,
+        JsonWriter::decimal,
+    )?;
+    json.optional("max_demand", reading.max_demand, |json, (at, demand)| {
+        json.begin_object()?;
+        write_peak(json, at, |json| json.decimal(demand))?;
+        json.end_object()
+    })?;
+    if !reading.peak_history.is_empty() {
+        json.key("peak_history")?;
+        json.begin_array()?;
+        for peak in reading.peak_history.iter() {
+            json.begin_object()?;
+            write_peak(&mut json, peak.at, |json| {
+                // Stored in W, but written in kW like the other demand values.
+                json.decimal(Decimal::new(peak.demand as i64, 3, None))
+            })?;
+            json.end_object()?;
+        }
+        json.end_array()?;
+    }
+    json.key("mbus")?;
+    json.begin_array()?;
+    for (i, mbus) in reading.mbus.iter().enumerate() {
+        if let Some(mbus) = mbus {
+            // Channels are numbered from 1.
+            write_mbus(&mut json, i as u8 + 1, mbus)?;
+        }
+    }
+    json.end_array()?;
+    if !reading.custom.is_empty() {
+        json.key("custom")?;
+        json.begin_array()?;
+        for (obis, value) in reading.custom.iter() {
+            json.begin_object()?;
+            json.key("obis")?;
+            json.string(format_args!(
+                "{}-{}:{}.{}.{}",
+                obis[0], obis[1], obis[2], obis[3], obis[4]
+            ))?;
+            write_value(&mut json, *value)?;
+            json.end_object()?;
+        }
+        json.end_array()?;
+    }
+    json.end_object()
+}
+
+/// Writes `alarm` as a single JSON object, with the kind of event, and its
+/// values.
+pub fn write_alarm<W: Write>(writer: &mut W, alarm: &Alarm) -> fmt::Result {
+    let mut json = JsonWriter::new(writer);
+    json.begin_object()?;
+    json.optional("at_ms", alarm.at, JsonWriter::number)?;
+    json.key("event")?;
+    match alarm.event {
+        Event::TariffChanged { from, to } => {
+            json.string("tariff_changed")?;
+            json.optional("from", from, JsonWriter::number)?;
+            json.key("to")?;
+            json.number(to)?;
+        }
+        Event::ThresholdExceeded { power } => {
+            json.string("threshold_exceeded")?;
+            json.key("power_w")?;
+            json.number(power)?;
+        }
+        Event::ThresholdCleared { power } => {
+            json.string("threshold_cleared")?;
+            json.key("power_w")?;
+            json.number(power)?;
+        }
+        Event::ReturnStarted { power } => {
+            json.string("return_started")?;
+            json.key("power_w")?;
+            json.number(power)?;
+        }
+        Event::ReturnStopped => json.string("return_stopped")?,
+    }
+    json.end_object()
+}
+
+/// Writes `sample` of the metrics of `telemetry` as a single JSON object,
+/// under the key of each metric, along with when the reading that it was
+/// taken of was received, if known. Missing values are left out.
+pub fn write_sample<W: Write>(
+    writer: &mut W,
+    telemetry: &Telemetry,
+    sample: &Sample,
+    received_at: Option<i64>,
+) -> fmt::Result {
+    let mut json = JsonWriter::new(writer);
+    json.begin_object()?;
+    json.optional("received_at_ms", received_at, JsonWriter::number)?;
+    for (metric, value) in telemetry.metrics().iter().zip(sample) {
+        if let Some(value) = value {
+            json.key(metric.key())?;
+            json.decimal(*value)?;
+        }
+    }
+    json.end_object()
+}
+
+fn write_registers<W: Write>(
+    json: &mut JsonWriter<W>,
+    registers: &[Option<Decimal>],
+) -> fmt::Result {
+    json.begin_array()?;
+    for register in registers {
+        match register {
+            Some(value) => json.decimal(*value)?,
+            None => json.null()?,
+        }
+    }
+    json.end_array()
+}
+
+fn write_phase<W: Write>(json: &mut JsonWriter<W>, phase: &PhaseReading) -> fmt::Result {
+    json.begin_object()?;
+    json.optional("voltage_v", phase.voltage, JsonWriter::decimal)?;
+    json.optional("current_a", phase.current, JsonWriter::decimal)?;
+    json.optional(
+        "power_delivered_kw",
+        phase.power_delivered,
+        JsonWriter::decimal,
+    )?;
+    json.optional(
+        "power_returned_kw",
+        phase.power_returned,
+        JsonWriter::decimal,
+    )?;
+    json.end_object()
+}
+
+fn write_peak<W: Write, F>(json: &mut JsonWriter<W>, at: Timestamp, demand: F) -> fmt::Result
+where
+    F: FnOnce(&mut JsonWriter<W>) -> fmt::Result,
+{
+    json.key("timestamp")?;
+    json.string(at)?;
+    json.key("demand_kw")?;
+    demand(json)
+}
+
+fn write_mbus<W: Write>(json: &mut JsonWriter<W>, channel: u8, mbus: &MbusReading) -> fmt::Result {
+    json.begin_object()?;
+    json.key("channel")?;
+    json.number(channel)?;
+    json.optional("device", mbus.device, |json, device| match device {
+        MbusDevice::Gas => json.string("gas"),
+        MbusDevice::Heat => json.string("heat"),
+        MbusDevice::WarmWater => json.string("warm_water"),
+        MbusDevice::Water => json.string("water"),
+        MbusDevice::Cooling => json.string("cooling"),
+        MbusDevice::Other(device_type) => json.number(device_type),
+    })?;
+    json.optional("equipment_id", mbus.equipment_id, JsonWriter::string)?;
+    if let Some((timestamp, value)) = mbus.reading {
+        json.key("timestamp")?;
+        json.string(timestamp)?;
+        write_value(json, value)?;
+    }
+    json.end_object()
+}
+
+// Writes a value whose unit isn't implied by its key.
+fn write_value<W: Write>(json: &mut JsonWriter<W>, value: Decimal) -> fmt::Result {
+    json.key("value")?;
+    json.decimal(value)?;
+    json.optional("unit", value.unit, |json, unit| json.string(unit.as_str()))
+}
@@ -0,0 +1,207 @@
+//! A status LED that blinks in a pattern for each state of the meter reader,
+//! so that it can be told at a glance whether it works.
+//!
+//! The LED is driven by `poll`, which should be called every few tens of ms,
+//! such as from a periodic timer, and never blocks. Patterns either repeat
+//! until another one is shown, or play a number of times, after which the
+//! repeating pattern that was shown before continues.
+
+use core::fmt::Debug;
+
+use embedded_hal::digital::v2::OutputPin;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Colour {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    Magenta,
+}
+
+impl Colour {
+    // Red, green and blue.
+    fn channels(self) -> [bool; 3] {
+        match self {
+            Colour::Off => [false, false, false],
+            Colour::Red => [true, false, false],
+            Colour::Green => [false, true, false],
+            Colour::Blue => [false, false, true],
+            Colour::Yellow => [true, true, false],
+            Colour::Magenta => [true, false, true],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// Blue, until the main loop starts.
+    Booting,
+    /// A slow yellow blink, while no telegrams arrive.
+    WaitingForTelegram,
+    /// A short green blink every few seconds, while all is well.
+    TelegramOk,
+    /// Two red flashes, once.
+    CrcError,
+    /// Magenta blinking, while the broker can't be reached.
+    NetworkDown,
+    /// Fast red blinking for a while, after restarting from a crash.
+    Panic,
+}
+
+impl Pattern {
+    // How long the LED shows each colour, in ms.
+    fn steps(self) -> &'static [(Colour, u16)] {
+        match self {
+            Pattern::Booting => &[(Colour::Blue, 1000)],
+            Pattern::WaitingForTelegram => &[(Colour::Yellow, 200), (Colour::Off, 1800)],
+            Pattern::TelegramOk => &[(Colour::Green, 50), (Colour::Off, 2950)],
+            Pattern::CrcError => &[
+                (Colour::Red, 100),
+                (Colour::Off, 100),
+                (Colour::Red, 100),
+                (Colour::Off, 500),
+            ],
+            Pattern::NetworkDown => &[(Colour::Magenta, 500), (Colour::Off, 500)],
+            Pattern::Panic => &[(Colour::Red, 50), (Colour::Off, 50)],
+        }
+    }
+
+    // How many times the pattern plays, or `None` if it repeats.
+    fn plays(self) -> Option<u16> {
+        match self {
+            Pattern::CrcError => Some(1),
+            Pattern::Panic => Some(300),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Playing {
+    pattern: Pattern,
+    step: usize,
+    step_since: i64,
+    plays_left: u16,
+}
+
+impl Playing {
+    fn new(pattern: Pattern, now: i64) -> Self {
+        Self {
+            pattern,
+            step: 0,
+            step_since: now,
+            plays_left: pattern.plays().unwrap_or(0),
+        }
+    }
+}
+
+/// An RGB LED on three pins, each of which lights its colour when high.
+pub struct RgbLed<R, G, B> {
+    red: R,
+    green: G,
+    blue: B,
+}
+
+impl<R, G, B, E> RgbLed<R, G, B>
+where
+    R: OutputPin<Error = E>,
+    G: OutputPin<Error = E>,
+    B: OutputPin<Error = E>,
+    E: Debug,
+{
+    pub fn new(red: R, green: G, blue: B) -> Self {
+        Self { red, green, blue }
+    }
+
+    fn show(&mut self, colour: Colour) -> Result<(), E> {
+        let [red, green, blue] = colour.channels();
+        set(&mut self.red, red)?;
+        set(&mut self.green, green)?;
+        set(&mut self.blue, blue)
+    }
+}
+
+fn set<P: OutputPin>(pin: &mut P, high: bool) -> Result<(), P::Error> {
+    if high {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
+}
+
+pub struct StatusLed<R, G, B> {
+    led: RgbLed<R, G, B>,
+    repeating: Playing,
+    // Plays over `repeating`.
+    once: Option<Playing>,
+    colour: Option<Colour>,
+}
+
+impl<R, G, B, E> StatusLed<R, G, B>
+where
+    R: OutputPin<Error = E>,
+    G: OutputPin<Error = E>,
+    B: OutputPin<Error = E>,
+    E: Debug,
+{
+    /// Starts with `Pattern::Booting`.
+    pub fn new(led: RgbLed<R, G, B>, now: i64) -> Self {
+        let mut status = Self {
+            led,
+            repeating: Playing::new(Pattern::Booting, now),
+            once: None,
+            colour: None,
+        };
+        status.poll(now);
+        status
+    }
+
+    /// Plays `pattern`. A repeating pattern that is already shown continues
+    /// where it was.
+    pub fn show(&mut self, now: i64, pattern: Pattern) {
+        match pattern.plays() {
+            Some(_) => self.once = Some(Playing::new(pattern, now)),
+            None if pattern == self.repeating.pattern => {}
+            None => self.repeating = Playing::new(pattern, now),
+        }
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        advance(&mut self.repeating, now);
+        if let Some(once) = self.once.as_mut() {
+            if !advance(once, now) {
+                self.once = None;
+            }
+        }
+        let playing = self.once.as_ref().unwrap_or(&self.repeating);
+        let (colour, _) = playing.pattern.steps()[playing.step];
+        if self.colour != Some(colour) {
+            self.colour = Some(colour);
+            if let Err(err) = self.led.show(colour) {
+                log::warn!("Failed to set status LED: {:?}", err);
+            }
+        }
+    }
+}
+
+// Moves on to the step that should be shown at `now`. Returns false once a
+// pattern that only plays a number of times has finished.
+fn advance(playing: &mut Playing, now: i64) -> bool {
+    let steps = playing.pattern.steps();
+    loop {
+        let (_, duration) = steps[playing.step];
+        if now - playing.step_since < duration as i64 {
+            return true;
+        }
+        playing.step_since += duration as i64;
+        playing.step = (playing.step + 1) % steps.len();
+        if playing.step == 0 && playing.pattern.plays().is_some() {
+            playing.plays_left -= 1;
+            if playing.plays_left == 0 {
+                return false;
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! The parts of the meter reader that don't touch the hardware: the DSMR
+//! parser and the framing it is fed through, the ring buffer, the validation
+//! of readings, the telemetry registry, the history and the events derived
+//! from readings, the JSON they are published as, the software timers of the
+//! main loop, and the settings, the relay rules among them, and the console
+//! commands that change them. Unlike the firmware, which needs the `target`
+//! feature, these also build for the host, so that they can be unit tested
+//! and fuzzed on a PC:
+//!
+//! ```text
+//! cargo test -p meter-reader --no-default-features --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The CRC of the telegrams is checked by `dsmr42`, which builds for the host
+//! as it is.
+//!
+//! With the `target` feature, it also has the UART that telegrams are
+//! received by, and the clocks and the DMA that it depends on, which the
+//! firmware shares with the `hil-tests` firmware.
+
+#![no_std]
+
+#[cfg(feature = "target")]
+pub mod clock;
+pub mod command;
+pub mod config;
+#[cfg(feature = "target")]
+pub mod cpu_clock;
+#[cfg(feature = "target")]
+pub mod dma;
+pub mod dsmr;
+pub mod events;
+pub mod framing;
+pub mod history;
+pub mod json;
+pub mod ring_buffer;
+pub mod rules;
+pub mod telemetry;
+pub mod timers;
+#[cfg(feature = "target")]
+pub mod uart;
+pub mod validation;
+
+#[cfg(test)]
+extern crate std;
@@ -0,0 +1,133 @@
+//! Log levels per module, which can be changed at runtime.
+//!
+//! The syslog logger applies them to every record. The BSP's USB logger
+//! can't be told about them, so over USB, the highest level of any module
+//! applies to all of them.
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::{cell::RefCell, cmp};
+
+use cortex_m::interrupt::{self, Mutex};
+use log::{LevelFilter, Metadata};
+
+const MAX_MODULES: usize = 8;
+const MODULE_NAME_SZ: usize = 32;
+
+type ModuleName = ArrayString<[u8; MODULE_NAME_SZ]>;
+
+// Created on first use, since it can't be created in a constant.
+static FILTER: Mutex<RefCell<Option<Filter>>> = Mutex::new(RefCell::new(None));
+
+#[derive(Debug)]
+pub enum FilterError {
+    TooManyModules,
+    NameTooLong,
+}
+
+struct Filter {
+    default: LevelFilter,
+    modules: ArrayVec<[(ModuleName, LevelFilter); MAX_MODULES]>,
+}
+
+impl Filter {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| matches(module, target))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, cmp::max)
+    }
+}
+
+/// Sets the level of modules without a level of their own.
+pub fn set_default(level: LevelFilter) {
+    update(|filter| {
+        filter.default = level;
+        Ok(())
+    })
+    .unwrap_or(())
+}
+
+/// Sets the level of `module`, which is a module path such as `smoltcp`, or
+/// the name of one of our own modules, such as `mqtt`. Submodules get the
+/// same level.
+pub fn set(module: &str, level: LevelFilter) -> Result<(), FilterError> {
+    let name = ModuleName::from(module).map_err(|_| FilterError::NameTooLong)?;
+    update(|filter| {
+        match filter
+            .modules
+            .iter_mut()
+            .find(|(module, _)| *module == name)
+        {
+            Some((_, existing)) => *existing = level,
+            None => filter
+                .modules
+                .try_push((name, level))
+                .map_err(|_| FilterError::TooManyModules)?,
+        }
+        Ok(())
+    })
+}
+
+/// Gives `module` the default level again.
+pub fn reset(module: &str) {
+    update(|filter| {
+        filter.modules.retain(|(name, _)| name.as_str() != module);
+        Ok(())
+    })
+    .unwrap_or(())
+}
+
+pub fn enabled(metadata: &Metadata) -> bool {
+    with(|filter| metadata.level() <= filter.level(metadata.target()))
+}
+
+/// Calls `f` with the default level, followed by every module and its level.
+pub fn for_each<F: FnMut(Option<&str>, LevelFilter)>(mut f: F) {
+    with(|filter| {
+        f(None, filter.default);
+        for (module, level) in filter.modules.iter() {
+            f(Some(module), *level);
+        }
+    })
+}
+
+fn update<F>(f: F) -> Result<(), FilterError>
+where
+    F: FnOnce(&mut Filter) -> Result<(), FilterError>,
+{
+    with(|filter| {
+        f(filter)?;
+        // Records above this are discarded before even reaching a logger.
+        log::set_max_level(filter.max_level());
+        Ok(())
+    })
+}
+
+fn with<F: FnOnce(&mut Filter) -> R, R>(f: F) -> R {
+    interrupt::free(|cs| {
+        let mut filter = FILTER.borrow(cs).borrow_mut();
+        f(filter.get_or_insert_with(|| Filter {
+            default: LevelFilter::Info,
+            modules: ArrayVec::new(),
+        }))
+    })
+}
+
+// Whether `target` is `module`, or a submodule of it, either from the crate
+// root or from our own crate.
+fn matches(module: &str, target: &str) -> bool {
+    let within = |path: &str| {
+        path == module || path.starts_with(module) && path[module.len()..].starts_with("::")
+    };
+    within(target)
+        || target
+            .find("::")
+            .map_or(false, |at| within(&target[at + 2..]))
+}
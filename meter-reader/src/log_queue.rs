@@ -0,0 +1,160 @@
+//! Lock-free queue of formatted log records, so that records can be logged
+//! from anywhere, including interrupt handlers, without masking interrupts or
+//! waiting for a backend. The main loop drains it.
+//!
+//! This is the bounded MPMC queue by Dmitry Vyukov: every slot has a
+//! sequence number that tells whether it is free to be written, or holds a
+//! record to be read, in the current lap around the queue. Records that
+//! don't fit are dropped and counted.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use log::Level;
+
+// A power of two, so that positions stay consecutive when they wrap around.
+const SLOTS: usize = 32;
+/// Longer messages are truncated.
+pub const MESSAGE_SZ: usize = 256;
+
+const EMPTY_SLOT: Slot = Slot {
+    sequence: AtomicUsize::new(0),
+    record: UnsafeCell::new(Record::EMPTY),
+};
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub level: Level,
+    len: usize,
+    message: [u8; MESSAGE_SZ],
+}
+
+impl Record {
+    pub const EMPTY: Self = Self {
+        level: Level::Error,
+        len: 0,
+        message: [0; MESSAGE_SZ],
+    };
+
+    pub fn message(&self) -> &str {
+        // Only whole characters are written.
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MESSAGE_SZ - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.message[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+struct Slot {
+    /// The sequence number minus the index of the slot, so that every slot
+    /// starts out at 0.
+    sequence: AtomicUsize,
+    record: UnsafeCell<Record>,
+}
+
+pub struct LogQueue {
+    slots: [Slot; SLOTS],
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+    dropped: AtomicU32,
+}
+
+// Slots are only accessed by whoever claimed them through their sequence.
+unsafe impl Sync for LogQueue {}
+
+impl LogQueue {
+    pub const fn new() -> Self {
+        Self {
+            slots: [EMPTY_SLOT; SLOTS],
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Queues a record, returning `false` if the queue is full.
+    pub fn push(&self, level: Level, message: fmt::Arguments) -> bool {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        let index = loop {
+            let index = pos % SLOTS;
+            let sequence = self.sequence(index);
+            match sequence.wrapping_sub(pos) as isize {
+                0 => match self.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break index,
+                    Err(current) => pos = current,
+                },
+                // The slot still holds a record from the previous lap.
+                diff if diff < 0 => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                _ => pos = self.enqueue_pos.load(Ordering::Relaxed),
+            }
+        };
+        let record = unsafe { &mut *self.slots[index].record.get() };
+        record.level = level;
+        record.len = 0;
+        let _ = record.write_fmt(message);
+        self.set_sequence(index, pos.wrapping_add(1));
+        true
+    }
+
+    /// Moves the oldest record into `record`, returning `false` if there is
+    /// none.
+    pub fn pop(&self, record: &mut Record) -> bool {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        let index = loop {
+            let index = pos % SLOTS;
+            let sequence = self.sequence(index);
+            match sequence.wrapping_sub(pos.wrapping_add(1)) as isize {
+                0 => match self.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break index,
+                    Err(current) => pos = current,
+                },
+                // The slot has not been written in this lap yet.
+                diff if diff < 0 => return false,
+                _ => pos = self.dequeue_pos.load(Ordering::Relaxed),
+            }
+        };
+        *record = unsafe { *self.slots[index].record.get() };
+        self.set_sequence(index, pos.wrapping_add(SLOTS));
+        true
+    }
+
+    /// The number of records that did not fit in the queue.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn sequence(&self, index: usize) -> usize {
+        let stored = self.slots[index].sequence.load(Ordering::Acquire);
+        stored.wrapping_add(index)
+    }
+
+    fn set_sequence(&self, index: usize, sequence: usize) {
+        let stored = sequence.wrapping_sub(index);
+        self.slots[index].sequence.store(stored, Ordering::Release);
+    }
+}
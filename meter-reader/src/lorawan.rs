@@ -0,0 +1,600 @@
+//! A LoRaWAN 1.0 class A end device in the EU868 band, on an SX1276 radio,
+//! for meters that are out of reach of any network.
+//!
+//! The device joins over the air, and then sends a summary of the latest
+//! reading every `interval_ms`: the totals and the power, and the readings
+//! of the M-Bus devices, in the encoding of `binary`, as an unconfirmed
+//! uplink on port `FPORT`. Downlinks, and so MAC commands, aren't handled,
+//! which is why ADR is off.
+//!
+//! Uplinks are also held back to stay within the 1% duty cycle that the band
+//! allows, through the time each one spends on the air.
+//!
+//! The session, the uplink frame counter and the DevNonce of the last join
+//! request are kept in flash, the same way as `Config`, so that a reset
+//! neither needs a new join nor makes the network server drop frames with a
+//! counter it has seen already. Since flash wears, the frame counter is only
+//! saved every `FCNT_SAVE_INTERVAL` uplinks, as a limit below which all
+//! counters are used, and the device goes on from that limit after a reset.
+
+mod crypto;
+mod sx127x;
+
+use core::{convert::Infallible, fmt::Debug};
+
+use arrayvec::ArrayVec;
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+use crate::{
+    binary,
+    dsmr::{MbusReading, Reading},
+    flash::{Flash, SECTOR_SZ},
+};
+
+use self::crypto::Key;
+pub use self::sx127x::Sx127x;
+use self::sx127x::{Channel, Status};
+
+/// The port that readings are sent on.
+pub const FPORT: u8 = 1;
+// The default channels of EU868, which every network has.
+const UPLINK_FREQUENCIES_HZ: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+const RX2_FREQUENCY_HZ: u32 = 869_525_000;
+const JOIN_ACCEPT_DELAY_1_MS: i64 = 5_000;
+const JOIN_ACCEPT_DELAY_2_MS: i64 = 6_000;
+// The receiver starts a little early, since the clock is only read every
+// loop iteration, and waits for a preamble for this many symbols.
+const RX_EARLY_MS: i64 = 20;
+const RX_TIMEOUT_SYMBOLS: u16 = 32;
+// How much longer than its time on the air the radio may take.
+const RADIO_MARGIN_MS: i64 = 1_000;
+// The time off the air after a transmission, as a multiple of the time on
+// the air, for a duty cycle of 1%.
+const DUTY_CYCLE_OFF_FACTOR: i64 = 99;
+const JOIN_RETRY_MS: i64 = 30_000;
+// Join retries back off exponentially, to about an hour.
+const JOIN_MAX_BACKOFF_SHIFT: u32 = 7;
+const FCNT_SAVE_INTERVAL: u32 = 32;
+
+// The most that EU868 allows in a frame, at SF7 to SF9.
+const MAX_PAYLOAD_SZ: usize = 222;
+// The header, the port and the MIC, without options.
+const FRAME_OVERHEAD_SZ: usize = 13;
+const MAX_FRAME_SZ: usize = MAX_PAYLOAD_SZ + FRAME_OVERHEAD_SZ;
+const MIC_SZ: usize = 4;
+const MHDR_JOIN_REQUEST: u8 = 0x00;
+const MHDR_JOIN_ACCEPT: u8 = 0x20;
+const MHDR_UNCONFIRMED_UP: u8 = 0x40;
+// A join accept, with or without a list of extra channels.
+const JOIN_ACCEPT_SZ: [usize; 2] = [17, 33];
+
+const STATE_VERSION: u8 = 1;
+const RECORD_SZ: usize = 52;
+const ERASED: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LoraConfig {
+    /// As shown by the network server, most significant byte first.
+    pub dev_eui: [u8; 8],
+    pub join_eui: [u8; 8],
+    pub app_key: Key,
+    pub interval_ms: i64,
+    /// From 7 to 12. SF10 and up only fit 51 bytes, which isn't enough for
+    /// a summary with M-Bus devices.
+    pub spreading_factor: u8,
+    /// Of the second receive window of a join, which is SF12 by the
+    /// specification, and SF9 on The Things Network.
+    pub rx2_spreading_factor: u8,
+    /// From 2 to 17.
+    pub tx_power_dbm: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Session {
+    dev_addr: u32,
+    nwk_skey: Key,
+    app_skey: Key,
+}
+
+// What is kept in flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Persisted {
+    dev_nonce: u16,
+    session: Option<Session>,
+    // Uplinks use frame counters below this.
+    fcnt_limit: u32,
+}
+
+impl Persisted {
+    fn encode(&self, generation: u32) -> [u8; RECORD_SZ] {
+        let mut record = [0; RECORD_SZ];
+        record[0] = STATE_VERSION;
+        record[1..5].copy_from_slice(&generation.to_le_bytes());
+        record[5..7].copy_from_slice(&self.dev_nonce.to_le_bytes());
+        record[7..11].copy_from_slice(&self.fcnt_limit.to_le_bytes());
+        if let Some(session) = self.session {
+            record[11] = 1;
+            record[12..16].copy_from_slice(&session.dev_addr.to_le_bytes());
+            record[16..32].copy_from_slice(&session.nwk_skey);
+            record[32..48].copy_from_slice(&session.app_skey);
+        }
+        let crc = dsmr42::crc16(&record[..RECORD_SZ - 2]);
+        record[RECORD_SZ - 2..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    // Along with its generation.
+    fn decode(record: &[u8]) -> Option<(u32, Self)> {
+        let crc = u16::from_le_bytes([record[RECORD_SZ - 2], record[RECORD_SZ - 1]]);
+        if record[0] != STATE_VERSION || dsmr42::crc16(&record[..RECORD_SZ - 2]) != crc {
+            return None;
+        }
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+        };
+        let key_at = |at: usize| {
+            let mut key = [0; 16];
+            key.copy_from_slice(&record[at..at + 16]);
+            key
+        };
+        let session = match record[11] {
+            1 => Some(Session {
+                dev_addr: u32_at(12),
+                nwk_skey: key_at(16),
+                app_skey: key_at(32),
+            }),
+            _ => None,
+        };
+        let persisted = Self {
+            dev_nonce: u16::from_le_bytes([record[5], record[6]]),
+            fcnt_limit: u32_at(7),
+            session,
+        };
+        Some((u32_at(1), persisted))
+    }
+}
+
+// Two sectors of records, like `ConfigStore`.
+struct Store {
+    sectors: [usize; 2],
+    active: usize,
+    offset: usize,
+    generation: u32,
+}
+
+impl Store {
+    fn load(flash: &Flash, sectors: [usize; 2]) -> (Self, Option<Persisted>) {
+        let mut store = Self {
+            sectors,
+            active: 0,
+            offset: SECTOR_SZ,
+            generation: 0,
+        };
+        let mut latest = None;
+        let mut ends = [0; 2];
+        for (index, sector) in sectors.iter().enumerate() {
+            let mut offset = 0;
+            while offset + RECORD_SZ <= SECTOR_SZ {
+                let record = flash.read(*sector, offset, RECORD_SZ);
+                if record[0] == ERASED {
+                    break;
+                }
+                offset += RECORD_SZ;
+                match Persisted::decode(record) {
+                    Some((generation, persisted))
+                        if latest.is_none() || generation > store.generation =>
+                    {
+                        store.generation = generation;
+                        store.active = index;
+                        latest = Some(persisted);
+                    }
+                    _ => {}
+                }
+            }
+            ends[index] = offset;
+        }
+        if latest.is_some() {
+            store.offset = ends[store.active];
+        }
+        (store, latest)
+    }
+
+    fn save(&mut self, flash: &mut Flash, persisted: &Persisted) {
+        let generation = self.generation.wrapping_add(1);
+        let record = persisted.encode(generation);
+        let sector = self.sectors[self.active];
+        let fits = self.offset + RECORD_SZ <= SECTOR_SZ
+            && flash
+                .read(sector, self.offset, RECORD_SZ)
+                .iter()
+                .all(|byte| *byte == ERASED);
+        if !fits {
+            self.active = 1 - self.active;
+            self.offset = 0;
+            flash.erase(self.sectors[self.active]);
+        }
+        flash.program(self.sectors[self.active], self.offset, &record);
+        self.offset += RECORD_SZ;
+        self.generation = generation;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Transmitting {
+        since: i64,
+        airtime_ms: i64,
+        channel: Channel,
+        join: bool,
+    },
+    /// After a join request, until receive window 1 or 2 opens.
+    WaitingForWindow {
+        window: u8,
+        sent_at: i64,
+        channel: Channel,
+    },
+    Receiving {
+        window: u8,
+        sent_at: i64,
+        since: i64,
+        channel: Channel,
+    },
+}
+
+pub struct Lorawan<SPI, NCS> {
+    radio: Sx127x<SPI, NCS>,
+    config: LoraConfig,
+    store: Store,
+    persisted: Persisted,
+    fcnt_up: u32,
+    state: State,
+    join_at: i64,
+    join_attempts: u32,
+    uplink_at: i64,
+    // Not before then, for the duty cycle.
+    transmit_at: i64,
+    next_channel: usize,
+    pending: Option<ArrayVec<[u8; MAX_PAYLOAD_SZ]>>,
+}
+
+impl<SPI, NCS, E> Lorawan<SPI, NCS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    NCS: OutputPin<Error = Infallible>,
+    E: Debug,
+{
+    /// Takes up the session in `sectors` from before a reset, if any, or
+    /// joins once `poll` is called.
+    pub fn new(
+        radio: Sx127x<SPI, NCS>,
+        config: LoraConfig,
+        flash: &Flash,
+        sectors: [usize; 2],
+    ) -> Self {
+        let (store, persisted) = Store::load(flash, sectors);
+        let persisted = persisted.unwrap_or(Persisted {
+            dev_nonce: 0,
+            session: None,
+            fcnt_limit: 0,
+        });
+        match persisted.session {
+            Some(session) => log::info!(
+                "Resuming LoRaWAN session of {:08X} at frame {}",
+                session.dev_addr,
+                persisted.fcnt_limit
+            ),
+            None => log::info!("No LoRaWAN session, joining"),
+        }
+        Self {
+            radio,
+            config,
+            store,
+            fcnt_up: persisted.fcnt_limit,
+            persisted,
+            state: State::Idle,
+            join_at: 0,
+            join_attempts: 0,
+            uplink_at: 0,
+            transmit_at: 0,
+            next_channel: 0,
+            pending: None,
+        }
+    }
+
+    /// Replaces the summary that is sent next with one of `reading`.
+    pub fn queue_reading(&mut self, reading: &Reading) {
+        let mut summary = Reading {
+            timestamp: reading.timestamp,
+            delivered: reading.delivered,
+            returned: reading.returned,
+            power_delivered: reading.power_delivered,
+            power_returned: reading.power_returned,
+            ..Reading::default()
+        };
+        for (summary, mbus) in summary.mbus.iter_mut().zip(&reading.mbus) {
+            *summary = mbus.map(|mbus| MbusReading {
+                equipment_id: None,
+                ..mbus
+            });
+        }
+        let mut payload = [0; MAX_PAYLOAD_SZ];
+        match binary::encode_reading(&mut payload, &summary) {
+            Ok(len) => self.pending = Some(payload[..len].iter().copied().collect()),
+            Err(_) => log::warn!("Reading summary doesn't fit in a LoRaWAN frame"),
+        }
+    }
+
+    pub fn poll(&mut self, now: i64, flash: &mut Flash) {
+        if let Err(err) = self.step(now, flash) {
+            log::warn!("LoRa radio failed: {:?}", err);
+            let _ = self.radio.standby();
+            self.state = State::Idle;
+        }
+    }
+
+    fn step(&mut self, now: i64, flash: &mut Flash) -> Result<(), sx127x::Error<E>> {
+        match self.state {
+            State::Idle if now < self.transmit_at => {}
+            State::Idle => match self.persisted.session {
+                None if now >= self.join_at => self.join(now, flash)?,
+                Some(session) if now >= self.uplink_at && self.pending.is_some() => {
+                    self.uplink(now, flash, session)?
+                }
+                _ => {}
+            },
+            State::Transmitting {
+                since,
+                airtime_ms,
+                channel,
+                join,
+            } => match self.radio.poll()? {
+                Status::Sent => {
+                    self.transmit_at = now + airtime_ms * DUTY_CYCLE_OFF_FACTOR;
+                    self.state = if join {
+                        State::WaitingForWindow {
+                            window: 1,
+                            sent_at: now,
+                            channel,
+                        }
+                    } else {
+                        State::Idle
+                    };
+                }
+                _ if now - since > airtime_ms + RADIO_MARGIN_MS => {
+                    log::warn!("LoRa radio didn't finish sending");
+                    self.radio.standby()?;
+                    self.transmit_at = now + airtime_ms * DUTY_CYCLE_OFF_FACTOR;
+                    self.state = State::Idle;
+                }
+                _ => {}
+            },
+            State::WaitingForWindow {
+                window,
+                sent_at,
+                channel,
+            } => {
+                let delay = match window {
+                    1 => JOIN_ACCEPT_DELAY_1_MS,
+                    _ => JOIN_ACCEPT_DELAY_2_MS,
+                };
+                if now >= sent_at + delay - RX_EARLY_MS {
+                    self.radio.receive(channel, RX_TIMEOUT_SYMBOLS)?;
+                    self.state = State::Receiving {
+                        window,
+                        sent_at,
+                        since: now,
+                        channel,
+                    };
+                }
+            }
+            State::Receiving {
+                window,
+                sent_at,
+                since,
+                channel,
+            } => {
+                let received = match self.radio.poll()? {
+                    Status::Received(len) => {
+                        let mut packet = [0; JOIN_ACCEPT_SZ[1]];
+                        if JOIN_ACCEPT_SZ.contains(&len) {
+                            self.radio.read_packet(&mut packet[..len])?;
+                            self.accept(now, flash, &mut packet[..len])
+                        } else {
+                            false
+                        }
+                    }
+                    Status::Nothing => false,
+                    _ if now - since
+                        > airtime_ms(channel.spreading_factor, JOIN_ACCEPT_SZ[1])
+                            + RADIO_MARGIN_MS =>
+                    {
+                        self.radio.standby()?;
+                        false
+                    }
+                    _ => return Ok(()),
+                };
+                self.state = match (received, window) {
+                    (true, _) => State::Idle,
+                    (false, 1) => State::WaitingForWindow {
+                        window: 2,
+                        sent_at,
+                        channel: Channel {
+                            frequency_hz: RX2_FREQUENCY_HZ,
+                            spreading_factor: self.config.rx2_spreading_factor,
+                        },
+                    },
+                    (false, _) => {
+                        let backoff =
+                            JOIN_RETRY_MS << self.join_attempts.min(JOIN_MAX_BACKOFF_SHIFT);
+                        log::warn!(
+                            "No LoRaWAN join accept, trying again in {} s",
+                            backoff / 1000
+                        );
+                        self.join_at = now + backoff;
+                        State::Idle
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn join(&mut self, now: i64, flash: &mut Flash) -> Result<(), sx127x::Error<E>> {
+        // A nonce must never be used again, not even after a reset.
+        self.persisted.dev_nonce = self.persisted.dev_nonce.wrapping_add(1);
+        self.store.save(flash, &self.persisted);
+        self.join_attempts = self.join_attempts.saturating_add(1);
+
+        let mut request = [0; 23];
+        request[0] = MHDR_JOIN_REQUEST;
+        request[1..9].copy_from_slice(&reversed(self.config.join_eui));
+        request[9..17].copy_from_slice(&reversed(self.config.dev_eui));
+        request[17..19].copy_from_slice(&self.persisted.dev_nonce.to_le_bytes());
+        let mic = crypto::cmac(&self.config.app_key, &request[..19]);
+        request[19..].copy_from_slice(&mic[..MIC_SZ]);
+        log::info!("Sending LoRaWAN join request {}", self.join_attempts);
+        self.transmit(now, &request, true)
+    }
+
+    // Whether `packet` is a join accept for our last request, in which case
+    // the session starts.
+    fn accept(&mut self, now: i64, flash: &mut Flash, packet: &mut [u8]) -> bool {
+        if packet[0] != MHDR_JOIN_ACCEPT {
+            return false;
+        }
+        // The network server encrypts with AES decryption, so that devices
+        // only need encryption.
+        for block in packet[1..].chunks_mut(16) {
+            let mut buffer = [0; 16];
+            buffer.copy_from_slice(block);
+            crypto::encrypt_block(&self.config.app_key, &mut buffer);
+            block.copy_from_slice(&buffer);
+        }
+        let (message, mic) = packet.split_at(packet.len() - MIC_SZ);
+        if crypto::cmac(&self.config.app_key, message)[..MIC_SZ] != *mic {
+            return false;
+        }
+        let session_key = |kind: u8| {
+            let mut block = [0; 16];
+            block[0] = kind;
+            // The AppNonce and the NetID.
+            block[1..7].copy_from_slice(&message[1..7]);
+            block[7..9].copy_from_slice(&self.persisted.dev_nonce.to_le_bytes());
+            crypto::encrypt_block(&self.config.app_key, &mut block);
+            block
+        };
+        let session = Session {
+            dev_addr: u32::from_le_bytes([message[7], message[8], message[9], message[10]]),
+            nwk_skey: session_key(0x01),
+            app_skey: session_key(0x02),
+        };
+        log::info!("Joined LoRaWAN network as {:08X}", session.dev_addr);
+        self.persisted.session = Some(session);
+        self.persisted.fcnt_limit = FCNT_SAVE_INTERVAL;
+        self.store.save(flash, &self.persisted);
+        self.fcnt_up = 0;
+        self.join_attempts = 0;
+        self.uplink_at = now;
+        true
+    }
+
+    fn uplink(
+        &mut self,
+        now: i64,
+        flash: &mut Flash,
+        session: Session,
+    ) -> Result<(), sx127x::Error<E>> {
+        let mut payload = match self.pending.take() {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        self.uplink_at = now + self.config.interval_ms;
+        if payload.len() > max_payload_sz(self.config.spreading_factor) {
+            log::warn!(
+                "Reading summary of {} bytes doesn't fit at SF{}",
+                payload.len(),
+                self.config.spreading_factor
+            );
+            return Ok(());
+        }
+        if self.fcnt_up >= self.persisted.fcnt_limit {
+            self.persisted.fcnt_limit = self.fcnt_up + FCNT_SAVE_INTERVAL;
+            self.store.save(flash, &self.persisted);
+        }
+        let fcnt = self.fcnt_up;
+        self.fcnt_up += 1;
+        crypto::crypt_payload(&session.app_skey, session.dev_addr, fcnt, 0, &mut payload);
+
+        // The MIC is over a block B0, followed by the frame.
+        let mut buffer = [0; 16 + MAX_FRAME_SZ];
+        let (b0, frame) = buffer.split_at_mut(16);
+        frame[0] = MHDR_UNCONFIRMED_UP;
+        frame[1..5].copy_from_slice(&session.dev_addr.to_le_bytes());
+        // No ADR, no ACK and no options.
+        frame[5] = 0;
+        frame[6..8].copy_from_slice(&(fcnt as u16).to_le_bytes());
+        frame[8] = FPORT;
+        frame[9..9 + payload.len()].copy_from_slice(&payload);
+        let len = 9 + payload.len();
+        b0[0] = 0x49;
+        b0[6..10].copy_from_slice(&session.dev_addr.to_le_bytes());
+        b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        b0[15] = len as u8;
+        let mic = crypto::cmac(&session.nwk_skey, &buffer[..16 + len]);
+        buffer[16 + len..16 + len + MIC_SZ].copy_from_slice(&mic[..MIC_SZ]);
+        log::debug!("Sending LoRaWAN uplink {} of {} bytes", fcnt, len + MIC_SZ);
+        self.transmit(now, &buffer[16..16 + len + MIC_SZ], false)
+    }
+
+    fn transmit(&mut self, now: i64, packet: &[u8], join: bool) -> Result<(), sx127x::Error<E>> {
+        let channel = Channel {
+            frequency_hz: UPLINK_FREQUENCIES_HZ[self.next_channel],
+            spreading_factor: self.config.spreading_factor,
+        };
+        self.next_channel = (self.next_channel + 1) % UPLINK_FREQUENCIES_HZ.len();
+        self.radio.transmit(channel, packet)?;
+        self.state = State::Transmitting {
+            since: now,
+            airtime_ms: airtime_ms(channel.spreading_factor, packet.len()),
+            channel,
+            join,
+        };
+        Ok(())
+    }
+}
+
+// The largest FRMPayload at a spreading factor, without options.
+fn max_payload_sz(spreading_factor: u8) -> usize {
+    match spreading_factor {
+        7 | 8 => MAX_PAYLOAD_SZ,
+        9 => 115,
+        _ => 51,
+    }
+}
+
+// The time that a packet of `len` bytes spends on the air, rounded up, as
+// in Semtech's SX1276 datasheet, at 125 kHz, coding rate 4/5, with an
+// explicit header and a CRC.
+fn airtime_ms(spreading_factor: u8, len: usize) -> i64 {
+    let sf = spreading_factor as i64;
+    let symbol_us = (1 << sf) * 8;
+    let low_data_rate = if sf >= 11 { 1 } else { 0 };
+    let bits = 8 * len as i64 - 4 * sf + 28 + 16;
+    let per_block = 4 * (sf - 2 * low_data_rate);
+    let blocks = ((bits + per_block - 1) / per_block).max(0);
+    let symbols_x4 = (PREAMBLE_SYMBOLS_X4 + 4 * (8 + blocks * 5)) as i64;
+    (symbols_x4 * symbol_us / 4 + 999) / 1000
+}
+
+// The preamble of 8 symbols, and 4.25 for the sync word, times 4.
+const PREAMBLE_SYMBOLS_X4: i64 = 49;
+
+// EUIs go over the air least significant byte first.
+fn reversed(mut eui: [u8; 8]) -> [u8; 8] {
+    eui.reverse();
+    eui
+}
@@ -0,0 +1,75 @@
+//! The AES-128 that LoRaWAN 1.0 secures frames with: AES-CMAC for the MIC,
+//! and AES in counter mode for the payload, as described in its
+//! specification and in RFC 4493.
+
+use aes::{cipher::generic_array::GenericArray, Aes128, BlockEncrypt, NewBlockCipher};
+
+pub type Key = [u8; 16];
+const BLOCK_SZ: usize = 16;
+
+pub fn encrypt_block(key: &Key, block: &mut [u8; BLOCK_SZ]) {
+    Aes128::new(GenericArray::from_slice(key)).encrypt_block(GenericArray::from_mut_slice(block));
+}
+
+/// The AES-CMAC of `message`.
+pub fn cmac(key: &Key, message: &[u8]) -> [u8; BLOCK_SZ] {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut subkey = [0; BLOCK_SZ];
+    cipher.encrypt_block(GenericArray::from_mut_slice(&mut subkey));
+    subkey = double(&subkey);
+
+    let blocks = (message.len() + BLOCK_SZ - 1) / BLOCK_SZ;
+    let (full, last) = message.split_at(blocks.saturating_sub(1) * BLOCK_SZ);
+    let mut last_block = [0; BLOCK_SZ];
+    last_block[..last.len()].copy_from_slice(last);
+    if last.len() < BLOCK_SZ {
+        last_block[last.len()] = 0x80;
+        subkey = double(&subkey);
+    }
+    xor(&mut last_block, &subkey);
+
+    let mut mac = [0; BLOCK_SZ];
+    for block in full
+        .chunks(BLOCK_SZ)
+        .chain(core::iter::once(&last_block[..]))
+    {
+        xor(&mut mac, block);
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut mac));
+    }
+    mac
+}
+
+/// Encrypts or decrypts the payload of a data frame in place. `dir` is 0 for
+/// uplinks.
+pub fn crypt_payload(key: &Key, dev_addr: u32, fcnt: u32, dir: u8, payload: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for (i, chunk) in payload.chunks_mut(BLOCK_SZ).enumerate() {
+        let mut block = [0; BLOCK_SZ];
+        block[0] = 0x01;
+        block[5] = dir;
+        block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        block[15] = i as u8 + 1;
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut block));
+        xor(chunk, &block);
+    }
+}
+
+// Multiplies by x in GF(2^128), as CMAC derives its subkeys.
+fn double(block: &[u8; BLOCK_SZ]) -> [u8; BLOCK_SZ] {
+    let mut doubled = [0; BLOCK_SZ];
+    for i in 0..BLOCK_SZ {
+        let carry = block.get(i + 1).map_or(0, |next| next >> 7);
+        doubled[i] = block[i] << 1 | carry;
+    }
+    if block[0] & 0x80 != 0 {
+        doubled[BLOCK_SZ - 1] ^= 0x87;
+    }
+    doubled
+}
+
+fn xor(block: &mut [u8], with: &[u8]) {
+    for (byte, with) in block.iter_mut().zip(with) {
+        *byte ^= with;
+    }
+}
@@ -0,0 +1,225 @@
+//! Just enough of a Semtech SX1276 or SX1278 in LoRa mode to send a packet,
+//! and to listen for one in a receive window, without blocking. Whether the
+//! radio is done is read from its interrupt flags over SPI, so DIO0 doesn't
+//! have to be connected.
+
+use core::convert::Infallible;
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_LNA: u8 = 0x0C;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_SYMB_TIMEOUT_LSB: u8 = 0x1F;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ_2: u8 = 0x3B;
+const REG_VERSION: u8 = 0x42;
+const VERSION: u8 = 0x12;
+const WRITE: u8 = 0x80;
+
+const MODE_LORA: u8 = 0x80;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_SINGLE: u8 = 0x06;
+const IRQ_RX_TIMEOUT: u8 = 0x80;
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 0x20;
+const IRQ_TX_DONE: u8 = 0x08;
+// 125 kHz, coding rate 4/5, explicit header.
+const MODEM_CONFIG_1: u8 = 0x72;
+// The payload CRC, which uplinks have and downlinks don't.
+const MODEM_CONFIG_2_CRC: u8 = 0x04;
+const MODEM_CONFIG_3_AGC: u8 = 0x04;
+const MODEM_CONFIG_3_LOW_DATA_RATE: u8 = 0x08;
+// Downlinks are sent with the I and Q signals inverted, so that devices don't
+// hear each other.
+const INVERT_IQ_TX: (u8, u8) = (0x27, 0x1D);
+const INVERT_IQ_RX: (u8, u8) = (0x67, 0x19);
+const LNA_MAX_GAIN: u8 = 0x23;
+const PREAMBLE_SYMBOLS: u16 = 8;
+// The sync word of public LoRaWAN networks.
+const SYNC_WORD: u8 = 0x34;
+const PA_BOOST: u8 = 0x80;
+// In Hz.
+const OSCILLATOR_HZ: u64 = 32_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Spi(E),
+    /// The version register didn't read as an SX1276, so there is none, or
+    /// it isn't wired up right.
+    NotFound(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Busy,
+    Sent,
+    /// Received a packet of this many bytes, which `read_packet` returns.
+    Received(usize),
+    /// Nothing came in the receive window, or it was corrupted.
+    Nothing,
+}
+
+/// What is sent or received on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub frequency_hz: u32,
+    /// From 7 to 12, at 125 kHz.
+    pub spreading_factor: u8,
+}
+
+pub struct Sx127x<SPI, NCS> {
+    spi: SPI,
+    ncs: NCS,
+}
+
+impl<SPI, NCS, E> Sx127x<SPI, NCS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    NCS: OutputPin<Error = Infallible>,
+{
+    /// Puts the radio, which has just been reset, in LoRa mode, sending at
+    /// `power_dbm`, from 2 to 17, on PA_BOOST.
+    pub fn new(spi: SPI, mut ncs: NCS, power_dbm: u8) -> Result<Self, Error<E>> {
+        let _ = ncs.set_high();
+        let mut radio = Self { spi, ncs };
+        let version = radio.read(REG_VERSION)?;
+        if version != VERSION {
+            return Err(Error::NotFound(version));
+        }
+        // LoRa mode can only be entered while asleep.
+        radio.write(REG_OP_MODE, MODE_SLEEP)?;
+        radio.write(REG_OP_MODE, MODE_LORA | MODE_SLEEP)?;
+        radio.write(REG_PA_CONFIG, PA_BOOST | (power_dbm.clamp(2, 17) - 2))?;
+        radio.write(REG_LNA, LNA_MAX_GAIN)?;
+        radio.write(REG_SYNC_WORD, SYNC_WORD)?;
+        radio.write_all(REG_PREAMBLE_MSB, &PREAMBLE_SYMBOLS.to_be_bytes())?;
+        radio.write(REG_FIFO_TX_BASE_ADDR, 0)?;
+        radio.write(REG_FIFO_RX_BASE_ADDR, 0)?;
+        radio.write(REG_OP_MODE, MODE_LORA | MODE_STANDBY)?;
+        Ok(radio)
+    }
+
+    /// Starts sending `packet`. `poll` returns `Sent` once it is done.
+    pub fn transmit(&mut self, channel: Channel, packet: &[u8]) -> Result<(), Error<E>> {
+        self.configure(channel, true)?;
+        self.write(REG_FIFO_ADDR_PTR, 0)?;
+        self.write_all(REG_FIFO, packet)?;
+        self.write(REG_PAYLOAD_LENGTH, packet.len() as u8)?;
+        self.write(REG_OP_MODE, MODE_LORA | MODE_TX)
+    }
+
+    /// Starts listening for a packet, for at most `timeout_symbols`, from 4
+    /// to 1023, until a preamble is found.
+    pub fn receive(&mut self, channel: Channel, timeout_symbols: u16) -> Result<(), Error<E>> {
+        self.configure(channel, false)?;
+        let timeout = timeout_symbols.clamp(4, 0x3FF);
+        let config_2 = self.read(REG_MODEM_CONFIG_2)?;
+        self.write(REG_MODEM_CONFIG_2, config_2 | (timeout >> 8) as u8)?;
+        self.write(REG_SYMB_TIMEOUT_LSB, timeout as u8)?;
+        self.write(REG_OP_MODE, MODE_LORA | MODE_RX_SINGLE)
+    }
+
+    pub fn poll(&mut self) -> Result<Status, Error<E>> {
+        let flags = self.read(REG_IRQ_FLAGS)?;
+        let status = if flags & IRQ_TX_DONE != 0 {
+            Status::Sent
+        } else if flags & IRQ_RX_DONE != 0 && flags & IRQ_PAYLOAD_CRC_ERROR == 0 {
+            Status::Received(self.read(REG_RX_NB_BYTES)? as usize)
+        } else if flags & (IRQ_RX_DONE | IRQ_RX_TIMEOUT) != 0 {
+            Status::Nothing
+        } else {
+            return Ok(Status::Busy);
+        };
+        self.write(REG_IRQ_FLAGS, 0xFF)?;
+        Ok(status)
+    }
+
+    /// Reads the packet that was received into `buffer`, which must be as
+    /// long as it.
+    pub fn read_packet(&mut self, buffer: &mut [u8]) -> Result<(), Error<E>> {
+        let at = self.read(REG_FIFO_RX_CURRENT_ADDR)?;
+        self.write(REG_FIFO_ADDR_PTR, at)?;
+        self.select(|spi| {
+            spi.write(&[REG_FIFO])?;
+            spi.transfer(buffer)?;
+            Ok(())
+        })
+    }
+
+    /// Stops sending or receiving.
+    pub fn standby(&mut self) -> Result<(), Error<E>> {
+        self.write(REG_OP_MODE, MODE_LORA | MODE_STANDBY)?;
+        self.write(REG_IRQ_FLAGS, 0xFF)
+    }
+
+    fn configure(&mut self, channel: Channel, uplink: bool) -> Result<(), Error<E>> {
+        self.write(REG_OP_MODE, MODE_LORA | MODE_STANDBY)?;
+        let frf = ((channel.frequency_hz as u64) << 19) / OSCILLATOR_HZ;
+        self.write_all(REG_FRF_MSB, &(frf as u32).to_be_bytes()[1..])?;
+        self.write(REG_MODEM_CONFIG_1, MODEM_CONFIG_1)?;
+        let crc = if uplink { MODEM_CONFIG_2_CRC } else { 0 };
+        self.write(REG_MODEM_CONFIG_2, channel.spreading_factor << 4 | crc)?;
+        // Symbols of more than 16 ms need it.
+        let low_data_rate = if channel.spreading_factor >= 11 {
+            MODEM_CONFIG_3_LOW_DATA_RATE
+        } else {
+            0
+        };
+        self.write(REG_MODEM_CONFIG_3, MODEM_CONFIG_3_AGC | low_data_rate)?;
+        let (invert_iq, invert_iq_2) = if uplink { INVERT_IQ_TX } else { INVERT_IQ_RX };
+        self.write(REG_INVERT_IQ, invert_iq)?;
+        self.write(REG_INVERT_IQ_2, invert_iq_2)?;
+        self.write(REG_IRQ_FLAGS, 0xFF)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut value = [register, 0];
+        self.select(|spi| {
+            spi.transfer(&mut value)?;
+            Ok(())
+        })?;
+        Ok(value[1])
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.write_all(register, &[value])
+    }
+
+    // Writes `data` to `register` and the ones after it, or to the FIFO.
+    fn write_all(&mut self, register: u8, data: &[u8]) -> Result<(), Error<E>> {
+        self.select(|spi| {
+            spi.write(&[register | WRITE])?;
+            spi.write(data)
+        })
+    }
+
+    fn select<F>(&mut self, access: F) -> Result<(), Error<E>>
+    where
+        F: FnOnce(&mut SPI) -> Result<(), E>,
+    {
+        let _ = self.ncs.set_low();
+        let result = access(&mut self.spi);
+        let _ = self.ncs.set_high();
+        result.map_err(Error::Spi)
+    }
+}
@@ -1,73 +1,604 @@
 #![no_std]
 #![no_main]
 
-mod clock;
+mod adc;
+mod autobaud;
+mod binary;
+mod build_info;
+mod button;
+mod can;
+mod climate;
+mod config_store;
+mod console;
+mod datalog;
+mod diag;
+mod display;
+mod dma_spi;
+mod ds18b20;
+mod esp_at;
+mod fault;
+mod flash;
+mod homeassistant;
+mod http;
+mod i2c;
+mod idle;
+mod influx;
+mod led;
+mod log_filter;
+mod log_queue;
+mod lorawan;
+mod mdns;
+mod metrics;
+mod mirror;
+mod modbus;
 mod mqtt;
 mod network;
+mod onewire;
+mod ota;
+mod p1;
 mod panic;
+mod pulse;
+mod pwm;
 mod random;
-mod uart;
+mod relay;
+mod request;
+mod rtc;
+mod scheduler;
+mod sdcard;
+mod signature;
+#[cfg(feature = "sim")]
+mod sim;
+mod sntp;
+mod syslog;
+mod system;
+mod tempmon;
+mod uplink;
+mod watchdog;
 
+use arrayvec::ArrayVec;
+use core::{num::NonZeroU8, ops::Range};
+use dsmr42::{TelegramParseError, Unit};
+#[cfg(feature = "enc28j60")]
 use embedded_hal::digital::v1_compat::OldOutputPin;
 use hal::ccm::{spi, PLL1};
-use mqtt::MqttClient;
+use meter_reader::{
+    clock, command, config, cpu_clock, dma, dsmr, events, history, json, ring_buffer, rules,
+    telemetry, timers, uart, validation,
+};
+use mqtt::{MqttClient, MqttConfig, Qos};
 use teensy4_bsp::{
-    hal::{self, ccm, gpio::GPIO, iomuxc::gpio::Pin},
+    hal::{self, ccm, gpio::GPIO},
     t40, usb,
     usb::LoggingConfig,
     SysTick,
 };
 
+#[cfg(feature = "sim")]
+use crate::sim::{SimConfig, Simulator, Transport};
+
 use crate::{
+    adc::{Adc, AdcConfig, AdcInput, AdcPin, Averaging, Measure},
+    autobaud::{AutoBaud, DSMR_LINE_SETTINGS},
+    button::{Button, Press},
+    can::{CanConfig, CanOutput, FrameMap, Id, Quantity, Signal, Width},
+    climate::{dht22::Dht22, sht3x::Sht3x, ClimateConfig},
     clock::Clock,
-    hal::gpio::Output,
+    config::{Config, IpConfig},
+    config_store::ConfigStore,
+    console::Console,
+    cpu_clock::CpuClock,
+    datalog::DataLog,
+    diag::Diag,
+    display::{Display, DisplayConfig},
+    ds18b20::{Ds18b20Config, Ds18b20Sensor, Thermometers},
+    esp_at::{EspAt, WifiConfig},
+    events::{Alarm, EventDetector},
+    flash::Flash,
+    history::History,
+    http::HttpServer,
+    i2c::I2c,
+    idle::Idle,
+    influx::{InfluxClient, InfluxConfig},
+    led::{Pattern, RgbLed, StatusLed},
+    lorawan::{LoraConfig, Lorawan, Sx127x},
+    mdns::MdnsResponder,
+    metrics::Diagnostics,
+    mirror::{MirrorServer, UartMirror},
+    modbus::{meters, ModbusConfig, ModbusDevice, ModbusMaster},
     network::{
-        client::TcpClientStore,
-        driver::{create_enc28j60, Enc28j60Phy},
+        client::{TcpClientStore, UdpClientStore},
+        driver,
         stack::NetworkStack,
     },
+    onewire::OneWire,
+    ota::{BootOutcome, OtaClient, OtaConfig},
+    p1::{P1Input, P1Meter, P1MeterConfig},
+    panic::PanicPolicy,
+    pulse::{PulseConfig, PulseCounter, S0Channel, S0Input},
+    pwm::{Pwm, PwmChannel, PwmConfig, PwmOutput},
     random::Random,
-    uart::DsmrUart,
+    relay::{RelayOutput, Relays},
+    request::DataRequest,
+    rtc::Rtc,
+    scheduler::Scheduler,
+    sdcard::SdLogger,
+    sntp::SntpClient,
+    syslog::{SyslogClient, SyslogConfig},
+    telemetry::Telemetry,
+    tempmon::TempMon,
+    timers::TimerWheel,
+    uart::{DsmrUart, DsmrUartError, Rs485Config},
+    validation::Validator,
+    watchdog::{Supervisor, Task},
 };
 
 const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+// Reset once a task of the main loop hasn't checked in for this long.
+const WATCHDOG_TIMEOUT_MS: Option<u32> = Some(8_000);
+// Feed it this often, if every task has checked in.
+const WATCHDOG_FEED_INTERVAL_MS: i64 = 100;
+// Warn when a task of the main loop takes longer than this.
+const TASK_BUDGETS_US: [(Task, u32); 6] = [
+    (Task::Uart, 500),
+    (Task::Parser, 5_000),
+    (Task::Network, 10_000),
+    (Task::Logger, 2_000),
+    (Task::Button, 100),
+    // Reading a DS18B20 takes about 10 ms, and a DHT22 about 6 ms.
+    (Task::Sensors, 20_000),
+];
+const PANIC_POLICY: PanicPolicy = PanicPolicy::Reset { delay_ms: 1_000 };
+// Send log records to a syslog collector instead of over USB. The BSP's USB
+// logger can't be combined with another logger, so it is one or the other.
+const SYSLOG_CONFIG: Option<SyslogConfig> = None;
 const SPI_CLOCK_HZ: u32 = 16_000_000;
+// With the `w5500` feature, the DMA channels that serve LPSPI4, and how long
+// a transfer may take. A frame takes about 0.8 ms at 16 MHz.
+#[cfg(feature = "w5500")]
+const SPI_RX_DMA_CHANNEL: usize = 1;
+#[cfg(feature = "w5500")]
+const SPI_TX_DMA_CHANNEL: usize = 2;
+#[cfg(feature = "w5500")]
+const SPI_TIMEOUT_US: u32 = 5_000;
+// Log readings to an SD card on LPSPI3, with its chip select on pin 0.
+const SD_CARD: bool = false;
+const SD_SPI_CLOCK_HZ: u32 = 16_000_000;
+// Log readings to flash while the MQTT broker can't be reached, and replay
+// them to the backlog topic once it can.
+const DATALOG: bool = false;
+const DATALOG_SECTORS: Range<usize> = 0..8;
+const DATALOG_INTERVAL_MS: i64 = 60_000;
+// Send a summary of the latest reading over LoRaWAN, through an SX1276 on
+// LPSPI3, with its chip select on pin 20 and its reset on pin 21, for meters
+// without a network. It can't share LPSPI3 with the SD card. The session is
+// kept in `LORAWAN_SECTORS`.
+const LORAWAN_CONFIG: Option<LoraConfig> = None;
+const LORAWAN_SECTORS: [usize; 2] = [10, 11];
+const LORA_SPI_CLOCK_HZ: u32 = 8_000_000;
+// The remaining flash sectors track firmware updates, and hold the settings
+// of `Config`.
+const OTA_STATE_SECTOR: usize = 12;
+const CONFIG_SECTORS: [usize; 2] = [13, 14];
+// Only run and install images signed with this Ed25519 key, which
+// `sign-image.py --public-key` prints.
+const IMAGE_PUBLIC_KEY: Option<[u8; 32]> = None;
+// Download firmware updates from an HTTP server when asked to on the console,
+// such as `/meter-reader.ota` from port 8000. Set `IMAGE_PUBLIC_KEY` along
+// with it, or any image that is served gets installed. `None` leaves updates
+// off.
+const OTA_CONFIG: Option<OtaConfig> = None;
+// Keep an update once it has run this long, otherwise it is rolled back at
+// the next reset.
+const OTA_CONFIRM_AFTER_MS: i64 = 60_000;
+// Sleep between main loop iterations for at most this long, until the UARTs
+// receive data. The network isn't a wake source, so this bounds its latency.
+// `None` spins instead.
+const IDLE_MAX_SLEEP_MS: Option<u32> = Some(5);
+// Lower the core clock to this, such as 132 MHz, once the network hasn't been
+// busy for a while. `None` keeps it at 600 MHz.
+const CPU_IDLE_HZ: Option<u32> = None;
+const CPU_IDLE_AFTER_MS: i64 = 1_000;
+// Warn when the die gets hotter than this, in thousandths of a degree
+// Celsius, and keep the core clock at `CPU_IDLE_HZ` until it cools down. The
+// i.MX RT1062 is rated up to 95 °C.
+const TEMPERATURE_LIMIT_MC: i32 = 85_000;
+const TEMPERATURE_CHECK_INTERVAL_MS: i64 = 5_000;
+// Set the SRTC to the time from SNTP this often, so that it keeps the time
+// through resets.
+const RTC_SYNC_INTERVAL_MS: i64 = 10 * 60_000;
+// Publish the diagnostics to `MQTT_CONFIG.diagnostics_topic` this often.
+const MQTT_DIAGNOSTICS_INTERVAL_MS: i64 = 60_000;
+// Show the state of the meter reader on an RGB LED, with red on pin 3, green
+// on pin 4 and blue on pin 5. The onboard LED can't be used, as pin 13 is the
+// SPI clock of the ENC28J60. Pin 4 is left to PWM while the LED is off.
+const STATUS_LED: bool = false;
+const STATUS_LED_STEP_MS: i64 = 50;
+// Show the power delivered as the duty cycles of FlexPWM2, on pin 33, and on
+// pin 4 if the status LED is off: a moving-coil gauge and a backlight, at
+// this frequency. `None` leaves PWM off.
+const PWM_FREQUENCY_HZ: Option<u32> = None;
+const PWM_OUTPUTS: &[PwmOutput] = &[
+    PwmOutput {
+        channel: PwmChannel::B,
+        full_scale_w: 10_000,
+        min_percent: 0.0,
+    },
+    PwmOutput {
+        channel: PwmChannel::A,
+        full_scale_w: 5_000,
+        min_percent: 10.0,
+    },
+];
+// A button between pin 6 and ground. A short press logs the status, a press
+// of 3 s resets the config to the defaults, and one of 10 s enters the
+// bootloader, each once it is released. Pin 6 gets a pull-up while it is on.
+const BUTTON: bool = false;
+// Count the pulses of the S0 outputs of other kWh meters, connected between
+// pins 29 to 32 and ground, in the order of `S0_CHANNELS`, and add their
+// energy to every reading. The totals are kept in `S0_SECTORS`.
+const S0: bool = false;
+const S0_CHANNELS: &[S0Channel] = &[
+    S0Channel {
+        name: "heat_pump",
+        obis: [1, 2, 1, 8, 0, 255],
+        pulses_per_kwh: 1_000,
+    },
+    S0Channel {
+        name: "car_charger",
+        obis: [1, 3, 1, 8, 0, 255],
+        pulses_per_kwh: 2_000,
+    },
+];
+const S0_SAVE_INTERVAL_MS: i64 = 15 * 60_000;
+const S0_SECTORS: [usize; 2] = [8, 9];
+// Drive relays 1 and 2 from pins 34 and 35, which are closed by the rules of
+// the config, `set rule.<n>` in the console.
+const RELAYS: bool = false;
+// Read DS18B20 temperature sensors on a 1-Wire bus on pin 36, with a 4.7 kΩ
+// pull-up to 3.3 V, this often, and add their temperatures to every reading.
+// `None` leaves the bus off.
+const DS18B20_INTERVAL_MS: Option<i64> = None;
+const DS18B20_SENSORS: &[Ds18b20Sensor] = &[
+    Ds18b20Sensor {
+        name: "ambient",
+        rom: None,
+        obis: [0, 1, 128, 41, 0, 255],
+    },
+    Ds18b20Sensor {
+        name: "boiler",
+        rom: None,
+        obis: [0, 1, 128, 42, 0, 255],
+    },
+];
+// Show the power, the energy used today and the state of the broker
+// connection on an OLED, over I2C with SCL on pin 19 and SDA on pin 18, such
+// as an SSD1306 at 0x3C. `None` leaves the display off.
+const DISPLAY_CONFIG: Option<DisplayConfig> = None;
+// Measure the temperature and humidity with an SHT3x, on the I2C bus of the
+// display, at `SHT3X_ADDRESS`.
+const SHT3X: bool = false;
+const SHT3X_ADDRESS: u8 = climate::sht3x::DEFAULT_ADDRESS;
+const SHT3X_CONFIG: ClimateConfig = ClimateConfig {
+    name: "cupboard",
+    interval_ms: 10_000,
+    temperature_obis: [0, 1, 128, 43, 0, 255],
+    humidity_obis: [0, 1, 128, 44, 0, 255],
+};
+// Or with a DHT22 on pin 37.
+const DHT22: bool = false;
+const DHT22_CONFIG: ClimateConfig = ClimateConfig {
+    name: "cupboard",
+    interval_ms: 10_000,
+    temperature_obis: [0, 1, 128, 45, 0, 255],
+    humidity_obis: [0, 1, 128, 46, 0, 255],
+};
+// Sends a page of the frame every step, once the last one is done.
+const DISPLAY_STEP_MS: i64 = 10;
+// The DMA channel that feeds LPI2C1, and how long a transfer may take before
+// the bus is reset. A page of the display takes about 3.5 ms at 400 kHz.
+const I2C_DMA_CHANNEL: usize = 0;
+const I2C_TIMEOUT_US: u32 = 10_000;
+// Send every reading as a line of JSON to a TCP collector, through an ESP8266
+// or ESP32 with the AT firmware on LPUART3, with TX on pin 17 and RX on pin
+// 16. The other clients stay on Ethernet.
+const WIFI_CONFIG: Option<WifiConfig> = None;
+const WIFI_BAUD: u32 = 115200;
+// Send readings as CAN frames through a transceiver on FlexCAN1, with TX on
+// pin 22 and RX on pin 23, at this bit rate, at most every `CAN_INTERVAL_MS`.
+// `None` leaves CAN off.
+const CAN_BIT_RATE: Option<u32> = None;
+const CAN_INTERVAL_MS: i64 = 1_000;
+// The power in W, the power of each phase in W, the voltage of each phase in
+// 0.1 V, and the energy delivered and returned in Wh.
+const CAN_FRAMES: &[FrameMap] = &[
+    FrameMap {
+        id: Id::Standard(0x300),
+        len: 4,
+        signals: &[Signal::new(Quantity::PowerNet, 0, Width::I32, 1)],
+    },
+    FrameMap {
+        id: Id::Standard(0x301),
+        len: 6,
+        signals: &[
+            Signal::new(Quantity::PhasePowerNet(0), 0, Width::I16, 1),
+            Signal::new(Quantity::PhasePowerNet(1), 2, Width::I16, 1),
+            Signal::new(Quantity::PhasePowerNet(2), 4, Width::I16, 1),
+        ],
+    },
+    FrameMap {
+        id: Id::Standard(0x302),
+        len: 6,
+        signals: &[
+            Signal::new(Quantity::Voltage(0), 0, Width::U16, 100),
+            Signal::new(Quantity::Voltage(1), 2, Width::U16, 100),
+            Signal::new(Quantity::Voltage(2), 4, Width::U16, 100),
+        ],
+    },
+    FrameMap {
+        id: Id::Standard(0x303),
+        len: 8,
+        signals: &[
+            Signal::new(Quantity::EnergyDelivered, 0, Width::U32, 1),
+            Signal::new(Quantity::EnergyReturned, 4, Width::U32, 1),
+        ],
+    },
+];
+// Sample analog inputs on pins 22 and 23 at this rate, and add their values
+// to every reading, under their OBIS codes. The pins are only free while CAN
+// is off. `None` leaves the ADC off.
+const ADC_SAMPLE_HZ: Option<u32> = None;
+const ADC_AVERAGING: Averaging = Averaging::Of8;
+// The DMA channel that takes the results from ADC_ETC.
+const ADC_DMA_CHANNEL: usize = 4;
+// The current through a 30 A to 1 V CT clamp, biased to half of 3.3 V, and
+// the light on a photodiode, in percent.
+const ADC_INPUTS: &[AdcInput] = &[
+    AdcInput {
+        name: "heat_pump_current",
+        obis: [0, 1, 128, 7, 0, 255],
+        pin: AdcPin::P22,
+        measure: Measure::Rms,
+        gain: 30.0 * 3.3 / 4096.0,
+        offset: 0.0,
+        scale: 2,
+        unit: Some(Unit::A),
+    },
+    AdcInput {
+        name: "photodiode",
+        obis: [0, 1, 128, 96, 0, 255],
+        pin: AdcPin::P23,
+        measure: Measure::Mean,
+        gain: 100.0 / 4096.0,
+        offset: 0.0,
+        scale: 1,
+        unit: None,
+    },
+];
+// Read kWh meters over Modbus RTU, through an RS-485 transceiver on LPUART1,
+// with TX on pin 24, RX on pin 25, and driver enable on pin 28, at this baud
+// rate, every `MODBUS_INTERVAL_MS`. `None` leaves Modbus off.
+const MODBUS_BAUD: Option<u32> = None;
+const MODBUS_INTERVAL_MS: i64 = 5_000;
+const MODBUS_RESPONSE_TIMEOUT_MS: i64 = 200;
+const MODBUS_DEVICES: &[ModbusDevice] = &[ModbusDevice {
+    address: 1,
+    name: "sdm630",
+    registers: meters::SDM630,
+    setup: &[],
+}];
+// The DMA channel that feeds LPUART1.
+const MODBUS_DMA_CHANNEL: usize = 3;
+// With the receiver of the transceiver enabled while sending, such as with
+// RE tied to ground, so that collisions are noticed.
+const MODBUS_RS485: Rs485Config = Rs485Config {
+    turnaround_us: 100,
+    echo: false,
+    timeout_us: 500_000,
+};
+const MODBUS_READ_BUF_SZ: usize = 512;
+// Serve the console on LPUART4, with RX on pin 7 and TX on pin 8, at this
+// baud rate, such as 115200. Anyone on the pins can change the config and
+// reboot. `None` leaves the console off.
+const CONSOLE_BAUD: Option<u32> = None;
 const DSMR_42_BAUD: u32 = 115200;
 const DSMR_INVERTED: bool = false;
+// Detect the meter's line settings instead of assuming DSMR_42_BAUD 8N1,
+// which waits up to `DSMR_AUTOBAUD_TIMEOUT_MS` for each setting that is
+// tried. Only the main P1 UART is switched to what is detected: the extra P1
+// meters and the mirror UART stay on the configured `dsmr_baud` 8N1, which is
+// DSMR_42_BAUD by default.
+const DSMR_AUTOBAUD: bool = false;
+// DSMR 2.2 meters only send a telegram every 10 seconds.
+const DSMR_AUTOBAUD_TIMEOUT_MS: i64 = 12_000;
+// Older meters need to be polled for telegrams through the data request
+// line. With `None`, the line is held high and the meter sends at its own
+// pace.
+const DSMR_REQUEST_INTERVAL_MS: Option<i64> = None;
+// Re-assert the data request line if no telegram arrives in time.
+const DSMR_REQUEST_TIMEOUT_MS: i64 = 15_000;
+// With the `sim` feature, a simulated meter sends a telegram every second in
+// place of the one on the P1 port, through the DSMR UART in loopback mode, or
+// straight into its read buffer with `Transport::Memory`.
+#[cfg(feature = "sim")]
+const SIM_CONFIG: SimConfig = SimConfig {
+    transport: Transport::Loopback,
+    interval_ms: 1_000,
+    // 2021-06-01 08:00 UTC, a Tuesday.
+    start: 1_622_534_400,
+    solar_peak_w: 3_000,
+    seed: 0x5EED_1E55,
+};
+// Telegrams are parsed as they come in, so this only needs to hold the data
+// received during a single main loop iteration.
+const DSMR_READ_BUF_SZ: usize = 256;
+// DSMR 5 meters send a telegram every second, so this covers 15 minutes.
+const DSMR_HISTORY_SZ: usize = 900;
+// Report when more than this many W are being delivered.
+const POWER_THRESHOLD_W: i64 = 3000;
+const POWER_HYSTERESIS_W: i64 = 200;
+// Readings whose registers increase by more than 50 kWh, or whose timestamp
+// is more than 5 minutes off from the wall-clock time, are discarded.
+const MAX_REGISTER_JUMP: i64 = 50_000;
+const MAX_CLOCK_DRIFT_MS: i64 = 5 * 60_000;
 const ETH_ADDR: [u8; 6] = [0xEE, 0x00, 0x00, 0x0E, 0x4C, 0xA2];
+// Use IpConfig::Static to configure the address and gateway manually.
+const IP_CONFIG: IpConfig = IpConfig::Dhcp;
+const HTTP_PORT: u16 = 80;
+// Accept `POST /api/reboot` and `POST /api/bootloader` from anyone on the
+// network.
+const HTTP_SYSTEM_COMMANDS: bool = false;
+// Forward the raw P1 stream, as it comes from the meter, to a client of this
+// TCP port, such as 2000, for tools that read a P1 port themselves, such as
+// DSMR-reader and Domoticz. `None` leaves it off.
+const P1_MIRROR_PORT: Option<u16> = None;
+// Also send it out of LPUART8, with TX on pin 20, at the bit rate of the
+// meter, while the LoRa radio doesn't take the pin. Pin 21 is taken as RX,
+// but not read. It can't be sent over USB, which the BSP only logs over.
+const P1_MIRROR_UART: bool = false;
+// Read more P1 meters, for installs with a meter per dwelling, at the bit
+// rate of the first: one on LPUART3, with RX on pin 16, while Wi-Fi is off,
+// and one on LPUART8, with RX on pin 21, while neither the LoRa radio nor the
+// P1 mirror takes it. Their TX pins, 17 and 20, are taken, but not used.
+const P1_METERS: [Option<P1MeterConfig>; p1::MAX_METERS] = [None, None];
+// Advertised over mDNS as <hostname>.local.
+const MDNS_HOSTNAME: &str = "smart-meter";
+const INFLUX_CONFIG: InfluxConfig = InfluxConfig {
+    server_addr: [10, 190, 30, 14],
+    server_port: 8086,
+    path: "/api/v2/write?org=home&bucket=smart_meter&precision=ms",
+    token: None,
+    measurement: "smart_meter",
+    sample_interval: 10_000,
+    batch_size: 6,
+    flush_interval: 60_000,
+};
+// There is no DNS resolver, so the server must be given by its address.
+const SNTP_SERVER: [u8; 4] = [10, 190, 30, 1];
+const MQTT_CONFIG: MqttConfig = MqttConfig {
+    broker_addr: [10, 190, 30, 14],
+    broker_port: 1883,
+    client_id: "smart-meter-reader",
+    status_topic: "smart_meter/status",
+    build_topic: "smart_meter/status/build",
+    usage_topic: "smart_meter/usage",
+    reading_topic: "smart_meter/reading",
+    sample_topic: "smart_meter/sample",
+    backlog_topic: "smart_meter/backlog",
+    alarm_topic: "smart_meter/alarm",
+    diagnostics_topic: Some("smart_meter/diagnostics"),
+    qos: Qos::AtMostOnce,
+    discovery_prefix: Some("homeassistant"),
+};
+
+// Used until a config has been saved. Settings that can be changed at
+// runtime override the constants above.
+const DEFAULT_CONFIG: Config = Config {
+    dsmr_baud: DSMR_42_BAUD,
+    ip_config: IP_CONFIG,
+    mqtt_broker_addr: MQTT_CONFIG.broker_addr,
+    mqtt_broker_port: MQTT_CONFIG.broker_port,
+    report_interval_ms: INFLUX_CONFIG.sample_interval as u32,
+    // None, so that no relay switches unless it was set up to. To close relay
+    // 1 once more than 1 kW has been returned for 5 minutes:
+    //
+    // Some(Rule {
+    //     relay: 1,
+    //     quantity: rules::Quantity::PowerReturned,
+    //     above: true,
+    //     threshold: 1000,
+    //     delay_s: 300,
+    // }),
+    rules: [None; rules::MAX_RULES],
+};
+
+// Work in the main loop that is done on `timers`, rather than every
+// iteration.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Timer {
+    FeedWatchdog,
+    ConfirmUpdate,
+    CheckTemperature,
+    SyncRtc,
+    StatusLed,
+    Display,
+    ReportDiagnostics,
+}
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    let stack_bot = 0u8;
+    let mut diag = Diag::paint();
     // Take control of the peripherals.
     let mut per = teensy4_bsp::Peripherals::take().unwrap();
-    let core_per = cortex_m::Peripherals::take().unwrap();
+    let mut core_per = cortex_m::Peripherals::take().unwrap();
+
+    // Before anything else, since it may replace the firmware and reset.
+    let mut flash = Flash::take().unwrap();
+    let ota_outcome = ota::boot(&mut flash, OTA_STATE_SECTOR);
 
-    // Enable serial USB logging.
     let mut systick = SysTick::new(core_per.SYST);
-    let _ = usb::init(
-        &systick,
-        LoggingConfig {
-            max_level: LOG_LEVEL,
-            filters: &[],
-        },
-    )
-    .unwrap();
+    if SYSLOG_CONFIG.is_some() {
+        // Records are queued until the network is up.
+        syslog::init(LOG_LEVEL).unwrap();
+        log::info!("Syslog logging initialised");
+    } else {
+        // Enable serial USB logging.
+        let _ = usb::init(
+            &systick,
+            LoggingConfig {
+                // Limited by `log_filter` instead, which can change.
+                max_level: log::LevelFilter::Trace,
+                filters: &[],
+            },
+        )
+        .unwrap();
+        log_filter::set_default(LOG_LEVEL);
 
-    // Wait a bit for the host to catch up.
-    systick.delay(5000);
-    log::info!("USB logging initialised");
+        // Wait a bit for the host to catch up.
+        systick.delay(5000);
+        log::info!("USB logging initialised");
+    }
+
+    log::info!("Running {}", build_info::BUILD_INFO);
+    panic::set_policy(PANIC_POLICY);
+    let mut rtc = Rtc::init();
+    log::info!("Boot {} since the last power loss", rtc.boot_count());
+    if watchdog::caused_reset() {
+        log::warn!("Restarted by the watchdog");
+    }
+    let last_crash = panic::take_last_crash();
+    if let Some(crash) = last_crash {
+        log::warn!("Restarted after a crash: {}", crash);
+    }
+
+    match ota_outcome {
+        Some(BootOutcome::Installed) => log::info!(
+            "Running an update, which is kept once it has run for {} s",
+            OTA_CONFIRM_AFTER_MS / 1000
+        ),
+        Some(BootOutcome::RolledBack) => log::warn!("Rolled back an update that was reset"),
+        None => {}
+    }
+    if let Some(public_key) = IMAGE_PUBLIC_KEY {
+        // After a reset, an update on trial is rolled back by `ota::boot`.
+        if let Err(err) = signature::verify_running(&flash, &public_key) {
+            panic!("Refusing to run this firmware: {}", err);
+        }
+        log::info!("Firmware signature verified");
+    }
+    let mut config_store = ConfigStore::load(&flash, CONFIG_SECTORS, DEFAULT_CONFIG);
+    let config = config_store.config();
 
     // Set the default clock speed (600MHz).
-    let (_, ipg) = per
-        .ccm
+    per.ccm
         .pll1
         .set_arm_clock(PLL1::ARM_HZ, &mut per.ccm.handle, &mut per.dcdc);
-    let mut clock = Clock::init(per.ccm.perclk, ipg, &mut per.ccm.handle, per.gpt2);
+    let mut clock = Clock::init(per.ccm.perclk, &mut per.ccm.handle, per.gpt2);
+    // This also starts the cycle counter, which the I2C timeouts use.
+    let mut scheduler = Scheduler::new(&mut core_per.DCB, &mut core_per.DWT, &TASK_BUDGETS_US);
 
     // Configure the SPI clock. All SPI builders must be extracted at once,
     // so we discard the ones we don't need.
-    let (_, _, _, spi4_builder) = per.spi.clock(
+    let (_, _, spi3_builder, spi4_builder) = per.spi.clock(
         &mut per.ccm.handle,
         spi::ClockSelect::Pll2,
         spi::PrescalarSelect::LPSPI_PODF_5,
@@ -80,19 +611,52 @@ fn main() -> ! {
         ccm::uart::PrescalarSelect::DIVIDE_1,
     );
 
+    // Configure I2C, for the display and the SHT3x.
+    let (i2c1_builder, _, _, _) = per.i2c.clock(
+        &mut per.ccm.handle,
+        ccm::i2c::ClockSelect::OSC,
+        ccm::i2c::PrescalarSelect::DIVIDE_3,
+    );
+
     let pins = t40::into_pins(per.iomuxc);
 
     // Set SPI pin assignments.
     let mut spi4 = spi4_builder.build(pins.p11, pins.p12, pins.p13);
     // SET UART pin assignments.
-    let mut uart = uarts
+    let uart = uarts
         .uart2
-        .init(pins.p14, pins.p15, DSMR_42_BAUD)
+        .init(pins.p14, pins.p15, config.dsmr_baud)
         .unwrap_or_else(|err| {
             log::error!("Failed to configure UART: {:?}", err);
             panic!();
         });
-    uart.set_rx_inversion(DSMR_INVERTED);
+
+    // LPUART3 is left to a P1 meter while Wi-Fi is off.
+    let (mut wifi, lpuart3) = match WIFI_CONFIG {
+        Some(config) => match uarts.uart3.init(pins.p17, pins.p16, WIFI_BAUD) {
+            Ok(uart) => (Some(EspAt::new(uart, config, clock.millis())), None),
+            Err(err) => {
+                log::warn!("Failed to configure Wi-Fi UART: {:?}", err);
+                (None, None)
+            }
+        },
+        None => (None, Some((uarts.uart3, pins.p17, pins.p16))),
+    };
+    // Not a closure, which would take all of `uarts` and `pins`.
+    let mut console = match CONSOLE_BAUD {
+        Some(baud) => match uarts.uart4.init(pins.p8, pins.p7, baud) {
+            Ok(uart) => {
+                let mut console = Console::new(uart);
+                console.set_last_crash(last_crash);
+                Some(console)
+            }
+            Err(err) => {
+                log::warn!("Failed to configure console UART: {:?}", err);
+                None
+            }
+        },
+        None => None,
+    };
 
     // Set SPI clock speed.
     match spi4.set_clock_speed(hal::spi::ClockSpeed(SPI_CLOCK_HZ)) {
@@ -104,57 +668,877 @@ fn main() -> ! {
         }
     }
 
-    let mut dsmr_uart = DsmrUart::new(uart);
+    // LPSPI3 is built once, and taken by the SD card or the LoRa radio.
+    let mut spi3 = if SD_CARD || LORAWAN_CONFIG.is_some() {
+        Some(spi3_builder.build(pins.p26, pins.p1, pins.p27))
+    } else {
+        None
+    };
+    let mut sd_card = match spi3.take() {
+        Some(spi3) if SD_CARD => {
+            let set_clock = |spi: &mut hal::spi::SPI<_>, hz| {
+                if let Err(err) = spi.set_clock_speed(hal::spi::ClockSpeed(hz)) {
+                    log::warn!("Unable to set SD card SPI clock speed: {:?}", err);
+                }
+            };
+            let cs = GPIO::new(pins.p0).output();
+            Some(SdLogger::new(spi3, cs, set_clock, SD_SPI_CLOCK_HZ))
+        }
+        // Left for the LoRa radio.
+        unused => {
+            spi3 = unused;
+            None
+        }
+    };
+
+    let mut datalog = if DATALOG {
+        Some(DataLog::mount(
+            &mut flash,
+            DATALOG_SECTORS,
+            DATALOG_INTERVAL_MS,
+        ))
+    } else {
+        None
+    };
+
+    // Pins 20 and 21 are left to the P1 mirror while the LoRa radio is off.
+    let (lora_pins, mirror_pins) = if LORAWAN_CONFIG.is_some() && !SD_CARD {
+        (Some((pins.p20, pins.p21)), None)
+    } else {
+        (None, Some((pins.p20, pins.p21)))
+    };
+    let mut lorawan = match LORAWAN_CONFIG {
+        Some(_) if SD_CARD => {
+            log::warn!("LPSPI3 is taken by the SD card, not using LoRaWAN");
+            None
+        }
+        Some(config) => match (spi3.take(), lora_pins) {
+            (Some(mut spi3), Some((p20, p21))) => {
+                if let Err(err) = spi3.set_clock_speed(hal::spi::ClockSpeed(LORA_SPI_CLOCK_HZ)) {
+                    log::warn!("Unable to set LoRa SPI clock speed: {:?}", err);
+                }
+                let ncs = GPIO::new(p20).output();
+                let mut rst = GPIO::new(p21).output();
+                rst.clear();
+                systick.delay(1);
+                rst.set();
+                systick.delay(5);
+                match Sx127x::new(spi3, ncs, config.tx_power_dbm) {
+                    Ok(radio) => Some(Lorawan::new(radio, config, &flash, LORAWAN_SECTORS)),
+                    Err(err) => {
+                        log::warn!("Failed to set up the LoRa radio: {:?}", err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        },
+        None => None,
+    };
+
+    // Pins 22 and 23 are left to the ADC while CAN is off.
+    let (mut can, adc_pins) = match CAN_BIT_RATE {
+        Some(bit_rate) => {
+            let config = CanConfig {
+                bit_rate,
+                interval_ms: CAN_INTERVAL_MS,
+                frames: CAN_FRAMES,
+            };
+            let can = match CanOutput::init(pins.p22, pins.p23, config) {
+                Ok(can) => Some(can),
+                Err(err) => {
+                    log::warn!("Failed to start the CAN bus: {:?}", err);
+                    None
+                }
+            };
+            (can, None)
+        }
+        None => (None, Some((pins.p22, pins.p23))),
+    };
+    let mut adc = match (ADC_SAMPLE_HZ, adc_pins) {
+        (Some(sample_hz), Some((p22, p23))) => {
+            let dma = unsafe { dma::Channel::new(ADC_DMA_CHANNEL, adc::DMA_SOURCE) };
+            let config = AdcConfig {
+                sample_hz,
+                averaging: ADC_AVERAGING,
+                inputs: ADC_INPUTS,
+            };
+            match Adc::init(p22, p23, dma, config) {
+                Ok(adc) => Some(adc),
+                Err(err) => {
+                    log::warn!("Failed to start the ADC: {:?}", err);
+                    None
+                }
+            }
+        }
+        (Some(_), None) => {
+            log::warn!("Not sampling analog inputs, since CAN has their pins");
+            None
+        }
+        (None, _) => None,
+    };
+
+    // Pin 4 is left to PWM while the status LED is off.
+    let (mut status_led, pwm_a) = if STATUS_LED {
+        let led = RgbLed::new(
+            GPIO::new(pins.p3).output(),
+            GPIO::new(pins.p4).output(),
+            GPIO::new(pins.p5).output(),
+        );
+        let mut status_led = StatusLed::new(led, clock.millis());
+        if last_crash.is_some() {
+            status_led.show(clock.millis(), Pattern::Panic);
+        }
+        (Some(status_led), None)
+    } else {
+        (None, Some(pins.p4))
+    };
+    let mut pwm = match PWM_FREQUENCY_HZ {
+        Some(frequency_hz) => {
+            let config = PwmConfig {
+                frequency_hz,
+                outputs: PWM_OUTPUTS,
+            };
+            match Pwm::init(pwm_a, pins.p33, config) {
+                Ok(pwm) => Some(pwm),
+                Err(err) => {
+                    log::warn!("Failed to start PWM: {:?}", err);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut i2c = if DISPLAY_CONFIG.is_some() || SHT3X {
+        let mut i2c = i2c1_builder.build(pins.p19, pins.p18);
+        if let Err(err) = i2c.set_clock_speed(hal::i2c::ClockSpeed::KHz400) {
+            log::warn!("Unable to set I2C clock speed: {:?}", err);
+        }
+        let dma = unsafe { dma::Channel::new(I2C_DMA_CHANNEL, i2c::DMA_SOURCE) };
+        Some(I2c::new(i2c, dma, I2C_TIMEOUT_US))
+    } else {
+        None
+    };
+    let mut display = match (DISPLAY_CONFIG, i2c.as_mut()) {
+        (Some(config), Some(i2c)) => Some(Display::init(i2c, config)),
+        _ => None,
+    };
+    let mut sht3x = if SHT3X {
+        Some(Sht3x::new(SHT3X_ADDRESS, SHT3X_CONFIG))
+    } else {
+        None
+    };
+    let mut dht22 = if DHT22 {
+        Some(Dht22::new(pins.p37, DHT22_CONFIG))
+    } else {
+        None
+    };
 
-    let ncs = make_output_pin(pins.p10);
-    let rst = make_output_pin(pins.p9);
-    let driver = create_enc28j60(&mut systick, spi4, ncs, rst, ETH_ADDR);
+    let mut button = if BUTTON {
+        Some(Button::new(pins.p6))
+    } else {
+        None
+    };
+
+    let mut pulses = if S0 {
+        let inputs = [
+            S0Input::new(pins.p29),
+            S0Input::new(pins.p30),
+            S0Input::new(pins.p31),
+            S0Input::new(pins.p32),
+        ];
+        let config = PulseConfig {
+            channels: S0_CHANNELS,
+            save_interval_ms: S0_SAVE_INTERVAL_MS,
+        };
+        Some(PulseCounter::new(inputs, config, &flash, S0_SECTORS))
+    } else {
+        None
+    };
+
+    let mut relays = if RELAYS {
+        let outputs = [RelayOutput::new(pins.p34), RelayOutput::new(pins.p35)];
+        Some(Relays::new(outputs, config.rules))
+    } else {
+        None
+    };
+
+    let mut thermometers = match DS18B20_INTERVAL_MS {
+        Some(interval_ms) => {
+            let config = Ds18b20Config {
+                sensors: DS18B20_SENSORS,
+                interval_ms,
+            };
+            Some(Thermometers::new(OneWire::new(pins.p36), config))
+        }
+        None => None,
+    };
+
+    let mut modbus = match MODBUS_BAUD {
+        Some(baud) => match uarts.uart1.init(pins.p24, pins.p25, baud) {
+            Ok(uart) => {
+                let mut uart = DsmrUart::<_, MODBUS_READ_BUF_SZ>::new(uart, false);
+                let source = uart::TX_DMA_SOURCES[0];
+                let dma = unsafe { dma::Channel::new(MODBUS_DMA_CHANNEL, source) };
+                uart.set_rs485(dma, MODBUS_RS485);
+                let config = ModbusConfig {
+                    baud,
+                    parity: None,
+                    devices: MODBUS_DEVICES,
+                    interval_ms: MODBUS_INTERVAL_MS,
+                    response_timeout_ms: MODBUS_RESPONSE_TIMEOUT_MS,
+                };
+                Some(ModbusMaster::new(
+                    uart,
+                    GPIO::new(pins.p28).output(),
+                    config,
+                ))
+            }
+            Err(err) => {
+                log::warn!("Failed to configure Modbus UART: {:?}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    // LPUART8 is left to a P1 meter while the P1 mirror doesn't take it.
+    let (mut uart_mirror, lpuart8) = match (P1_MIRROR_UART, mirror_pins) {
+        (true, Some((p20, p21))) => match uarts.uart8.init(p20, p21, config.dsmr_baud) {
+            Ok(mut uart) => {
+                uart.set_tx_fifo(NonZeroU8::new(4));
+                (Some(UartMirror::new(uart)), None)
+            }
+            Err(err) => {
+                log::warn!("Failed to configure P1 mirror UART: {:?}", err);
+                (None, None)
+            }
+        },
+        (true, None) => {
+            log::warn!("Pin 20 is taken by the LoRa radio, not mirroring P1 to a UART");
+            (None, None)
+        }
+        (false, pins) => (None, pins.map(|(p20, p21)| (uarts.uart8, p20, p21))),
+    };
+    let mut p1_meter_a = match (P1_METERS[0], lpuart3) {
+        (Some(meter), Some((uart, tx, rx))) => match uart.init(tx, rx, config.dsmr_baud) {
+            Ok(uart) => {
+                let uart = DsmrUart::<_, DSMR_READ_BUF_SZ>::new(uart, meter.rx_inverted);
+                Some(P1Meter::new(uart, meter))
+            }
+            Err(err) => {
+                log::warn!(
+                    "Failed to configure the UART of P1 meter {}: {:?}",
+                    meter.name,
+                    err
+                );
+                None
+            }
+        },
+        (Some(meter), None) => {
+            log::warn!(
+                "LPUART3 is taken by Wi-Fi, not reading P1 meter {}",
+                meter.name
+            );
+            None
+        }
+        (None, _) => None,
+    };
+    let mut p1_meter_b = match (P1_METERS[1], lpuart8) {
+        (Some(meter), Some((uart, tx, rx))) => match uart.init(tx, rx, config.dsmr_baud) {
+            Ok(uart) => {
+                let uart = DsmrUart::<_, DSMR_READ_BUF_SZ>::new(uart, meter.rx_inverted);
+                Some(P1Meter::new(uart, meter))
+            }
+            Err(err) => {
+                log::warn!(
+                    "Failed to configure the UART of P1 meter {}: {:?}",
+                    meter.name,
+                    err
+                );
+                None
+            }
+        },
+        (Some(meter), None) => {
+            log::warn!(
+                "LPUART8 is taken by the LoRa radio or the P1 mirror, not reading P1 meter {}",
+                meter.name
+            );
+            None
+        }
+        (None, _) => None,
+    };
+    let mut dsmr_request = DataRequest::new(
+        GPIO::new(pins.p2).output(),
+        DSMR_REQUEST_INTERVAL_MS,
+        DSMR_REQUEST_TIMEOUT_MS,
+    );
+    let mut dsmr_uart = DsmrUart::<_, DSMR_READ_BUF_SZ>::new(uart, DSMR_INVERTED);
+    match dsmr_uart.self_test(&mut clock) {
+        Ok(()) => log::info!("UART self test passed"),
+        Err(err) => log::error!("UART self test failed: {:?}", err),
+    }
+    #[cfg(feature = "sim")]
+    let mut simulator = Simulator::new(SIM_CONFIG, &mut dsmr_uart);
+    let mut autobaud = AutoBaud::new(
+        DSMR_LINE_SETTINGS,
+        autobaud::dsmr_header,
+        DSMR_AUTOBAUD_TIMEOUT_MS,
+    );
+
+    #[cfg(feature = "enc28j60")]
+    let driver = {
+        let ncs = make_output_pin(pins.p10);
+        let rst = make_output_pin(pins.p9);
+        driver::create_enc28j60(&mut systick, spi4, ncs, rst, ETH_ADDR)
+    };
+    #[cfg(feature = "w5500")]
+    let driver = {
+        let (rx_source, tx_source) = dma_spi::DMA_SOURCES[3];
+        let rx_dma = unsafe { dma::Channel::new(SPI_RX_DMA_CHANNEL, rx_source) };
+        let tx_dma = unsafe { dma::Channel::new(SPI_TX_DMA_CHANNEL, tx_source) };
+        let spi = dma_spi::DmaSpi::new(spi4, rx_dma, tx_dma, SPI_TIMEOUT_US);
+        let mut ncs = GPIO::new(pins.p10).output();
+        ncs.set_fast(true);
+        let rst = GPIO::new(pins.p9).output();
+        driver::create_w5500(&mut systick, spi, ncs, rst, ETH_ADDR)
+    };
     let mut random = Random::new(clock.ticks());
     let mut store = network::BackingStore::new();
 
-    let mut network = NetworkStack::new(driver, &mut clock, &mut store, ETH_ADDR);
+    let mut network = NetworkStack::new(driver, &mut clock, &mut store, ETH_ADDR, config.ip_config);
+
+    let mut telemetry = Telemetry::new();
+    telemetry.register_meter();
+    if let Some(pulses) = pulses.as_ref() {
+        pulses.register(&mut telemetry);
+    }
+    if let Some(adc) = adc.as_ref() {
+        adc.register(&mut telemetry);
+    }
+    if let Some(thermometers) = thermometers.as_ref() {
+        thermometers.register(&mut telemetry);
+    }
+    if let Some(sht3x) = sht3x.as_ref() {
+        sht3x.register(&mut telemetry);
+    }
+    if let Some(dht22) = dht22.as_ref() {
+        dht22.register(&mut telemetry);
+    }
+    log::info!("Registered {} metrics", telemetry.metrics().len());
 
     let mut client_store = TcpClientStore::new();
-    let mut client = MqttClient::new();
+    let mut client = MqttClient::new(
+        MqttConfig {
+            broker_addr: config.mqtt_broker_addr,
+            broker_port: config.mqtt_broker_port,
+            ..MQTT_CONFIG
+        },
+        telemetry.clone(),
+    );
 
     network.add_client(&mut client, &mut client_store);
+    let mut http_store = TcpClientStore::new();
+    let mut http = HttpServer::new(HTTP_PORT, HTTP_SYSTEM_COMMANDS, telemetry.clone());
+    network.add_client(&mut http, &mut http_store);
+    let mut mirror_store = TcpClientStore::new();
+    let mut mirror = P1_MIRROR_PORT.map(MirrorServer::new);
+    if let Some(mirror) = mirror.as_mut() {
+        network.add_client(mirror, &mut mirror_store);
+    }
+    let mut influx_store = TcpClientStore::new();
+    let mut influx = InfluxClient::new(InfluxConfig {
+        sample_interval: config.report_interval_ms as i64,
+        ..INFLUX_CONFIG
+    });
+    network.add_client(&mut influx, &mut influx_store);
+    let mut sntp_store = UdpClientStore::new();
+    let mut sntp = SntpClient::new(SNTP_SERVER);
+    network.add_udp_client(&mut sntp, &mut sntp_store);
+    let mut mdns_store = UdpClientStore::new();
+    let mut mdns = MdnsResponder::new(MDNS_HOSTNAME, HTTP_PORT);
+    network.add_udp_client(&mut mdns, &mut mdns_store);
+    network.join_multicast_group(&mut clock, mdns::MDNS_GROUP);
+    let mut ota_store = TcpClientStore::new();
+    let mut ota = OTA_CONFIG.map(|config| OtaClient::new(config, OTA_STATE_SECTOR));
+    if let Some(ota) = ota.as_mut() {
+        network.add_client(ota, &mut ota_store);
+    }
+    let mut syslog_store = UdpClientStore::new();
+    let mut syslog = SYSLOG_CONFIG.map(SyslogClient::new);
+    if let Some(syslog) = syslog.as_mut() {
+        network.add_udp_client(syslog, &mut syslog_store);
+    }
 
-    let stack_top = 0u8;
-    log::info!("STACK_BOT: {:p}", &stack_bot);
-    log::info!("STACK_TOP: {:p}", &stack_top);
-    let stack_bot_addr = (&stack_bot as *const u8) as usize;
-    let stack_top_addr = (&stack_top as *const u8) as usize;
-    log::info!("STACK_SZE: {}K", (stack_top_addr - stack_bot_addr) / 1024);
-
+    let mut parser = dsmr::Parser::new(&[]);
+    // At 16 bytes a sample, too large for the stack.
+    let history = cortex_m::singleton!(: History<DSMR_HISTORY_SZ> = History::new()).unwrap();
+    let mut events = EventDetector::new(POWER_THRESHOLD_W, POWER_HYSTERESIS_W);
+    let mut validator = Validator::new(MAX_REGISTER_JUMP, MAX_CLOCK_DRIFT_MS);
+    let mut last_telegram_at = None;
+    let mut supervisor = WATCHDOG_TIMEOUT_MS.map(Supervisor::start);
+    let mut timers = TimerWheel::<Timer, 8>::new();
+    let mut idle = Idle::new();
+    // The DSMR UART and the console.
+    idle.wake_on_uart(2);
+    idle.wake_on_uart(4);
+    if wifi.is_some() || p1_meter_a.is_some() {
+        idle.wake_on_uart(3);
+    }
+    if p1_meter_b.is_some() {
+        idle.wake_on_uart(8);
+    }
+    if modbus.is_some() {
+        idle.wake_on_uart(1);
+    }
+    if let Some(button) = button.as_ref() {
+        let (module, bit) = button.gpio();
+        idle.wake_on_gpio(module, bit);
+    }
+    for (module, bit) in pulses.iter().flat_map(PulseCounter::gpios) {
+        idle.wake_on_gpio(module, bit);
+    }
+    let mut network_poll_at = None;
+    let mut cpu_clock = match CPU_IDLE_HZ {
+        Some(idle_hz) => Some(CpuClock::new(
+            per.ccm.pll1,
+            per.ccm.handle,
+            per.dcdc,
+            idle_hz,
+            CPU_IDLE_AFTER_MS,
+        )),
+        None => None,
+    };
+    let mut tempmon = TempMon::init(TEMPERATURE_LIMIT_MC);
+    timers.every(
+        clock.millis(),
+        TEMPERATURE_CHECK_INTERVAL_MS,
+        Timer::CheckTemperature,
+    );
+    timers.every(clock.millis(), RTC_SYNC_INTERVAL_MS, Timer::SyncRtc);
+    if MQTT_CONFIG.diagnostics_topic.is_some() {
+        timers.every(
+            clock.millis(),
+            MQTT_DIAGNOSTICS_INTERVAL_MS,
+            Timer::ReportDiagnostics,
+        );
+    }
+    if status_led.is_some() {
+        timers.every(clock.millis(), STATUS_LED_STEP_MS, Timer::StatusLed);
+    }
+    if display.is_some() {
+        timers.every(clock.millis(), DISPLAY_STEP_MS, Timer::Display);
+    }
+    if supervisor.is_some() {
+        timers.every(
+            clock.millis(),
+            WATCHDOG_FEED_INTERVAL_MS,
+            Timer::FeedWatchdog,
+        );
+    }
+    if ota_outcome == Some(BootOutcome::Installed) {
+        timers.after(clock.millis(), OTA_CONFIRM_AFTER_MS, Timer::ConfirmUpdate);
+    }
+    diag.check_stack();
+    log::info!(
+        "Using {} of {} bytes of stack so far",
+        diag.stats().stack_used,
+        diag.stats().stack_size
+    );
+    // Of different LPUARTs, so they are polled as `P1Input`s.
+    let mut p1_meters = ArrayVec::<[&mut dyn P1Input; p1::MAX_METERS]>::new();
+    if let Some(meter) = p1_meter_a.as_mut() {
+        p1_meters.push(meter);
+    }
+    if let Some(meter) = p1_meter_b.as_mut() {
+        p1_meters.push(meter);
+    }
     log::info!("Entering main loop");
     loop {
-        dsmr_uart.poll();
-        network.poll(&mut clock);
-        network.poll_client(&mut random, &mut client);
-        let (read, res) = dsmr42::parse(&dsmr_uart.get_buffer());
-        match res {
-            Ok(telegram) => {
-                log::info!("Got new telegram: {}", telegram.device_id);
-                client.queue_telegram(telegram);
-            }
-            Err(dsmr42::TelegramParseError::Incomplete) => {}
-            Err(err) => {
-                let buffer = dsmr_uart.get_buffer();
-                log::warn!(
-                    "Failed to parse telegram ({} bytes): {:?}, buffer: {:?}",
-                    buffer.len(),
+        if let Some(max_sleep_ms) = IDLE_MAX_SLEEP_MS {
+            let mut until_poll =
+                network_poll_at.map_or(max_sleep_ms as i64, |at| at - clock.millis());
+            // The mirror UART only holds a few bytes, so it is kept fed.
+            if uart_mirror.as_ref().map_or(false, UartMirror::is_sending) {
+                until_poll = 0;
+            }
+            // As is the simulated meter, or its telegrams take too long.
+            #[cfg(feature = "sim")]
+            if simulator.is_sending() {
+                until_poll = 0;
+            }
+            idle.sleep(&clock, until_poll.clamp(0, max_sleep_ms as i64) as u32);
+        }
+        diag.on_iteration(clock.ticks());
+        // Timers can't use the clock, which they are polled with.
+        let now = clock.millis();
+        let mut report_diagnostics = false;
+        timers.poll(clock.millis(), |timer| match timer {
+            Timer::FeedWatchdog => {
+                if let Some(supervisor) = supervisor.as_mut() {
+                    supervisor.poll();
+                }
+            }
+            Timer::ConfirmUpdate => ota::confirm(&mut flash, OTA_STATE_SECTOR),
+            Timer::CheckTemperature => {
+                let too_hot = tempmon.poll();
+                if let Some(cpu_clock) = cpu_clock.as_mut() {
+                    cpu_clock.throttle(too_hot);
+                }
+            }
+            Timer::SyncRtc => {
+                if let Some(time) = sntp.time(now) {
+                    rtc.set(time);
+                    // At the next midnight UTC.
+                    rtc.set_alarm(((time / 86_400_000 + 1) * 86_400) as u32);
+                }
+            }
+            Timer::StatusLed => {
+                if let Some(status_led) = status_led.as_mut() {
+                    let pattern = if !client.is_ready() {
+                        Pattern::NetworkDown
+                    } else if last_telegram_at.map_or(true, |at| now - at > DSMR_REQUEST_TIMEOUT_MS)
+                    {
+                        Pattern::WaitingForTelegram
+                    } else {
+                        Pattern::TelegramOk
+                    };
+                    status_led.show(now, pattern);
+                    status_led.poll(now);
+                }
+            }
+            Timer::Display => {
+                if let (Some(display), Some(i2c)) = (display.as_mut(), i2c.as_mut()) {
+                    display.set_network_up(client.is_ready());
+                    display.poll(now, i2c);
+                }
+            }
+            Timer::ReportDiagnostics => report_diagnostics = true,
+        });
+        if rtc.take_alarm() {
+            log::info!(
+                "Up for {} h, boot {} since the last power loss",
+                rtc.uptime() / 3_600_000,
+                rtc.boot_count()
+            );
+        }
+        scheduler.run(Task::Uart, || {
+            if let Some(adc) = adc.as_mut() {
+                adc.poll();
+            }
+            if let Some(modbus) = modbus.as_mut() {
+                modbus.poll(clock.millis());
+                if let Some(values) = modbus.take_updated() {
+                    http.update_meter(values);
+                }
+            }
+            for meter in p1_meters.iter_mut() {
+                let now = clock.millis();
+                let wall_time = |received_at: i64| {
+                    sntp.time(received_at)
+                        .or_else(|| rtc::now().map(|time| time - (now - received_at)))
+                };
+                if let Some(reading) = meter.poll(now, &wall_time) {
+                    log::info!("Got new telegram of P1 meter {}", meter.name());
+                    http.update_p1_meter(meter.name(), &reading);
+                    client.queue_p1_reading(meter.name(), reading);
+                }
+            }
+            dsmr_request.poll(clock.millis());
+            #[cfg(feature = "sim")]
+            simulator.poll(clock.millis(), &mut dsmr_uart);
+            if DSMR_AUTOBAUD && !autobaud.is_locked() {
+                autobaud.poll(&mut dsmr_uart, clock.millis());
+                return;
+            }
+            match dsmr_uart.poll_at(clock.millis()) {
+                Ok(()) => {}
+                Err(DsmrUartError::BufferFull) => {
+                    log::warn!(
+                        "UART read buffer full, discarding buffer contents ({} bytes dropped so far)",
+                        dsmr_uart.stats().dropped_bytes
+                    );
+                    dsmr_uart.clear();
+                    parser.reset();
+                }
+                Err(err) => log::warn!(
+                    "Error during UART polling: {:?} ({} line errors so far)",
                     err,
-                    core::str::from_utf8(buffer)
-                );
-                dsmr_uart.clear();
+                    dsmr_uart.stats().line_errors()
+                ),
+            }
+        });
+        network_poll_at = scheduler.run(Task::Network, || {
+            let poll_at = network.poll(&mut clock);
+            network.poll_client(&mut random, &mut client);
+            network.poll_client(&mut random, &mut influx);
+            if let Some(datalog) = datalog.as_mut() {
+                if let Some(position) = client.take_replayed() {
+                    datalog.mark_replayed(&mut flash, position);
+                }
+                if client.wants_backlog() {
+                    if let Some((position, record)) = datalog.next_pending(&flash) {
+                        client.queue_backlog(position, record);
+                    }
+                }
+            }
+            if let Some(ota) = ota.as_mut() {
+                network.poll_client(&mut random, ota);
+                ota.write_pending(&mut flash);
+            }
+            network.poll_udp_client(&mut clock, &mut random, &mut sntp);
+            network.poll_udp_client(&mut clock, &mut random, &mut mdns);
+            network.poll_client(&mut random, &mut http);
+            if let Some(mirror) = mirror.as_mut() {
+                network.poll_client(&mut random, mirror);
+            }
+            if let Some(wifi) = wifi.as_mut() {
+                wifi.poll(clock.millis());
+                let received = wifi.received().len();
+                if received > 0 {
+                    log::debug!("Wi-Fi collector sent {} bytes", received);
+                    wifi.consume(received);
+                }
+            }
+            if let Some(lorawan) = lorawan.as_mut() {
+                lorawan.poll(clock.millis(), &mut flash);
+            }
+            if let Some(can) = can.as_mut() {
+                can.poll();
             }
+            poll_at
+        });
+        if let Some(cpu_clock) = cpu_clock.as_mut() {
+            // Sockets with data to send want to be polled right away.
+            let busy = network_poll_at.map_or(false, |at| at <= clock.millis());
+            cpu_clock.poll(clock.millis(), busy);
+        }
+        scheduler.run(Task::Logger, || {
+            if let Some(syslog) = syslog.as_mut() {
+                syslog.set_clock(sntp.clock());
+                network.poll_udp_client(&mut clock, &mut random, syslog);
+            }
+        });
+        // Runs without a button as well, so that it checks in.
+        scheduler.run(Task::Button, || {
+            if let Some(pulses) = pulses.as_mut() {
+                pulses.poll(clock.millis(), &mut flash);
+            }
+            match button
+                .as_mut()
+                .and_then(|button| button.poll(clock.millis()))
+            {
+                Some(Press::Short) => log::info!(
+                    "Up for {} s, {} telegrams parsed, {} rejected, broker {}",
+                    clock.millis() / 1000,
+                    parser.stats().parsed,
+                    parser.stats().rejected(),
+                    if client.is_ready() {
+                        "connected"
+                    } else {
+                        "not connected"
+                    }
+                ),
+                Some(Press::Long) => {
+                    log::warn!("Resetting the config to the defaults");
+                    config_store.save(&mut flash, DEFAULT_CONFIG);
+                    system::reboot();
+                }
+                Some(Press::VeryLong) => system::enter_bootloader(),
+                None => {}
+            }
+        });
+        // Runs without sensors as well, so that it checks in.
+        scheduler.run(Task::Sensors, || {
+            if let Some(thermometers) = thermometers.as_mut() {
+                thermometers.poll(clock.millis());
+            }
+            if let (Some(sht3x), Some(i2c)) = (sht3x.as_mut(), i2c.as_mut()) {
+                sht3x.poll(clock.millis(), i2c);
+            }
+            if let Some(dht22) = dht22.as_mut() {
+                dht22.poll(clock.millis());
+            }
+        });
+        let mut p1_stats = [None; p1::MAX_METERS];
+        for (stats, meter) in p1_stats.iter_mut().zip(&p1_meters) {
+            *stats = Some(meter.stats());
+        }
+        let diagnostics = Diagnostics {
+            uptime_ms: clock.millis(),
+            uart: dsmr_uart.stats(),
+            parse: parser.stats(),
+            implausible: validator.rejected(),
+            clock: sntp.clock(),
+            sntp_failures: sntp.failures(),
+            log_dropped: syslog.as_ref().map_or(0, SyslogClient::dropped),
+            sd_dropped: sd_card.as_ref().map_or(0, SdLogger::dropped),
+            backlog_pending: datalog.as_ref().map_or(0, DataLog::pending),
+            backlog_dropped: datalog.as_ref().map_or(0, DataLog::dropped),
+            mirror_dropped: mirror.as_ref().map_or(0, MirrorServer::dropped)
+                + uart_mirror.as_ref().map_or(0, UartMirror::dropped),
+            uplink: client.queue_stats(),
+            diag: diag.stats(),
+            tasks: scheduler.stats(),
+            idle: idle.stats(),
+            temperature_mc: tempmon.temperature(),
+            boot_count: rtc.boot_count(),
+            wifi: wifi.as_ref().map(EspAt::stats),
+            can: can.as_ref().map(CanOutput::stats),
+            modbus: modbus.as_ref().map(ModbusMaster::stats),
+            p1_meters: p1_stats,
+            relays: relays.as_ref().map(Relays::closed),
+        };
+        http.set_diagnostics(diagnostics);
+        if report_diagnostics {
+            client.queue_diagnostics(diagnostics);
         }
-        if read > 0 {
-            dsmr_uart.consume(read);
+        if let Some(console) = console.as_mut() {
+            console.set_diagnostics(diagnostics);
+            console.poll(&mut flash, &mut config_store);
+            if console.take_update_request() {
+                match ota.as_mut() {
+                    Some(ota) => ota.start(),
+                    None => log::warn!("Updates are not configured"),
+                }
+            }
+        }
+        let telegram = scheduler.run(Task::Parser, || {
+            if DSMR_AUTOBAUD && !autobaud.is_locked() {
+                // Leave the data for autobaud to look at. The parser has
+                // nothing to do until then.
+                return None;
+            }
+            let (consumed, telegram) = parser.feed(dsmr_uart.peek());
+            let raw = &dsmr_uart.peek()[..consumed];
+            if let Some(mirror) = mirror.as_mut() {
+                mirror.feed(raw);
+            }
+            if let Some(uart_mirror) = uart_mirror.as_mut() {
+                uart_mirror.feed(raw);
+                uart_mirror.poll();
+            }
+            let received_at = dsmr_uart.timestamp(consumed.saturating_sub(1));
+            dsmr_uart.consume(consumed);
+            telegram.map(|telegram| (received_at, telegram))
+        });
+        if let Some((received_at, telegram)) = telegram {
+            dsmr_request.on_telegram(clock.millis());
+            if let (Some(now), Some(last)) = (received_at, last_telegram_at) {
+                log::debug!("Telegram received {} ms after the previous one", now - last);
+            }
+            last_telegram_at = received_at.or(last_telegram_at);
+            match telegram {
+                Ok((telegram, mut reading)) => {
+                    log::info!("Got new telegram: {}", telegram.device_id);
+                    log::debug!(
+                        "Delivering {:?} W, returning {:?} W",
+                        reading.power_delivered.and_then(|power| power.w()),
+                        reading.power_returned.and_then(|power| power.w())
+                    );
+                    let now = clock.millis();
+                    // Before SNTP has answered, the SRTC may still know the
+                    // time from before the last reset.
+                    let received_at = received_at.unwrap_or(now);
+                    reading.received_at = sntp
+                        .time(received_at)
+                        .or_else(|| rtc::now().map(|time| time - (now - received_at)));
+                    let plausible =
+                        validator.check(received_at, reading.received_at, &reading, |anomaly| {
+                            log::warn!("Implausible reading: {:?}", anomaly)
+                        });
+                    if !plausible {
+                        log::warn!(
+                            "Discarding telegram ({} discarded so far)",
+                            validator.rejected()
+                        );
+                        continue;
+                    }
+                    if let Some(adc) = adc.as_ref() {
+                        adc.append_to(&mut reading);
+                    }
+                    if let Some(pulses) = pulses.as_ref() {
+                        pulses.append_to(&mut reading);
+                    }
+                    if let Some(thermometers) = thermometers.as_ref() {
+                        thermometers.append_to(&mut reading);
+                    }
+                    if let Some(sht3x) = sht3x.as_ref() {
+                        sht3x.append_to(&mut reading);
+                    }
+                    if let Some(dht22) = dht22.as_ref() {
+                        dht22.append_to(&mut reading);
+                    }
+                    let sample = telemetry.sample(Some(&reading), diagnostics.reader_state());
+                    history.record(received_at, &reading);
+                    if let [Some(one), Some(five), Some(fifteen)] = history.aggregates(now) {
+                        log::debug!(
+                            "Average power {} W (1 min), {} W (5 min), {} W (15 min)",
+                            one.average,
+                            five.average,
+                            fifteen.average
+                        );
+                    }
+                    events.update(&reading, |event| {
+                        log::info!("Meter event: {:?}", event);
+                        client.queue_alarm(Alarm {
+                            event,
+                            at: reading.received_at,
+                        });
+                    });
+                    if let Some(relays) = relays.as_mut() {
+                        relays.update(received_at, &reading);
+                    }
+                    http.update(&reading);
+                    if let Some(console) = console.as_mut() {
+                        console.update(&reading);
+                    }
+                    if let Some(display) = display.as_mut() {
+                        display.update(&reading);
+                    }
+                    if let Some(pwm) = pwm.as_mut() {
+                        pwm.show(&reading);
+                    }
+                    if let Some(wifi) = wifi.as_mut() {
+                        wifi.send_reading(&reading);
+                    }
+                    if let Some(lorawan) = lorawan.as_mut() {
+                        lorawan.queue_reading(&reading);
+                    }
+                    if let Some(can) = can.as_mut() {
+                        can.queue_reading(now, &reading);
+                    }
+                    influx.queue_reading(now, &reading);
+                    if let Some(sd_card) = sd_card.as_mut() {
+                        sd_card.record(now, &reading, &telemetry, &sample);
+                    }
+                    if let Some(datalog) = datalog.as_mut() {
+                        if !client.is_ready() {
+                            datalog.record(&mut flash, now, &reading);
+                        }
+                    }
+                    client.queue_telegram(telegram, reading, sample);
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Failed to parse telegram ({} rejected so far): {:?}",
+                        parser.stats().rejected(),
+                        err
+                    );
+                    if let (Some(status_led), TelegramParseError::CrcMismatch(_)) =
+                        (status_led.as_mut(), &err)
+                    {
+                        status_led.show(clock.millis(), Pattern::CrcError);
+                    }
+                }
+            }
         }
     }
 
-    fn make_output_pin<P: Pin>(pin: P) -> OldOutputPin<GPIO<P, Output>> {
+    #[cfg(feature = "enc28j60")]
+    fn make_output_pin<P: hal::iomuxc::gpio::Pin>(
+        pin: P,
+    ) -> OldOutputPin<GPIO<P, hal::gpio::Output>> {
         let mut gpio = GPIO::new(pin).output();
         gpio.set_fast(true);
         OldOutputPin::new(gpio)
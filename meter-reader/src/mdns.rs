@@ -0,0 +1,442 @@
+//! mDNS (RFC 6762) responder that advertises the HTTP API through DNS-SD
+//! (RFC 6763), so that the meter reader can be found on the LAN without
+//! knowing its address.
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{self, Write};
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{network::client::UdpClient, random::Random};
+
+const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+// Must fit in the socket's send buffer.
+const PACKET_SZ: usize = 1024;
+const MAX_NAME_SZ: usize = 128;
+// Compression pointers may point to other pointers, but not forever.
+const MAX_POINTERS: usize = 16;
+const MAX_RECORDS: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+// Set on records that only we answer for, so that caches replace them.
+const CACHE_FLUSH: u16 = 0x8000;
+// Set on questions whose asker wants a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+// Response, authoritative answer.
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+// As recommended by RFC 6762, in seconds.
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+// Announcements are repeated once, a second apart.
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL_MS: i64 = 1000;
+
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+
+/// A service type that is advertised, with an instance named after the host.
+pub struct Service {
+    pub kind: &'static str,
+    pub txt: &'static [&'static str],
+}
+
+/// Both are served by the HTTP server.
+pub const SERVICES: &[Service] = &[
+    Service {
+        kind: "_p1meter._tcp",
+        txt: &["path=/api/telegram"],
+    },
+    Service {
+        kind: "_http._tcp",
+        txt: &["path=/"],
+    },
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Record {
+    /// PTR from the service enumeration name to the service type.
+    ServiceType(usize),
+    /// PTR from the service type to our instance of it.
+    Instance(usize),
+    Srv(usize),
+    Txt(usize),
+    /// A record of our hostname.
+    Host,
+}
+
+type Records = ArrayVec<[Record; MAX_RECORDS]>;
+type Name = ArrayString<[u8; MAX_NAME_SZ]>;
+
+pub struct MdnsResponder {
+    handle: Option<SocketHandle>,
+    hostname: &'static str,
+    port: u16,
+    /// The address that was last announced.
+    address: Option<Ipv4Address>,
+    announcements: u8,
+    next_announcement_at: i64,
+}
+
+impl UdpClient for MdnsResponder {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<UdpSocket>,
+        now: i64,
+        _random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if !socket.is_open() {
+            if let Err(err) = socket.bind(MDNS_PORT) {
+                log::warn!("Failed to bind mDNS socket: {}", err);
+                return;
+            }
+        }
+
+        let address = match interface.ipv4_addr() {
+            Some(address) if !address.is_unspecified() => address,
+            _ => return,
+        };
+        if self.address != Some(address) {
+            self.address = Some(address);
+            self.announcements = ANNOUNCEMENTS;
+            self.next_announcement_at = now;
+        }
+
+        while socket.can_recv() {
+            // The query must be copied out, because the socket can't be
+            // written to while it is borrowed.
+            let mut query = [0; PACKET_SZ];
+            let (len, from) = match socket.recv_slice(&mut query) {
+                Ok(received) => received,
+                Err(err) => {
+                    log::warn!("Failed to receive mDNS query: {}", err);
+                    break;
+                }
+            };
+            let (answers, unicast) = match self.answers(&query[..len]) {
+                Some((answers, unicast)) if !answers.is_empty() => (answers, unicast),
+                _ => continue,
+            };
+            // Legacy resolvers that don't send from port 5353 expect a
+            // unicast response, as do questions that ask for one.
+            let to = if unicast || from.port != MDNS_PORT {
+                from
+            } else {
+                IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT)
+            };
+            self.respond(&mut socket, address, &answers, to);
+        }
+
+        if self.announcements > 0 && now >= self.next_announcement_at {
+            log::debug!("Announcing {}.local at {}", self.hostname, address);
+            let mut records = Records::new();
+            for i in 0..SERVICES.len() {
+                records.push(Record::ServiceType(i));
+                records.push(Record::Instance(i));
+                records.push(Record::Srv(i));
+                records.push(Record::Txt(i));
+            }
+            records.push(Record::Host);
+            let to = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+            self.respond(&mut socket, address, &records, to);
+            self.announcements -= 1;
+            self.next_announcement_at = now + ANNOUNCE_INTERVAL_MS;
+        }
+    }
+}
+
+impl MdnsResponder {
+    /// Advertises `hostname`.local, and the `SERVICES` on `port`.
+    pub fn new(hostname: &'static str, port: u16) -> Self {
+        Self {
+            handle: None,
+            hostname,
+            port,
+            address: None,
+            announcements: 0,
+            next_announcement_at: 0,
+        }
+    }
+
+    // Returns the records that answer the questions in `query`, if it is a
+    // valid query, and whether a unicast response was asked for.
+    fn answers(&self, query: &[u8]) -> Option<(Records, bool)> {
+        let flags = read_u16(query, 2)?;
+        // Ignore responses, including those of other responders.
+        if flags & 0x8000 != 0 {
+            return None;
+        }
+        let questions = read_u16(query, 4)?;
+        let mut answers = Records::new();
+        let mut unicast = false;
+        let mut at = 12;
+        for _ in 0..questions {
+            let mut name = Name::new();
+            at = read_name(query, at, &mut name)?;
+            let kind = read_u16(query, at)?;
+            let class = read_u16(query, at + 2)?;
+            at += 4;
+            let unicast_response = class & UNICAST_RESPONSE != 0;
+            let class = class & !UNICAST_RESPONSE;
+            if class != CLASS_IN && class != CLASS_ANY {
+                continue;
+            }
+            unicast |= unicast_response;
+            self.answer(&name, kind, &mut answers);
+        }
+        Some((answers, unicast))
+    }
+
+    fn answer(&self, name: &str, kind: u16, answers: &mut Records) {
+        let wants = |wanted| kind == wanted || kind == TYPE_ANY;
+        let mut add = |record| {
+            if !answers.contains(&record) {
+                let _ = answers.try_push(record);
+            }
+        };
+        if wants(TYPE_PTR) && name.eq_ignore_ascii_case(SERVICE_ENUMERATION) {
+            for i in 0..SERVICES.len() {
+                add(Record::ServiceType(i));
+            }
+        }
+        for (i, service) in SERVICES.iter().enumerate() {
+            if wants(TYPE_PTR) && is_name(name, format_args!("{}.local", service.kind)) {
+                add(Record::Instance(i));
+                add(Record::Srv(i));
+                add(Record::Txt(i));
+                add(Record::Host);
+            }
+            if is_name(
+                name,
+                format_args!("{}.{}.local", self.hostname, service.kind),
+            ) {
+                if wants(TYPE_SRV) {
+                    add(Record::Srv(i));
+                    add(Record::Host);
+                }
+                if wants(TYPE_TXT) {
+                    add(Record::Txt(i));
+                }
+            }
+        }
+        if wants(TYPE_A) && is_name(name, format_args!("{}.local", self.hostname)) {
+            add(Record::Host);
+        }
+    }
+
+    fn respond(
+        &self,
+        socket: &mut SocketRef<UdpSocket>,
+        address: Ipv4Address,
+        records: &[Record],
+        to: IpEndpoint,
+    ) {
+        let mut packet = Packet {
+            buf: [0; PACKET_SZ],
+            len: 0,
+        };
+        if self.write_response(&mut packet, address, records).is_err() {
+            log::warn!("mDNS response does not fit in {} bytes", PACKET_SZ);
+            return;
+        }
+        if let Err(err) = socket.send_slice(&packet.buf[..packet.len], to) {
+            log::warn!("Failed to send mDNS response to {}: {}", to, err);
+        }
+    }
+
+    fn write_response(
+        &self,
+        packet: &mut Packet,
+        address: Ipv4Address,
+        records: &[Record],
+    ) -> Result<(), TooLong> {
+        // ID 0, no questions, and only answers.
+        packet.u16(0)?;
+        packet.u16(RESPONSE_FLAGS)?;
+        packet.u16(0)?;
+        packet.u16(records.len() as u16)?;
+        packet.u16(0)?;
+        packet.u16(0)?;
+        for record in records {
+            self.write_record(packet, address, *record)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(
+        &self,
+        packet: &mut Packet,
+        address: Ipv4Address,
+        record: Record,
+    ) -> Result<(), TooLong> {
+        let host = self.hostname;
+        match record {
+            Record::ServiceType(i) => {
+                packet.name(format_args!("{}", SERVICE_ENUMERATION))?;
+                packet.header(TYPE_PTR, CLASS_IN, SERVICE_TTL)?;
+                packet.rdata(|packet| packet.name(format_args!("{}.local", SERVICES[i].kind)))
+            }
+            Record::Instance(i) => {
+                let kind = SERVICES[i].kind;
+                packet.name(format_args!("{}.local", kind))?;
+                packet.header(TYPE_PTR, CLASS_IN, SERVICE_TTL)?;
+                packet.rdata(|packet| packet.name(format_args!("{}.{}.local", host, kind)))
+            }
+            Record::Srv(i) => {
+                packet.name(format_args!("{}.{}.local", host, SERVICES[i].kind))?;
+                packet.header(TYPE_SRV, CLASS_IN | CACHE_FLUSH, HOST_TTL)?;
+                packet.rdata(|packet| {
+                    // Priority and weight.
+                    packet.u16(0)?;
+                    packet.u16(0)?;
+                    packet.u16(self.port)?;
+                    packet.name(format_args!("{}.local", host))
+                })
+            }
+            Record::Txt(i) => {
+                packet.name(format_args!("{}.{}.local", host, SERVICES[i].kind))?;
+                packet.header(TYPE_TXT, CLASS_IN | CACHE_FLUSH, SERVICE_TTL)?;
+                packet.rdata(|packet| {
+                    for entry in SERVICES[i].txt {
+                        packet.string(entry.as_bytes())?;
+                    }
+                    Ok(())
+                })
+            }
+            Record::Host => {
+                packet.name(format_args!("{}.local", host))?;
+                packet.header(TYPE_A, CLASS_IN | CACHE_FLUSH, HOST_TTL)?;
+                packet.rdata(|packet| packet.bytes(address.as_bytes()))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TooLong;
+
+struct Packet {
+    buf: [u8; PACKET_SZ],
+    len: usize,
+}
+
+impl Packet {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), TooLong> {
+        let slot = self
+            .buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(TooLong)?;
+        slot.copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), TooLong> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    // A character string, which is preceded by its length.
+    fn string(&mut self, string: &[u8]) -> Result<(), TooLong> {
+        if string.len() > 255 {
+            return Err(TooLong);
+        }
+        self.bytes(&[string.len() as u8])?;
+        self.bytes(string)
+    }
+
+    // Names are written without compression, which keeps this simple at the
+    // cost of a larger response.
+    fn name(&mut self, name: fmt::Arguments<'_>) -> Result<(), TooLong> {
+        let mut dotted = Name::new();
+        dotted.write_fmt(name).map_err(|_| TooLong)?;
+        for label in dotted.split('.') {
+            if label.len() > 63 {
+                return Err(TooLong);
+            }
+            self.string(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn header(&mut self, kind: u16, class: u16, ttl: u32) -> Result<(), TooLong> {
+        self.u16(kind)?;
+        self.u16(class)?;
+        self.bytes(&ttl.to_be_bytes())
+    }
+
+    // Writes the record data, preceded by its length.
+    fn rdata<F>(&mut self, write: F) -> Result<(), TooLong>
+    where
+        F: FnOnce(&mut Self) -> Result<(), TooLong>,
+    {
+        let start = self.len;
+        self.u16(0)?;
+        write(self)?;
+        let len = (self.len - start - 2) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+fn read_u16(packet: &[u8], at: usize) -> Option<u16> {
+    let bytes = packet.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Reads the name at `at` into `name`, in lowercase and separated by dots,
+// and returns where the name ends.
+fn read_name(packet: &[u8], mut at: usize, name: &mut Name) -> Option<usize> {
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => return Some(end.unwrap_or(at + 1)),
+            // A pointer to a name elsewhere in the packet.
+            len if len & 0xC0 == 0xC0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(at + 2);
+                at = (len & 0x3F) << 8 | *packet.get(at + 1)? as usize;
+            }
+            len if len < 64 => {
+                let label = packet.get(at + 1..at + 1 + len)?;
+                if !name.is_empty() {
+                    name.try_push('.').ok()?;
+                }
+                for c in label {
+                    name.try_push((*c as char).to_ascii_lowercase()).ok()?;
+                }
+                at += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn is_name(name: &str, expected: fmt::Arguments<'_>) -> bool {
+    let mut formatted = Name::new();
+    formatted.write_fmt(expected).is_ok() && name.eq_ignore_ascii_case(&formatted)
+}
@@ -0,0 +1,574 @@
+use core::fmt::{self, Display, Write};
+
+use dsmr42::Decimal;
+
+use crate::{
+    can::CanStats,
+    diag::DiagStats,
+    dsmr::ParseStats,
+    esp_at::WifiStats,
+    idle::IdleStats,
+    modbus::{MeterValues, ModbusStats},
+    p1::{P1Stats, MAX_METERS},
+    relay::MAX_RELAYS,
+    scheduler::TaskStats,
+    sntp::WallClock,
+    telemetry::{Kind, ReaderState, Sample, Source, Telemetry},
+    uart::DsmrUartStats,
+    uplink::QueueStats,
+};
+
+/// Internal state of the meter reader that is exported with the readings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Diagnostics {
+    pub uptime_ms: i64,
+    pub uart: DsmrUartStats,
+    pub parse: ParseStats,
+    /// Readings discarded by the `Validator`.
+    pub implausible: u32,
+    pub clock: WallClock,
+    /// SNTP requests that failed.
+    pub sntp_failures: u32,
+    /// Log records that were not sent to the syslog collector.
+    pub log_dropped: u32,
+    /// Readings that were not written to the SD card.
+    pub sd_dropped: u32,
+    /// Readings in the flash data log that have yet to be replayed.
+    pub backlog_pending: u32,
+    /// Readings that were erased from the flash data log before they were
+    /// replayed.
+    pub backlog_dropped: u32,
+    /// Bytes of the P1 stream that were not mirrored.
+    pub mirror_dropped: u32,
+    /// What was lost from the queue of messages to the MQTT broker.
+    pub uplink: QueueStats,
+    pub diag: DiagStats,
+    /// In the order of `Task::ALL`.
+    pub tasks: [TaskStats; 6],
+    pub idle: IdleStats,
+    /// The temperature of the die, in thousandths of a degree Celsius.
+    pub temperature_mc: Option<i32>,
+    /// Boots since the SRTC last lost power.
+    pub boot_count: u32,
+    /// If the Wi-Fi uplink is used.
+    pub wifi: Option<WifiStats>,
+    /// If readings are sent over CAN.
+    pub can: Option<CanStats>,
+    /// If Modbus meters are read.
+    pub modbus: Option<ModbusStats>,
+    /// Of each of the other P1 meters, if any.
+    pub p1_meters: [Option<P1Stats>; MAX_METERS],
+    /// If relays are switched, whether each is closed.
+    pub relays: Option<[bool; MAX_RELAYS]>,
+}
+
+impl Diagnostics {
+    /// What the metrics of the meter reader in a `Telemetry` are taken from.
+    pub fn reader_state(&self) -> ReaderState {
+        ReaderState {
+            uptime_ms: self.uptime_ms,
+            temperature_mc: self.temperature_mc,
+        }
+    }
+}
+
+/// Writes `sample` of the metrics of `telemetry`, those of the meter in the
+/// samples of the other `p1_meters`, by their names, the values of the Modbus
+/// `meters`, and `diagnostics` in the Prometheus text exposition format.
+pub fn write_metrics<W: Write>(
+    writer: &mut W,
+    telemetry: &Telemetry,
+    sample: &Sample,
+    p1_meters: &[(&str, Sample)],
+    meters: &[MeterValues],
+    diagnostics: &Diagnostics,
+) -> fmt::Result {
+    let mut metrics = Metrics(writer);
+    metrics.write_telemetry(telemetry, sample, p1_meters)?;
+    if !meters.is_empty() {
+        metrics.write_meters(meters)?;
+    }
+    metrics.write_diagnostics(diagnostics)
+}
+
+struct Metrics<'w, W: Write>(&'w mut W);
+
+impl<'w, W: Write> Metrics<'w, W> {
+    fn family<N: Display>(&mut self, name: N, kind: &str, help: &str) -> fmt::Result {
+        writeln!(self.0, "# HELP {} {}", name, help)?;
+        writeln!(self.0, "# TYPE {} {}", name, kind)
+    }
+
+    /// `labels` is written between braces, so it should look like
+    /// `name="value"`.
+    fn sample<N: Display, V: Display>(
+        &mut self,
+        name: N,
+        labels: Option<fmt::Arguments<'_>>,
+        value: V,
+    ) -> fmt::Result {
+        match labels {
+            Some(labels) => writeln!(self.0, "{}{{{}}} {}", name, labels, value),
+            None => writeln!(self.0, "{} {}", name, value),
+        }
+    }
+
+    fn decimal<N: Display>(
+        &mut self,
+        name: N,
+        labels: Option<fmt::Arguments<'_>>,
+        value: Option<Decimal>,
+    ) -> fmt::Result {
+        match value {
+            // Without a unit, decimals are formatted as plain numbers.
+            Some(value) => self.sample(name, labels, Decimal::new(value.value, value.scale, None)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_telemetry(
+        &mut self,
+        telemetry: &Telemetry,
+        sample: &Sample,
+        p1_meters: &[(&str, Sample)],
+    ) -> fmt::Result {
+        let metrics = telemetry.metrics();
+        for (i, metric) in metrics.iter().enumerate() {
+            // Each family is written once, where its first metric is.
+            if metrics[..i]
+                .iter()
+                .any(|other| other.is_same_family(metric))
+            {
+                continue;
+            }
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            self.family(metric.family(), kind, metric.help)?;
+            for (other, value) in metrics[i..].iter().zip(&sample[i..]) {
+                if !other.is_same_family(metric) {
+                    continue;
+                }
+                match other.label {
+                    Some(label) => self.decimal(
+                        other.family(),
+                        Some(format_args!("{}=\"{}\"", label.key(), label)),
+                        *value,
+                    )?,
+                    None => self.decimal(other.family(), None, *value)?,
+                }
+            }
+            if metric.source != Source::Meter {
+                continue;
+            }
+            for (name, sample) in p1_meters {
+                for (other, value) in metrics[i..].iter().zip(&sample[i..]) {
+                    if !other.is_same_family(metric) {
+                        continue;
+                    }
+                    match other.label {
+                        Some(label) => self.decimal(
+                            other.family(),
+                            Some(format_args!(
+                                "meter=\"{}\",{}=\"{}\"",
+                                name,
+                                label.key(),
+                                label
+                            )),
+                            *value,
+                        )?,
+                        None => self.decimal(
+                            other.family(),
+                            Some(format_args!("meter=\"{}\"", name)),
+                            *value,
+                        )?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_meters(&mut self, meters: &[MeterValues]) -> fmt::Result {
+        self.family(
+            "meter_modbus_value",
+            "gauge",
+            "Latest values of each Modbus meter.",
+        )?;
+        for meter in meters {
+            for (register, value) in meter.registers.iter().zip(&meter.values) {
+                self.decimal(
+                    "meter_modbus_value",
+                    Some(format_args!(
+                        "address=\"{}\",meter=\"{}\",register=\"{}\",unit=\"{}\"",
+                        meter.address, meter.name, register.name, register.unit
+                    )),
+                    *value,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_diagnostics(&mut self, diagnostics: &Diagnostics) -> fmt::Result {
+        self.family(
+            "reader_boots_total",
+            "counter",
+            "Boots since the real-time clock last lost power.",
+        )?;
+        self.sample("reader_boots_total", None, diagnostics.boot_count)?;
+
+        let uart = &diagnostics.uart;
+        self.family(
+            "reader_uart_received_bytes_total",
+            "counter",
+            "Bytes received from the meter.",
+        )?;
+        self.sample(
+            "reader_uart_received_bytes_total",
+            None,
+            uart.bytes_received,
+        )?;
+        self.family(
+            "reader_uart_dropped_bytes_total",
+            "counter",
+            "Bytes received that did not fit in the read buffer.",
+        )?;
+        self.sample("reader_uart_dropped_bytes_total", None, uart.dropped_bytes)?;
+        for meter in diagnostics.p1_meters.iter().flatten() {
+            self.sample(
+                "reader_uart_dropped_bytes_total",
+                Some(format_args!("meter=\"{}\"", meter.name)),
+                meter.dropped_bytes,
+            )?;
+        }
+        self.family(
+            "reader_uart_errors_total",
+            "counter",
+            "Errors reported by the UART.",
+        )?;
+        for (kind, count) in [
+            ("overrun", uart.overruns),
+            ("framing", uart.framing_errors),
+            ("parity", uart.parity_errors),
+            ("noise", uart.noise_errors),
+        ] {
+            self.sample(
+                "reader_uart_errors_total",
+                Some(format_args!("kind=\"{}\"", kind)),
+                count,
+            )?;
+        }
+
+        let parse = &diagnostics.parse;
+        self.family(
+            "reader_telegrams_total",
+            "counter",
+            "Telegrams received from the meter.",
+        )?;
+        self.sample(
+            "reader_telegrams_total",
+            Some(format_args!("result=\"parsed\"")),
+            parse.parsed,
+        )?;
+        self.sample(
+            "reader_telegrams_total",
+            Some(format_args!("result=\"crc_mismatch\"")),
+            parse.crc_mismatches,
+        )?;
+        self.sample(
+            "reader_telegrams_total",
+            Some(format_args!("result=\"malformed\"")),
+            parse.malformed,
+        )?;
+        for meter in diagnostics.p1_meters.iter().flatten() {
+            for (result, count) in [
+                ("parsed", meter.parse.parsed),
+                ("crc_mismatch", meter.parse.crc_mismatches),
+                ("malformed", meter.parse.malformed),
+            ]
+            .iter()
+            {
+                self.sample(
+                    "reader_telegrams_total",
+                    Some(format_args!(
+                        "meter=\"{}\",result=\"{}\"",
+                        meter.name, result
+                    )),
+                    count,
+                )?;
+            }
+        }
+        self.family(
+            "reader_implausible_readings_total",
+            "counter",
+            "Parsed telegrams that were discarded as implausible.",
+        )?;
+        self.sample(
+            "reader_implausible_readings_total",
+            None,
+            diagnostics.implausible,
+        )?;
+        for meter in diagnostics.p1_meters.iter().flatten() {
+            self.sample(
+                "reader_implausible_readings_total",
+                Some(format_args!("meter=\"{}\"", meter.name)),
+                meter.implausible,
+            )?;
+        }
+
+        self.family(
+            "reader_clock_synced",
+            "gauge",
+            "Whether the wall-clock time is known.",
+        )?;
+        self.sample(
+            "reader_clock_synced",
+            None,
+            diagnostics.clock.is_synced() as u8,
+        )?;
+        self.family(
+            "reader_clock_drift_ppm",
+            "gauge",
+            "Estimated drift of the reader's clock.",
+        )?;
+        self.sample(
+            "reader_clock_drift_ppm",
+            None,
+            diagnostics.clock.drift_ppm(),
+        )?;
+        self.family(
+            "reader_sntp_failures_total",
+            "counter",
+            "SNTP requests that were not answered in time or not usable.",
+        )?;
+        self.sample(
+            "reader_sntp_failures_total",
+            None,
+            diagnostics.sntp_failures,
+        )?;
+        self.family(
+            "reader_log_records_dropped_total",
+            "counter",
+            "Log records that could not be sent to the syslog collector.",
+        )?;
+        self.sample(
+            "reader_log_records_dropped_total",
+            None,
+            diagnostics.log_dropped,
+        )?;
+        self.family(
+            "reader_sd_dropped_readings_total",
+            "counter",
+            "Readings that could not be written to the SD card.",
+        )?;
+        self.sample(
+            "reader_sd_dropped_readings_total",
+            None,
+            diagnostics.sd_dropped,
+        )?;
+        self.family(
+            "reader_backlog_pending_readings",
+            "gauge",
+            "Readings logged to flash that have yet to be replayed.",
+        )?;
+        self.sample(
+            "reader_backlog_pending_readings",
+            None,
+            diagnostics.backlog_pending,
+        )?;
+        self.family(
+            "reader_backlog_dropped_readings_total",
+            "counter",
+            "Readings logged to flash that were erased before they were replayed.",
+        )?;
+        self.sample(
+            "reader_backlog_dropped_readings_total",
+            None,
+            diagnostics.backlog_dropped,
+        )?;
+        self.family(
+            "reader_mirror_dropped_bytes_total",
+            "counter",
+            "Bytes of the P1 stream that could not be mirrored.",
+        )?;
+        self.sample(
+            "reader_mirror_dropped_bytes_total",
+            None,
+            diagnostics.mirror_dropped,
+        )?;
+        let uplink = &diagnostics.uplink;
+        self.family(
+            "reader_uplink_dropped_total",
+            "counter",
+            "Messages to the MQTT broker that were replaced or dropped before they were sent.",
+        )?;
+        self.sample(
+            "reader_uplink_dropped_total",
+            Some(format_args!("class=\"reading\"")),
+            uplink.replaced_readings,
+        )?;
+        self.sample(
+            "reader_uplink_dropped_total",
+            Some(format_args!("class=\"diagnostics\"")),
+            uplink.dropped_diagnostics,
+        )?;
+        self.sample(
+            "reader_uplink_dropped_total",
+            Some(format_args!("class=\"alarm\"")),
+            uplink.dropped_alarms,
+        )?;
+
+        let diag = &diagnostics.diag;
+        self.family(
+            "reader_stack_size_bytes",
+            "gauge",
+            "Memory set aside for the stack.",
+        )?;
+        self.sample("reader_stack_size_bytes", None, diag.stack_size)?;
+        self.family(
+            "reader_stack_used_bytes",
+            "gauge",
+            "The most the stack has grown since the meter reader started.",
+        )?;
+        self.sample("reader_stack_used_bytes", None, diag.stack_used)?;
+        self.family(
+            "reader_main_loop_iterations_total",
+            "counter",
+            "Iterations of the main loop.",
+        )?;
+        self.sample(
+            "reader_main_loop_iterations_total",
+            None,
+            diag.loop_iterations,
+        )?;
+        self.family(
+            "reader_main_loop_average_seconds",
+            "gauge",
+            "Average duration of recent main loop iterations.",
+        )?;
+        self.sample(
+            "reader_main_loop_average_seconds",
+            None,
+            Decimal::new(diag.loop_average_us as i64, 6, None),
+        )?;
+        self.family(
+            "reader_main_loop_max_seconds",
+            "gauge",
+            "Longest main loop iteration since the meter reader started.",
+        )?;
+        self.sample(
+            "reader_main_loop_max_seconds",
+            None,
+            Decimal::new(diag.loop_max_us as i64, 6, None),
+        )?;
+        self.family(
+            "reader_sleeping_ratio",
+            "gauge",
+            "Part of the last 10 seconds spent sleeping between main loop iterations.",
+        )?;
+        self.sample(
+            "reader_sleeping_ratio",
+            None,
+            Decimal::new(diagnostics.idle.sleeping_percent as i64, 2, None),
+        )?;
+        if let Some(wifi) = diagnostics.wifi {
+            self.family(
+                "reader_wifi_resets_total",
+                "counter",
+                "Resets of the Wi-Fi module.",
+            )?;
+            self.sample("reader_wifi_resets_total", None, wifi.resets)?;
+            self.family(
+                "reader_wifi_dropped_readings_total",
+                "counter",
+                "Readings that could not be queued for the Wi-Fi uplink.",
+            )?;
+            self.sample("reader_wifi_dropped_readings_total", None, wifi.dropped)?;
+            self.family(
+                "reader_wifi_sent_bytes_total",
+                "counter",
+                "Bytes sent over the Wi-Fi uplink.",
+            )?;
+            self.sample("reader_wifi_sent_bytes_total", None, wifi.bytes_sent)?;
+        }
+        if let Some(can) = diagnostics.can {
+            self.family(
+                "reader_can_sent_frames_total",
+                "counter",
+                "Frames sent on the CAN bus.",
+            )?;
+            self.sample("reader_can_sent_frames_total", None, can.sent)?;
+            self.family(
+                "reader_can_dropped_frames_total",
+                "counter",
+                "Frames that could not be queued for the CAN bus.",
+            )?;
+            self.sample("reader_can_dropped_frames_total", None, can.dropped)?;
+            self.family(
+                "reader_can_bus_off_total",
+                "counter",
+                "Times that the CAN controller went bus off.",
+            )?;
+            self.sample("reader_can_bus_off_total", None, can.bus_off)?;
+            self.family(
+                "reader_can_errors",
+                "gauge",
+                "Error counters of the CAN controller.",
+            )?;
+            self.sample(
+                "reader_can_errors",
+                Some(format_args!("direction=\"tx\"")),
+                can.tx_errors,
+            )?;
+            self.sample(
+                "reader_can_errors",
+                Some(format_args!("direction=\"rx\"")),
+                can.rx_errors,
+            )?;
+        }
+        if let Some(modbus) = diagnostics.modbus {
+            self.family(
+                "reader_modbus_requests_total",
+                "counter",
+                "Requests sent to Modbus devices.",
+            )?;
+            self.sample("reader_modbus_requests_total", None, modbus.requests)?;
+            self.family(
+                "reader_modbus_errors_total",
+                "counter",
+                "Modbus requests that failed, by kind.",
+            )?;
+            for (kind, count) in [
+                ("timeout", modbus.timeouts),
+                ("bad_response", modbus.bad_responses),
+                ("exception", modbus.exceptions),
+                ("collision", modbus.collisions),
+            ]
+            .iter()
+            {
+                self.sample(
+                    "reader_modbus_errors_total",
+                    Some(format_args!("kind=\"{}\"", kind)),
+                    count,
+                )?;
+            }
+        }
+        if let Some(relays) = diagnostics.relays {
+            self.family(
+                "reader_relay_closed",
+                "gauge",
+                "Whether a relay is closed by its rules.",
+            )?;
+            for (i, closed) in relays.iter().enumerate() {
+                self.sample(
+                    "reader_relay_closed",
+                    Some(format_args!("relay=\"{}\"", i + 1)),
+                    *closed as u8,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Forwards the raw P1 stream, every byte as it was received from the meter,
+//! for tools such as DSMR-reader and Domoticz that read a P1 port
+//! themselves, so that the meter reader can act as a network P1 bridge while
+//! still parsing the telegrams.
+//!
+//! The bytes are forwarded once the parser has taken them, so whatever the
+//! meter sent, CRC mismatches included, is passed on as is. Bytes that don't
+//! fit in the buffer, because the receiving end doesn't keep up, are dropped,
+//! which the receiving end sees as a malformed telegram.
+
+use embedded_hal::serial;
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, TcpSocket},
+};
+
+use crate::{network::client::TcpClient, random::Random, ring_buffer::RingBuffer};
+
+// Two telegrams of a three-phase meter with gas.
+const BUF_SZ: usize = 2048;
+
+// The bytes still to be forwarded.
+struct Pending {
+    buffer: RingBuffer<BUF_SZ>,
+    dropped: u32,
+}
+
+impl Pending {
+    const fn new() -> Self {
+        Self {
+            buffer: RingBuffer::new(),
+            dropped: 0,
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            if !self.buffer.push(*byte) {
+                let dropped = (bytes.len() - i) as u32;
+                self.dropped = self.dropped.saturating_add(dropped);
+                return;
+            }
+        }
+    }
+}
+
+/// Serves the P1 stream on a TCP port, to one client at a time, which
+/// receives what comes in from the moment it connects.
+pub struct MirrorServer {
+    handle: Option<SocketHandle>,
+    port: u16,
+    connected: bool,
+    pending: Pending,
+}
+
+impl TcpClient for MirrorServer {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<TcpSocket>,
+        _random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if !socket.is_open() {
+            if self.connected {
+                log::info!("P1 mirror client disconnected");
+                self.connected = false;
+            }
+            if let Err(err) = socket.listen(self.port) {
+                log::warn!("Failed to listen on port {}: {}", self.port, err);
+            }
+            return;
+        }
+        if !socket.may_send() {
+            return;
+        }
+        if !self.connected {
+            log::info!(
+                "P1 mirror client connected from {}",
+                socket.remote_endpoint()
+            );
+            self.connected = true;
+        }
+        // Anything the client sends is of no interest.
+        if socket.can_recv() {
+            let _ = socket.recv(|buf| (buf.len(), ()));
+        }
+        if !socket.may_recv() {
+            // The client has closed its end.
+            socket.close();
+            return;
+        }
+        while socket.can_send() && !self.pending.buffer.is_empty() {
+            let buffer = &mut self.pending.buffer;
+            match socket.send_slice(buffer.peek()) {
+                Ok(sent) => buffer.consume(sent),
+                Err(err) => {
+                    log::warn!("Failed to forward the P1 stream: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl MirrorServer {
+    pub fn new(port: u16) -> Self {
+        Self {
+            handle: None,
+            port,
+            connected: false,
+            pending: Pending::new(),
+        }
+    }
+
+    /// Forwards `bytes` to the client, if there is one.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.connected {
+            self.pending.feed(bytes);
+        }
+    }
+
+    /// Bytes that could not be forwarded to the client.
+    pub fn dropped(&self) -> u32 {
+        self.pending.dropped
+    }
+}
+
+/// Sends the P1 stream out of a serial port, not inverted, as a USB serial
+/// adapter expects it.
+pub struct UartMirror<S> {
+    serial: S,
+    pending: Pending,
+}
+
+impl<S> UartMirror<S>
+where
+    S: serial::Write<u8>,
+{
+    pub fn new(serial: S) -> Self {
+        Self {
+            serial,
+            pending: Pending::new(),
+        }
+    }
+
+    /// Queues `bytes` to be sent.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.pending.feed(bytes);
+    }
+
+    /// Sends as much of what was queued as the serial port takes.
+    pub fn poll(&mut self) {
+        while let Some(byte) = self.pending.buffer.peek().first().copied() {
+            if self.serial.write(byte).is_err() {
+                break;
+            }
+            self.pending.buffer.consume(1);
+        }
+    }
+
+    /// Whether there is anything left to send.
+    pub fn is_sending(&self) -> bool {
+        !self.pending.buffer.is_empty()
+    }
+
+    /// Bytes that could not be sent.
+    pub fn dropped(&self) -> u32 {
+        self.pending.dropped
+    }
+}
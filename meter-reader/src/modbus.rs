@@ -0,0 +1,479 @@
+//! A Modbus RTU master on RS-485, for kWh meters without a P1 port, such as
+//! those of solar inverters or heat pumps, which are read alongside the P1
+//! meter, on the same main loop.
+//!
+//! Every `interval_ms`, the devices are read one after another: first the
+//! holding registers of `setup` are written, once, with function code 6 or
+//! 16, and then the registers of the map are read, with function code 3 or
+//! 4, in as few requests as adjacent registers allow. A device that doesn't
+//! answer is skipped for the rest of the round, and its values are forgotten
+//! until it answers again. It is set up again then, since it may have lost
+//! power.
+//!
+//! A request is only sent once the bus has been quiet for 3.5 characters, as
+//! the specification asks.
+
+pub mod meters;
+
+use core::convert::Infallible;
+
+use arrayvec::ArrayVec;
+use dsmr42::Decimal;
+use embedded_hal::{digital::v2::OutputPin, serial::Read};
+use teensy4_bsp::hal::{iomuxc::prelude::consts, uart::Parity};
+
+use crate::uart::{DsmrUart, DsmrUartError, MAX_SEND_SZ};
+
+pub const MAX_DEVICES: usize = 4;
+/// The most registers in a map that are read.
+pub const MAX_REGISTERS: usize = 16;
+// Per request, which keeps responses short.
+const MAX_READ_REGISTERS: u16 = 32;
+const MAX_WRITE_REGISTERS: usize = 123;
+// The address, the function code, the byte count, the data and the CRC.
+const MAX_RESPONSE_SZ: usize = 5 + 2 * MAX_READ_REGISTERS as usize;
+const FC_READ_HOLDING: u8 = 3;
+const FC_READ_INPUT: u8 = 4;
+const FC_WRITE_SINGLE: u8 = 6;
+const FC_WRITE_MULTIPLE: u8 = 16;
+const EXCEPTION: u8 = 0x80;
+// Above 19200 baud, the specification fixes the gap at 1.75 ms.
+const MIN_FRAME_GAP_MS: i64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// Read with function code 3.
+    Holding,
+    /// Read with function code 4.
+    Input,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    U16,
+    I16,
+    /// Two registers, high word first, as are the others.
+    U32,
+    I32,
+    F32,
+}
+
+impl Format {
+    fn registers(self) -> u16 {
+        match self {
+            Format::U16 | Format::I16 => 1,
+            Format::U32 | Format::I32 | Format::F32 => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Register {
+    /// Unique among the registers of the map.
+    pub name: &'static str,
+    pub function: Function,
+    pub address: u16,
+    pub format: Format,
+    /// For integers, the decimals that the device implies, so that 2301 with
+    /// a scale of 1 is 230.1. Floats are rounded to this many decimals.
+    pub scale: u8,
+    pub unit: &'static str,
+}
+
+/// Holding registers that are written before a device is first read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Setup {
+    pub address: u16,
+    pub values: &'static [u16],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModbusDevice {
+    pub address: u8,
+    /// Names the device in the metrics, such as `sdm630`.
+    pub name: &'static str,
+    /// Such as `meters::SDM630`.
+    pub registers: &'static [Register],
+    pub setup: &'static [Setup],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ModbusConfig {
+    pub baud: u32,
+    pub parity: Option<Parity>,
+    pub devices: &'static [ModbusDevice],
+    pub interval_ms: i64,
+    pub response_timeout_ms: i64,
+}
+
+/// The latest values of a device.
+#[derive(Clone, Copy, Debug)]
+pub struct MeterValues {
+    pub address: u8,
+    pub name: &'static str,
+    pub registers: &'static [Register],
+    /// In the order of `registers`, `None` if they couldn't be read.
+    pub values: [Option<Decimal>; MAX_REGISTERS],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModbusStats {
+    pub requests: u32,
+    pub timeouts: u32,
+    /// Responses with a bad CRC, or that didn't fit their request.
+    pub bad_responses: u32,
+    pub exceptions: u32,
+    pub collisions: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Request {
+    /// Of `setup`.
+    Setup(usize),
+    /// Of `entries` in the map, from `first`.
+    Read { first: usize, entries: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Until the frame gap has passed, or the next round is due.
+    Idle {
+        until: i64,
+    },
+    Sending(Request),
+    Waiting {
+        request: Request,
+        since: i64,
+    },
+}
+
+pub struct ModbusMaster<M, P, const N: usize> {
+    uart: DsmrUart<M, N>,
+    de: P,
+    config: ModbusConfig,
+    frame_gap_ms: i64,
+    state: State,
+    round_at: i64,
+    device: usize,
+    next_setup: usize,
+    next_entry: usize,
+    set_up: [bool; MAX_DEVICES],
+    values: [[Option<Decimal>; MAX_REGISTERS]; MAX_DEVICES],
+    response: ArrayVec<[u8; MAX_RESPONSE_SZ]>,
+    updated: Option<usize>,
+    stats: ModbusStats,
+}
+
+impl<M: consts::Unsigned, P, const N: usize> ModbusMaster<M, P, N>
+where
+    P: OutputPin<Error = Infallible>,
+{
+    /// Takes `uart`, in RS-485 mode, and `de`, the pin that enables its
+    /// driver.
+    pub fn new(mut uart: DsmrUart<M, N>, mut de: P, mut config: ModbusConfig) -> Self {
+        let _ = uart.set_baud(config.baud);
+        uart.set_parity(config.parity);
+        let _ = de.set_low();
+        if config.devices.len() > MAX_DEVICES {
+            log::warn!("Only reading the first {} Modbus devices", MAX_DEVICES);
+            config.devices = &config.devices[..MAX_DEVICES];
+        }
+        // 3.5 characters of 11 bits, rounded up.
+        let frame_gap_ms = (38_500 + config.baud as i64 - 1) / config.baud.max(1) as i64;
+        Self {
+            uart,
+            de,
+            config,
+            frame_gap_ms: frame_gap_ms.max(MIN_FRAME_GAP_MS),
+            state: State::Idle { until: 0 },
+            round_at: 0,
+            device: 0,
+            next_setup: 0,
+            next_entry: 0,
+            set_up: [false; MAX_DEVICES],
+            values: [[None; MAX_REGISTERS]; MAX_DEVICES],
+            response: ArrayVec::new(),
+            updated: None,
+            stats: ModbusStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ModbusStats {
+        ModbusStats {
+            collisions: self.uart.stats().collisions,
+            ..self.stats
+        }
+    }
+
+    /// The values of the device that was read last, once per round.
+    pub fn take_updated(&mut self) -> Option<MeterValues> {
+        let index = self.updated.take()?;
+        let device = self.config.devices[index];
+        Some(MeterValues {
+            address: device.address,
+            name: device.name,
+            registers: device.registers,
+            values: self.values[index],
+        })
+    }
+
+    pub fn poll(&mut self, now: i64) {
+        match self.state {
+            State::Idle { until } if now < until => {}
+            State::Idle { .. } => match self.next_request() {
+                Some(request) => self.send(now, request),
+                None => self.next_device(now),
+            },
+            State::Sending(request) => match self.uart.poll_send(&mut self.de) {
+                Ok(()) => {
+                    self.state = State::Waiting {
+                        request,
+                        since: now,
+                    }
+                }
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(err)) => {
+                    log::warn!("Modbus request failed: {:?}", err);
+                    self.fail(now);
+                }
+            },
+            State::Waiting { request, since } => self.receive(now, request, since),
+        }
+    }
+
+    fn next_request(&mut self) -> Option<Request> {
+        let device = self.config.devices.get(self.device)?;
+        if !self.set_up[self.device] {
+            if self.next_setup < device.setup.len() {
+                return Some(Request::Setup(self.next_setup));
+            }
+            self.set_up[self.device] = true;
+        }
+        let registers = &device.registers[..device.registers.len().min(MAX_REGISTERS)];
+        let first = registers.get(self.next_entry)?;
+        let mut end = first.address.wrapping_add(first.format.registers());
+        let mut entries = 1;
+        for next in &registers[self.next_entry + 1..] {
+            let next_end = end.wrapping_add(next.format.registers());
+            if next.function != first.function
+                || next.address != end
+                || next_end.wrapping_sub(first.address) > MAX_READ_REGISTERS
+            {
+                break;
+            }
+            end = next_end;
+            entries += 1;
+        }
+        Some(Request::Read {
+            first: self.next_entry,
+            entries,
+        })
+    }
+
+    fn send(&mut self, now: i64, request: Request) {
+        let device = &self.config.devices[self.device];
+        let mut frame = ArrayVec::<[u8; MAX_SEND_SZ]>::new();
+        frame.push(device.address);
+        match request {
+            Request::Setup(index) => {
+                let setup = device.setup[index];
+                let values = &setup.values[..setup.values.len().min(MAX_WRITE_REGISTERS)];
+                if let [value] = values {
+                    frame.push(FC_WRITE_SINGLE);
+                    frame.extend(setup.address.to_be_bytes().iter().copied());
+                    frame.extend(value.to_be_bytes().iter().copied());
+                } else {
+                    frame.push(FC_WRITE_MULTIPLE);
+                    frame.extend(setup.address.to_be_bytes().iter().copied());
+                    frame.extend((values.len() as u16).to_be_bytes().iter().copied());
+                    frame.push(2 * values.len() as u8);
+                    for value in values {
+                        frame.extend(value.to_be_bytes().iter().copied());
+                    }
+                }
+            }
+            Request::Read { first, entries } => {
+                let registers = &device.registers[first..first + entries];
+                let count: u16 = registers.iter().map(|r| r.format.registers()).sum();
+                frame.push(match registers[0].function {
+                    Function::Holding => FC_READ_HOLDING,
+                    Function::Input => FC_READ_INPUT,
+                });
+                frame.extend(registers[0].address.to_be_bytes().iter().copied());
+                frame.extend(count.to_be_bytes().iter().copied());
+            }
+        }
+        let crc = crc16(&frame);
+        frame.extend(crc.to_le_bytes().iter().copied());
+
+        // Whatever came in since is too late for the last request.
+        let _ = self.uart.poll();
+        self.uart.clear();
+        self.response.clear();
+        match self.uart.start_send(&frame, &mut self.de) {
+            Ok(()) => {
+                self.stats.requests += 1;
+                self.state = State::Sending(request);
+            }
+            Err(err) => {
+                log::warn!("Failed to send Modbus request: {:?}", err);
+                self.fail(now);
+            }
+        }
+    }
+
+    fn receive(&mut self, now: i64, request: Request, since: i64) {
+        loop {
+            match self.uart.read() {
+                Ok(byte) => {
+                    if self.response.try_push(byte).is_err() {
+                        break;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                // The CRC won't match.
+                Err(nb::Error::Other(DsmrUartError::Read(_))) => {}
+                Err(nb::Error::Other(_)) => break,
+            }
+        }
+        let complete = match expected_len(&self.response) {
+            Some(len) if self.response.len() >= len => Some(len),
+            _ if self.response.is_full() => Some(self.response.len()),
+            _ => None,
+        };
+        let len = match complete {
+            Some(len) => len,
+            None if now - since > self.config.response_timeout_ms => {
+                let device = &self.config.devices[self.device];
+                log::warn!(
+                    "Modbus device {} ({}) didn't answer",
+                    device.address,
+                    device.name
+                );
+                self.stats.timeouts += 1;
+                return self.fail(now);
+            }
+            None => return,
+        };
+        let response = &self.response[..len];
+        let address = self.config.devices[self.device].address;
+        let crc = crc16(&response[..len - 2]).to_le_bytes();
+        if response[0] != address || response[len - 2..] != crc {
+            log::warn!("Bad Modbus response from device {}", address);
+            self.stats.bad_responses += 1;
+            return self.fail(now);
+        }
+        if response[1] & EXCEPTION != 0 {
+            log::warn!(
+                "Modbus device {} answered with exception {}",
+                address,
+                response[2]
+            );
+            self.stats.exceptions += 1;
+        }
+        match request {
+            Request::Setup(_) => self.next_setup += 1,
+            Request::Read { first, entries } => {
+                self.read_values(first, entries, len);
+                self.next_entry = first + entries;
+            }
+        }
+        self.state = State::Idle {
+            until: now + self.frame_gap_ms,
+        };
+    }
+
+    // Takes the values of `entries` from `first` from the response, which
+    // has been checked.
+    fn read_values(&mut self, first: usize, entries: usize, len: usize) {
+        let registers = &self.config.devices[self.device].registers[first..first + entries];
+        let values = &mut self.values[self.device][first..first + entries];
+        let data = &self.response[3.min(len)..len - 2];
+        let exception = self.response[1] & EXCEPTION != 0;
+        let mut at = 0;
+        for (register, value) in registers.iter().zip(values) {
+            let size = 2 * register.format.registers() as usize;
+            *value = match data.get(at..at + size) {
+                Some(words) if !exception && data.len() == self.response[2] as usize => {
+                    decode(register, words)
+                }
+                _ => None,
+            };
+            at += size;
+        }
+    }
+
+    fn next_device(&mut self, now: i64) {
+        self.updated = Some(self.device);
+        self.device += 1;
+        self.next_setup = 0;
+        self.next_entry = 0;
+        self.state = if self.device < self.config.devices.len() {
+            State::Idle {
+                until: now + self.frame_gap_ms,
+            }
+        } else {
+            self.device = 0;
+            self.round_at = (self.round_at + self.config.interval_ms).max(now);
+            State::Idle {
+                until: self.round_at,
+            }
+        };
+    }
+
+    // Skips the rest of the device.
+    fn fail(&mut self, now: i64) {
+        self.values[self.device] = [None; MAX_REGISTERS];
+        self.set_up[self.device] = false;
+        self.next_device(now);
+    }
+}
+
+fn expected_len(response: &[u8]) -> Option<usize> {
+    match response {
+        [_, function, ..] if function & EXCEPTION != 0 => Some(5),
+        [_, FC_READ_HOLDING, bytes, ..] | [_, FC_READ_INPUT, bytes, ..] => {
+            Some(5 + *bytes as usize)
+        }
+        [_, FC_WRITE_SINGLE, ..] | [_, FC_WRITE_MULTIPLE, ..] => Some(8),
+        [_, _, ..] => Some(response.len()),
+        _ => None,
+    }
+}
+
+fn decode(register: &Register, words: &[u8]) -> Option<Decimal> {
+    let high = u16::from_be_bytes([words[0], words[1]]);
+    let double = || (high as u32) << 16 | u16::from_be_bytes([words[2], words[3]]) as u32;
+    let value = match register.format {
+        Format::U16 => high as i64,
+        Format::I16 => high as i16 as i64,
+        Format::U32 => double() as i64,
+        Format::I32 => double() as i32 as i64,
+        Format::F32 => {
+            let value = f32::from_bits(double());
+            if !value.is_finite() {
+                return None;
+            }
+            let scaled = value * 10i64.pow(register.scale as u32) as f32;
+            let rounding = if scaled < 0.0 { -0.5 } else { 0.5 };
+            (scaled + rounding) as i64
+        }
+    };
+    Some(Decimal::new(value, register.scale, None))
+}
+
+// The CRC-16 of Modbus, which starts at 0xFFFF, unlike that of DSMR.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -1,5 +1,5 @@
-use arrayvec::ArrayString;
-use core::fmt::{Debug, Display};
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::{Debug, Display, Write};
 use dsmr42::Telegram;
 use embedded_mqtt::{
     codec::{Decodable, Encodable},
@@ -7,6 +7,7 @@ use embedded_mqtt::{
     fixed_header::PublishFlags,
     packet::Packet,
     payload,
+    qos::QoS,
     status::Status,
     variable_header::connect::Flags,
     variable_header::VariableHeader,
@@ -25,26 +26,87 @@ use smoltcp::{
     wire::Ipv4Address,
 };
 
-use crate::{network::client::TcpClient, network::stack, random::Random};
-
-const REMOTE_HOST: [u8; 4] = [10, 190, 30, 14];
-const REMOTE_PORT: u16 = 1883;
+use crate::{
+    build_info::BUILD_INFO,
+    datalog::{self, Position},
+    dsmr::Reading,
+    events::Alarm,
+    homeassistant, json,
+    metrics::{self, Diagnostics},
+    network::client::TcpClient,
+    network::stack,
+    p1,
+    random::Random,
+    telemetry::{Sample, Telemetry},
+    uplink::{Class, Message, QueueStats, UplinkQueue},
+};
 
 const BACKOFF_CAP: u32 = 400000;
 const INITIAL_BACKOFF: u32 = 1000;
 
+const TELEGRAM_SZ: usize = 512;
+const READING_SZ: usize = 2048;
+const SAMPLE_SZ: usize = 2048;
+const ALARM_SZ: usize = 256;
+// Like the output of `stats` in the console.
+const DIAGNOSTICS_SZ: usize = 4096;
+// Room for the topic and the headers of a publish packet.
+const PUBLISH_OVERHEAD: usize = 128;
+
 const KEEPALIVE: u16 = 30;
 
-const CLIENT_ID: &str = "smart-meter-reader";
+/// The quality of service to publish with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Qos {
+    AtMostOnce,
+    /// The broker acknowledges every publish. Alarms and telegrams that were
+    /// not acknowledged before the connection was lost are published again
+    /// after reconnecting, the telegram unless a newer one has been received
+    /// since.
+    AtLeastOnce,
+}
 
-const STATUS_TOPIC: &str = "smart_meter/status";
-const USAGE_TOPIC: &str = "smart_meter/usage";
+#[derive(Copy, Clone, Debug)]
+pub struct MqttConfig {
+    pub broker_addr: [u8; 4],
+    pub broker_port: u16,
+    pub client_id: &'static str,
+    /// Set to `online` once connected, and to `offline` by the broker when
+    /// the connection is lost.
+    pub status_topic: &'static str,
+    /// Set to the version of the firmware, as JSON, once connected.
+    pub build_topic: &'static str,
+    /// Receives every alarm as JSON.
+    pub alarm_topic: &'static str,
+    /// Receives the diagnostics every so often, in the Prometheus text
+    /// exposition format, if set.
+    pub diagnostics_topic: Option<&'static str>,
+    /// Receives every telegram in the flat format of `Telegram::serialize`.
+    pub usage_topic: &'static str,
+    /// Receives every reading as JSON, and under `<reading_topic>/<name>`
+    /// every reading of each of the other P1 meters.
+    pub reading_topic: &'static str,
+    /// Receives the values of every metric of the `Telemetry` along with
+    /// every reading, as a flat JSON object, which the sensors announced to
+    /// Home Assistant read their state from.
+    pub sample_topic: &'static str,
+    /// Receives the readings that were logged to flash while the broker
+    /// could not be reached, oldest first, in the encoding of `binary`.
+    pub backlog_topic: &'static str,
+    pub qos: Qos,
+    /// Announces a sensor for every metric to Home Assistant under this
+    /// discovery prefix, which is usually `homeassistant`.
+    pub discovery_prefix: Option<&'static str>,
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum MqttState {
     Unconnected,
     Connecting,
     Connected,
+    /// Publishing the Home Assistant discovery config of the metric at this
+    /// index.
+    Announcing(usize),
     Ready,
     Invalid,
 }
@@ -55,13 +117,39 @@ impl Display for MqttState {
     }
 }
 
+// A telegram to publish, with its reading and the sample that was taken of
+// it, and whether it has been published before.
+struct Publication {
+    telegram: Telegram,
+    reading: Reading,
+    sample: Sample,
+    retry: bool,
+}
+
 pub struct MqttClient {
     handle: Option<SocketHandle>,
     connected: bool,
     next_backoff: u32,
     current_backoff: u32,
     mqtt_state: MqttState,
-    queued_telegram: Option<Telegram>,
+    config: MqttConfig,
+    telemetry: Telemetry,
+    queue: UplinkQueue<Publication, Diagnostics, Alarm>,
+    // The last telegram published with QoS 1, until all of its publishes
+    // have been acknowledged.
+    unacked_telegram: Option<Publication>,
+    // The last alarm published with QoS 1, until it has been acknowledged.
+    unacked_alarm: Option<(u16, Alarm)>,
+    // The latest readings of the other P1 meters, by their names, which are
+    // published after everything in `queue`, and are not retried.
+    queued_p1_readings: ArrayVec<[(&'static str, Reading); p1::MAX_METERS]>,
+    awaiting_acks: ArrayVec<[u16; 3]>,
+    // A logged reading to replay, and where it is logged.
+    queued_backlog: Option<(Position, ArrayVec<[u8; datalog::MAX_RECORD_SZ]>)>,
+    // A replayed reading that the broker has yet to acknowledge.
+    unacked_backlog: Option<(u16, Position)>,
+    replayed: Option<Position>,
+    next_packet_id: u16,
 }
 
 impl TcpClient for MqttClient {
@@ -84,8 +172,6 @@ impl TcpClient for MqttClient {
         // Because of this we track both states here.
         if socket.may_send() && !self.connected {
             self.connected = true;
-            self.next_backoff = INITIAL_BACKOFF;
-            self.current_backoff = 0;
             log::debug!(
                 "Connected {} -> {}, keepalive {:?}, timeout {:?}",
                 socket.local_endpoint(),
@@ -101,6 +187,31 @@ impl TcpClient for MqttClient {
                 socket.local_endpoint(),
                 socket.remote_endpoint()
             );
+            self.awaiting_acks.clear();
+            // Replayed readings stay logged until they are acknowledged, so
+            // they will be replayed again.
+            self.queued_backlog = None;
+            self.unacked_backlog = None;
+            if let Some((_, alarm)) = self.unacked_alarm.take() {
+                log::info!("Alarm was not acknowledged, publishing it again once reconnected");
+                self.queue.retry(Message::Alarm(alarm));
+            }
+            if let Some(mut unacked) = self.unacked_telegram.take() {
+                unacked.retry = true;
+                if self.queue.retry(Message::Reading(unacked)) {
+                    log::info!(
+                        "Telegram was not acknowledged, publishing it again once reconnected"
+                    );
+                }
+            }
+        }
+
+        if self.mqtt_state == MqttState::Invalid && socket.is_active() {
+            // Start over with a fresh connection, which is subject to the
+            // usual backoff.
+            log::warn!("Closing connection after MQTT protocol error");
+            socket.abort();
+            return;
         }
 
         if !socket.is_active() {
@@ -131,9 +242,36 @@ impl TcpClient for MqttClient {
             match self.mqtt_state {
                 MqttState::Unconnected => self.connect_mqtt(socket),
                 MqttState::Connected => self.send_status(socket),
+                MqttState::Announcing(index) => self.send_discovery(socket, index),
                 MqttState::Ready => {
-                    if let Some(telegram) = self.queued_telegram.take() {
-                        self.send_telegram(socket, telegram);
+                    let room = socket.send_capacity() - socket.send_queue();
+                    match self.queue.next_class() {
+                        // Waits for the broker to take what was sent before,
+                        // while newer readings replace the queued one.
+                        Some(class) if room < room_for(class) => {}
+                        // Alarms are published one at a time, in order.
+                        Some(Class::Alarm) if self.unacked_alarm.is_some() => {}
+                        Some(_) => match self.queue.pop() {
+                            Some(Message::Alarm(alarm)) => self.send_alarm(socket, alarm),
+                            Some(Message::Reading(publication)) => {
+                                self.send_telegram(socket, publication)
+                            }
+                            Some(Message::Diagnostics(diagnostics)) => {
+                                self.send_diagnostics(socket, diagnostics)
+                            }
+                            None => {}
+                        },
+                        None if !self.queued_p1_readings.is_empty() => {
+                            if room >= READING_SZ + PUBLISH_OVERHEAD {
+                                let (name, reading) = self.queued_p1_readings.remove(0);
+                                self.send_p1_reading(socket, name, reading);
+                            }
+                        }
+                        None => {
+                            if let Some((position, record)) = self.queued_backlog.take() {
+                                self.send_backlog(socket, position, record);
+                            }
+                        }
                     }
                 }
                 _ => {}
@@ -143,18 +281,29 @@ impl TcpClient for MqttClient {
 }
 
 impl MqttClient {
-    pub fn new() -> Self {
+    /// Publishes samples of the metrics of `telemetry`.
+    pub fn new(config: MqttConfig, telemetry: Telemetry) -> Self {
         Self {
             handle: None,
             connected: false,
             next_backoff: INITIAL_BACKOFF,
             current_backoff: 0,
             mqtt_state: MqttState::Unconnected,
-            queued_telegram: None,
+            config,
+            telemetry,
+            queue: UplinkQueue::new(),
+            unacked_telegram: None,
+            unacked_alarm: None,
+            queued_p1_readings: ArrayVec::new(),
+            awaiting_acks: ArrayVec::new(),
+            queued_backlog: None,
+            unacked_backlog: None,
+            replayed: None,
+            next_packet_id: 1,
         }
     }
 
-    fn connect_mqtt(&mut self, socket: SocketRef<TcpSocket>) {
+    fn connect_mqtt(&mut self, mut socket: SocketRef<TcpSocket>) {
         log::debug!("Creating MQTT connect request");
         self.mqtt_state = MqttState::Connecting;
         let mut flags = Flags::default();
@@ -167,10 +316,10 @@ impl MqttClient {
             flags,
             KEEPALIVE,
         );
-        let will = payload::connect::Will::new(STATUS_TOPIC, b"offline");
-        let payload = payload::connect::Connect::new(CLIENT_ID, Some(will), None, None);
+        let will = payload::connect::Will::new(self.config.status_topic, b"offline");
+        let payload = payload::connect::Connect::new(self.config.client_id, Some(will), None, None);
         match Packet::connect(header, payload) {
-            Ok(packet) => match self.send_packet(socket, packet) {
+            Ok(packet) => match self.send_packet(&mut socket, packet) {
                 Ok(_) => log::debug!("Sent MQTT connect request"),
                 Err(err) => log::warn!("Failed to send connect packet: {}", err),
             },
@@ -178,40 +327,296 @@ impl MqttClient {
         }
     }
 
-    pub fn send_status(&mut self, socket: SocketRef<TcpSocket>) {
-        self.send_pub(socket, STATUS_TOPIC, b"online");
-        log::debug!("MQTT State: Connected -> Ready");
-        self.mqtt_state = MqttState::Ready;
+    pub fn send_status(&mut self, mut socket: SocketRef<TcpSocket>) {
+        self.send_pub(&mut socket, self.config.status_topic, b"online", false);
+        let mut build = ArrayString::<[_; 256]>::new();
+        match BUILD_INFO.write_json(&mut build) {
+            Ok(()) => {
+                self.send_pub(
+                    &mut socket,
+                    self.config.build_topic,
+                    build.as_bytes(),
+                    false,
+                );
+            }
+            Err(_) => log::warn!("Build info is too large"),
+        }
+        if self.config.discovery_prefix.is_some() {
+            log::debug!("MQTT State: Connected -> Announcing");
+            self.mqtt_state = MqttState::Announcing(0);
+        } else {
+            log::debug!("MQTT State: Connected -> Ready");
+            self.mqtt_state = MqttState::Ready;
+        }
+    }
+
+    // Discovery configs are published one at a time, since all of them
+    // together don't fit in the socket's send buffer.
+    fn send_discovery(&mut self, mut socket: SocketRef<TcpSocket>, index: usize) {
+        let (prefix, metric) = match (
+            self.config.discovery_prefix,
+            self.telemetry.metrics().get(index),
+        ) {
+            (Some(prefix), Some(metric)) => (prefix, *metric),
+            _ => {
+                log::debug!("MQTT State: Announcing -> Ready");
+                self.mqtt_state = MqttState::Ready;
+                return;
+            }
+        };
+        let mut payload = ArrayString::<[_; 1024]>::new();
+        if socket.send_capacity() - socket.send_queue() < payload.capacity() + 256 {
+            return;
+        }
+        let mut topic = ArrayString::<[_; 128]>::new();
+        let written = homeassistant::write_topic(&mut topic, prefix, &self.config, &metric)
+            .and_then(|()| homeassistant::write_config(&mut payload, &self.config, &metric));
+        match written {
+            Ok(()) => {
+                self.send_pub(&mut socket, &topic, payload.as_bytes(), false);
+            }
+            Err(_) => log::warn!("Discovery config for {} is too large", metric.key()),
+        }
+        self.mqtt_state = MqttState::Announcing(index + 1);
     }
 
-    pub fn queue_telegram(&mut self, telegram: Telegram) {
-        self.queued_telegram = Some(telegram);
+    /// Queues `telegram`, and `sample` of the metrics, which was taken of
+    /// its `reading`.
+    pub fn queue_telegram(&mut self, telegram: Telegram, reading: Reading, sample: Sample) {
+        // Only the latest telegram is of interest, so it replaces any that
+        // still had to be published again.
+        self.queue.push(Message::Reading(Publication {
+            telegram,
+            reading,
+            sample,
+            retry: false,
+        }));
     }
 
-    fn send_telegram(&mut self, socket: SocketRef<TcpSocket>, telegram: Telegram) {
-        let mut content = ArrayString::<[_; 512]>::new();
+    /// Queues `reading` of the other P1 meter `name`, which replaces the one
+    /// of that meter that is still queued.
+    pub fn queue_p1_reading(&mut self, name: &'static str, reading: Reading) {
+        let queued = &mut self.queued_p1_readings;
+        match queued.iter_mut().find(|(other, _)| *other == name) {
+            Some((_, latest)) => *latest = reading,
+            None => {
+                let _ = queued.try_push((name, reading));
+            }
+        }
+    }
+
+    pub fn queue_alarm(&mut self, alarm: Alarm) {
+        self.queue.push(Message::Alarm(alarm));
+    }
+
+    /// Queues `diagnostics`, if there is a topic for them.
+    pub fn queue_diagnostics(&mut self, diagnostics: Diagnostics) {
+        if self.config.diagnostics_topic.is_some() {
+            self.queue.push(Message::Diagnostics(diagnostics));
+        }
+    }
+
+    /// What was lost from the queue of messages to publish.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Whether a logged reading can be queued for replay, which is only when
+    /// the broker is reachable and the previous one has been replayed.
+    pub fn wants_backlog(&self) -> bool {
+        self.mqtt_state == MqttState::Ready
+            && self.queued_backlog.is_none()
+            && self.unacked_backlog.is_none()
+            && self.replayed.is_none()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.mqtt_state == MqttState::Ready
+    }
+
+    pub fn queue_backlog(&mut self, position: Position, record: &[u8]) {
+        let mut queued = ArrayVec::new();
+        // Records are never larger than this.
+        let _ = queued.try_extend_from_slice(record);
+        self.queued_backlog = Some((position, queued));
+    }
+
+    /// Returns where the last replayed reading is logged, once the broker has
+    /// received it.
+    pub fn take_replayed(&mut self) -> Option<Position> {
+        self.replayed.take()
+    }
+
+    fn send_backlog(
+        &mut self,
+        mut socket: SocketRef<TcpSocket>,
+        position: Position,
+        record: ArrayVec<[u8; datalog::MAX_RECORD_SZ]>,
+    ) {
+        // Leave room for the topic and the packet's headers.
+        if socket.send_capacity() - socket.send_queue() < record.len() + 128 {
+            self.queued_backlog = Some((position, record));
+            return;
+        }
+        let topic = self.config.backlog_topic;
+        match self.send_pub(&mut socket, topic, &record, false) {
+            Some(id) => self.unacked_backlog = Some((id, position)),
+            // Either published with QoS 0, or it could not be encoded, which
+            // won't get any better by trying again.
+            None => self.replayed = Some(position),
+        }
+    }
+
+    fn send_alarm(&mut self, mut socket: SocketRef<TcpSocket>, alarm: Alarm) {
+        let mut content = ArrayString::<[_; ALARM_SZ]>::new();
+        match json::write_alarm(&mut content, &alarm) {
+            Ok(()) => {
+                let topic = self.config.alarm_topic;
+                if let Some(id) = self.send_pub(&mut socket, topic, content.as_bytes(), false) {
+                    self.unacked_alarm = Some((id, alarm));
+                }
+            }
+            Err(_) => log::warn!("Alarm does not fit in {} bytes", content.capacity()),
+        }
+    }
+
+    fn send_p1_reading(
+        &mut self,
+        mut socket: SocketRef<TcpSocket>,
+        name: &'static str,
+        reading: Reading,
+    ) {
+        let mut topic = ArrayString::<[_; 128]>::new();
+        let mut content = ArrayString::<[_; READING_SZ]>::new();
+        let written = write!(topic, "{}/{}", self.config.reading_topic, name)
+            .and_then(|()| json::write_reading(&mut content, &reading));
+        match written {
+            Ok(()) => {
+                self.send_pub(&mut socket, &topic, content.as_bytes(), false);
+            }
+            Err(_) => log::warn!("Reading of P1 meter {} is too large to publish", name),
+        }
+    }
+
+    fn send_diagnostics(&mut self, mut socket: SocketRef<TcpSocket>, diagnostics: Diagnostics) {
+        let topic = match self.config.diagnostics_topic {
+            Some(topic) => topic,
+            None => return,
+        };
+        // Only the metrics of the meter reader itself.
+        let telemetry = Telemetry::new();
+        let sample = telemetry.sample(None, diagnostics.reader_state());
+        let mut content = ArrayString::<[_; DIAGNOSTICS_SZ]>::new();
+        match metrics::write_metrics(&mut content, &telemetry, &sample, &[], &[], &diagnostics) {
+            // Not retried if it isn't acknowledged, newer ones will follow.
+            Ok(()) => {
+                self.send_pub(&mut socket, topic, content.as_bytes(), false);
+            }
+            Err(_) => log::warn!("Diagnostics do not fit in {} bytes", content.capacity()),
+        }
+    }
+
+    fn send_telegram(&mut self, mut socket: SocketRef<TcpSocket>, publication: Publication) {
+        let Publication {
+            telegram,
+            reading,
+            sample,
+            retry,
+        } = publication;
+        self.awaiting_acks.clear();
+
+        let mut content = ArrayString::<[_; TELEGRAM_SZ]>::new();
 
         telegram.serialize(&mut content);
 
-        self.send_pub(socket, USAGE_TOPIC, content.as_bytes());
+        let usage_id = self.send_pub(
+            &mut socket,
+            self.config.usage_topic,
+            content.as_bytes(),
+            retry,
+        );
+
+        let mut content = ArrayString::<[_; READING_SZ]>::new();
+        let reading_id = match json::write_reading(&mut content, &reading) {
+            Ok(()) => self.send_pub(
+                &mut socket,
+                self.config.reading_topic,
+                content.as_bytes(),
+                retry,
+            ),
+            Err(_) => {
+                log::warn!("Reading does not fit in {} bytes", content.capacity());
+                None
+            }
+        };
+
+        let mut content = ArrayString::<[_; SAMPLE_SZ]>::new();
+        let sample_id =
+            match json::write_sample(&mut content, &self.telemetry, &sample, reading.received_at) {
+                Ok(()) => self.send_pub(
+                    &mut socket,
+                    self.config.sample_topic,
+                    content.as_bytes(),
+                    retry,
+                ),
+                Err(_) => {
+                    log::warn!("Sample does not fit in {} bytes", content.capacity());
+                    None
+                }
+            };
+
+        self.awaiting_acks
+            .extend(usage_id.into_iter().chain(reading_id).chain(sample_id));
+        if !self.awaiting_acks.is_empty() {
+            self.unacked_telegram = Some(Publication {
+                telegram,
+                reading,
+                sample,
+                retry,
+            });
+        }
     }
 
-    fn send_pub(&mut self, socket: SocketRef<TcpSocket>, topic: &str, payload: &[u8]) {
+    /// Publishes `payload` with the configured QoS, returning the packet
+    /// identifier to expect an acknowledgement for, if any.
+    fn send_pub(
+        &mut self,
+        socket: &mut SocketRef<TcpSocket>,
+        topic: &str,
+        payload: &[u8],
+        retry: bool,
+    ) -> Option<u16> {
         log::info!("Publishing {} bytes to {}", payload.len(), topic);
-        let header = variable_header::publish::Publish::new(topic, None);
-
         let mut flags = PublishFlags::default();
         flags.set_retain(true);
+        let packet_id = match self.config.qos {
+            Qos::AtMostOnce => None,
+            Qos::AtLeastOnce => {
+                flags.set_qos(QoS::AtLeastOnce);
+                flags.set_dup(retry);
+                Some(self.packet_id())
+            }
+        };
+        let header = variable_header::publish::Publish::new(topic, packet_id);
+
         match Packet::publish(flags, header, payload).map(|p| self.send_packet(socket, p)) {
             Err(err) => log::warn!("Failed to encode publish packet: {}", err),
             Ok(Err(err)) => log::warn!("Failed to send publish packet: {}", err),
-            Ok(Ok(())) => {}
+            Ok(Ok(())) => return packet_id,
         }
+        None
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        // Zero is not a valid packet identifier.
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
     }
 
     fn send_packet(
         &mut self,
-        mut socket: SocketRef<TcpSocket>,
+        socket: &mut SocketRef<TcpSocket>,
         packet: Packet,
     ) -> smoltcp::Result<()> {
         log::info!("Sending {:?}: {:?}", packet.fixed_header().r#type(), packet);
@@ -231,6 +636,7 @@ impl MqttClient {
         log::debug!("{:#?}", packet);
         match packet.fixed_header().r#type() {
             PacketType::Connack => self.handle_connack(packet),
+            PacketType::Puback => self.handle_puback(packet),
             PacketType::Pingresp => {}
             _ => self.invalid_packet(packet),
         }
@@ -259,6 +665,10 @@ impl MqttClient {
                 connack::ReturnCode::Accepted => {
                     log::debug!("MQTT State: Connecting -> Connected");
                     self.mqtt_state = MqttState::Connected;
+                    // Only reset the backoff now, so that a broker that
+                    // refuses the connection isn't retried immediately.
+                    self.next_backoff = INITIAL_BACKOFF;
+                    self.current_backoff = 0;
                 }
                 other => {
                     log::warn!("MQTT Connection request denied: {:?}", other);
@@ -269,6 +679,43 @@ impl MqttClient {
         }
     }
 
+    fn handle_puback(&mut self, packet: Packet) {
+        match packet.variable_header() {
+            Some(VariableHeader::Puback(id)) => {
+                let id = id.packet_identifier();
+                if let Some((awaiting, _)) = self.unacked_alarm {
+                    if awaiting == id {
+                        self.unacked_alarm = None;
+                        return;
+                    }
+                }
+                if let Some((awaiting, position)) = self.unacked_backlog {
+                    if awaiting == id {
+                        self.unacked_backlog = None;
+                        self.replayed = Some(position);
+                        return;
+                    }
+                }
+                match self
+                    .awaiting_acks
+                    .iter()
+                    .position(|awaiting| *awaiting == id)
+                {
+                    Some(index) => {
+                        self.awaiting_acks.remove(index);
+                    }
+                    // The status message, or a telegram that was superseded.
+                    None => return,
+                }
+                if self.awaiting_acks.is_empty() {
+                    log::debug!("Telegram acknowledged");
+                    self.unacked_telegram = None;
+                }
+            }
+            _ => self.invalid_packet(packet),
+        }
+    }
+
     fn try_connect(&mut self, mut socket: SocketRef<TcpSocket>, random: &mut Random) {
         if self.current_backoff > 0 {
             self.current_backoff -= 1;
@@ -280,8 +727,8 @@ impl MqttClient {
         self.next_backoff = self.next_backoff.saturating_mul(2).min(BACKOFF_CAP);
 
         let local = stack::generate_local_port(random);
-        let remote = IpAddress::Ipv4(Ipv4Address(REMOTE_HOST));
-        let remote = IpEndpoint::new(remote, REMOTE_PORT);
+        let remote = IpAddress::Ipv4(Ipv4Address(self.config.broker_addr));
+        let remote = IpEndpoint::new(remote, self.config.broker_port);
         log::debug!(
             "Socket inactive, trying to connect 0.0.0.0:{} -> {}, backoff {} if connect fails",
             local,
@@ -294,3 +741,12 @@ impl MqttClient {
         }
     }
 }
+
+// What publishing a message of `class` takes in the send buffer, at most.
+fn room_for(class: Class) -> usize {
+    match class {
+        Class::Alarm => ALARM_SZ + PUBLISH_OVERHEAD,
+        Class::Reading => TELEGRAM_SZ + READING_SZ + SAMPLE_SZ + 3 * PUBLISH_OVERHEAD,
+        Class::Diagnostics => DIAGNOSTICS_SZ + PUBLISH_OVERHEAD,
+    }
+}
@@ -1,5 +1,7 @@
 pub mod client;
 pub mod driver;
 pub mod stack;
+#[cfg(feature = "w5500")]
+pub mod w5500;
 
 pub use stack::BackingStore;
@@ -1,13 +1,20 @@
 use smoltcp::{
     iface::EthernetInterface,
     phy,
-    socket::{SocketHandle, SocketRef, TcpSocket},
+    socket::{SocketHandle, SocketRef, TcpSocket, UdpPacketMetadata, UdpSocket},
 };
 
 use crate::random::Random;
 
 const RX_BUF_SZ: usize = 4096;
-const TX_BUF_SZ: usize = 4096;
+// Large enough for a full HTTP response.
+const TX_BUF_SZ: usize = 8192;
+
+const UDP_RX_BUF_SZ: usize = 1024;
+// Large enough for an mDNS announcement.
+const UDP_TX_BUF_SZ: usize = 1024;
+const UDP_RX_MET_SZ: usize = 4;
+const UDP_TX_MET_SZ: usize = 4;
 
 pub trait TcpClient {
     fn set_socket_handle(&mut self, handle: SocketHandle);
@@ -34,3 +41,34 @@ impl TcpClientStore {
         }
     }
 }
+
+pub trait UdpClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle);
+    fn get_socket_handle(&mut self) -> SocketHandle;
+    fn poll<DeviceT>(
+        &mut self,
+        interface: &mut EthernetInterface<DeviceT>,
+        socket: SocketRef<UdpSocket>,
+        now: i64,
+        random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>;
+}
+
+pub struct UdpClientStore {
+    pub rx_metadata: [UdpPacketMetadata; UDP_RX_MET_SZ],
+    pub tx_metadata: [UdpPacketMetadata; UDP_TX_MET_SZ],
+    pub rx_buffer: [u8; UDP_RX_BUF_SZ],
+    pub tx_buffer: [u8; UDP_TX_BUF_SZ],
+}
+
+impl UdpClientStore {
+    pub fn new() -> Self {
+        UdpClientStore {
+            rx_metadata: [UdpPacketMetadata::EMPTY; UDP_RX_MET_SZ],
+            tx_metadata: [UdpPacketMetadata::EMPTY; UDP_TX_MET_SZ],
+            rx_buffer: [0; UDP_RX_BUF_SZ],
+            tx_buffer: [0; UDP_TX_BUF_SZ],
+        }
+    }
+}
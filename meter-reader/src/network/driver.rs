@@ -1,12 +1,19 @@
 #![allow(deprecated)] // Required because enc28j60 depends on v1.
 
-use core::result::Result;
+#[cfg(all(feature = "enc28j60", feature = "w5500"))]
+compile_error!("Enable only one of the `enc28j60` and `w5500` features");
+#[cfg(not(any(feature = "enc28j60", feature = "w5500")))]
+compile_error!("Enable one of the `enc28j60` and `w5500` features");
 
+use core::{fmt::Debug, result::Result};
+
+use embedded_hal::blocking::spi::{Transfer, Write};
+#[cfg(feature = "enc28j60")]
 use embedded_hal::{
     blocking::spi::{transfer, write},
-    blocking::spi::{Transfer, Write},
     digital::v1::OutputPin,
 };
+#[cfg(feature = "enc28j60")]
 use enc28j60::Enc28j60;
 use smoltcp::{
     phy::{self, ChecksumCapabilities, DeviceCapabilities},
@@ -14,27 +21,46 @@ use smoltcp::{
 };
 use teensy4_bsp::SysTick;
 
+#[cfg(feature = "w5500")]
+use super::w5500::{self, W5500};
+
+#[cfg(feature = "enc28j60")]
 const TX_BUF: usize = enc28j60::MAX_FRAME_LENGTH as usize;
+#[cfg(feature = "enc28j60")]
 const RX_BUF: usize = enc28j60::BUF_SZ as usize - TX_BUF;
 // smoltcp's RX buffer is ENC28J60's RX buffer minus "a little bit".
 // This should reduce the likelihood of smoltcp announcing a window size in
 // excess of what ENC28J60 can store.
+#[cfg(feature = "enc28j60")]
+const PHY_RX_BUF: usize = RX_BUF - BUF_TOLERANCE;
+#[cfg(feature = "enc28j60")]
 const BUF_TOLERANCE: usize = 256;
 
-type DriverError = enc28j60::Error<teensy4_bsp::hal::spi::Error>;
+// The W5500 queues up frames in its own 16 KiB, so a frame at a time is
+// enough here.
+#[cfg(feature = "w5500")]
+const TX_BUF: usize = w5500::MAX_FRAME_SZ;
+#[cfg(feature = "w5500")]
+const PHY_RX_BUF: usize = w5500::MAX_FRAME_SZ;
+
+#[cfg(feature = "enc28j60")]
 type SpiError = teensy4_bsp::hal::spi::Error;
 
 // This trait isn't meant to be a generic abstraction over any network driver,
 // it's just here so we can program our smoltcp glue against a simple trait
-// instead of the generic soup resulting from Enc28j60 and its trait bounds.
+// instead of the generic soup resulting from the drivers and their trait
+// bounds. The one that is used is chosen by cargo feature.
 pub trait Driver: 'static {
-    fn pending_packets(&mut self) -> Result<u8, SpiError>;
+    type Error: Debug;
 
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, SpiError>;
+    fn pending_packets(&mut self) -> Result<u8, Self::Error>;
 
-    fn transmit(&mut self, buffer: &[u8]) -> Result<(), DriverError>;
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, Self::Error>;
+
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), Self::Error>;
 }
 
+#[cfg(feature = "enc28j60")]
 impl<SPI, NCS, INT, RESET> Driver for Enc28j60<SPI, NCS, INT, RESET>
 where
     SPI: Transfer<u8, Error = SpiError> + Write<u8, Error = SpiError> + 'static,
@@ -42,13 +68,15 @@ where
     INT: enc28j60::IntPin + 'static,
     RESET: enc28j60::ResetPin + 'static,
 {
+    type Error = enc28j60::Error<SpiError>;
+
     #[inline]
-    fn pending_packets(&mut self) -> Result<u8, SpiError> {
-        Enc28j60::pending_packets(self)
+    fn pending_packets(&mut self) -> Result<u8, Self::Error> {
+        Enc28j60::pending_packets(self).map_err(enc28j60::Error::Spi)
     }
 
     #[inline]
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, SpiError> {
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, Self::Error> {
         log::trace!("Requesting next packet from device");
         match Enc28j60::receive(self, buffer) {
             Ok(recv) => {
@@ -61,13 +89,13 @@ where
             }
             Err(err) => {
                 log::warn!("Receive failed: {:?}", err);
-                Err(err)
+                Err(enc28j60::Error::Spi(err))
             }
         }
     }
 
     #[inline]
-    fn transmit(&mut self, buffer: &[u8]) -> Result<(), DriverError> {
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), Self::Error> {
         log::trace!("Sending {} bytes to device", buffer.len());
         match Enc28j60::transmit(self, buffer) {
             Ok(()) => {
@@ -82,6 +110,42 @@ where
     }
 }
 
+#[cfg(feature = "w5500")]
+impl<SPI, NCS, E> Driver for W5500<SPI, NCS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'static,
+    NCS: embedded_hal::digital::v2::OutputPin<Error = core::convert::Infallible> + 'static,
+    E: Debug + 'static,
+{
+    type Error = w5500::Error<E>;
+
+    #[inline]
+    fn pending_packets(&mut self) -> Result<u8, Self::Error> {
+        Ok(self.has_frame()? as u8)
+    }
+
+    #[inline]
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, Self::Error> {
+        log::trace!("Requesting next packet from device");
+        let recv = W5500::receive(self, buffer).map_err(|err| {
+            log::warn!("Receive failed: {:?}", err);
+            err
+        })?;
+        log::trace!("Got next packet from device, {} bytes", recv);
+        Ok(recv)
+    }
+
+    #[inline]
+    fn transmit(&mut self, buffer: &[u8]) -> Result<(), Self::Error> {
+        log::trace!("Sending {} bytes to device", buffer.len());
+        W5500::transmit(self, buffer).map_err(|err| {
+            log::warn!("Failed to send {} bytes to device", buffer.len());
+            err
+        })
+    }
+}
+
+#[cfg(feature = "enc28j60")]
 pub fn create_enc28j60<SPI, PNCS, PRST>(
     delay: &mut SysTick,
     spi: SPI,
@@ -121,25 +185,61 @@ where
     }
 }
 
-pub struct Enc28j60Phy<D: Driver> {
-    rx_buffer: [u8; RX_BUF - BUF_TOLERANCE],
+#[cfg(feature = "w5500")]
+pub fn create_w5500<SPI, PNCS, PRST, E>(
+    delay: &mut SysTick,
+    spi: SPI,
+    ncs: PNCS,
+    mut rst: PRST,
+    addr: [u8; 6],
+) -> W5500<SPI, PNCS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    PNCS: embedded_hal::digital::v2::OutputPin<Error = core::convert::Infallible>,
+    PRST: embedded_hal::digital::v2::OutputPin<Error = core::convert::Infallible>,
+    E: Debug,
+{
+    log::debug!("Initialising W5500 driver");
+    // Hold reset for at least 500 us, and give the PLL 1 ms to lock.
+    let _ = rst.set_low();
+    delay.delay(1);
+    let _ = rst.set_high();
+    delay.delay(2);
+
+    match W5500::new(spi, ncs, addr) {
+        Ok(mut w5500) => {
+            match w5500.is_link_up() {
+                Ok(up) => log::debug!("W5500 setup done, link up: {}", up),
+                Err(err) => log::warn!("Failed to read W5500 link state: {:?}", err),
+            }
+            w5500
+        }
+        Err(err) => {
+            log::error!("Failed to initialise W5500: {:?}", err);
+            panic!();
+        }
+    }
+}
+
+pub struct Phy<D: Driver> {
+    rx_buffer: [u8; PHY_RX_BUF],
     tx_buffer: [u8; TX_BUF],
     driver: D,
 }
 
-impl<D: Driver> Enc28j60Phy<D> {
+impl<D: Driver> Phy<D> {
     pub fn new(driver: D) -> Self {
         Self {
-            rx_buffer: [0; RX_BUF - BUF_TOLERANCE],
+            rx_buffer: [0; PHY_RX_BUF],
             tx_buffer: [0; TX_BUF],
             driver,
         }
     }
 }
 
-impl<'a, D: 'a + Driver> phy::Device<'a> for Enc28j60Phy<D> {
-    type RxToken = Enc28j60RxToken<'a>;
-    type TxToken = Enc28j60TxToken<'a, D>;
+impl<'a, D: 'a + Driver> phy::Device<'a> for Phy<D> {
+    type RxToken = PhyRxToken<'a>;
+    type TxToken = PhyTxToken<'a, D>;
 
     // DeviceCapabilities contains a private field, so we can't apply this suggestion
     #[allow(clippy::field_reassign_with_default)]
@@ -164,10 +264,10 @@ impl<'a, D: 'a + Driver> phy::Device<'a> for Enc28j60Phy<D> {
                 .map_err(|e| log::warn!("Failed to receive packet from driver: {:?}", e))
                 .ok()?;
             Some((
-                Enc28j60RxToken {
+                PhyRxToken {
                     buffer: &mut self.rx_buffer,
                 },
-                Enc28j60TxToken {
+                PhyTxToken {
                     buffer: &mut self.tx_buffer,
                     driver: &mut self.driver,
                 },
@@ -178,18 +278,18 @@ impl<'a, D: 'a + Driver> phy::Device<'a> for Enc28j60Phy<D> {
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(Enc28j60TxToken {
+        Some(PhyTxToken {
             buffer: &mut self.tx_buffer,
             driver: &mut self.driver,
         })
     }
 }
 
-pub struct Enc28j60RxToken<'a> {
+pub struct PhyRxToken<'a> {
     buffer: &'a mut [u8],
 }
 
-impl<'a> phy::RxToken for Enc28j60RxToken<'a> {
+impl<'a> phy::RxToken for PhyRxToken<'a> {
     fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
@@ -198,12 +298,12 @@ impl<'a> phy::RxToken for Enc28j60RxToken<'a> {
     }
 }
 
-pub struct Enc28j60TxToken<'a, D> {
+pub struct PhyTxToken<'a, D> {
     buffer: &'a mut [u8],
     driver: &'a mut D,
 }
 
-impl<'a, D: Driver> phy::TxToken for Enc28j60TxToken<'a, D> {
+impl<'a, D: Driver> phy::TxToken for PhyTxToken<'a, D> {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
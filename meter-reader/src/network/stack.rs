@@ -5,13 +5,19 @@ use smoltcp::{
     iface::{EthernetInterface, EthernetInterfaceBuilder, Neighbor, NeighborCache, Route, Routes},
     socket::{
         RawPacketMetadata, RawSocketBuffer, SocketSet, SocketSetItem, TcpSocket, TcpSocketBuffer,
+        UdpSocket, UdpSocketBuffer,
     },
-    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address},
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr},
 };
 
-use crate::{clock::Clock, network::driver::Driver, Enc28j60Phy, Random};
+use crate::{
+    clock::Clock,
+    config::IpConfig,
+    network::driver::{Driver, Phy},
+    Random,
+};
 
-use super::client::{TcpClient, TcpClientStore};
+use super::client::{TcpClient, TcpClientStore, UdpClient, UdpClientStore};
 
 const EPHEMERAL_PORT_START: u16 = 49152;
 const EPHEMERAL_PORT_COUNT: u16 = 16383;
@@ -23,7 +29,11 @@ const DHCP_TX_MET_SZ: usize = 4;
 
 const NEIGH_CACHE_SZ: usize = 64;
 
-const SOCKET_STORE_SZ: usize = 2;
+// mDNS.
+const MULTICAST_GROUPS_SZ: usize = 1;
+
+// DHCP, MQTT, HTTP, SNTP, InfluxDB, mDNS, syslog and OTA updates.
+const SOCKET_STORE_SZ: usize = 8;
 
 pub struct BackingStore<'store> {
     dhcp_rx_buffer: [u8; DHCP_RX_BUF_SZ],
@@ -33,6 +43,7 @@ pub struct BackingStore<'store> {
     neigh_cache: [Option<(IpAddress, Neighbor)>; NEIGH_CACHE_SZ],
     address_store: [IpCidr; 1],
     route_store: [Option<(IpCidr, Route)>; 1],
+    multicast_store: [Option<(Ipv4Address, ())>; MULTICAST_GROUPS_SZ],
     socket_store: [Option<SocketSetItem<'store, 'store>>; SOCKET_STORE_SZ],
 }
 
@@ -46,14 +57,15 @@ impl<'store> BackingStore<'store> {
             neigh_cache: [None; NEIGH_CACHE_SZ],
             address_store: [IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)],
             route_store: [None; 1],
+            multicast_store: [None; MULTICAST_GROUPS_SZ],
             socket_store: Default::default(),
         }
     }
 }
 
 pub struct NetworkStack<'store, D: Driver> {
-    interface: EthernetInterface<'store, 'store, 'store, Enc28j60Phy<D>>,
-    dhcp_client: Dhcpv4Client,
+    interface: EthernetInterface<'store, 'store, 'store, Phy<D>>,
+    dhcp_client: Option<Dhcpv4Client>,
     sockets: SocketSet<'store, 'store, 'store>,
 }
 
@@ -63,9 +75,10 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
         clock: &mut Clock,
         store: &'store mut BackingStore<'store>,
         addr: [u8; 6],
+        ip_config: IpConfig,
     ) -> NetworkStack<'store, D> {
         log::info!("Starting network setup");
-        let device = Enc28j60Phy::new(driver);
+        let device = Phy::new(driver);
         let eth_addr = EthernetAddress(addr);
         let neigh_cache = NeighborCache::new(&mut store.neigh_cache[..]);
         let routes = Routes::new(&mut store.route_store[..]);
@@ -75,30 +88,49 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
             .neighbor_cache(neigh_cache)
             .ip_addrs(&mut store.address_store[..])
             .routes(routes)
+            .ipv4_multicast_groups(&mut store.multicast_store[..])
             .finalize();
 
-        let dhcp_rx_buffer = RawSocketBuffer::new(
-            &mut store.dhcp_tx_metadata[..],
-            &mut store.dhcp_rx_buffer[..],
-        );
-        let dhcp_tx_buffer = RawSocketBuffer::new(
-            &mut store.dhcp_rx_metadata[..],
-            &mut store.dhcp_tx_buffer[..],
-        );
         let mut sockets = SocketSet::new(&mut store.socket_store[..]);
 
-        let dhcp_client = Dhcpv4Client::new(
-            &mut sockets,
-            dhcp_rx_buffer,
-            dhcp_tx_buffer,
-            clock.instant(),
-        );
+        let dhcp_client = match ip_config {
+            IpConfig::Dhcp => {
+                let dhcp_rx_buffer = RawSocketBuffer::new(
+                    &mut store.dhcp_tx_metadata[..],
+                    &mut store.dhcp_rx_buffer[..],
+                );
+                let dhcp_tx_buffer = RawSocketBuffer::new(
+                    &mut store.dhcp_rx_metadata[..],
+                    &mut store.dhcp_tx_buffer[..],
+                );
+                Some(Dhcpv4Client::new(
+                    &mut sockets,
+                    dhcp_rx_buffer,
+                    dhcp_tx_buffer,
+                    clock.instant(),
+                ))
+            }
+            IpConfig::Static { .. } => None,
+        };
 
-        Self {
+        let mut stack = Self {
             interface,
             dhcp_client,
             sockets,
+        };
+        if let IpConfig::Static {
+            address,
+            prefix_len,
+            gateway,
+        } = ip_config
+        {
+            log::info!("Using static IP configuration");
+            stack.configure(
+                Ipv4Cidr::new(Ipv4Address(address), prefix_len),
+                Ipv4Address(gateway),
+            );
         }
+        stack
     }
 
     pub fn add_client<C: TcpClient>(&mut self, client: &mut C, store: &'store mut TcpClientStore) {
@@ -109,6 +141,26 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
         client.set_socket_handle(self.sockets.add(socket));
     }
 
+    pub fn add_udp_client<C: UdpClient>(
+        &mut self,
+        client: &mut C,
+        store: &'store mut UdpClientStore,
+    ) {
+        let socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut store.rx_metadata[..], &mut store.rx_buffer[..]),
+            UdpSocketBuffer::new(&mut store.tx_metadata[..], &mut store.tx_buffer[..]),
+        );
+        client.set_socket_handle(self.sockets.add(socket));
+    }
+
+    /// Receives packets sent to `group` from now on.
+    pub fn join_multicast_group(&mut self, clock: &mut Clock, group: Ipv4Address) {
+        match self.interface.join_multicast_group(group, clock.instant()) {
+            Ok(_) => log::info!("Joined multicast group {}", group),
+            Err(err) => log::warn!("Failed to join multicast group {}: {}", group, err),
+        }
+    }
+
     pub fn poll(&mut self, clock: &mut Clock) -> Option<i64> {
         match self.interface.poll(&mut self.sockets, clock.instant()) {
             Ok(processed) if processed => {
@@ -119,10 +171,11 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
             }
             _ => {}
         }
-        match self
-            .dhcp_client
-            .poll(&mut self.interface, &mut self.sockets, clock.instant())
-        {
+        let dhcp = match self.dhcp_client.as_mut() {
+            Some(client) => client.poll(&mut self.interface, &mut self.sockets, clock.instant()),
+            None => Ok(None),
+        };
+        match dhcp {
             Ok(Some(config)) => self.handle_dhcp(config),
             Err(err) if err == smoltcp::Error::Malformed => {
                 // This will happen from time to time on most networks,
@@ -152,6 +205,21 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
         }
     }
 
+    pub fn poll_udp_client<C: UdpClient>(
+        &mut self,
+        clock: &mut Clock,
+        random: &mut Random,
+        client: &mut C,
+    ) {
+        // Same as with TCP clients.
+        let addr = self.interface.ipv4_addr();
+        if addr.is_some() && !addr.unwrap().is_unspecified() {
+            let socket = client.get_socket_handle();
+            let socket = self.sockets.get(socket);
+            client.poll(&mut self.interface, socket, clock.millis(), random);
+        }
+    }
+
     fn handle_dhcp(&mut self, cfg: Dhcpv4Config) {
         log::info!(
             "Received DHCP configuration: {:?} via {:?}, DNS {:?}",
@@ -165,27 +233,7 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
                 address: Some(cidr),
                 router: Some(router),
                 ..
-            } => {
-                self.interface.update_ip_addrs(|addrs| {
-                    let addr = addrs.iter_mut().next().unwrap();
-                    log::info!("Received CIDR: {}", cidr);
-                    *addr = IpCidr::Ipv4(cidr);
-                });
-                if let Some(prev_route) = self
-                    .interface
-                    .routes_mut()
-                    .add_default_ipv4_route(router)
-                    .unwrap()
-                {
-                    log::info!(
-                        "Replaced previous route {} with {}",
-                        prev_route.via_router,
-                        router
-                    );
-                } else {
-                    log::info!("Added new default route via {}", router);
-                }
-            }
+            } => self.configure(cidr, router),
             cfg => {
                 log::warn!(
                     "DHCP configuration did not contain address or DNS: {:?}",
@@ -194,6 +242,28 @@ impl<'store, D: Driver> NetworkStack<'store, D> {
             }
         }
     }
+
+    fn configure(&mut self, cidr: Ipv4Cidr, router: Ipv4Address) {
+        self.interface.update_ip_addrs(|addrs| {
+            let addr = addrs.iter_mut().next().unwrap();
+            log::info!("Using CIDR: {}", cidr);
+            *addr = IpCidr::Ipv4(cidr);
+        });
+        if let Some(prev_route) = self
+            .interface
+            .routes_mut()
+            .add_default_ipv4_route(router)
+            .unwrap()
+        {
+            log::info!(
+                "Replaced previous route {} with {}",
+                prev_route.via_router,
+                router
+            );
+        } else {
+            log::info!("Added new default route via {}", router);
+        }
+    }
 }
 
 #[inline]
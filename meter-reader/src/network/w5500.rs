@@ -0,0 +1,231 @@
+//! The WIZnet W5500, with socket 0 in MACRAW mode, so that it sends and
+//! receives whole Ethernet frames, and smoltcp does the rest, as with the
+//! ENC28J60. Socket 0 is given all 16 KiB of the receive and transmit
+//! buffers, since it is the only socket that is used.
+//!
+//! Every access is a single SPI frame of a 16-bit address, a control byte
+//! that selects the block and the direction, and the data, framed by chip
+//! select.
+
+use core::convert::Infallible;
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+/// The largest frame, without the FCS, which the W5500 handles.
+pub const MAX_FRAME_SZ: usize = 1514;
+
+const BLOCK_COMMON: u8 = 0;
+const BLOCK_SOCKET0: u8 = 1;
+const BLOCK_TX0: u8 = 2;
+const BLOCK_RX0: u8 = 3;
+const CONTROL_WRITE: u8 = 1 << 2;
+
+const MR: u16 = 0x0000;
+const MR_RST: u8 = 1 << 7;
+const SHAR: u16 = 0x0009;
+const PHYCFGR: u16 = 0x002E;
+const PHYCFGR_LNK: u8 = 1 << 0;
+const VERSIONR: u16 = 0x0039;
+const VERSION: u8 = 0x04;
+
+const SN_MR: u16 = 0x0000;
+// MACRAW, without the MAC filter, which would also drop the multicast frames
+// of mDNS. smoltcp drops what isn't for us.
+const SN_MR_MACRAW: u8 = 0x04;
+const SN_CR: u16 = 0x0001;
+const SN_CR_OPEN: u8 = 0x01;
+const SN_CR_SEND: u8 = 0x20;
+const SN_CR_RECV: u8 = 0x40;
+const SN_SR: u16 = 0x0003;
+const SOCK_MACRAW: u8 = 0x42;
+const SN_RXBUF_SIZE: u16 = 0x001E;
+const SN_TXBUF_SIZE: u16 = 0x001F;
+const SN_TX_FSR: u16 = 0x0020;
+const SN_TX_WR: u16 = 0x0024;
+const SN_RX_RSR: u16 = 0x0026;
+const SN_RX_RD: u16 = 0x0028;
+const SOCKETS: u8 = 8;
+// In KiB.
+const SOCKET0_BUF_KB: u8 = 16;
+// Register reads before giving up on a command or a reset.
+const MAX_POLLS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Spi(E),
+    /// The version register didn't read as a W5500, so there is none, or
+    /// it isn't wired up right.
+    NotFound(u8),
+    /// A reset or a command didn't finish.
+    Timeout,
+    /// Socket 0 didn't open in MACRAW mode.
+    NotOpen(u8),
+    /// A frame that is larger than `MAX_FRAME_SZ`, or the buffer it is
+    /// received in.
+    FrameTooLarge(usize),
+}
+
+pub struct W5500<SPI, NCS> {
+    spi: SPI,
+    ncs: NCS,
+}
+
+impl<SPI, NCS, E> W5500<SPI, NCS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    NCS: OutputPin<Error = Infallible>,
+{
+    /// Resets the chip, sets its MAC address to `addr`, and opens socket 0.
+    pub fn new(spi: SPI, mut ncs: NCS, addr: [u8; 6]) -> Result<Self, Error<E>> {
+        let _ = ncs.set_high();
+        let mut w5500 = Self { spi, ncs };
+        let version = w5500.read_u8(BLOCK_COMMON, VERSIONR)?;
+        if version != VERSION {
+            return Err(Error::NotFound(version));
+        }
+        w5500.write(BLOCK_COMMON, MR, &[MR_RST])?;
+        w5500.wait_until(|w5500| Ok(w5500.read_u8(BLOCK_COMMON, MR)? & MR_RST == 0))?;
+        w5500.write(BLOCK_COMMON, SHAR, &addr)?;
+        for socket in 0..SOCKETS {
+            let size = if socket == 0 { SOCKET0_BUF_KB } else { 0 };
+            let block = socket * 4 + BLOCK_SOCKET0;
+            w5500.write(block, SN_RXBUF_SIZE, &[size])?;
+            w5500.write(block, SN_TXBUF_SIZE, &[size])?;
+        }
+        w5500.write(BLOCK_SOCKET0, SN_MR, &[SN_MR_MACRAW])?;
+        w5500.command(SN_CR_OPEN)?;
+        let status = w5500.read_u8(BLOCK_SOCKET0, SN_SR)?;
+        if status != SOCK_MACRAW {
+            return Err(Error::NotOpen(status));
+        }
+        Ok(w5500)
+    }
+
+    pub fn is_link_up(&mut self) -> Result<bool, Error<E>> {
+        Ok(self.read_u8(BLOCK_COMMON, PHYCFGR)? & PHYCFGR_LNK != 0)
+    }
+
+    /// Whether a frame has been received.
+    pub fn has_frame(&mut self) -> Result<bool, Error<E>> {
+        Ok(self.read_u16_stable(BLOCK_SOCKET0, SN_RX_RSR)? > 0)
+    }
+
+    /// Moves the next frame into `buffer`, and returns its length, or 0 if
+    /// none has been received.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<u16, Error<E>> {
+        if !self.has_frame()? {
+            return Ok(0);
+        }
+        let read_at = self.read_u16(BLOCK_SOCKET0, SN_RX_RD)?;
+        // Each frame starts with its length, which includes these 2 bytes.
+        let mut header = [0; 2];
+        self.read(BLOCK_RX0, read_at, &mut header)?;
+        let len = u16::from_be_bytes(header).saturating_sub(2);
+        let result = if len as usize > buffer.len() {
+            Err(Error::FrameTooLarge(len as usize))
+        } else {
+            self.read(
+                BLOCK_RX0,
+                read_at.wrapping_add(2),
+                &mut buffer[..len as usize],
+            )?;
+            Ok(len)
+        };
+        // A frame that doesn't fit is skipped, so the next one can be read.
+        let end = read_at.wrapping_add(2).wrapping_add(len);
+        self.write(BLOCK_SOCKET0, SN_RX_RD, &end.to_be_bytes())?;
+        self.command(SN_CR_RECV)?;
+        result
+    }
+
+    /// Sends `frame`, once there is room for it.
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<(), Error<E>> {
+        if frame.len() > MAX_FRAME_SZ {
+            return Err(Error::FrameTooLarge(frame.len()));
+        }
+        let len = frame.len() as u16;
+        self.wait_until(|w5500| Ok(w5500.read_u16_stable(BLOCK_SOCKET0, SN_TX_FSR)? >= len))?;
+        let write_at = self.read_u16(BLOCK_SOCKET0, SN_TX_WR)?;
+        self.write(BLOCK_TX0, write_at, frame)?;
+        self.write(
+            BLOCK_SOCKET0,
+            SN_TX_WR,
+            &write_at.wrapping_add(len).to_be_bytes(),
+        )?;
+        self.command(SN_CR_SEND)
+    }
+
+    // Runs a command of socket 0, which is cleared once it has been taken.
+    fn command(&mut self, command: u8) -> Result<(), Error<E>> {
+        self.write(BLOCK_SOCKET0, SN_CR, &[command])?;
+        self.wait_until(|w5500| Ok(w5500.read_u8(BLOCK_SOCKET0, SN_CR)? == 0))
+    }
+
+    fn wait_until<F>(&mut self, mut done: F) -> Result<(), Error<E>>
+    where
+        F: FnMut(&mut Self) -> Result<bool, Error<E>>,
+    {
+        for _ in 0..MAX_POLLS {
+            if done(self)? {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read_u8(&mut self, block: u8, address: u16) -> Result<u8, Error<E>> {
+        let mut value = [0];
+        self.read(block, address, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_u16(&mut self, block: u8, address: u16) -> Result<u16, Error<E>> {
+        let mut value = [0; 2];
+        self.read(block, address, &mut value)?;
+        Ok(u16::from_be_bytes(value))
+    }
+
+    // The free space and received size can change between the reads of
+    // their two bytes, so they are read until they read the same twice.
+    fn read_u16_stable(&mut self, block: u8, address: u16) -> Result<u16, Error<E>> {
+        let mut last = self.read_u16(block, address)?;
+        for _ in 0..MAX_POLLS {
+            let value = self.read_u16(block, address)?;
+            if value == last {
+                return Ok(value);
+            }
+            last = value;
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read(&mut self, block: u8, address: u16, buffer: &mut [u8]) -> Result<(), Error<E>> {
+        let [high, low] = address.to_be_bytes();
+        self.select(|spi| {
+            spi.write(&[high, low, block << 3])?;
+            spi.transfer(buffer)?;
+            Ok(())
+        })
+    }
+
+    fn write(&mut self, block: u8, address: u16, data: &[u8]) -> Result<(), Error<E>> {
+        let [high, low] = address.to_be_bytes();
+        self.select(|spi| {
+            spi.write(&[high, low, block << 3 | CONTROL_WRITE])?;
+            spi.write(data)
+        })
+    }
+
+    fn select<F>(&mut self, access: F) -> Result<(), Error<E>>
+    where
+        F: FnOnce(&mut SPI) -> Result<(), E>,
+    {
+        let _ = self.ncs.set_low();
+        let result = access(&mut self.spi);
+        let _ = self.ncs.set_high();
+        result.map_err(Error::Spi)
+    }
+}
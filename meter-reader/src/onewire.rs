@@ -0,0 +1,238 @@
+//! A bit-banged 1-Wire master, on a pin that is pulled up by a resistor of
+//! 4.7 kΩ to 3.3 V. The pin is only ever driven low, by making it an output,
+//! and released by making it an input again, so that a device can hold the
+//! bus low as well.
+//!
+//! Each time slot is timed with the cycle counter, with interrupts masked,
+//! since a slot that stretches can turn a 1 into a 0. A reset takes about a
+//! millisecond, and every bit about 70 µs, all of which block.
+
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use cortex_m::{interrupt, peripheral::DWT};
+use teensy4_bsp::hal::{
+    gpio::GPIO,
+    iomuxc::{
+        self, gpio::Pin, prelude::consts::Unsigned, Config, Hysteresis, PullKeep, PullKeepSelect,
+        PullUpDown, IOMUX,
+    },
+};
+
+use crate::cpu_clock;
+
+/// The family code, the serial number and the CRC of a device.
+pub type Rom = [u8; 8];
+
+pub const MAX_DEVICES: usize = 8;
+
+const SEARCH_ROM: u8 = 0xF0;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xCC;
+
+// Of GPIO1 to 4.
+const GPIO_BASES: [usize; 4] = [0x401B_8000, 0x401B_C000, 0x401C_0000, 0x401C_4000];
+const GDIR: usize = 0x04;
+const PSR: usize = 0x08;
+const DR_CLEAR: usize = 0x88;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OneWireError {
+    /// No device answered a reset.
+    NoPresence,
+    /// The bus was low before a reset, such as when it is shorted.
+    Stuck,
+    /// Devices answered a search inconsistently.
+    Search,
+    Crc,
+}
+
+pub struct OneWire {
+    base: usize,
+    mask: u32,
+}
+
+impl OneWire {
+    /// Releases `pin`, with the internal pull-up as well, which is too weak
+    /// for more than a device or two on its own. It must be on GPIO1 to 4,
+    /// which is where pins start out.
+    pub fn new<P: Pin + IOMUX>(mut pin: P) -> Self {
+        let config = Config::zero()
+            .set_hysteresis(Hysteresis::Enabled)
+            .set_pull_keep(PullKeep::Enabled)
+            .set_pull_keep_select(PullKeepSelect::Pull)
+            .set_pullupdown(PullUpDown::Pullup22k);
+        iomuxc::configure(&mut pin, config);
+        // Muxes the pad to the GPIO, which keeps the pin, since it isn't
+        // given back.
+        let _ = GPIO::new(pin);
+        let mut bus = Self {
+            base: GPIO_BASES[P::Module::USIZE - 1],
+            mask: 1 << P::Offset::USIZE,
+        };
+        unsafe { write(bus.base + DR_CLEAR, bus.mask) };
+        bus.release();
+        bus
+    }
+
+    /// Resets every device, which then waits for a ROM command.
+    pub fn reset(&mut self) -> Result<(), OneWireError> {
+        if !self.is_high() {
+            return Err(OneWireError::Stuck);
+        }
+        self.drive_low();
+        delay_us(480);
+        let present = interrupt::free(|_| {
+            self.release();
+            delay_us(70);
+            !self.is_high()
+        });
+        // The rest of the presence pulse, and the time to recover after it.
+        delay_us(410);
+        if present {
+            Ok(())
+        } else {
+            Err(OneWireError::NoPresence)
+        }
+    }
+
+    /// Resets the bus and addresses the device with `rom`, or every device
+    /// with `None`, such as to start a conversion on all of them at once.
+    pub fn select(&mut self, rom: Option<&Rom>) -> Result<(), OneWireError> {
+        self.reset()?;
+        match rom {
+            Some(rom) => {
+                self.write_byte(MATCH_ROM);
+                for byte in rom {
+                    self.write_byte(*byte);
+                }
+            }
+            None => self.write_byte(SKIP_ROM),
+        }
+        Ok(())
+    }
+
+    /// Finds the ROMs of the devices on the bus, in the order of their
+    /// serial numbers, as in Maxim's application note 187. Devices past
+    /// `MAX_DEVICES` are left out.
+    pub fn search(&mut self) -> Result<ArrayVec<[Rom; MAX_DEVICES]>, OneWireError> {
+        let mut roms = ArrayVec::new();
+        let mut rom = [0; 8];
+        // The bit, from 1, at which the last search took the 0 branch,
+        // where a 1 branch is left to take.
+        let mut last_discrepancy = 0;
+        loop {
+            self.reset()?;
+            self.write_byte(SEARCH_ROM);
+            let mut discrepancy = 0;
+            for bit in 1..=64 {
+                let (byte, mask) = ((bit - 1) / 8, 1 << ((bit - 1) % 8));
+                let direction = match (self.read_bit(), self.read_bit()) {
+                    (true, true) => return Err(OneWireError::Search),
+                    (id, complement) if id != complement => id,
+                    // Devices on both branches.
+                    _ => {
+                        let direction = if bit < last_discrepancy {
+                            rom[byte] & mask != 0
+                        } else {
+                            bit == last_discrepancy
+                        };
+                        if !direction {
+                            discrepancy = bit;
+                        }
+                        direction
+                    }
+                };
+                if direction {
+                    rom[byte] |= mask;
+                } else {
+                    rom[byte] &= !mask;
+                }
+                self.write_bit(direction);
+            }
+            if crc8(&rom[..7]) != rom[7] {
+                return Err(OneWireError::Crc);
+            }
+            if roms.try_push(rom).is_err() {
+                log::warn!("More than {} devices on the 1-Wire bus", MAX_DEVICES);
+                return Ok(roms);
+            }
+            last_discrepancy = discrepancy;
+            if last_discrepancy == 0 {
+                return Ok(roms);
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        for bit in 0..8 {
+            self.write_bit(byte & 1 << bit != 0);
+        }
+    }
+
+    pub fn read_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, bit| byte | (self.read_bit() as u8) << bit)
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        let (low_us, high_us) = if bit { (6, 64) } else { (60, 10) };
+        interrupt::free(|_| {
+            self.drive_low();
+            delay_us(low_us);
+            self.release();
+            delay_us(high_us);
+        });
+    }
+
+    fn read_bit(&mut self) -> bool {
+        interrupt::free(|_| {
+            self.drive_low();
+            delay_us(6);
+            self.release();
+            delay_us(9);
+            let bit = self.is_high();
+            delay_us(55);
+            bit
+        })
+    }
+
+    fn drive_low(&mut self) {
+        unsafe { write(self.base + GDIR, read(self.base + GDIR) | self.mask) };
+    }
+
+    fn release(&mut self) {
+        unsafe { write(self.base + GDIR, read(self.base + GDIR) & !self.mask) };
+    }
+
+    fn is_high(&self) -> bool {
+        unsafe { read(self.base + PSR) & self.mask != 0 }
+    }
+}
+
+/// The CRC-8 of 1-Wire devices, with the polynomial x^8 + x^5 + x^4 + 1,
+/// least significant bit first.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8C
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn delay_us(us: u32) {
+    let since = DWT::get_cycle_count();
+    let cycles = us * (cpu_clock::hz() / 1_000_000);
+    while DWT::get_cycle_count().wrapping_sub(since) < cycles {}
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,514 @@
+//! Firmware updates over the network, with rollback.
+//!
+//! The program area of the flash is split into two slots. The boot ROM always
+//! boots slot A, so slot B holds the update while it is downloaded by an
+//! `OtaClient`. Once its CRC checks out, it is staged in the state sector,
+//! and at the next boot, `boot` swaps the slots and resets into the update.
+//!
+//! The update then runs on trial, with the previous firmware kept in slot B,
+//! until `confirm` is called. A reset before that, such as by a panic or the
+//! watchdog, swaps the slots back.
+//!
+//! There is no bootloader of our own, so the swap is done by the firmware
+//! that is about to be replaced, running from ITCM. It takes a few seconds,
+//! and a power loss halfway leaves a mix of both images that doesn't boot,
+//! which takes reflashing over USB to recover from.
+//!
+//! An update is the binary image of the firmware, from the start of the
+//! flash, followed by a trailer of the magic `P1FW`, the length of the image
+//! and its CRC-32, each a little-endian `u32`. With a public key configured,
+//! the image must end in a signature block, which is checked by `signature`
+//! before the update is staged.
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::{
+    fmt::{self, Write},
+    ptr,
+};
+use cortex_m::{interrupt, peripheral::SCB};
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, TcpSocket},
+    time::Duration,
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    flash::{Flash, PROGRAM_SECTORS, SECTOR_SZ},
+    network::client::TcpClient,
+    network::stack,
+    random::Random,
+    signature,
+};
+
+/// The sectors of each slot. The program must fit in one.
+pub const SLOT_SECTORS: usize = PROGRAM_SECTORS / 2;
+const SLOT_A: usize = 0;
+const SLOT_B: usize = SLOT_SECTORS;
+const SLOT_SZ: usize = SLOT_SECTORS * SECTOR_SZ;
+
+const IMAGE_MAGIC: u32 = 0x5746_3150;
+const TRAILER_SZ: usize = 12;
+const STATE_MAGIC: u32 = 0x5055_3150;
+const STATE_HEADER_SZ: usize = 16;
+// Flags after the header of the state sector, which are cleared once the
+// slots have been swapped, the new firmware has booted, and it has been
+// confirmed.
+const SWAPPED: usize = STATE_HEADER_SZ;
+const BOOTED: usize = STATE_HEADER_SZ + 1;
+const CONFIRMED: usize = STATE_HEADER_SZ + 2;
+const ERASED: u8 = 0xFF;
+const CLEARED: u8 = 0x00;
+
+// Where the boot ROM finds the image vector table, whose boot data holds the
+// length of the running image.
+const FLASH_BASE: usize = 0x6000_0000;
+const IVT_ADDR: usize = 0x6000_1000;
+const IVT_BOOT_DATA: usize = 0x10;
+const BOOT_DATA_LEN: usize = 0x04;
+
+const HEAD_SZ: usize = 512;
+// Received, but not yet written to flash.
+const PENDING_SZ: usize = 4096;
+
+#[derive(Copy, Clone, Debug)]
+pub struct OtaConfig {
+    pub server_addr: [u8; 4],
+    pub server_port: u16,
+    /// Path of the update on the HTTP server.
+    pub path: &'static str,
+    /// Only updates signed with this Ed25519 key are staged.
+    pub public_key: Option<[u8; 32]>,
+}
+
+/// What `boot` found the previous boot left behind.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BootOutcome {
+    /// This is the first boot of an update, which is on trial.
+    Installed,
+    /// An update was rolled back, and this is the firmware from before it.
+    RolledBack,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Kind {
+    Install,
+    Rollback,
+}
+
+// The swap that the state sector describes.
+#[derive(Copy, Clone, Debug)]
+struct Swap {
+    kind: Kind,
+    sectors: usize,
+}
+
+/// Installs a staged update, or rolls back an update that was reset while on
+/// trial, and resets into the result. This must be called before anything
+/// else runs, since it may replace the running program. `state_sector` is
+/// the sector of `Flash` that updates are tracked in.
+pub fn boot(flash: &mut Flash, state_sector: usize) -> Option<BootOutcome> {
+    let swap = read_swap(flash, state_sector)?;
+    let flag = |flash: &Flash, offset| flash.read(state_sector, offset, 1)[0];
+    if flag(flash, SWAPPED) == ERASED {
+        swap_slots(flash, swap.sectors);
+        flash.program(state_sector, SWAPPED, &[CLEARED]);
+        SCB::sys_reset();
+    }
+    if flag(flash, BOOTED) == ERASED {
+        flash.program(state_sector, BOOTED, &[CLEARED]);
+        return Some(match swap.kind {
+            Kind::Install => BootOutcome::Installed,
+            Kind::Rollback => BootOutcome::RolledBack,
+        });
+    }
+    if swap.kind == Kind::Install && flag(flash, CONFIRMED) == ERASED {
+        // Reset while on trial.
+        write_swap(flash, state_sector, Kind::Rollback, swap.sectors);
+        swap_slots(flash, swap.sectors);
+        flash.program(state_sector, SWAPPED, &[CLEARED]);
+        SCB::sys_reset();
+    }
+    None
+}
+
+/// Keeps the running update, if it is on trial.
+pub fn confirm(flash: &mut Flash, state_sector: usize) {
+    if on_trial(flash, state_sector) {
+        flash.program(state_sector, CONFIRMED, &[CLEARED]);
+        log::info!("Update confirmed");
+    }
+}
+
+fn on_trial(flash: &Flash, state_sector: usize) -> bool {
+    let flags = flash.read(state_sector, SWAPPED, 3);
+    match read_swap(flash, state_sector) {
+        Some(swap) => swap.kind == Kind::Install && flags[2] == ERASED,
+        None => false,
+    }
+}
+
+fn read_swap(flash: &Flash, state_sector: usize) -> Option<Swap> {
+    let header = flash.read(state_sector, 0, STATE_HEADER_SZ);
+    let u32_at = |at: usize| {
+        u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+    };
+    let kind = match header[4] {
+        0 => Kind::Install,
+        1 => Kind::Rollback,
+        _ => return None,
+    };
+    let sectors = u32_at(8) as usize;
+    if u32_at(0) != STATE_MAGIC || sectors > SLOT_SECTORS {
+        return None;
+    }
+    Some(Swap { kind, sectors })
+}
+
+fn write_swap(flash: &mut Flash, state_sector: usize, kind: Kind, sectors: usize) {
+    let mut header = [0; STATE_HEADER_SZ];
+    header[0..4].copy_from_slice(&STATE_MAGIC.to_le_bytes());
+    header[4] = kind as u8;
+    header[8..12].copy_from_slice(&(sectors as u32).to_le_bytes());
+    flash.erase(state_sector);
+    flash.program(state_sector, 0, &header);
+}
+
+// Swaps the first `sectors` of both slots, with interrupts disabled, so that
+// nothing can run that reads from the flash while the image changes.
+fn swap_slots(flash: &mut Flash, sectors: usize) {
+    let mut a = [0; SECTOR_SZ];
+    let mut b = [0; SECTOR_SZ];
+    interrupt::free(|_| {
+        for sector in 0..sectors {
+            a.copy_from_slice(flash.read_program(SLOT_A + sector, 0, SECTOR_SZ));
+            b.copy_from_slice(flash.read_program(SLOT_B + sector, 0, SECTOR_SZ));
+            // The code doing this runs from ITCM, and reads nothing from
+            // the flash.
+            unsafe {
+                flash.erase_program(SLOT_A + sector);
+                flash.program_program(SLOT_A + sector, 0, &b);
+                flash.erase_program(SLOT_B + sector);
+                flash.program_program(SLOT_B + sector, 0, &a);
+            }
+        }
+    });
+}
+
+/// The length of the image that the boot ROM loaded, without its signature
+/// block.
+pub fn running_len() -> usize {
+    let boot_data = unsafe { ptr::read_volatile((IVT_ADDR + IVT_BOOT_DATA) as *const u32) };
+    let boot_data = boot_data as usize;
+    // Something is off if either doesn't lie within the slot, so then
+    // everything is swapped.
+    if boot_data < IVT_ADDR || boot_data >= FLASH_BASE + SLOT_SZ {
+        return SLOT_SZ;
+    }
+    let len = unsafe { ptr::read_volatile((boot_data + BOOT_DATA_LEN) as *const u32) };
+    (len as usize).min(SLOT_SZ)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    Requesting,
+    ReceivingHead,
+    /// Receiving an update of `len` bytes, including its trailer.
+    ReceivingBody {
+        len: usize,
+    },
+    Staged,
+}
+
+/// Downloads an update over HTTP into slot B, and stages it once it has
+/// been verified. The slots are only swapped at the next boot.
+pub struct OtaClient {
+    handle: Option<SocketHandle>,
+    config: OtaConfig,
+    state_sector: usize,
+    state: State,
+    requested: bool,
+    head: ArrayVec<[u8; HEAD_SZ]>,
+    pending: ArrayVec<[u8; PENDING_SZ]>,
+    received: usize,
+    written: usize,
+}
+
+impl TcpClient for OtaClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<TcpSocket>,
+        random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if !socket.is_active() {
+            match self.state {
+                State::Idle | State::Staged => {}
+                // Left for `write_pending`.
+                State::ReceivingBody { len } if self.received == len => {}
+                _ => self.fail("connection closed before the update was received"),
+            }
+            if self.requested {
+                self.try_connect(socket, random);
+            }
+            return;
+        }
+
+        match self.state {
+            State::Requesting if socket.may_send() => {
+                let mut request = ArrayString::<[u8; HEAD_SZ]>::new();
+                let written = write!(
+                    request,
+                    "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    self.config.path,
+                    Ipv4Address(self.config.server_addr)
+                );
+                match written.map(|()| socket.send_slice(request.as_bytes())) {
+                    Ok(Ok(sent)) if sent == request.len() => self.state = State::ReceivingHead,
+                    _ => {
+                        self.fail("could not send the request");
+                        socket.abort();
+                    }
+                }
+            }
+            State::ReceivingHead if socket.can_recv() => {
+                let head = &mut self.head;
+                let _ = socket.recv(|buf| {
+                    // Only up to the end of the head, the rest is the body.
+                    let mut len = 0;
+                    for byte in buf {
+                        if head.is_full() || head.ends_with(b"\r\n\r\n") {
+                            break;
+                        }
+                        head.push(*byte);
+                        len += 1;
+                    }
+                    (len, ())
+                });
+                if self.head.ends_with(b"\r\n\r\n") {
+                    match parse_head(&self.head) {
+                        Ok(len) => {
+                            log::info!("Downloading an update of {} bytes", len);
+                            self.received = 0;
+                            self.written = 0;
+                            self.state = State::ReceivingBody { len };
+                        }
+                        Err(err) => {
+                            self.fail(err);
+                            socket.abort();
+                        }
+                    }
+                } else if self.head.is_full() {
+                    self.fail("response head too long");
+                    socket.abort();
+                }
+            }
+            State::ReceivingBody { len } if socket.can_recv() => {
+                let pending = &mut self.pending;
+                let remaining = len - self.received;
+                let received = socket.recv(|buf| {
+                    let len = buf
+                        .len()
+                        .min(pending.capacity() - pending.len())
+                        .min(remaining);
+                    pending.extend(buf[..len].iter().copied());
+                    (len, ())
+                });
+                self.received += received.unwrap_or(0);
+                if self.received == len {
+                    socket.close();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl OtaClient {
+    /// Keeps its state in `state_sector` of the flash, as `boot` does.
+    pub fn new(config: OtaConfig, state_sector: usize) -> Self {
+        Self {
+            handle: None,
+            config,
+            state_sector,
+            state: State::Idle,
+            requested: false,
+            head: ArrayVec::new(),
+            pending: ArrayVec::new(),
+            received: 0,
+            written: 0,
+        }
+    }
+
+    /// Downloads the update at the next poll.
+    pub fn start(&mut self) {
+        match self.state {
+            State::Idle | State::Staged => self.requested = true,
+            _ => log::info!("Already downloading an update"),
+        }
+    }
+
+    /// Writes what has been received to slot B, and stages the update once
+    /// it has been received and verified.
+    pub fn write_pending(&mut self, flash: &mut Flash) {
+        let len = match self.state {
+            State::ReceivingBody { len } => len,
+            _ => return,
+        };
+        if self.written == 0 && !self.pending.is_empty() {
+            if on_trial(flash, self.state_sector) {
+                // Slot B holds what it would be rolled back to.
+                self.fail("the running update has not been confirmed yet");
+                return;
+            }
+            // Anything staged before is about to be overwritten.
+            flash.erase(self.state_sector);
+        }
+        while !self.pending.is_empty() {
+            let sector = SLOT_B + self.written / SECTOR_SZ;
+            let offset = self.written % SECTOR_SZ;
+            let chunk = self.pending.len().min(SECTOR_SZ - offset);
+            // Slot B isn't part of the running program.
+            unsafe {
+                if offset == 0 {
+                    flash.erase_program(sector);
+                }
+                flash.program_program(sector, offset, &self.pending[..chunk]);
+            }
+            self.pending.drain(..chunk);
+            self.written += chunk;
+        }
+        if self.written == len {
+            match verify(flash, len, self.config.public_key) {
+                Ok(image_len) => {
+                    // The running image may be followed by a signature block.
+                    let running_len = running_len() + signature::BLOCK_SZ;
+                    let sectors = (image_len.max(running_len) + SECTOR_SZ - 1) / SECTOR_SZ;
+                    write_swap(flash, self.state_sector, Kind::Install, sectors);
+                    log::info!("Update of {} bytes staged, reboot to install", image_len);
+                    self.state = State::Staged;
+                }
+                Err(err) => self.fail(err),
+            }
+        }
+    }
+
+    fn try_connect(&mut self, mut socket: SocketRef<TcpSocket>, random: &mut Random) {
+        self.requested = false;
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        let local = stack::generate_local_port(random);
+        let remote = IpAddress::Ipv4(Ipv4Address(self.config.server_addr));
+        let remote = IpEndpoint::new(remote, self.config.server_port);
+        log::info!("Requesting update {} from {}", self.config.path, remote);
+        match socket.connect(remote, local) {
+            Ok(()) => {
+                self.head.clear();
+                self.pending.clear();
+                self.state = State::Requesting;
+            }
+            Err(err) => self.fail(err),
+        }
+    }
+
+    fn fail<E: fmt::Display>(&mut self, err: E) {
+        log::warn!("Update failed: {}", err);
+        self.pending.clear();
+        self.state = State::Idle;
+    }
+}
+
+// Returns the length of the body of a successful response.
+fn parse_head(head: &[u8]) -> Result<usize, &'static str> {
+    let head = core::str::from_utf8(head).map_err(|_| "malformed response")?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    if status != Some("200") {
+        return Err("server did not respond with 200 OK");
+    }
+    let len = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_at(line.find(':')?);
+            if name.eq_ignore_ascii_case("content-length") {
+                value[1..].trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .ok_or("response has no Content-Length")?;
+    if len < TRAILER_SZ || len - TRAILER_SZ > SLOT_SZ {
+        return Err("update does not fit in a slot");
+    }
+    Ok(len)
+}
+
+// Checks the trailer of the update of `len` bytes in slot B, and its
+// signature if there is a `public_key`, returning the length of its image.
+fn verify(flash: &Flash, len: usize, public_key: Option<[u8; 32]>) -> Result<usize, &'static str> {
+    let mut trailer = [0; TRAILER_SZ];
+    for (i, byte) in trailer.iter_mut().enumerate() {
+        let at = len - TRAILER_SZ + i;
+        *byte = flash.read_program(SLOT_B + at / SECTOR_SZ, at % SECTOR_SZ, 1)[0];
+    }
+    let u32_at = |at: usize| {
+        u32::from_le_bytes([
+            trailer[at],
+            trailer[at + 1],
+            trailer[at + 2],
+            trailer[at + 3],
+        ])
+    };
+    let image_len = u32_at(4) as usize;
+    if u32_at(0) != IMAGE_MAGIC || image_len != len - TRAILER_SZ {
+        return Err("update has no valid trailer");
+    }
+    let mut crc = Crc32::new();
+    for start in (0..image_len).step_by(SECTOR_SZ) {
+        let chunk = (image_len - start).min(SECTOR_SZ);
+        crc.update(flash.read_program(SLOT_B + start / SECTOR_SZ, 0, chunk));
+    }
+    if crc.finish() != u32_at(8) {
+        return Err("CRC mismatch");
+    }
+    if let Some(public_key) = public_key {
+        let signed_len = image_len
+            .checked_sub(signature::BLOCK_SZ)
+            .ok_or("image is not signed")?;
+        signature::verify(flash, SLOT_B, signed_len, &public_key)?;
+    }
+    Ok(image_len)
+}
+
+// CRC-32 as used by zlib, computed bit by bit, since it is only used for
+// updates.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -0,0 +1,121 @@
+//! More P1 meters, for installs with a meter per dwelling, next to the one
+//! that the rest of the meter reader is built around. Each is read from the
+//! RX pin of an LPUART of its own, and parsed and validated on its own, and
+//! its readings are published under its name, by MQTT and in the metrics.
+//! They are not logged, nor used for the history, the events or the relays.
+//!
+//! The LPUARTs are of different types, so the main loop goes over them as
+//! `P1Input`s.
+
+use teensy4_bsp::hal::iomuxc::prelude::consts;
+
+use crate::{
+    dsmr::{self, ParseStats, Reading},
+    uart::{DsmrUart, DsmrUartError},
+    validation::Validator,
+};
+
+pub const MAX_METERS: usize = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct P1MeterConfig {
+    /// Such as `flat_2`, in lower snake case, for its topic and its metrics.
+    pub name: &'static str,
+    /// See `DsmrUart::new`.
+    pub rx_inverted: bool,
+    /// See `Validator::new`.
+    pub max_jump: i64,
+    pub max_drift: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct P1Stats {
+    pub name: &'static str,
+    pub parse: ParseStats,
+    /// Readings discarded by the `Validator`.
+    pub implausible: u32,
+    pub dropped_bytes: u32,
+}
+
+pub trait P1Input {
+    fn name(&self) -> &'static str;
+    /// Reads what the meter sent, as of `now`, and returns the reading once
+    /// a telegram is complete, if it is plausible. `wall_time` gives the
+    /// Unix time in ms at a time of our own, if it is known, which the
+    /// reading is checked against and is given as its `received_at`.
+    fn poll(&mut self, now: i64, wall_time: &dyn Fn(i64) -> Option<i64>) -> Option<Reading>;
+    fn stats(&self) -> P1Stats;
+}
+
+pub struct P1Meter<M, const N: usize> {
+    config: P1MeterConfig,
+    uart: DsmrUart<M, N>,
+    parser: dsmr::Parser<'static>,
+    validator: Validator,
+}
+
+impl<M: consts::Unsigned, const N: usize> P1Meter<M, N> {
+    pub fn new(uart: DsmrUart<M, N>, config: P1MeterConfig) -> Self {
+        Self {
+            config,
+            uart,
+            parser: dsmr::Parser::new(&[]),
+            validator: Validator::new(config.max_jump, config.max_drift),
+        }
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> P1Input for P1Meter<M, N> {
+    fn name(&self) -> &'static str {
+        self.config.name
+    }
+
+    fn poll(&mut self, now: i64, wall_time: &dyn Fn(i64) -> Option<i64>) -> Option<Reading> {
+        match self.uart.poll_at(now) {
+            Ok(()) => {}
+            Err(DsmrUartError::BufferFull) => {
+                log::warn!(
+                    "Read buffer of P1 meter {} full, discarding its contents",
+                    self.config.name
+                );
+                self.uart.clear();
+                self.parser.reset();
+            }
+            Err(err) => log::warn!("Error reading P1 meter {}: {:?}", self.config.name, err),
+        }
+        let (consumed, telegram) = self.parser.feed(self.uart.peek());
+        let received_at = self.uart.timestamp(consumed.saturating_sub(1));
+        self.uart.consume(consumed);
+        let name = self.config.name;
+        match telegram? {
+            Ok((_, mut reading)) => {
+                let received_at = received_at.unwrap_or(now);
+                reading.received_at = wall_time(received_at);
+                let plausible =
+                    self.validator
+                        .check(received_at, reading.received_at, &reading, |anomaly| {
+                            log::warn!("Implausible reading of P1 meter {}: {:?}", name, anomaly)
+                        });
+                if plausible {
+                    Some(reading)
+                } else {
+                    None
+                }
+            }
+            Err(err) => {
+                log::warn!("Failed to parse telegram of P1 meter {}: {:?}", name, err);
+                None
+            }
+        }
+    }
+
+    fn stats(&self) -> P1Stats {
+        let uart = self.uart.stats();
+        P1Stats {
+            name: self.config.name,
+            parse: self.parser.stats(),
+            implausible: self.validator.rejected(),
+            dropped_bytes: uart.dropped_bytes,
+        }
+    }
+}
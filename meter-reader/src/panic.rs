@@ -1,13 +1,208 @@
+//! Panics are logged, and recorded at the very end of OCRAM, which nothing
+//! else uses and which survives a reset, so that they can be reported after
+//! the next boot. Teensyduino's `CrashReport` works the same way.
+//!
+//! What happens next depends on the `PanicPolicy`.
+
+use arrayvec::ArrayString;
 use core::{
+    cell::Cell,
+    fmt::{self, Display, Write},
+    mem,
     panic::PanicInfo,
+    ptr, slice,
     sync::atomic::{self, Ordering},
 };
 
+use cortex_m::{
+    asm,
+    interrupt::{self, Mutex},
+    peripheral::SCB,
+    register::msp,
+};
+
+use crate::{cpu_clock, rtc, sntp::DateTime};
+
+const RECORD_ADDR: usize = 0x2027_FF00;
+const MAGIC: u32 = 0x5245_4343;
+const MESSAGE_SZ: usize = 192;
+const CACHE_LINE_SZ: usize = 32;
+// Data cache clean by address.
+const SCB_DCCMVAC: usize = 0xE000_EF68;
+// Fault status and address registers.
+const SCB_CFSR: usize = 0xE000_ED28;
+const SCB_HFSR: usize = 0xE000_ED2C;
+const SCB_MMFAR: usize = 0xE000_ED34;
+const SCB_BFAR: usize = 0xE000_ED38;
+
+static POLICY: Mutex<Cell<PanicPolicy>> = Mutex::new(Cell::new(PanicPolicy::Halt));
+
+/// What to do after a panic has been recorded and logged. A running
+/// `Supervisor` resets the chip either way, once the tasks stop checking in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Loop forever.
+    Halt,
+    /// Reset the chip after `delay_ms`, which gives the logger time to send
+    /// the panic.
+    Reset { delay_ms: u32 },
+}
+
+pub fn set_policy(policy: PanicPolicy) {
+    interrupt::free(|cs| POLICY.borrow(cs).set(policy));
+}
+
+/// Registers at the time of a crash. Those that are not known are 0.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Registers {
+    pub pc: u32,
+    pub lr: u32,
+    pub psr: u32,
+    pub sp: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+impl Registers {
+    /// The current stack pointer and fault status registers.
+    pub fn capture() -> Self {
+        let read = |register: usize| unsafe { ptr::read_volatile(register as *const u32) };
+        Self {
+            sp: msp::read(),
+            cfsr: read(SCB_CFSR),
+            hfsr: read(SCB_HFSR),
+            mmfar: read(SCB_MMFAR),
+            bfar: read(SCB_BFAR),
+            ..Self::default()
+        }
+    }
+}
+
+/// A crash that happened before the last reset.
+#[derive(Clone, Copy, Debug)]
+pub struct Crash {
+    pub message: ArrayString<[u8; MESSAGE_SZ]>,
+    pub registers: Registers,
+    /// The Unix time in s, if the `rtc` was set.
+    pub at: Option<u32>,
+}
+
+impl Display for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.registers;
+        if let Some(at) = self.at {
+            write!(f, "at {}: ", DateTime::from_unix_millis(at as i64 * 1000))?;
+        }
+        write!(
+            f,
+            "{} (pc {:#010x}, lr {:#010x}, psr {:#010x}, sp {:#010x}, cfsr {:#010x}, \
+            hfsr {:#010x}, mmfar {:#010x}, bfar {:#010x})",
+            self.message, r.pc, r.lr, r.psr, r.sp, r.cfsr, r.hfsr, r.mmfar, r.bfar
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Record {
+    magic: u32,
+    registers: Registers,
+    // 0 if the time wasn't known.
+    at: u32,
+    len: u32,
+    message: [u8; MESSAGE_SZ],
+    crc: u32,
+}
+
+impl Record {
+    fn crc(&self) -> u32 {
+        // Everything but the CRC itself. There is no padding.
+        let len = mem::size_of::<Self>() - mem::size_of::<u32>();
+        let bytes = unsafe { slice::from_raw_parts(self as *const Self as *const u8, len) };
+        dsmr42::crc16(bytes) as u32
+    }
+}
+
+/// Returns the crash that happened before the last reset, if any, and
+/// forgets about it.
+pub fn take_last_crash() -> Option<Crash> {
+    let crash = recorded_crash();
+    unsafe { ptr::write_volatile(RECORD_ADDR as *mut u32, 0) };
+    crash
+}
+
+/// Whether a crash has been recorded since the last call to
+/// `take_last_crash`, so that a later one doesn't hide it.
+pub fn crash_recorded() -> bool {
+    recorded_crash().is_some()
+}
+
+fn recorded_crash() -> Option<Crash> {
+    let record = unsafe { ptr::read_volatile(RECORD_ADDR as *const Record) };
+    let len = record.len as usize;
+    if record.magic != MAGIC || len > MESSAGE_SZ || record.crc != record.crc() {
+        return None;
+    }
+    let mut message = ArrayString::new();
+    // The message was truncated on a character boundary.
+    message.push_str(core::str::from_utf8(&record.message[..len]).ok()?);
+    Some(Crash {
+        message,
+        registers: record.registers,
+        at: Some(record.at).filter(|at| *at != 0),
+    })
+}
+
+/// Records a crash, to be reported after the next boot.
+pub fn record_crash(message: fmt::Arguments, registers: Registers) {
+    let mut truncating = Truncating(ArrayString::<[u8; MESSAGE_SZ]>::new());
+    let _ = truncating.write_fmt(message);
+    let mut record = Record {
+        magic: MAGIC,
+        registers,
+        at: rtc::now().map_or(0, |now| (now / 1000) as u32),
+        len: truncating.0.len() as u32,
+        message: [0; MESSAGE_SZ],
+        crc: 0,
+    };
+    record.message[..truncating.0.len()].copy_from_slice(truncating.0.as_bytes());
+    record.crc = record.crc();
+    unsafe { ptr::write_volatile(RECORD_ADDR as *mut Record, record) };
+    // Only memory survives a reset, not the data cache.
+    asm::dsb();
+    for line in (RECORD_ADDR..RECORD_ADDR + mem::size_of::<Record>()).step_by(CACHE_LINE_SZ) {
+        unsafe { ptr::write_volatile(SCB_DCCMVAC as *mut u32, line as u32) };
+    }
+    asm::dsb();
+}
+
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    record_crash(format_args!("{}", info), Registers::capture());
     log::error!("PANIC {}", info);
+    if let PanicPolicy::Reset { delay_ms } = interrupt::free(|cs| POLICY.borrow(cs).get()) {
+        asm::delay(delay_ms.saturating_mul(cpu_clock::hz() / 1000));
+        SCB::sys_reset();
+    }
     loop {
         atomic::compiler_fence(Ordering::SeqCst);
     }
 }
+
+// Writes as much as fits, and silently drops the rest.
+struct Truncating(ArrayString<[u8; MESSAGE_SZ]>);
+
+impl Write for Truncating {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.try_push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
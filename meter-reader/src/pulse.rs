@@ -0,0 +1,353 @@
+//! Counting the pulses of the S0 outputs of other kWh meters, such as those
+//! of a heat pump or a car charger. An S0 output connects its pin to ground
+//! for every pulse, of at least 30 ms.
+//!
+//! Like the `Button`, the GPIO latches edges, so that pulses are seen even
+//! while the main loop is busy, and so that `Idle` can wake on them. A level
+//! is only taken once it has been stable for `DEBOUNCE_MS`. The energy of
+//! each channel, from its pulses per kWh, is added to every reading under
+//! its OBIS code.
+//!
+//! The totals are kept in flash, the same way as `Config`, every
+//! `save_interval_ms` if they changed, since flash wears. Pulses since the
+//! last save are lost at a reset.
+
+use core::ptr;
+
+use dsmr42::{Decimal, Unit};
+use teensy4_bsp::hal::{
+    gpio::GPIO,
+    iomuxc::{
+        self, gpio::Pin, prelude::consts::Unsigned, Config, Hysteresis, PullKeep, PullKeepSelect,
+        PullUpDown, IOMUX,
+    },
+};
+
+use crate::{
+    dsmr::Reading,
+    flash::{Flash, SECTOR_SZ},
+    telemetry::{self, Kind, Label, Metric, Source, Telemetry, Value},
+};
+
+pub const MAX_CHANNELS: usize = 4;
+const DEBOUNCE_MS: i64 = 10;
+
+// Of GPIO1 to 4, which are the ones with interrupts.
+const GPIO_BASES: [usize; 4] = [0x401B_8000, 0x401B_C000, 0x401C_0000, 0x401C_4000];
+const PSR: usize = 0x08;
+const ISR: usize = 0x18;
+const EDGE_SEL: usize = 0x1C;
+
+// A record holds the version, the generation, the totals, and a CRC.
+const STATE_VERSION: u8 = 1;
+const RECORD_SZ: usize = 1 + 4 + 8 * MAX_CHANNELS + 2;
+const ERASED: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct S0Channel {
+    /// What the meter measures, such as `heat_pump`, in lower snake case,
+    /// for its metric.
+    pub name: &'static str,
+    /// The code that the energy is added to readings under, in kWh, which
+    /// should be one that the meter doesn't send, such as 1-2:1.8.0.
+    pub obis: [u8; 6],
+    /// As printed on the meter, such as 1000 imp/kWh.
+    pub pulses_per_kwh: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PulseConfig {
+    /// On the inputs of `PulseCounter::new`, in order.
+    pub channels: &'static [S0Channel],
+    pub save_interval_ms: i64,
+}
+
+/// A pin that an S0 output is connected to.
+pub struct S0Input {
+    base: usize,
+    mask: u32,
+    module: usize,
+    offset: usize,
+}
+
+impl S0Input {
+    /// Pulls `pin` up, so the S0 output only needs to connect it to ground.
+    /// It must be on GPIO1 to 4, which is where pins start out.
+    pub fn new<P: Pin + IOMUX>(mut pin: P) -> Self {
+        let config = Config::zero()
+            .set_hysteresis(Hysteresis::Enabled)
+            .set_pull_keep(PullKeep::Enabled)
+            .set_pull_keep_select(PullKeepSelect::Pull)
+            .set_pullupdown(PullUpDown::Pullup22k);
+        iomuxc::configure(&mut pin, config);
+        // Muxes the pad to the GPIO, which keeps the pin, since it isn't
+        // given back.
+        let _ = GPIO::new(pin);
+        let base = GPIO_BASES[P::Module::USIZE - 1];
+        let mask = 1 << P::Offset::USIZE;
+        unsafe {
+            // Latch both edges.
+            write(base + EDGE_SEL, read(base + EDGE_SEL) | mask);
+            write(base + ISR, mask);
+        }
+        Self {
+            base,
+            mask,
+            module: P::Module::USIZE,
+            offset: P::Offset::USIZE,
+        }
+    }
+
+    /// The GPIO module, from 1, and the bit of the pin, for `Idle`.
+    pub fn gpio(&self) -> (usize, usize) {
+        (self.module, self.offset)
+    }
+
+    fn take_edge(&mut self) -> bool {
+        let edge = unsafe { read(self.base + ISR) } & self.mask != 0;
+        if edge {
+            unsafe { write(self.base + ISR, self.mask) };
+        }
+        edge
+    }
+
+    fn is_active(&self) -> bool {
+        unsafe { read(self.base + PSR) }
+        &self.mask == 0
+    }
+}
+
+struct Counter {
+    input: S0Input,
+    channel: S0Channel,
+    // The level that was read last, and since when.
+    level: bool,
+    level_since: i64,
+    // The level that was taken last.
+    active: bool,
+    pulses: u64,
+}
+
+pub struct PulseCounter {
+    counters: [Option<Counter>; MAX_CHANNELS],
+    config: PulseConfig,
+    store: Store,
+    saved: [u64; MAX_CHANNELS],
+    save_at: i64,
+}
+
+impl PulseCounter {
+    /// Counts the channels of `config` on `inputs`, from the totals that
+    /// were saved in `sectors`, if any.
+    pub fn new(
+        inputs: [S0Input; MAX_CHANNELS],
+        config: PulseConfig,
+        flash: &Flash,
+        sectors: [usize; 2],
+    ) -> Self {
+        let (store, saved) = Store::load(flash, sectors);
+        let saved = saved.unwrap_or([0; MAX_CHANNELS]);
+        if config.channels.len() > MAX_CHANNELS {
+            log::warn!("Only counting the first {} S0 channels", MAX_CHANNELS);
+        }
+        let counter = |i: usize, input| {
+            config.channels.get(i).map(|channel| Counter {
+                input,
+                channel: *channel,
+                level: false,
+                level_since: 0,
+                active: false,
+                pulses: saved[i],
+            })
+        };
+        let [first, second, third, fourth] = inputs;
+        let counters = [
+            counter(0, first),
+            counter(1, second),
+            counter(2, third),
+            counter(3, fourth),
+        ];
+        log::info!("S0 pulse totals: {:?}", saved);
+        Self {
+            counters,
+            config,
+            store,
+            saved,
+            save_at: config.save_interval_ms,
+        }
+    }
+
+    /// The GPIOs of the inputs that are counted, for `Idle`.
+    pub fn gpios(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counters
+            .iter()
+            .flatten()
+            .map(|counter| counter.input.gpio())
+    }
+
+    pub fn poll(&mut self, now: i64, flash: &mut Flash) {
+        for counter in self.counters.iter_mut().flatten() {
+            let edge = counter.input.take_edge();
+            let level = counter.input.is_active();
+            if edge || level != counter.level {
+                counter.level = level;
+                counter.level_since = now;
+            } else if now - counter.level_since >= DEBOUNCE_MS && level != counter.active {
+                counter.active = level;
+                if level {
+                    counter.pulses += 1;
+                }
+            }
+        }
+        if now >= self.save_at {
+            self.save_at = now + self.config.save_interval_ms;
+            let totals = self.totals();
+            if totals != self.saved {
+                self.store.save(flash, &totals);
+                self.saved = totals;
+            }
+        }
+    }
+
+    /// Adds the energy of each channel to `reading`, as custom values.
+    pub fn append_to(&self, reading: &mut Reading) {
+        for counter in self.counters.iter().flatten() {
+            // To the Wh, however many pulses per kWh there are.
+            let wh = counter.pulses * 1_000 / counter.channel.pulses_per_kwh.max(1) as u64;
+            let energy = Decimal::new(wh as i64, 3, Some(Unit::KWh));
+            if reading
+                .custom
+                .try_push((counter.channel.obis, energy))
+                .is_err()
+            {
+                log::warn!("No room for S0 channel {:?}", counter.channel.obis);
+            }
+        }
+    }
+
+    /// Registers the energy of each channel.
+    pub fn register(&self, telemetry: &mut Telemetry) {
+        for counter in self.counters.iter().flatten() {
+            telemetry.register(Metric {
+                source: Source::Pulse,
+                name: "energy",
+                label: Some(Label::Name(counter.channel.name)),
+                unit: Some(telemetry::Unit::KWh),
+                kind: Kind::Counter,
+                help: "Energy counted from S0 pulses",
+                value: Value::Custom(counter.channel.obis),
+            });
+        }
+    }
+
+    fn totals(&self) -> [u64; MAX_CHANNELS] {
+        let mut totals = self.saved;
+        for (total, counter) in totals.iter_mut().zip(&self.counters) {
+            if let Some(counter) = counter {
+                *total = counter.pulses;
+            }
+        }
+        totals
+    }
+}
+
+// Two sectors of records, like `ConfigStore`.
+struct Store {
+    sectors: [usize; 2],
+    active: usize,
+    offset: usize,
+    generation: u32,
+}
+
+impl Store {
+    fn load(flash: &Flash, sectors: [usize; 2]) -> (Self, Option<[u64; MAX_CHANNELS]>) {
+        let mut store = Self {
+            sectors,
+            active: 0,
+            offset: SECTOR_SZ,
+            generation: 0,
+        };
+        let mut latest = None;
+        let mut ends = [0; 2];
+        for (index, sector) in sectors.iter().enumerate() {
+            let mut offset = 0;
+            while offset + RECORD_SZ <= SECTOR_SZ {
+                let record = flash.read(*sector, offset, RECORD_SZ);
+                if record[0] == ERASED {
+                    break;
+                }
+                offset += RECORD_SZ;
+                match decode(record) {
+                    Some((generation, totals))
+                        if latest.is_none() || generation > store.generation =>
+                    {
+                        store.generation = generation;
+                        store.active = index;
+                        latest = Some(totals);
+                    }
+                    _ => {}
+                }
+            }
+            ends[index] = offset;
+        }
+        if latest.is_some() {
+            store.offset = ends[store.active];
+        }
+        (store, latest)
+    }
+
+    fn save(&mut self, flash: &mut Flash, totals: &[u64; MAX_CHANNELS]) {
+        let generation = self.generation.wrapping_add(1);
+        let record = encode(generation, totals);
+        let sector = self.sectors[self.active];
+        let fits = self.offset + RECORD_SZ <= SECTOR_SZ
+            && flash
+                .read(sector, self.offset, RECORD_SZ)
+                .iter()
+                .all(|byte| *byte == ERASED);
+        if !fits {
+            self.active = 1 - self.active;
+            self.offset = 0;
+            flash.erase(self.sectors[self.active]);
+        }
+        flash.program(self.sectors[self.active], self.offset, &record);
+        self.offset += RECORD_SZ;
+        self.generation = generation;
+    }
+}
+
+fn encode(generation: u32, totals: &[u64; MAX_CHANNELS]) -> [u8; RECORD_SZ] {
+    let mut record = [0; RECORD_SZ];
+    record[0] = STATE_VERSION;
+    record[1..5].copy_from_slice(&generation.to_le_bytes());
+    for (i, total) in totals.iter().enumerate() {
+        record[5 + 8 * i..13 + 8 * i].copy_from_slice(&total.to_le_bytes());
+    }
+    let crc = dsmr42::crc16(&record[..RECORD_SZ - 2]);
+    record[RECORD_SZ - 2..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+// Along with its generation.
+fn decode(record: &[u8]) -> Option<(u32, [u64; MAX_CHANNELS])> {
+    let crc = u16::from_le_bytes([record[RECORD_SZ - 2], record[RECORD_SZ - 1]]);
+    if record[0] != STATE_VERSION || dsmr42::crc16(&record[..RECORD_SZ - 2]) != crc {
+        return None;
+    }
+    let mut totals = [0; MAX_CHANNELS];
+    for (i, total) in totals.iter_mut().enumerate() {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&record[5 + 8 * i..13 + 8 * i]);
+        *total = u64::from_le_bytes(bytes);
+    }
+    let generation = u32::from_le_bytes([record[1], record[2], record[3], record[4]]);
+    Some((generation, totals))
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,209 @@
+//! Submodule 0 of FlexPWM2, for a moving-coil gauge that shows the power, or
+//! a backlight that brightens with it: channel A on pin 4, which is only free
+//! without the status LED, and channel B on pin 33.
+//!
+//! Both channels are edge aligned, from the start of every period, in
+//! independent mode. New duty cycles are buffered, and only take effect
+//! together, at the start of the period after `commit`.
+//!
+//! The counter runs on the IPG clock, which `CpuClock` changes, so the
+//! frequency drops while the core idles. The duty cycles stay as they are.
+
+use core::ptr;
+
+use teensy4_bsp::hal::iomuxc::emc::{EMC_06, EMC_07};
+
+use crate::dsmr::Reading;
+
+const FLEXPWM2: usize = 0x403E_0000;
+// Of submodule 0.
+const SM0_INIT: usize = FLEXPWM2 + 0x02;
+const SM0_CTRL2: usize = FLEXPWM2 + 0x04;
+const SM0_CTRL: usize = FLEXPWM2 + 0x06;
+const SM0_VAL0: usize = FLEXPWM2 + 0x0A;
+const SM0_VAL1: usize = FLEXPWM2 + 0x0E;
+const SM0_VAL2: usize = FLEXPWM2 + 0x12;
+const SM0_VAL3: usize = FLEXPWM2 + 0x16;
+const SM0_VAL4: usize = FLEXPWM2 + 0x1A;
+const SM0_VAL5: usize = FLEXPWM2 + 0x1E;
+const OUTEN: usize = FLEXPWM2 + 0x180;
+const MCTRL: usize = FLEXPWM2 + 0x188;
+const FCTRL0: usize = FLEXPWM2 + 0x18C;
+const FSTS0: usize = FLEXPWM2 + 0x18E;
+
+// Independent channels, which also run while the core waits.
+const CTRL2_INDEP: u16 = 1 << 13;
+const CTRL2_WAITEN: u16 = 1 << 14;
+// Reloads at the start of every period.
+const CTRL_FULL: u16 = 1 << 10;
+const CTRL_PRSC_SHIFT: u16 = 4;
+const OUTEN_PWMA0: u16 = 1 << 8;
+const OUTEN_PWMB0: u16 = 1 << 4;
+const MCTRL_LDOK0: u16 = 1 << 0;
+const MCTRL_CLDOK0: u16 = 1 << 4;
+const MCTRL_RUN0: u16 = 1 << 8;
+// The fault inputs are active high, and the ones that were latched at
+// reset are cleared, so that they don't hold the outputs off.
+const FCTRL0_FLVL: u16 = 0xF << 12;
+const FSTS0_FFLAG: u16 = 0xF;
+
+// Gates the clock of FlexPWM2, with CG9.
+const CCM_CCGR4: usize = 0x400F_C078;
+const CG9: u32 = 0b11 << 18;
+
+// The pads of pins 4 and 33, which are muxed to PWMA and PWMB of submodule
+// 0, with a drive strength of R0/6.
+const MUX_A: usize = 0x401F_802C;
+const MUX_B: usize = 0x401F_8030;
+const PAD_A: usize = 0x401F_821C;
+const PAD_B: usize = 0x401F_8220;
+const MUX_FLEXPWM: u32 = 1;
+const PAD_CONFIG: u32 = 0x30;
+
+// At 600 MHz.
+const IPG_HZ: u32 = 150_000_000;
+const MAX_PRESCALER_SHIFT: u32 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PwmChannel {
+    /// On pin 4.
+    A,
+    /// On pin 33.
+    B,
+}
+
+/// What a channel shows.
+#[derive(Clone, Copy, Debug)]
+pub struct PwmOutput {
+    pub channel: PwmChannel,
+    /// The power delivered that gives a duty cycle of 100%. Returning gives
+    /// `min_percent`.
+    pub full_scale_w: i64,
+    /// At no power, such as to keep a backlight from going dark.
+    pub min_percent: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PwmConfig {
+    /// At 600 MHz.
+    pub frequency_hz: u32,
+    pub outputs: &'static [PwmOutput],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PwmError {
+    /// The period would take more than 16 bits at the largest prescaler.
+    Frequency(u32),
+}
+
+pub struct Pwm {
+    _pins: (Option<EMC_06>, EMC_07),
+    config: PwmConfig,
+    // Of the counter, per period.
+    period: u16,
+}
+
+impl Pwm {
+    /// Starts both channels at a duty cycle of 0%, which only makes it to pin
+    /// 4 with `a`.
+    pub fn init(a: Option<EMC_06>, b: EMC_07, config: PwmConfig) -> Result<Self, PwmError> {
+        let frequency_hz = config.frequency_hz;
+        let (shift, period) = (0..=MAX_PRESCALER_SHIFT)
+            .find_map(|shift| {
+                let counts = IPG_HZ / (frequency_hz.max(1) << shift);
+                if counts > 0 && counts <= u16::MAX as u32 {
+                    Some((shift, counts as u16))
+                } else {
+                    None
+                }
+            })
+            .ok_or(PwmError::Frequency(frequency_hz))?;
+        unsafe {
+            write32(CCM_CCGR4, read32(CCM_CCGR4) | CG9);
+            if a.is_some() {
+                write32(MUX_A, MUX_FLEXPWM);
+                write32(PAD_A, PAD_CONFIG);
+            }
+            write32(MUX_B, MUX_FLEXPWM);
+            write32(PAD_B, PAD_CONFIG);
+
+            write16(FCTRL0, FCTRL0_FLVL);
+            write16(FSTS0, FSTS0_FFLAG);
+            write16(MCTRL, read16(MCTRL) | MCTRL_CLDOK0);
+            write16(SM0_CTRL2, CTRL2_INDEP | CTRL2_WAITEN);
+            write16(SM0_CTRL, CTRL_FULL | (shift as u16) << CTRL_PRSC_SHIFT);
+            write16(SM0_INIT, 0);
+            write16(SM0_VAL0, 0);
+            write16(SM0_VAL1, period - 1);
+            // Both channels rise at the start of the period, and fall at
+            // their duty cycle.
+            for register in [SM0_VAL2, SM0_VAL3, SM0_VAL4, SM0_VAL5].iter() {
+                write16(*register, 0);
+            }
+            let outputs = if a.is_some() {
+                OUTEN_PWMA0 | OUTEN_PWMB0
+            } else {
+                OUTEN_PWMB0
+            };
+            write16(OUTEN, read16(OUTEN) | outputs);
+            write16(MCTRL, read16(MCTRL) | MCTRL_LDOK0 | MCTRL_RUN0);
+        }
+        log::info!("PWM started at {} Hz", frequency_hz);
+        Ok(Self {
+            _pins: (a, b),
+            config,
+            period,
+        })
+    }
+
+    /// Buffers the duty cycle of `channel`, from 0 to 100%, until `commit`.
+    pub fn set_duty(&mut self, channel: PwmChannel, percent: f32) {
+        let percent = percent.max(0.0).min(100.0);
+        // Past the end of the period at 100%, so that it never falls.
+        let counts = (self.period as f32 * percent / 100.0 + 0.5) as u16;
+        unsafe {
+            // The buffers can't be written while they wait to be loaded.
+            write16(MCTRL, read16(MCTRL) | MCTRL_CLDOK0);
+            let register = match channel {
+                PwmChannel::A => SM0_VAL3,
+                PwmChannel::B => SM0_VAL5,
+            };
+            write16(register, counts);
+        }
+    }
+
+    /// Loads the duty cycles that were set at the start of the next period.
+    pub fn commit(&mut self) {
+        unsafe { write16(MCTRL, read16(MCTRL) | MCTRL_LDOK0) };
+    }
+
+    /// Sets the outputs to the power of `reading`.
+    pub fn show(&mut self, reading: &Reading) {
+        let power = reading
+            .power_delivered
+            .and_then(|power| power.w())
+            .unwrap_or(0);
+        for output in self.config.outputs {
+            let share = power as f32 / output.full_scale_w.max(1) as f32;
+            let percent = output.min_percent + (100.0 - output.min_percent) * share.max(0.0);
+            self.set_duty(output.channel, percent);
+        }
+        self.commit();
+    }
+}
+
+unsafe fn read16(register: usize) -> u16 {
+    ptr::read_volatile(register as *const u16)
+}
+
+unsafe fn write16(register: usize, value: u16) {
+    ptr::write_volatile(register as *mut u16, value)
+}
+
+unsafe fn read32(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write32(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
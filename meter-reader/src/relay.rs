@@ -0,0 +1,121 @@
+//! Relays that switch loads on the readings, such as a boiler that should
+//! only heat with power that would otherwise be returned, on pins 34 and 35,
+//! which drive the coil of a relay or contactor through a transistor, as the
+//! `rules` of `Config` say.
+
+use core::ptr;
+
+use embedded_hal::digital::v2::OutputPin;
+use teensy4_bsp::hal::{
+    gpio::GPIO,
+    iomuxc::{gpio::Pin, prelude::consts::Unsigned, IOMUX},
+};
+
+use crate::{
+    dsmr::Reading,
+    rules::{Rule, MAX_RULES},
+};
+
+pub const MAX_RELAYS: usize = 2;
+
+// Of GPIO1 to 4.
+const GPIO_BASES: [usize; 4] = [0x401B_8000, 0x401B_C000, 0x401C_0000, 0x401C_4000];
+const DR_SET: usize = 0x84;
+const DR_CLEAR: usize = 0x88;
+
+/// A pin that drives a relay, high to close it.
+pub struct RelayOutput {
+    base: usize,
+    mask: u32,
+}
+
+impl RelayOutput {
+    /// Drives `pin` low, which leaves the relay open. It must be on GPIO1 to
+    /// 4, which is where pins start out.
+    pub fn new<P: Pin + IOMUX>(pin: P) -> Self {
+        // Makes it an output, which it stays, since the pin isn't given back.
+        let mut gpio = GPIO::new(pin).output();
+        let _ = gpio.set_low();
+        Self {
+            base: GPIO_BASES[P::Module::USIZE - 1],
+            mask: 1 << P::Offset::USIZE,
+        }
+    }
+
+    fn set(&mut self, closed: bool) {
+        let register = if closed { DR_SET } else { DR_CLEAR };
+        unsafe { ptr::write_volatile((self.base + register) as *mut u32, self.mask) };
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RuleState {
+    holds: bool,
+    // Since when it has held, or not, while `holds` says otherwise.
+    changing_since: Option<i64>,
+}
+
+pub struct Relays {
+    outputs: [RelayOutput; MAX_RELAYS],
+    rules: [Option<Rule>; MAX_RULES],
+    states: [RuleState; MAX_RULES],
+    closed: [bool; MAX_RELAYS],
+}
+
+impl Relays {
+    pub fn new(outputs: [RelayOutput; MAX_RELAYS], rules: [Option<Rule>; MAX_RULES]) -> Self {
+        Self {
+            outputs,
+            rules,
+            states: [RuleState::default(); MAX_RULES],
+            closed: [false; MAX_RELAYS],
+        }
+    }
+
+    /// Whether each relay is closed.
+    pub fn closed(&self) -> [bool; MAX_RELAYS] {
+        self.closed
+    }
+
+    /// Checks the rules against `reading`, which was received at `now`, and
+    /// switches the relays that they change. Rules on values that the
+    /// reading doesn't have stay as they were.
+    pub fn update(&mut self, now: i64, reading: &Reading) {
+        for (rule, state) in self.rules.iter().zip(&mut self.states) {
+            let rule = match rule {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let holds = match rule.quantity.value(reading) {
+                Some(value) if rule.above => value > rule.threshold as i64,
+                Some(value) => value < rule.threshold as i64,
+                None => continue,
+            };
+            if holds == state.holds {
+                state.changing_since = None;
+                continue;
+            }
+            let since = *state.changing_since.get_or_insert(now);
+            if now - since >= rule.delay_s as i64 * 1_000 {
+                log::info!("Relay rule {:?} now holds: {}", rule, holds);
+                state.holds = holds;
+                state.changing_since = None;
+            }
+        }
+
+        for (i, (output, closed)) in self.outputs.iter_mut().zip(&mut self.closed).enumerate() {
+            let close = self.rules.iter().zip(&self.states).any(|(rule, state)| {
+                rule.map_or(false, |rule| rule.relay as usize == i + 1) && state.holds
+            });
+            if close != *closed {
+                log::info!(
+                    "Relay {} {}",
+                    i + 1,
+                    if close { "closed" } else { "opened" }
+                );
+                output.set(close);
+                *closed = close;
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use core::fmt::Debug;
+
+use embedded_hal::digital::v2::OutputPin;
+
+// How long the request line is held low before it is asserted again after a
+// timeout, in ms. Meters only start sending on a rising edge.
+const REASSERT_DELAY_MS: i64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// The request line is low, waiting for the next poll.
+    Idle { since: i64 },
+    /// The request line is high, waiting for a telegram.
+    Requesting { since: i64 },
+    /// The request line is low after a timeout, and will be asserted again.
+    Reasserting { since: i64 },
+}
+
+/// Drives the P1 Data Request line. Meters only send telegrams while it is
+/// held high, which is usually left to the P1 cable, but some older meters
+/// need to be polled instead.
+pub struct DataRequest<P> {
+    pin: P,
+    interval: Option<i64>,
+    timeout: i64,
+    state: State,
+    timeouts: u32,
+}
+
+impl<P> DataRequest<P>
+where
+    P: OutputPin,
+    P::Error: Debug,
+{
+    /// If `interval` is `None`, the line is held high continuously, so that
+    /// the meter sends telegrams at its own pace. Otherwise, the line is only
+    /// asserted every `interval` ms, and released once a telegram has been
+    /// received. Either way, if no telegram is received within `timeout` ms
+    /// of asserting the line, it is released and asserted again.
+    pub fn new(pin: P, interval: Option<i64>, timeout: i64) -> Self {
+        Self {
+            pin,
+            interval,
+            timeout,
+            // Ensures the line is asserted on the first poll.
+            state: State::Reasserting {
+                since: i64::min_value(),
+            },
+            timeouts: 0,
+        }
+    }
+
+    /// Asserts or releases the request line when it is time to.
+    pub fn poll(&mut self, now: i64) {
+        match self.state {
+            State::Idle { since } => {
+                if now - since >= self.interval.unwrap_or(0) {
+                    self.assert(now);
+                }
+            }
+            State::Requesting { since } => {
+                if now - since >= self.timeout {
+                    self.timeouts = self.timeouts.saturating_add(1);
+                    log::warn!(
+                        "No telegram received {} ms after requesting one, requesting again ({} timeouts so far)",
+                        now - since,
+                        self.timeouts
+                    );
+                    self.release();
+                    self.state = State::Reasserting { since: now };
+                }
+            }
+            State::Reasserting { since } => {
+                if now.saturating_sub(since) >= REASSERT_DELAY_MS {
+                    self.assert(now);
+                }
+            }
+        }
+    }
+
+    /// Must be called whenever a telegram has been received, even if it
+    /// could not be parsed, since the meter did respond to the request.
+    pub fn on_telegram(&mut self, now: i64) {
+        match (self.state, self.interval) {
+            (State::Requesting { .. }, Some(_)) => {
+                self.release();
+                self.state = State::Idle { since: now };
+            }
+            (State::Requesting { .. }, None) => self.state = State::Requesting { since: now },
+            _ => {}
+        }
+    }
+
+    /// The number of times no telegram was received in time.
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts
+    }
+
+    fn assert(&mut self, now: i64) {
+        if let Err(err) = self.pin.set_high() {
+            log::error!("Failed to assert data request line: {:?}", err);
+        }
+        self.state = State::Requesting { since: now };
+    }
+
+    fn release(&mut self) {
+        if let Err(err) = self.pin.set_low() {
+            log::error!("Failed to release data request line: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use core::cmp;
+
+/// Fixed-size byte ring buffer.
+///
+/// Data is read through `peek`/`split_read`, which hand out slices into the
+/// buffer itself, and is only moved around when a contiguous view is
+/// explicitly requested through `make_contiguous`.
+pub struct RingBuffer<const N: usize> {
+    buffer: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends a byte, returning `false` if the buffer is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buffer[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Returns the longest contiguous slice at the start of the buffer.
+    pub fn peek(&self) -> &[u8] {
+        self.split_read().0
+    }
+
+    /// Returns the buffer contents as two slices, the second of which is only
+    /// non-empty if the contents wrap around the end of the buffer.
+    pub fn split_read(&self) -> (&[u8], &[u8]) {
+        let first_len = cmp::min(self.len, N - self.head);
+        (
+            &self.buffer[self.head..self.head + first_len],
+            &self.buffer[..self.len - first_len],
+        )
+    }
+
+    /// Rearranges the buffer so its contents are contiguous, and returns them.
+    pub fn make_contiguous(&mut self) -> &[u8] {
+        if self.head + self.len > N {
+            self.buffer.rotate_left(self.head);
+            self.head = 0;
+        }
+        &self.buffer[self.head..self.head + self.len]
+    }
+
+    /// Removes up to `count` bytes from the start of the buffer.
+    pub fn consume(&mut self, count: usize) {
+        let count = cmp::min(count, self.len);
+        self.len -= count;
+        self.head = if self.len == 0 {
+            // Rewinding keeps the next data contiguous for as long as possible.
+            0
+        } else {
+            (self.head + count) % N
+        };
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection, prelude::*};
+    use std::{collections::VecDeque, vec::Vec};
+
+    // Small, so that sequences wrap around and overflow often.
+    const N: usize = 16;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Push(Vec<u8>),
+        Consume(usize),
+        MakeContiguous,
+        Clear,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => collection::vec(any::<u8>(), 0..2 * N).prop_map(Op::Push),
+            4 => (0..2 * N).prop_map(Op::Consume),
+            1 => Just(Op::MakeContiguous),
+            1 => Just(Op::Clear),
+        ]
+    }
+
+    // Applies `op` to both `buffer` and `model`, of what it should hold.
+    fn apply(buffer: &mut RingBuffer<N>, model: &mut VecDeque<u8>, op: Op) {
+        match op {
+            Op::Push(bytes) => {
+                for byte in bytes {
+                    assert_eq!(model.len() < N, buffer.push(byte));
+                    if model.len() < N {
+                        model.push_back(byte);
+                    }
+                }
+            }
+            Op::Consume(count) => {
+                buffer.consume(count);
+                model.drain(..count.min(model.len()));
+            }
+            Op::MakeContiguous => {
+                let contents = buffer.make_contiguous().to_vec();
+                assert_eq!(model.iter().copied().collect::<Vec<_>>(), contents);
+                assert!(buffer.split_read().1.is_empty());
+            }
+            Op::Clear => {
+                buffer.clear();
+                model.clear();
+            }
+        }
+    }
+
+    fn check(buffer: &RingBuffer<N>, model: &VecDeque<u8>) {
+        assert_eq!(model.len(), buffer.len());
+        assert_eq!(model.is_empty(), buffer.is_empty());
+        assert_eq!(model.len() == N, buffer.is_full());
+        let (first, second) = buffer.split_read();
+        assert_eq!(first, buffer.peek());
+        assert!(second.is_empty() || !first.is_empty());
+        let contents: Vec<u8> = first.iter().chain(second).copied().collect();
+        assert_eq!(model.iter().copied().collect::<Vec<_>>(), contents);
+    }
+
+    proptest! {
+        #[test]
+        fn behaves_like_a_queue(ops in collection::vec(op(), 0..64)) {
+            let mut buffer = RingBuffer::<N>::new();
+            let mut model = VecDeque::new();
+            for op in ops {
+                apply(&mut buffer, &mut model, op);
+                check(&buffer, &model);
+            }
+        }
+
+        #[test]
+        fn emptied_buffer_rewinds(
+            ops in collection::vec(op(), 0..64),
+            bytes in collection::vec(any::<u8>(), 0..=N),
+        ) {
+            let mut buffer = RingBuffer::<N>::new();
+            let mut model = VecDeque::new();
+            for op in ops {
+                apply(&mut buffer, &mut model, op);
+            }
+            buffer.consume(N);
+            for byte in &bytes {
+                prop_assert!(buffer.push(*byte));
+            }
+            prop_assert_eq!(&bytes[..], buffer.peek());
+            prop_assert!(buffer.split_read().1.is_empty());
+        }
+    }
+}
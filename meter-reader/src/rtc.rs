@@ -0,0 +1,148 @@
+//! The secure real-time counter of SNVS, which keeps counting through resets,
+//! and with a coin cell on VBAT, through power loss as well.
+//!
+//! Once it has been set from SNTP, it gives the time right after a reset,
+//! before SNTP has answered, and to the panic handler. It also counts boots,
+//! in a general purpose register of SNVS, which survives resets just the same.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use cortex_m::peripheral::NVIC;
+use teensy4_bsp::hal::ral::interrupt;
+
+const SNVS: usize = 0x400D_4000;
+const LPCR: usize = SNVS + 0x38;
+const LPSR: usize = SNVS + 0x4C;
+const LPSRTCMR: usize = SNVS + 0x50;
+const LPSRTCLR: usize = SNVS + 0x54;
+const LPTAR: usize = SNVS + 0x58;
+const LPGPR0: usize = SNVS + 0x100;
+const LPGPR1: usize = SNVS + 0x104;
+const LPCR_SRTC_ENV: u32 = 1 << 0;
+const LPCR_LPTA_EN: u32 = 1 << 1;
+const LPSR_LPTA: u32 = 1 << 0;
+// Gates the clocks of SNVS, with the bits of CG14 and CG15.
+const CCM_CCGR5: usize = 0x400F_C07C;
+const CG14_CG15: u32 = 0b1111 << 28;
+// The counter has 47 bits, at 32768 Hz. The time alarm compares the 32 bits
+// that count seconds.
+const COUNTER_HZ: i64 = 32768;
+const COUNTER_MASK: i64 = (1 << 47) - 1;
+// In LPGPR0 once the counter has been set to the Unix time. Another value
+// means that SNVS lost power, or the counter was set by someone else, such
+// as the Teensy Loader, to a local time.
+const TIME_SET_MAGIC: u32 = 0x5452_5331;
+
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+
+pub struct Rtc {
+    boot_count: u32,
+    // The counter at boot.
+    booted_at: i64,
+}
+
+impl Rtc {
+    /// Starts the counter, if it isn't running yet, and counts this boot.
+    pub fn init() -> Self {
+        unsafe {
+            let ccgr5 = read(CCM_CCGR5);
+            write(CCM_CCGR5, ccgr5 | CG14_CG15);
+            write(LPCR, read(LPCR) | LPCR_SRTC_ENV);
+            while read(LPCR) & LPCR_SRTC_ENV == 0 {}
+        }
+        let boot_count = unsafe { read(LPGPR1) }.wrapping_add(1);
+        unsafe { write(LPGPR1, boot_count) };
+        Self {
+            boot_count,
+            booted_at: counter(),
+        }
+    }
+
+    /// Boots since SNVS last lost power, including this one.
+    pub fn boot_count(&self) -> u32 {
+        self.boot_count
+    }
+
+    /// Time since boot in ms, as counted by the SRTC.
+    pub fn uptime(&self) -> i64 {
+        ((counter() - self.booted_at) & COUNTER_MASK) * 1000 / COUNTER_HZ
+    }
+
+    /// Sets the counter to `unix`, the Unix time in ms.
+    pub fn set(&mut self, unix: i64) {
+        let elapsed = counter() - self.booted_at;
+        let value = (unix * COUNTER_HZ / 1000) & COUNTER_MASK;
+        unsafe {
+            // It can only be written while stopped.
+            write(LPCR, read(LPCR) & !LPCR_SRTC_ENV);
+            while read(LPCR) & LPCR_SRTC_ENV != 0 {}
+            write(LPSRTCMR, (value >> 32) as u32);
+            write(LPSRTCLR, value as u32);
+            write(LPCR, read(LPCR) | LPCR_SRTC_ENV);
+            while read(LPCR) & LPCR_SRTC_ENV == 0 {}
+            write(LPGPR0, TIME_SET_MAGIC);
+        }
+        // Keeps `uptime` going.
+        self.booted_at = value - elapsed;
+    }
+
+    /// Fires the alarm once the Unix time reaches `unix_s`, in seconds,
+    /// which `take_alarm` reports. Replaces an earlier alarm.
+    pub fn set_alarm(&mut self, unix_s: u32) {
+        unsafe {
+            write(LPCR, read(LPCR) & !LPCR_LPTA_EN);
+            while read(LPCR) & LPCR_LPTA_EN != 0 {}
+            write(LPTAR, unix_s);
+            write(LPSR, LPSR_LPTA);
+            write(LPCR, read(LPCR) | LPCR_LPTA_EN);
+            while read(LPCR) & LPCR_LPTA_EN == 0 {}
+            NVIC::unmask(interrupt::SNVS_HP_WRAPPER);
+        }
+    }
+
+    /// Whether the alarm fired since the last call.
+    pub fn take_alarm(&mut self) -> bool {
+        ALARM_FIRED.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// The Unix time in ms, or `None` if the counter hasn't been set by
+/// `Rtc::set` since SNVS last lost power.
+pub fn now() -> Option<i64> {
+    if unsafe { read(LPGPR0) } != TIME_SET_MAGIC {
+        return None;
+    }
+    Some(counter() * 1000 / COUNTER_HZ)
+}
+
+fn counter() -> i64 {
+    // The halves are read separately, so read until they match, around a
+    // carry.
+    loop {
+        let (high, low) = unsafe { (read(LPSRTCMR), read(LPSRTCLR)) };
+        if unsafe { (read(LPSRTCMR), read(LPSRTCLR)) } == (high, low) {
+            return ((high as i64 & 0x7FFF) << 32) | low as i64;
+        }
+    }
+}
+
+#[interrupt]
+fn SNVS_HP_WRAPPER() {
+    unsafe {
+        // Or it fires again right away, as the counter stays past the alarm.
+        write(LPCR, read(LPCR) & !LPCR_LPTA_EN);
+        write(LPSR, LPSR_LPTA);
+    }
+    ALARM_FIRED.store(true, Ordering::Relaxed);
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile(register as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile(register as *mut u32, value)
+}
@@ -0,0 +1,61 @@
+//! The rules that the relays switch on. A `Rule` of `Config` compares a
+//! value of every reading to a threshold, such as "the power returned is
+//! above 1000 W", and is only taken to hold, or to no longer hold, once it has
+//! done so for `delay_s`, so that a cloud passing over doesn't make a
+//! contactor chatter. A relay is closed while any of its rules holds.
+
+use crate::dsmr::Reading;
+
+pub const MAX_RULES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    /// In W.
+    PowerDelivered,
+    PowerReturned,
+    /// Delivered minus returned, which is negative while returning.
+    PowerNet,
+    Tariff,
+}
+
+impl Quantity {
+    pub const ALL: [Quantity; 4] = [
+        Quantity::PowerDelivered,
+        Quantity::PowerReturned,
+        Quantity::PowerNet,
+        Quantity::Tariff,
+    ];
+
+    /// As in the console.
+    pub fn name(self) -> &'static str {
+        match self {
+            Quantity::PowerDelivered => "delivered",
+            Quantity::PowerReturned => "returned",
+            Quantity::PowerNet => "net",
+            Quantity::Tariff => "tariff",
+        }
+    }
+
+    /// The value of `reading` that the rule compares, if it has it.
+    pub fn value(self, reading: &Reading) -> Option<i64> {
+        match self {
+            Quantity::PowerDelivered => reading.power_delivered?.w(),
+            Quantity::PowerReturned => reading.power_returned?.w(),
+            Quantity::PowerNet => {
+                Some(reading.power_delivered?.w()? - reading.power_returned?.w()?)
+            }
+            Quantity::Tariff => reading.tariff.map(i64::from),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// From 1.
+    pub relay: u8,
+    pub quantity: Quantity,
+    /// Whether the rule holds above `threshold`, or below it.
+    pub above: bool,
+    pub threshold: i32,
+    pub delay_s: u16,
+}
@@ -0,0 +1,96 @@
+//! Runs the tasks of the main loop one after the other, and keeps track of
+//! how long each of them takes compared to its budget.
+//!
+//! Tasks are cooperative: one that goes over its budget isn't interrupted,
+//! but it is counted and logged, so that the task that makes the main loop
+//! slow, and with it the UART and the network, can be found. Each run also
+//! checks the task in with the `Supervisor`.
+//!
+//! Tasks are timed with the cycle counter of the DWT, rather than the
+//! `Clock`, so that they can use the `Clock` themselves.
+
+use cortex_m::peripheral::{DCB, DWT};
+
+use crate::{
+    cpu_clock,
+    watchdog::{self, Task},
+};
+
+// Durations are averaged over roughly this many runs.
+const AVERAGE_SHIFT: u32 = 4;
+const TASKS: usize = Task::ALL.len();
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskStats {
+    pub runs: u32,
+    pub budget_us: u32,
+    /// The average duration of recent runs.
+    pub average_us: u32,
+    /// The longest run since boot.
+    pub max_us: u32,
+    /// Runs that took longer than the budget.
+    pub overruns: u32,
+}
+
+pub struct Scheduler {
+    stats: [TaskStats; TASKS],
+    // In us, scaled up by `AVERAGE_SHIFT`.
+    averages: [u32; TASKS],
+}
+
+impl Scheduler {
+    /// Starts the cycle counter. Tasks that have no budget in `budgets_us`
+    /// are never over budget.
+    pub fn new(dcb: &mut DCB, dwt: &mut DWT, budgets_us: &[(Task, u32)]) -> Self {
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+        let mut stats = [TaskStats {
+            budget_us: u32::MAX,
+            ..TaskStats::default()
+        }; TASKS];
+        for (task, budget_us) in budgets_us {
+            stats[*task as usize].budget_us = *budget_us;
+        }
+        Self {
+            stats,
+            averages: [0; TASKS],
+        }
+    }
+
+    /// Runs `task` by calling `run`, and returns what it returns.
+    pub fn run<R, F: FnOnce() -> R>(&mut self, task: Task, run: F) -> R {
+        let start = DWT::get_cycle_count();
+        let result = run();
+        // At the frequency the task ended at, which is close enough when it
+        // changed during the task.
+        let cycles_per_us = cpu_clock::hz() / 1_000_000;
+        let elapsed_us = DWT::get_cycle_count().wrapping_sub(start) / cycles_per_us;
+        watchdog::check_in(task);
+
+        let stats = &mut self.stats[task as usize];
+        let average = &mut self.averages[task as usize];
+        stats.runs = stats.runs.wrapping_add(1);
+        *average = *average - (*average >> AVERAGE_SHIFT) + elapsed_us;
+        stats.average_us = *average >> AVERAGE_SHIFT;
+        if elapsed_us > stats.budget_us {
+            stats.overruns = stats.overruns.wrapping_add(1);
+            // Only the worst overruns, so that a task that is always over
+            // budget doesn't flood the log.
+            if elapsed_us > stats.max_us {
+                log::warn!(
+                    "Task {} took {} us, over its budget of {} us",
+                    task.name(),
+                    elapsed_us,
+                    stats.budget_us
+                );
+            }
+        }
+        stats.max_us = stats.max_us.max(elapsed_us);
+        result
+    }
+
+    /// The statistics of each task, in the order of `Task::ALL`.
+    pub fn stats(&self) -> [TaskStats; TASKS] {
+        self.stats
+    }
+}
@@ -0,0 +1,277 @@
+//! Logs readings to an SD card in SPI mode, as CSV files named after the UTC
+//! date of their readings, such as `20201231.CSV`, with a column for every
+//! metric of the `Telemetry`.
+//!
+//! Lines are batched in RAM and appended once a minute. Every batch opens and
+//! closes its file, so that removing the card between batches is safe. When
+//! a write fails, the card is assumed to be gone, and it is initialised again
+//! until it is back. Lines that don't fit in the batch in the meantime are
+//! dropped.
+
+use arrayvec::ArrayString;
+use core::{
+    fmt::{self, Debug, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use dsmr42::Decimal;
+use embedded_hal::{digital::v2::OutputPin, spi::FullDuplex};
+use embedded_sdmmc::{
+    Controller, Directory, Mode, SdMmcError, SdMmcSpi, TimeSource, Timestamp, Volume, VolumeIdx,
+};
+
+use crate::{
+    dsmr::Reading,
+    sntp::DateTime,
+    telemetry::{Sample, Telemetry},
+};
+
+const BATCH_SZ: usize = 4096;
+const LINE_SZ: usize = 512;
+const HEADER_SZ: usize = 2048;
+const FLUSH_INTERVAL_MS: i64 = 60_000;
+const RETRY_INTERVAL_MS: i64 = 30_000;
+// Cards must be initialised at 400 kHz at most.
+const INIT_CLOCK_HZ: u32 = 400_000;
+// The earliest time FAT can store, 1980-01-01.
+const FAT_EPOCH: u32 = 315_532_800;
+
+type FileName = ArrayString<[u8; 12]>;
+type SdError = embedded_sdmmc::Error<SdMmcError>;
+
+// The time of the latest reading, in Unix seconds, which files are
+// timestamped with.
+static FILE_TIME: AtomicU32 = AtomicU32::new(FAT_EPOCH);
+
+struct FileClock;
+
+impl TimeSource for FileClock {
+    fn get_timestamp(&self) -> Timestamp {
+        let time = FILE_TIME.load(Ordering::Relaxed) as i64 * 1000;
+        let time = DateTime::from_unix_millis(time);
+        Timestamp {
+            year_since_1970: (time.year - 1970) as u8,
+            zero_indexed_month: time.month - 1,
+            zero_indexed_day: time.day - 1,
+            hours: time.hour,
+            minutes: time.minute,
+            seconds: time.second,
+        }
+    }
+}
+
+pub struct SdLogger<SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin,
+{
+    controller: Controller<SdMmcSpi<SPI, CS>, FileClock>,
+    set_clock: fn(&mut SPI, u32),
+    clock_hz: u32,
+    ready: bool,
+    retry_at: i64,
+    /// The file that the batch belongs to.
+    file: Option<FileName>,
+    batch: ArrayString<[u8; BATCH_SZ]>,
+    last_flush_at: i64,
+    dropped: u32,
+}
+
+impl<SPI, CS> SdLogger<SPI, CS>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin,
+{
+    /// `set_clock` sets the SPI clock speed in Hz. The card is initialised at
+    /// 400 kHz, after which it runs at `clock_hz`.
+    pub fn new(spi: SPI, cs: CS, set_clock: fn(&mut SPI, u32), clock_hz: u32) -> Self {
+        Self {
+            controller: Controller::new(SdMmcSpi::new(spi, cs), FileClock),
+            set_clock,
+            clock_hz,
+            ready: false,
+            retry_at: i64::min_value(),
+            file: None,
+            batch: ArrayString::new(),
+            last_flush_at: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Appends `sample` of the metrics of `telemetry`, taken of `reading`,
+    /// received at `now`, our own time, to the file of its date. Readings
+    /// are timestamped like in InfluxDB.
+    ///
+    /// Writing to the card takes a while, so this should be called right
+    /// after a telegram has been received, when there is time until the next
+    /// one.
+    pub fn record(&mut self, now: i64, reading: &Reading, telemetry: &Telemetry, sample: &Sample) {
+        let time = reading
+            .received_at
+            .or_else(|| reading.timestamp.map(|t| t.unix_time() * 1000));
+        let time = match time {
+            Some(time) => time,
+            None => return,
+        };
+        let date = DateTime::from_unix_millis(time);
+        let mut file = FileName::new();
+        // Always fits.
+        let _ = write!(file, "{:04}{:02}{:02}.CSV", date.year, date.month, date.day);
+        FILE_TIME.store(((time / 1000) as u32).max(FAT_EPOCH), Ordering::Relaxed);
+
+        if self.file != Some(file) {
+            if !self.batch.is_empty() && !self.flush(now, telemetry) {
+                self.drop_batch();
+            }
+            self.file = Some(file);
+        }
+        let mut line = ArrayString::<[u8; LINE_SZ]>::new();
+        if write_line(&mut line, date, sample).is_err() {
+            log::warn!("CSV line does not fit in {} bytes", LINE_SZ);
+            return;
+        }
+        if self.batch.remaining_capacity() < line.len() && !self.flush(now, telemetry) {
+            self.dropped = self.dropped.saturating_add(1);
+            return;
+        }
+        self.batch.push_str(&line);
+        if now - self.last_flush_at >= FLUSH_INTERVAL_MS {
+            self.flush(now, telemetry);
+        }
+    }
+
+    /// The number of readings that could not be written.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // Returns whether the batch was written.
+    fn flush(&mut self, now: i64, telemetry: &Telemetry) -> bool {
+        self.last_flush_at = now;
+        if !self.ready && !self.init(now) {
+            return false;
+        }
+        let file = match self.file {
+            Some(file) => file,
+            None => return true,
+        };
+        match append(&mut self.controller, &file, telemetry, &self.batch) {
+            Ok(()) => {
+                log::debug!("Wrote {} bytes to {}", self.batch.len(), file);
+                self.batch.clear();
+                true
+            }
+            Err(err) => {
+                log::warn!("Failed to write to SD card, was it removed? {:?}", err);
+                self.ready = false;
+                self.retry_at = now + RETRY_INTERVAL_MS;
+                false
+            }
+        }
+    }
+
+    fn init(&mut self, now: i64) -> bool {
+        if now < self.retry_at {
+            return false;
+        }
+        self.retry_at = now + RETRY_INTERVAL_MS;
+        (self.set_clock)(&mut *self.controller.device().spi(), INIT_CLOCK_HZ);
+        if let Err(err) = self.controller.device().init() {
+            log::warn!("Failed to initialise SD card: {:?}", err);
+            return false;
+        }
+        (self.set_clock)(&mut *self.controller.device().spi(), self.clock_hz);
+        match self.controller.device().card_size_bytes() {
+            Ok(size) => log::info!("SD card initialised, {} MB", size / 1_000_000),
+            Err(err) => log::info!("SD card initialised, unknown size: {:?}", err),
+        }
+        self.ready = true;
+        true
+    }
+
+    fn drop_batch(&mut self) {
+        let lines = self.batch.matches('\n').count() as u32;
+        self.dropped = self.dropped.saturating_add(lines);
+        log::warn!(
+            "Dropped {} readings that could not be written to SD card ({} so far)",
+            lines,
+            self.dropped
+        );
+        self.batch.clear();
+    }
+}
+
+fn append<SPI, CS>(
+    controller: &mut Controller<SdMmcSpi<SPI, CS>, FileClock>,
+    file: &str,
+    telemetry: &Telemetry,
+    batch: &str,
+) -> Result<(), SdError>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin,
+{
+    let mut volume = controller.get_volume(VolumeIdx(0))?;
+    let dir = controller.open_root_dir(&volume)?;
+    let result = append_in(controller, &mut volume, &dir, file, telemetry, batch);
+    controller.close_dir(&volume, dir);
+    result
+}
+
+fn append_in<SPI, CS>(
+    controller: &mut Controller<SdMmcSpi<SPI, CS>, FileClock>,
+    volume: &mut Volume,
+    dir: &Directory,
+    file: &str,
+    telemetry: &Telemetry,
+    batch: &str,
+) -> Result<(), SdError>
+where
+    SPI: FullDuplex<u8>,
+    SPI::Error: Debug,
+    CS: OutputPin,
+{
+    let mut file = controller.open_file_in_dir(volume, dir, file, Mode::ReadWriteCreateOrAppend)?;
+    let mut written = Ok(0);
+    if file.length() == 0 {
+        let mut header = ArrayString::<[u8; HEADER_SZ]>::new();
+        match write_header(&mut header, telemetry) {
+            Ok(()) => written = controller.write(volume, &mut file, header.as_bytes()),
+            Err(_) => log::warn!("CSV header does not fit in {} bytes", HEADER_SZ),
+        }
+    }
+    if written.is_ok() {
+        written = controller.write(volume, &mut file, batch.as_bytes());
+    }
+    // Close the file even if writing failed, so that the handle is released.
+    let closed = controller.close_file(volume, file);
+    written?;
+    closed
+}
+
+fn write_header<W: Write>(writer: &mut W, telemetry: &Telemetry) -> fmt::Result {
+    writer.write_str("time")?;
+    for metric in telemetry.metrics() {
+        write!(writer, ",{}", metric.key())?;
+    }
+    writer.write_str("\n")
+}
+
+fn write_line<W: Write>(writer: &mut W, time: DateTime, sample: &Sample) -> fmt::Result {
+    write!(writer, "{}", time)?;
+    for value in sample {
+        write_decimal(writer, *value)?;
+    }
+    writer.write_str("\n")
+}
+
+// Writes a column, which is empty if the value is missing.
+fn write_decimal<W: Write>(writer: &mut W, value: Option<Decimal>) -> fmt::Result {
+    match value {
+        // Without a unit, decimals are formatted as plain numbers.
+        Some(value) => write!(writer, ",{}", Decimal::new(value.value, value.scale, None)),
+        None => writer.write_str(","),
+    }
+}
@@ -0,0 +1,58 @@
+//! Ed25519 signatures over firmware images, so that only firmware signed with
+//! a known key is run or installed.
+//!
+//! A signed image is followed by a signature block of the magic `P1SG` and
+//! the 64-byte signature over the image, which `sign-image.py` appends. The
+//! image running from slot A is checked at startup, against the length in
+//! its boot data, and `ota` checks updates before staging them.
+//!
+//! There is no bootloader of our own, so an unsigned image is only stopped
+//! once it has started, by its own check. It keeps an attacker who can only
+//! reach the update server from installing their firmware, not one who can
+//! flash the Teensy over USB.
+
+use ed25519_compact::{PublicKey, Signature};
+
+use crate::{
+    flash::{Flash, SECTOR_SZ},
+    ota,
+};
+
+pub const BLOCK_SZ: usize = 4 + SIGNATURE_SZ;
+const MAGIC: u32 = 0x4753_3150;
+const SIGNATURE_SZ: usize = 64;
+
+/// Checks the signature block after the running image.
+pub fn verify_running(flash: &Flash, public_key: &[u8; 32]) -> Result<(), &'static str> {
+    verify(flash, 0, ota::running_len(), public_key)
+}
+
+/// Checks the signature block after the `len` bytes of the image that starts
+/// at `first_sector` of the program area.
+pub fn verify(
+    flash: &Flash,
+    first_sector: usize,
+    len: usize,
+    public_key: &[u8; 32],
+) -> Result<(), &'static str> {
+    let mut block = [0; BLOCK_SZ];
+    for (i, byte) in block.iter_mut().enumerate() {
+        let at = len + i;
+        *byte = flash.read_program(first_sector + at / SECTOR_SZ, at % SECTOR_SZ, 1)[0];
+    }
+    if u32::from_le_bytes([block[0], block[1], block[2], block[3]]) != MAGIC {
+        return Err("image is not signed");
+    }
+    let mut signature = [0; SIGNATURE_SZ];
+    signature.copy_from_slice(&block[4..]);
+    let mut state = PublicKey::new(*public_key)
+        .verify_incremental(&Signature::new(signature))
+        .map_err(|_| "malformed signature")?;
+    for start in (0..len).step_by(SECTOR_SZ) {
+        let chunk = (len - start).min(SECTOR_SZ);
+        state.absorb(flash.read_program(first_sector + start / SECTOR_SZ, 0, chunk));
+    }
+    state
+        .verify()
+        .map_err(|_| "image was not signed with the expected key")
+}
@@ -0,0 +1,305 @@
+//! A simulated DSMR 4.2 meter, for working on the meter reader on a bench
+//! without one. It sends a telegram every `interval_ms`, with a valid CRC,
+//! into the DSMR UART, so that everything from the UART on runs as it would
+//! with a meter.
+//!
+//! The household draws a power that wanders between `MIN_LOAD_W` and
+//! `MAX_LOAD_W`, and solar panels return up to `solar_peak_w` around noon,
+//! which the registers integrate. Nights and weekends are tariff 1, like
+//! most Dutch meters, by a clock of its own that starts at `start`, always in
+//! winter time. A gas meter on M-Bus channel 1 reports every 5 minutes.
+
+use arrayvec::ArrayString;
+use core::fmt::{self, Write as _};
+use embedded_hal::serial::Write;
+use teensy4_bsp::hal::iomuxc::prelude::consts;
+
+use crate::{random::Random, uart::DsmrUart};
+
+const TELEGRAM_SZ: usize = 1024;
+const MIN_LOAD_W: i64 = 150;
+const MAX_LOAD_W: i64 = 4_000;
+// The most the load changes by in a telegram.
+const LOAD_STEP_W: u32 = 120;
+// The sun is up from 7:00 to 19:00, winter or not.
+const SUNRISE_S: i64 = 7 * 3600;
+const SUNSET_S: i64 = 19 * 3600;
+const GAS_INTERVAL_S: i64 = 300;
+// Of gas, for heating, while the sun is down.
+const GAS_DM3_PER_HOUR: i64 = 400;
+const EQUIPMENT_ID: &str = "E0000000000000001";
+const GAS_EQUIPMENT_ID: &str = "G0000000000000001";
+// Central European Time.
+const UTC_OFFSET_S: i64 = 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Sent out of the DSMR UART, which receives it back in loopback mode,
+    /// so that the LPUART and its timing are part of it.
+    Loopback,
+    /// Put in the read buffer of the DSMR UART, all at once.
+    Memory,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimConfig {
+    pub transport: Transport,
+    pub interval_ms: i64,
+    /// When it starts, as a Unix time in s.
+    pub start: i64,
+    pub solar_peak_w: i64,
+    pub seed: u32,
+}
+
+pub struct Simulator {
+    config: SimConfig,
+    random: Random,
+    next_at: i64,
+    load_w: i64,
+    // Of each tariff, in mWh, so that small powers over short intervals
+    // add up.
+    delivered_mwh: [i64; 2],
+    returned_mwh: [i64; 2],
+    // Used since the gas meter last reported, in thousandths of dm³.
+    gas_mdm3: i64,
+    // When the gas meter last reported, as a Unix time in s, and its
+    // register then, in dm³.
+    gas_at: i64,
+    gas_dm3: i64,
+    telegram: ArrayString<[u8; TELEGRAM_SZ]>,
+    // Of `telegram`, the bytes that have been sent.
+    sent: usize,
+}
+
+impl Simulator {
+    pub fn new<M: consts::Unsigned, const N: usize>(
+        config: SimConfig,
+        uart: &mut DsmrUart<M, N>,
+    ) -> Self {
+        if config.transport == Transport::Loopback {
+            uart.set_loopback(true);
+        }
+        log::info!("Simulating a meter, through {:?}", config.transport);
+        Self {
+            config,
+            random: Random::new(config.seed),
+            next_at: 0,
+            load_w: 400,
+            delivered_mwh: [4_436_790_000, 4_234_483_000],
+            returned_mwh: [1_021_000_000, 2_310_000_000],
+            gas_mdm3: 0,
+            gas_at: config.start,
+            gas_dm3: 2_345_678,
+            telegram: ArrayString::new(),
+            sent: 0,
+        }
+    }
+
+    /// Starts the next telegram when it is due, and sends as much of it as
+    /// `uart` takes.
+    pub fn poll<M: consts::Unsigned, const N: usize>(
+        &mut self,
+        now: i64,
+        uart: &mut DsmrUart<M, N>,
+    ) {
+        if now >= self.next_at && self.sent == self.telegram.len() {
+            self.next_at = now + self.config.interval_ms;
+            self.step(now);
+            self.sent = 0;
+        }
+        let pending = &self.telegram.as_bytes()[self.sent..];
+        match self.config.transport {
+            Transport::Memory => self.sent += uart.inject_at(now, pending),
+            Transport::Loopback => {
+                for byte in pending {
+                    if uart.write(*byte).is_err() {
+                        break;
+                    }
+                    self.sent += 1;
+                }
+            }
+        }
+    }
+
+    /// Whether part of a telegram is still to be sent.
+    pub fn is_sending(&self) -> bool {
+        self.sent < self.telegram.len()
+    }
+
+    // Moves the meter on to `now`, and writes its telegram.
+    fn step(&mut self, now: i64) {
+        let time = self.config.start + now / 1000;
+        let local = time + UTC_OFFSET_S;
+        let day_s = local.rem_euclid(86_400);
+
+        let step = self.random.next(2 * LOAD_STEP_W + 1) as i64 - LOAD_STEP_W as i64;
+        self.load_w = (self.load_w + step).clamp(MIN_LOAD_W, MAX_LOAD_W);
+        let solar_w = if (SUNRISE_S..SUNSET_S).contains(&day_s) {
+            // Rising to its peak at noon, and falling after.
+            let half = (SUNSET_S - SUNRISE_S) / 2;
+            let from_noon = (day_s - SUNRISE_S - half).abs();
+            self.config.solar_peak_w * (half - from_noon) / half
+        } else {
+            0
+        };
+        let net_w = self.load_w - solar_w;
+        let tariff = tariff(local);
+        let interval_ms = self.config.interval_ms;
+        if net_w > 0 {
+            self.delivered_mwh[tariff - 1] += net_w * interval_ms / 3600;
+        } else {
+            self.returned_mwh[tariff - 1] += -net_w * interval_ms / 3600;
+        }
+        if solar_w == 0 {
+            self.gas_mdm3 += GAS_DM3_PER_HOUR * interval_ms / 3600;
+        }
+        if time - self.gas_at >= GAS_INTERVAL_S {
+            self.gas_at = time - time % GAS_INTERVAL_S;
+            self.gas_dm3 += self.gas_mdm3 / 1000;
+            self.gas_mdm3 %= 1000;
+        }
+
+        self.telegram.clear();
+        if self.write_telegram(local, tariff, net_w).is_err() {
+            log::warn!("Simulated telegram does not fit in {} bytes", TELEGRAM_SZ);
+            self.telegram.clear();
+        }
+    }
+
+    fn write_telegram(&mut self, local: i64, tariff: usize, net_w: i64) -> fmt::Result {
+        let delivered_w = net_w.max(0);
+        let returned_w = (-net_w).max(0);
+        // Of 230 V, give or take a few.
+        let voltage_dv = 2_300 + self.random.next(81) as i64 - 40;
+        let current_a = (delivered_w + returned_w) * 10 / voltage_dv;
+        let t = &mut self.telegram;
+
+        write!(t, "/ISk5\\2MT382-1000\r\n\r\n")?;
+        write!(t, "1-3:0.2.8(42)\r\n")?;
+        write!(t, "0-0:1.0.0(")?;
+        write_timestamp(t, local)?;
+        write!(t, ")\r\n0-0:96.1.1(")?;
+        write_hex(t, EQUIPMENT_ID)?;
+        write!(t, ")\r\n")?;
+        for (i, (delivered, returned)) in self
+            .delivered_mwh
+            .iter()
+            .zip(&self.returned_mwh)
+            .enumerate()
+        {
+            let (delivered, returned) = (delivered / 1000, returned / 1000);
+            write!(
+                t,
+                "1-0:1.8.{}({:06}.{:03}*kWh)\r\n",
+                i + 1,
+                delivered / 1000,
+                delivered % 1000
+            )?;
+            write!(
+                t,
+                "1-0:2.8.{}({:06}.{:03}*kWh)\r\n",
+                i + 1,
+                returned / 1000,
+                returned % 1000
+            )?;
+        }
+        write!(t, "0-0:96.14.0({:04})\r\n", tariff)?;
+        write!(
+            t,
+            "1-0:1.7.0({:02}.{:03}*kW)\r\n",
+            delivered_w / 1000,
+            delivered_w % 1000
+        )?;
+        write!(
+            t,
+            "1-0:2.7.0({:02}.{:03}*kW)\r\n",
+            returned_w / 1000,
+            returned_w % 1000
+        )?;
+        write!(t, "0-0:96.7.21(00002)\r\n0-0:96.7.9(00001)\r\n")?;
+        write!(t, "1-0:99.97.0(0)(0-0:96.7.19)\r\n")?;
+        write!(t, "1-0:32.32.0(00000)\r\n1-0:32.36.0(00000)\r\n")?;
+        write!(t, "0-0:96.13.1()\r\n0-0:96.13.0()\r\n")?;
+        write!(
+            t,
+            "1-0:32.7.0({:03}.{}*V)\r\n",
+            voltage_dv / 10,
+            voltage_dv % 10
+        )?;
+        write!(t, "1-0:31.7.0({:03}*A)\r\n", current_a)?;
+        write!(
+            t,
+            "1-0:21.7.0({:02}.{:03}*kW)\r\n",
+            delivered_w / 1000,
+            delivered_w % 1000
+        )?;
+        write!(
+            t,
+            "1-0:22.7.0({:02}.{:03}*kW)\r\n",
+            returned_w / 1000,
+            returned_w % 1000
+        )?;
+        write!(t, "0-1:24.1.0(003)\r\n0-1:96.1.0(")?;
+        write_hex(t, GAS_EQUIPMENT_ID)?;
+        write!(t, ")\r\n0-1:24.2.1(")?;
+        write_timestamp(t, self.gas_at + UTC_OFFSET_S)?;
+        write!(
+            t,
+            ")({:05}.{:03}*m3)\r\n!",
+            self.gas_dm3 / 1000,
+            self.gas_dm3 % 1000
+        )?;
+        let crc = dsmr42::crc16(t.as_bytes());
+        write!(t, "{:04X}\r\n", crc)
+    }
+}
+
+// Tariff 1 at night, from 23:00 to 7:00, and in the weekend, and tariff 2
+// otherwise, for a `local` Unix time.
+fn tariff(local: i64) -> usize {
+    let days = local.div_euclid(86_400);
+    let hour = local.rem_euclid(86_400) / 3600;
+    // 1970-01-01 was a Thursday.
+    let weekday = (days + 3).rem_euclid(7);
+    if weekday >= 5 || hour < 7 || hour >= 23 {
+        1
+    } else {
+        2
+    }
+}
+
+// As `YYMMDDhhmmssW`, of a `local` Unix time.
+fn write_timestamp<W: fmt::Write>(writer: &mut W, local: i64) -> fmt::Result {
+    let days = local.div_euclid(86_400);
+    let seconds = local.rem_euclid(86_400);
+    // The inverse of `Timestamp::unix_time`, of days since 1970-01-01, with
+    // years that start in March.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (year_of_era + era * 400, month + 3)
+    } else {
+        (year_of_era + era * 400 + 1, month - 9)
+    };
+    write!(
+        writer,
+        "{:02}{:02}{:02}{:02}{:02}{:02}W",
+        year % 100,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn write_hex<W: fmt::Write>(writer: &mut W, id: &str) -> fmt::Result {
+    id.bytes()
+        .try_for_each(|byte| write!(writer, "{:02X}", byte))
+}
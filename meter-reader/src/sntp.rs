@@ -0,0 +1,355 @@
+//! SNTP (RFC 4330) client that keeps track of the wall-clock time, so that
+//! readings can be timestamped independently of the meter's own clock.
+
+use core::fmt::{self, Display};
+
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    network::{client::UdpClient, stack},
+    random::Random,
+};
+
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_SZ: usize = 48;
+// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+// LI 0 (no warning), VN 4, mode 3 (client).
+const REQUEST_HEADER: u8 = 0x23;
+const MODE_SERVER: u8 = 4;
+const LI_UNSYNCHRONISED: u8 = 3;
+
+const SYNC_INTERVAL_MS: i64 = 60 * 60_000;
+// Until the first synchronisation succeeds, or after one failed.
+const RETRY_INTERVAL_MS: i64 = 10_000;
+const RESPONSE_TIMEOUT_MS: i64 = 5_000;
+// The drift is only estimated over intervals at least this long, since the
+// offsets themselves are only accurate to a few ms.
+const MIN_DRIFT_INTERVAL_MS: i64 = 10 * 60_000;
+// Anything beyond this is not drift, but a step of the server's clock.
+const MAX_DRIFT_PPM: i64 = 500;
+
+/// Converts our own clock, in ms since boot, to Unix time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WallClock {
+    /// Our own time and the Unix time at the last synchronisation, in ms.
+    synced: Option<(i64, i64)>,
+    /// How much faster the Unix time runs than our own clock, in ppm.
+    drift_ppm: i64,
+}
+
+impl WallClock {
+    /// The Unix time in ms at `now`, our own time, or `None` if the clock has
+    /// never been synchronised.
+    pub fn time(&self, now: i64) -> Option<i64> {
+        let (local, unix) = self.synced?;
+        let elapsed = now - local;
+        Some(unix + elapsed + elapsed * self.drift_ppm / 1_000_000)
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.is_some()
+    }
+
+    pub fn drift_ppm(&self) -> i64 {
+        self.drift_ppm
+    }
+
+    /// Records that it was `unix` at `now`, our own time. Returns how far the
+    /// clock was off, in ms.
+    fn sync(&mut self, now: i64, unix: i64) -> Option<i64> {
+        let error = self.time(now).map(|predicted| unix - predicted);
+        if let Some((local, prev)) = self.synced {
+            let elapsed = now - local;
+            if elapsed < MIN_DRIFT_INTERVAL_MS {
+                // Keep the older reference point, so that the next estimate
+                // covers a longer interval.
+                return error;
+            }
+            let drift = (unix - prev - elapsed) * 1_000_000 / elapsed;
+            if drift.abs() <= MAX_DRIFT_PPM {
+                self.drift_ppm = drift;
+            } else {
+                log::warn!("Ignoring implausible clock drift of {} ppm", drift);
+            }
+        }
+        self.synced = Some((now, unix));
+        error
+    }
+}
+
+/// A Unix time broken down into its date and time of day in UTC. Displays
+/// as an RFC 3339 timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl DateTime {
+    pub fn from_unix_millis(time: i64) -> Self {
+        let secs = time.div_euclid(1000);
+        let days = secs.div_euclid(86400);
+        let secs = secs.rem_euclid(86400);
+        // The inverse of `Timestamp::unix_time`, counting years from March
+        // so that the leap day comes last.
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = match month {
+            0..=9 => (era * 400 + year_of_era, month + 3),
+            _ => (era * 400 + year_of_era + 1, month - 9),
+        };
+        Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            millis: time.rem_euclid(1000) as u16,
+        }
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Idle {
+        next_at: i64,
+    },
+    /// Waiting for the response to a request that was sent at `sent_at`, our
+    /// own time, with `nonce` as its transmit timestamp.
+    Waiting {
+        sent_at: i64,
+        nonce: u64,
+    },
+}
+
+pub struct SntpClient {
+    handle: Option<SocketHandle>,
+    server: IpEndpoint,
+    state: State,
+    clock: WallClock,
+    failures: u32,
+}
+
+impl UdpClient for SntpClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<UdpSocket>,
+        now: i64,
+        random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        if !socket.is_open() {
+            let port = stack::generate_local_port(random);
+            if let Err(err) = socket.bind(port) {
+                log::warn!("Failed to bind SNTP socket to port {}: {}", port, err);
+                return;
+            }
+        }
+
+        while socket.can_recv() {
+            let response = match socket.recv() {
+                Ok((packet, from)) if from == self.server => Response::parse(packet),
+                Ok((_, from)) => {
+                    log::debug!("Ignoring UDP packet from {}", from);
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("Failed to receive SNTP response: {}", err);
+                    break;
+                }
+            };
+            if let State::Waiting { sent_at, nonce } = self.state {
+                match response {
+                    Some(response) if response.originate == nonce => {
+                        self.handle_response(sent_at, now, response)
+                    }
+                    Some(_) => log::debug!("Ignoring SNTP response to an earlier request"),
+                    None => log::warn!("Ignoring invalid SNTP response"),
+                }
+            }
+        }
+
+        match self.state {
+            State::Idle { next_at } if now >= next_at => {
+                self.send_request(&mut socket, now, random)
+            }
+            State::Waiting { sent_at, .. } if now - sent_at >= RESPONSE_TIMEOUT_MS => {
+                self.fail(now, "no response");
+            }
+            _ => {}
+        }
+    }
+}
+
+impl SntpClient {
+    pub fn new(server: [u8; 4]) -> Self {
+        Self {
+            handle: None,
+            server: IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(server)), NTP_PORT),
+            state: State::Idle {
+                next_at: i64::min_value(),
+            },
+            clock: WallClock::default(),
+            failures: 0,
+        }
+    }
+
+    /// The Unix time in ms at `now`, our own time, or `None` if the time has
+    /// never been synchronised.
+    pub fn time(&self, now: i64) -> Option<i64> {
+        self.clock.time(now)
+    }
+
+    pub fn clock(&self) -> WallClock {
+        self.clock
+    }
+
+    /// The number of requests that went unanswered or were answered with an
+    /// unusable response.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    fn send_request(&mut self, socket: &mut SocketRef<UdpSocket>, now: i64, random: &mut Random) {
+        // The server copies the transmit timestamp into the originate
+        // timestamp of its response. Our own time isn't known, so a random
+        // value is sent instead, which also identifies the response.
+        let nonce = (random.next_u32() as u64) << 32 | random.next_u32() as u64;
+        let mut request = [0; NTP_PACKET_SZ];
+        request[0] = REQUEST_HEADER;
+        request[40..48].copy_from_slice(&nonce.to_be_bytes());
+        match socket.send_slice(&request, self.server) {
+            Ok(()) => {
+                log::trace!("Sent SNTP request to {}", self.server);
+                self.state = State::Waiting {
+                    sent_at: now,
+                    nonce,
+                };
+            }
+            Err(err) => self.fail(now, err),
+        }
+    }
+
+    fn handle_response(&mut self, sent_at: i64, now: i64, response: Response) {
+        // Our own clock's offset from Unix time, assuming the request and the
+        // response took equally long.
+        let offset = ((response.receive - sent_at) + (response.transmit - now)) / 2;
+        let round_trip = (now - sent_at) - (response.transmit - response.receive);
+        match self.clock.sync(now, now + offset) {
+            Some(error) => log::info!(
+                "Synchronised clock with {}, {} ms off, drift {} ppm, round trip {} ms",
+                self.server,
+                error,
+                self.clock.drift_ppm,
+                round_trip
+            ),
+            None => log::info!(
+                "Synchronised clock with {}, round trip {} ms",
+                self.server,
+                round_trip
+            ),
+        }
+        self.state = State::Idle {
+            next_at: now + SYNC_INTERVAL_MS,
+        };
+    }
+
+    fn fail<D: Display>(&mut self, now: i64, reason: D) {
+        self.failures = self.failures.saturating_add(1);
+        log::warn!(
+            "SNTP request to {} failed: {} ({} failures so far)",
+            self.server,
+            reason,
+            self.failures
+        );
+        self.state = State::Idle {
+            next_at: now + RETRY_INTERVAL_MS,
+        };
+    }
+}
+
+struct Response {
+    originate: u64,
+    /// When the server received the request, in Unix ms.
+    receive: i64,
+    /// When the server sent the response, in Unix ms.
+    transmit: i64,
+}
+
+impl Response {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < NTP_PACKET_SZ {
+            return None;
+        }
+        let leap = packet[0] >> 6;
+        let mode = packet[0] & 0x07;
+        let stratum = packet[1];
+        // Stratum 0 is a "kiss-o'-death" message, telling us to back off.
+        if mode != MODE_SERVER || leap == LI_UNSYNCHRONISED || stratum == 0 {
+            return None;
+        }
+        let timestamp = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&packet[at..at + 8]);
+            u64::from_be_bytes(bytes)
+        };
+        let transmit = timestamp(40);
+        if transmit == 0 {
+            return None;
+        }
+        Some(Response {
+            originate: timestamp(24),
+            receive: unix_millis(timestamp(32)),
+            transmit: unix_millis(transmit),
+        })
+    }
+}
+
+// Converts an NTP timestamp, 32 bits of seconds and 32 bits of fraction, to
+// Unix time in ms.
+fn unix_millis(timestamp: u64) -> i64 {
+    let mut secs = (timestamp >> 32) as i64;
+    // The seconds wrap around in 2036. Timestamps with the highest bit clear
+    // are assumed to be after that, as RFC 4330 suggests.
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let millis = ((timestamp & 0xFFFF_FFFF) * 1000) >> 32;
+    (secs - NTP_UNIX_OFFSET) * 1000 + millis as i64
+}
@@ -0,0 +1,210 @@
+//! Log backend that forwards records to a syslog collector over UDP, in the
+//! format of RFC 5424.
+//!
+//! Records are queued by `LOGGER` and sent by a `SyslogClient` from the main
+//! loop, so that logging never touches the network stack itself, and never
+//! waits for it. Records logged before the network is up stay queued until
+//! then.
+
+use core::fmt::{self, Write};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use smoltcp::{
+    iface::EthernetInterface,
+    phy,
+    socket::{SocketHandle, SocketRef, UdpSocket},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+
+use crate::{
+    log_filter,
+    log_queue::{self, LogQueue},
+    network::{client::UdpClient, stack},
+    random::Random,
+    sntp::{DateTime, WallClock},
+};
+
+// Must fit in the socket's send buffer.
+const PACKET_SZ: usize = 512;
+// local0.
+const FACILITY: u8 = 16;
+
+pub static LOGGER: SyslogLogger = SyslogLogger {
+    queue: LogQueue::new(),
+};
+
+/// Installs `LOGGER` as the global logger, with `max_level` as the default
+/// of `log_filter`.
+pub fn init(max_level: LevelFilter) -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log_filter::set_default(max_level);
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SyslogConfig {
+    pub collector_addr: [u8; 4],
+    pub collector_port: u16,
+    pub hostname: &'static str,
+    pub app_name: &'static str,
+}
+
+/// Queues records for a `SyslogClient` to send. Logging only formats the
+/// record into a `LogQueue`, so it is safe from interrupt handlers, and
+/// doesn't keep interrupts masked while formatting.
+pub struct SyslogLogger {
+    queue: LogQueue,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Sending a record makes smoltcp log about it, which would keep the
+        // queue from ever draining.
+        (metadata.level() <= Level::Info || !metadata.target().starts_with("smoltcp"))
+            && log_filter::enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.queue.push(
+                record.level(),
+                format_args!("{}: {}", record.target(), record.args()),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends the records queued by `LOGGER`. They are timestamped when they are
+/// sent, since the logger doesn't know the time, which is at most one main
+/// loop iteration late for records logged once the network is up.
+pub struct SyslogClient {
+    handle: Option<SocketHandle>,
+    config: SyslogConfig,
+    collector: IpEndpoint,
+    clock: WallClock,
+    // Records that were dequeued but could not be sent.
+    failures: u32,
+}
+
+impl UdpClient for SyslogClient {
+    fn set_socket_handle(&mut self, handle: SocketHandle) {
+        self.handle = Some(handle);
+    }
+    fn get_socket_handle(&mut self) -> SocketHandle {
+        self.handle.unwrap()
+    }
+    fn poll<DeviceT>(
+        &mut self,
+        _interface: &mut EthernetInterface<DeviceT>,
+        mut socket: SocketRef<UdpSocket>,
+        now: i64,
+        random: &mut Random,
+    ) where
+        DeviceT: for<'d> phy::Device<'d>,
+    {
+        // Errors can't be logged here, since they would be queued for this
+        // very client.
+        if !socket.is_open() && socket.bind(stack::generate_local_port(random)).is_err() {
+            return;
+        }
+
+        let mut record = log_queue::Record::EMPTY;
+        let mut packet = Truncating::<PACKET_SZ>::new();
+        while socket.can_send() && LOGGER.queue.pop(&mut record) {
+            packet.clear();
+            let _ = self.write_header(&mut packet, severity(record.level), now);
+            let _ = packet.write_str(record.message());
+            if socket
+                .send_slice(packet.as_bytes(), self.collector)
+                .is_err()
+            {
+                self.failures = self.failures.saturating_add(1);
+                break;
+            }
+        }
+    }
+}
+
+impl SyslogClient {
+    pub fn new(config: SyslogConfig) -> Self {
+        let collector = IpAddress::Ipv4(Ipv4Address(config.collector_addr));
+        Self {
+            handle: None,
+            config,
+            collector: IpEndpoint::new(collector, config.collector_port),
+            clock: WallClock::default(),
+            failures: 0,
+        }
+    }
+
+    /// Replaces the clock that records are timestamped with.
+    pub fn set_clock(&mut self, clock: WallClock) {
+        self.clock = clock;
+    }
+
+    /// The number of records that were lost, either because they did not fit
+    /// in the queue or because they could not be sent.
+    pub fn dropped(&self) -> u32 {
+        LOGGER.queue.dropped().saturating_add(self.failures)
+    }
+
+    fn write_header<W: Write>(&self, writer: &mut W, severity: u8, now: i64) -> fmt::Result {
+        write!(writer, "<{}>1 ", FACILITY * 8 + severity)?;
+        match self.clock.time(now) {
+            Some(time) => write!(writer, "{}", DateTime::from_unix_millis(time))?,
+            None => writer.write_str("-")?,
+        }
+        // No process ID, message ID or structured data.
+        write!(
+            writer,
+            " {} {} - - - ",
+            self.config.hostname, self.config.app_name
+        )
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Writes as much as fits, and silently drops the rest.
+struct Truncating<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Truncating<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Write for Truncating<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! Restarting the meter reader, either into this firmware or into the
+//! bootloader, so that it can be reflashed without pressing the program
+//! button.
+
+use core::arch::asm;
+
+use cortex_m::{interrupt, peripheral::SCB};
+
+/// Something that has been asked of the system remotely, to be done once the
+/// answer has been sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemCommand {
+    Reboot,
+    EnterBootloader,
+}
+
+impl SystemCommand {
+    pub fn execute(self) -> ! {
+        match self {
+            SystemCommand::Reboot => reboot(),
+            SystemCommand::EnterBootloader => enter_bootloader(),
+        }
+    }
+}
+
+/// Restarts, which applies changed settings.
+pub fn reboot() -> ! {
+    log::info!("Rebooting");
+    SCB::sys_reset();
+}
+
+/// Restarts into HalfKay, the bootloader on the separate chip of the Teensy,
+/// which waits for `teensy_loader_cli` or the Teensy Loader to send new
+/// firmware over USB.
+pub fn enter_bootloader() -> ! {
+    log::info!("Entering the bootloader");
+    interrupt::disable();
+    // Like Teensyduino's `_reboot_Teensyduino_`: the bootloader chip watches
+    // the debug port, and takes over on this breakpoint.
+    unsafe { asm!("bkpt #251") };
+    // Only reached with a debugger attached, which caught the breakpoint.
+    SCB::sys_reset();
+}
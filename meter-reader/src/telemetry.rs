@@ -0,0 +1,625 @@
+//! A registry of everything that the meter reader measures, each value named,
+//! with its unit, so that uplinks can show all of them without knowing where
+//! they come from.
+//!
+//! The meter reader itself is registered when the `Telemetry` is created, and
+//! every other source, such as the P1 meter, the pulse counter or a
+//! temperature sensor, registers its values once it is set up. Sources other
+//! than the meter add their values to readings under OBIS codes, which their
+//! metrics are looked up by. For every reading, a `Sample` is taken of
+//! all values at once, which the uplinks render along with the registry.
+
+use core::fmt::{self, Display};
+
+use arrayvec::ArrayVec;
+use dsmr42::Decimal;
+
+use crate::dsmr::{Reading, MBUS_CHANNELS, TARIFFS};
+
+/// Enough for the meter, the meter reader, and about a dozen sensors.
+pub const MAX_METRICS: usize = 48;
+
+/// The values of the metrics of a `Telemetry`, in the same order, which are
+/// `None` if they are missing.
+pub type Sample = ArrayVec<[Option<Decimal>; MAX_METRICS]>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The P1 port.
+    Meter,
+    /// The S0 pulse counter.
+    Pulse,
+    Analog,
+    /// Temperature and humidity sensors.
+    Sensor,
+    /// The meter reader itself.
+    Reader,
+}
+
+impl Source {
+    /// The start of the names of its metrics.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Source::Meter => "meter",
+            Source::Pulse => "pulse",
+            Source::Analog => "analog",
+            Source::Sensor => "sensor",
+            Source::Reader => "reader",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Only ever goes up, such as energy.
+    Counter,
+    Gauge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    KWh,
+    KW,
+    W,
+    V,
+    A,
+    M3,
+    GJ,
+    Celsius,
+    Percent,
+    Seconds,
+}
+
+impl Unit {
+    /// As shown to people, such as `kWh`.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::KWh => "kWh",
+            Unit::KW => "kW",
+            Unit::W => "W",
+            Unit::V => "V",
+            Unit::A => "A",
+            Unit::M3 => "m³",
+            Unit::GJ => "GJ",
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Seconds => "s",
+        }
+    }
+
+    /// As the end of a metric name, like Prometheus names them.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Unit::KWh => "kwh",
+            Unit::KW => "kilowatts",
+            Unit::W => "watts",
+            Unit::V => "volts",
+            Unit::A => "amperes",
+            Unit::M3 => "cubic_meters",
+            Unit::GJ => "gigajoules",
+            Unit::Celsius => "celsius",
+            Unit::Percent => "percent",
+            Unit::Seconds => "seconds",
+        }
+    }
+}
+
+impl From<dsmr42::Unit> for Unit {
+    fn from(unit: dsmr42::Unit) -> Self {
+        match unit {
+            dsmr42::Unit::KWh => Unit::KWh,
+            dsmr42::Unit::KW => Unit::KW,
+            dsmr42::Unit::V => Unit::V,
+            dsmr42::Unit::A => Unit::A,
+            dsmr42::Unit::M3 => Unit::M3,
+            dsmr42::Unit::GJ => Unit::GJ,
+            dsmr42::Unit::S => Unit::Seconds,
+        }
+    }
+}
+
+/// Tells the metrics of the same name and unit apart. Tariffs, phases and
+/// channels are numbered from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Label {
+    Tariff(u8),
+    Phase(u8),
+    /// Of the M-Bus.
+    Channel(u8),
+    /// Of a sensor, such as `boiler`, in lower snake case.
+    Name(&'static str),
+}
+
+impl Label {
+    /// The name of the label in Prometheus.
+    pub fn key(self) -> &'static str {
+        match self {
+            Label::Tariff(_) => "tariff",
+            Label::Phase(_) => "phase",
+            Label::Channel(_) => "channel",
+            Label::Name(_) => "name",
+        }
+    }
+}
+
+/// The value, such as `1` for the first tariff, or `l1` for the first phase.
+impl Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Tariff(tariff) => write!(f, "{}", tariff),
+            Label::Phase(phase) => write!(f, "l{}", phase),
+            Label::Channel(channel) => write!(f, "{}", channel),
+            Label::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The values of the meter reader itself that metrics are taken from, as
+/// of a `Diagnostics`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReaderState {
+    pub uptime_ms: i64,
+    /// In m°C, if it could be read.
+    pub temperature_mc: Option<i32>,
+}
+
+/// Where the value of a metric is taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// By the index of the tariff.
+    Delivered(usize),
+    Returned(usize),
+    Tariff,
+    /// In W, rather than the kW the meter sends.
+    PowerDelivered,
+    PowerReturned,
+    /// By the index of the phase, in W.
+    PhasePowerDelivered(usize),
+    PhasePowerReturned(usize),
+    Voltage(usize),
+    Current(usize),
+    /// In W.
+    AverageDemand,
+    MaxDemand,
+    /// By the index of the channel.
+    Mbus(usize),
+    /// The custom value of the reading under this OBIS code.
+    Custom([u8; 6]),
+    Uptime,
+    /// Of the processor die.
+    Temperature,
+}
+
+impl Value {
+    fn get(self, reading: Option<&Reading>, reader: ReaderState) -> Option<Decimal> {
+        match self {
+            Value::Uptime => return Some(Decimal::new(reader.uptime_ms, 3, None)),
+            Value::Temperature => {
+                return reader
+                    .temperature_mc
+                    .map(|temperature| Decimal::new(temperature as i64, 3, None))
+            }
+            _ => {}
+        }
+        let reading = reading?;
+        match self {
+            Value::Delivered(tariff) => reading.delivered[tariff],
+            Value::Returned(tariff) => reading.returned[tariff],
+            Value::Tariff => reading
+                .tariff
+                .map(|tariff| Decimal::new(tariff as i64, 0, None)),
+            Value::PowerDelivered => watts(reading.power_delivered),
+            Value::PowerReturned => watts(reading.power_returned),
+            Value::PhasePowerDelivered(phase) => watts(reading.phases[phase].power_delivered),
+            Value::PhasePowerReturned(phase) => watts(reading.phases[phase].power_returned),
+            Value::Voltage(phase) => reading.phases[phase].voltage,
+            Value::Current(phase) => reading.phases[phase].current,
+            Value::AverageDemand => watts(reading.average_demand),
+            Value::MaxDemand => watts(reading.max_demand.map(|(_, demand)| demand)),
+            Value::Mbus(channel) => reading.mbus[channel]
+                .and_then(|mbus| mbus.reading)
+                .map(|(_, value)| value),
+            Value::Custom(obis) => reading
+                .custom
+                .iter()
+                .find(|(code, _)| *code == obis)
+                .map(|(_, value)| *value),
+            Value::Uptime | Value::Temperature => None,
+        }
+    }
+}
+
+fn watts(power: Option<Decimal>) -> Option<Decimal> {
+    power
+        .and_then(|power| power.w())
+        .map(|w| Decimal::new(w, 0, None))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metric {
+    pub source: Source,
+    /// In lower snake case, without the unit.
+    pub name: &'static str,
+    pub label: Option<Label>,
+    pub unit: Option<Unit>,
+    pub kind: Kind,
+    /// A short description, such as `Energy delivered to the client`.
+    pub help: &'static str,
+    pub value: Value,
+}
+
+impl Metric {
+    /// The name of its Prometheus family, such as
+    /// `meter_energy_delivered_kwh_total`, which it shares with the metrics
+    /// that differ only by their label.
+    pub fn family(&self) -> Family<'_> {
+        Family(self)
+    }
+
+    /// Unique among the metrics, such as `meter_energy_delivered_t1_kwh`, for
+    /// columns and keys.
+    pub fn key(&self) -> Key<'_> {
+        Key(self)
+    }
+
+    /// As shown to people, such as `Energy delivered, tariff 1`.
+    pub fn title(&self) -> Title<'_> {
+        Title(self)
+    }
+
+    pub fn is_same_family(&self, other: &Metric) -> bool {
+        self.source == other.source
+            && self.name == other.name
+            && self.unit == other.unit
+            && self.kind == other.kind
+    }
+}
+
+pub struct Family<'m>(&'m Metric);
+
+impl<'m> Display for Family<'m> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.0.source.prefix(), self.0.name)?;
+        if let Some(unit) = self.0.unit {
+            write!(f, "_{}", unit.suffix())?;
+        }
+        match self.0.kind {
+            Kind::Counter => f.write_str("_total"),
+            Kind::Gauge => Ok(()),
+        }
+    }
+}
+
+pub struct Key<'m>(&'m Metric);
+
+impl<'m> Display for Key<'m> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.0.source.prefix(), self.0.name)?;
+        match self.0.label {
+            Some(Label::Tariff(tariff)) => write!(f, "_t{}", tariff)?,
+            Some(Label::Channel(channel)) => write!(f, "_ch{}", channel)?,
+            Some(label) => write!(f, "_{}", label)?,
+            None => {}
+        }
+        match self.0.unit {
+            Some(unit) => write!(f, "_{}", unit.suffix()),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Title<'m>(&'m Metric);
+
+impl<'m> Display for Title<'m> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.help)?;
+        match self.0.label {
+            Some(Label::Name(name)) => write!(f, ", {}", name),
+            Some(label) => write!(f, ", {} {}", label.key(), label),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Telemetry {
+    metrics: ArrayVec<[Metric; MAX_METRICS]>,
+}
+
+impl Telemetry {
+    /// With the values of the meter reader itself.
+    pub fn new() -> Self {
+        let mut telemetry = Self {
+            metrics: ArrayVec::new(),
+        };
+        telemetry.register(Metric {
+            source: Source::Reader,
+            name: "uptime",
+            label: None,
+            unit: Some(Unit::Seconds),
+            kind: Kind::Gauge,
+            help: "Time since the meter reader started",
+            value: Value::Uptime,
+        });
+        telemetry.register(Metric {
+            source: Source::Reader,
+            name: "temperature",
+            label: None,
+            unit: Some(Unit::Celsius),
+            kind: Kind::Gauge,
+            help: "Temperature of the processor die",
+            value: Value::Temperature,
+        });
+        telemetry
+    }
+
+    /// Registers the values of the P1 meter, whether the meter sends them or
+    /// not, so that the metrics don't change with the telegrams.
+    pub fn register_meter(&mut self) {
+        for tariff in 0..TARIFFS {
+            self.register(meter(
+                "energy_delivered",
+                Some(Label::Tariff(tariff as u8 + 1)),
+                Some(Unit::KWh),
+                Kind::Counter,
+                "Energy delivered to the client",
+                Value::Delivered(tariff),
+            ));
+        }
+        for tariff in 0..TARIFFS {
+            self.register(meter(
+                "energy_returned",
+                Some(Label::Tariff(tariff as u8 + 1)),
+                Some(Unit::KWh),
+                Kind::Counter,
+                "Energy returned by the client",
+                Value::Returned(tariff),
+            ));
+        }
+        self.register(meter(
+            "tariff",
+            None,
+            None,
+            Kind::Gauge,
+            "The active tariff",
+            Value::Tariff,
+        ));
+        self.register(meter(
+            "power_delivered",
+            None,
+            Some(Unit::W),
+            Kind::Gauge,
+            "Power delivered to the client",
+            Value::PowerDelivered,
+        ));
+        self.register(meter(
+            "power_returned",
+            None,
+            Some(Unit::W),
+            Kind::Gauge,
+            "Power returned by the client",
+            Value::PowerReturned,
+        ));
+        let phases = [
+            (
+                "phase_power_delivered",
+                Some(Unit::W),
+                "Power delivered to the client per phase",
+                Value::PhasePowerDelivered as fn(usize) -> Value,
+            ),
+            (
+                "phase_power_returned",
+                Some(Unit::W),
+                "Power returned by the client per phase",
+                Value::PhasePowerReturned,
+            ),
+            ("voltage", Some(Unit::V), "Voltage", Value::Voltage),
+            ("current", Some(Unit::A), "Current", Value::Current),
+        ];
+        for (name, unit, help, value) in phases.iter() {
+            for phase in 0..3 {
+                self.register(meter(
+                    name,
+                    Some(Label::Phase(phase as u8 + 1)),
+                    *unit,
+                    Kind::Gauge,
+                    help,
+                    (*value)(phase),
+                ));
+            }
+        }
+        self.register(meter(
+            "average_demand",
+            None,
+            Some(Unit::W),
+            Kind::Gauge,
+            "Average demand in the current quarter hour",
+            Value::AverageDemand,
+        ));
+        self.register(meter(
+            "max_demand",
+            None,
+            Some(Unit::W),
+            Kind::Gauge,
+            "Highest average demand in the current month",
+            Value::MaxDemand,
+        ));
+        // In whatever unit the device sends, which isn't known up front.
+        for channel in 0..MBUS_CHANNELS {
+            self.register(meter(
+                "mbus_value",
+                Some(Label::Channel(channel as u8 + 1)),
+                None,
+                Kind::Gauge,
+                "Latest reading of an M-Bus device",
+                Value::Mbus(channel),
+            ));
+        }
+    }
+
+    pub fn register(&mut self, metric: Metric) {
+        if self.metrics.iter().any(|m| m.value == metric.value) {
+            log::warn!("Metric {} has the value of another", metric.key());
+        }
+        if self.metrics.try_push(metric).is_err() {
+            log::warn!("No room for metric {}", metric.key());
+        }
+    }
+
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    /// Takes the values of every metric from `reading`, if any, and from
+    /// `reader`.
+    pub fn sample(&self, reading: Option<&Reading>, reader: ReaderState) -> Sample {
+        self.metrics
+            .iter()
+            .map(|metric| metric.value.get(reading, reader))
+            .collect()
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn meter(
+    name: &'static str,
+    label: Option<Label>,
+    unit: Option<Unit>,
+    kind: Kind,
+    help: &'static str,
+    value: Value,
+) -> Metric {
+    Metric {
+        source: Source::Meter,
+        name,
+        label,
+        unit,
+        kind,
+        help,
+        value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{string::ToString, vec::Vec};
+
+    const STATE: ReaderState = ReaderState {
+        uptime_ms: 61_500,
+        temperature_mc: Some(41_250),
+    };
+
+    fn sensor(name: &'static str, obis: u8) -> Metric {
+        Metric {
+            source: Source::Sensor,
+            name,
+            label: None,
+            unit: Some(Unit::Celsius),
+            kind: Kind::Gauge,
+            help: "Temperature",
+            value: Value::Custom([0, 0, 0, 0, 0, obis]),
+        }
+    }
+
+    fn value(telemetry: &Telemetry, sample: &Sample, key: &str) -> Option<Decimal> {
+        let index = telemetry
+            .metrics()
+            .iter()
+            .position(|metric| metric.key().to_string() == key)
+            .unwrap();
+        sample[index]
+    }
+
+    #[test]
+    fn meter_metrics_have_unique_keys() {
+        let mut telemetry = Telemetry::new();
+        telemetry.register_meter();
+        let mut keys: Vec<_> = telemetry
+            .metrics()
+            .iter()
+            .map(|metric| metric.key().to_string())
+            .collect();
+        let registered = keys.len();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(registered, keys.len());
+        assert!(keys.contains(&"meter_energy_delivered_t1_kwh".to_string()));
+        assert!(keys.contains(&"meter_voltage_l3_volts".to_string()));
+        assert!(keys.contains(&"meter_mbus_value_ch4".to_string()));
+    }
+
+    #[test]
+    fn metric_with_the_value_of_another_is_registered() {
+        let mut telemetry = Telemetry::new();
+        telemetry.register(sensor("boiler", 1));
+        telemetry.register(sensor("boiler", 1));
+        // Still, so that the sample has a value for every metric.
+        assert_eq!(4, telemetry.metrics().len());
+        assert_eq!(4, telemetry.sample(None, STATE).len());
+    }
+
+    #[test]
+    fn metrics_beyond_capacity_are_dropped() {
+        let mut telemetry = Telemetry::new();
+        telemetry.register_meter();
+        for obis in 0..MAX_METRICS as u8 {
+            telemetry.register(sensor("boiler", obis));
+        }
+        assert_eq!(MAX_METRICS, telemetry.metrics().len());
+        assert_eq!(MAX_METRICS, telemetry.sample(None, STATE).len());
+    }
+
+    #[test]
+    fn sample_without_reading_has_only_reader_values() {
+        let mut telemetry = Telemetry::new();
+        telemetry.register_meter();
+        let sample = telemetry.sample(None, STATE);
+        assert_eq!(telemetry.metrics().len(), sample.len());
+        assert_eq!(
+            Some(Decimal::new(61_500, 3, None)),
+            value(&telemetry, &sample, "reader_uptime_seconds")
+        );
+        assert_eq!(
+            Some(Decimal::new(41_250, 3, None)),
+            value(&telemetry, &sample, "reader_temperature_celsius")
+        );
+        assert!(sample[2..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn sample_has_the_values_of_the_reading() {
+        let mut telemetry = Telemetry::new();
+        telemetry.register_meter();
+        telemetry.register(sensor("boiler", 1));
+        let energy = Decimal::new(4_436_790, 3, Some(dsmr42::Unit::KWh));
+        let mut reading = Reading::default();
+        reading.delivered[1] = Some(energy);
+        reading.power_delivered = Some(Decimal::new(1_190, 3, Some(dsmr42::Unit::KW)));
+        reading
+            .custom
+            .push(([0, 0, 0, 0, 0, 1], Decimal::new(605, 1, None)));
+        let sample = telemetry.sample(Some(&reading), STATE);
+        assert_eq!(
+            None,
+            value(&telemetry, &sample, "meter_energy_delivered_t1_kwh")
+        );
+        assert_eq!(
+            Some(energy),
+            value(&telemetry, &sample, "meter_energy_delivered_t2_kwh")
+        );
+        assert_eq!(
+            Some(Decimal::new(1_190, 0, None)),
+            value(&telemetry, &sample, "meter_power_delivered_watts")
+        );
+        assert_eq!(
+            Some(Decimal::new(605, 1, None)),
+            value(&telemetry, &sample, "sensor_boiler_celsius")
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! The temperature of the die, as measured by TEMPMON, which matters in a
+//! closed meter cupboard.
+//!
+//! TEMPMON measures periodically on its own, and counts down as the die
+//! warms up. Its counts are converted to degrees with the two calibration
+//! points that were fused into OCOTP: one at 25 °C, and one at a hot
+//! temperature.
+
+use core::ptr;
+
+const TEMPMON: usize = 0x400D_8180;
+const TEMPSENSE0: usize = TEMPMON;
+const TEMPSENSE0_SET: usize = TEMPMON + 0x04;
+const TEMPSENSE0_CLR: usize = TEMPMON + 0x08;
+const TEMPSENSE1: usize = TEMPMON + 0x10;
+const POWER_DOWN: u32 = 1 << 0;
+const MEASURE_TEMP: u32 = 1 << 1;
+const FINISHED: u32 = 1 << 2;
+// Measure every 0x8000 cycles of the 32 kHz clock, or once a second.
+const MEASURE_FREQ: u32 = 0x8000;
+// The calibration points of TEMPMON.
+const OCOTP_ANA1: usize = 0x401F_44E0;
+const ROOM_TEMP: i32 = 25;
+// Below the limit, the die has to cool down this much before it is no longer
+// too hot.
+const HYSTERESIS_MC: i32 = 5_000;
+
+pub struct TempMon {
+    hot_temp: i32,
+    hot_count: i32,
+    room_count: i32,
+    limit_mc: i32,
+    too_hot: bool,
+}
+
+impl TempMon {
+    /// Powers up the sensor. `poll` reports the die as too hot above
+    /// `limit_mc`, in thousandths of a degree Celsius.
+    pub fn init(limit_mc: i32) -> Self {
+        let ana1 = unsafe { ptr::read_volatile(OCOTP_ANA1 as *const u32) };
+        unsafe {
+            ptr::write_volatile(TEMPSENSE1 as *mut u32, MEASURE_FREQ);
+            ptr::write_volatile(TEMPSENSE0_CLR as *mut u32, POWER_DOWN);
+            ptr::write_volatile(TEMPSENSE0_SET as *mut u32, MEASURE_TEMP);
+        }
+        Self {
+            hot_temp: (ana1 & 0xFF) as i32,
+            hot_count: (ana1 >> 8 & 0xFFF) as i32,
+            room_count: (ana1 >> 20) as i32,
+            limit_mc,
+            too_hot: false,
+        }
+    }
+
+    /// The temperature of the die at the latest measurement, in thousandths
+    /// of a degree Celsius, or `None` before the first one.
+    pub fn temperature(&self) -> Option<i32> {
+        let sense = unsafe { ptr::read_volatile(TEMPSENSE0 as *const u32) };
+        if sense & FINISHED == 0 {
+            return None;
+        }
+        let count = (sense >> 8 & 0xFFF) as i32;
+        // Counts are linear in the temperature between the calibration
+        // points, and are extrapolated beyond them.
+        let range_mc = (self.hot_temp - ROOM_TEMP) * 1000;
+        let counts = (self.room_count - self.hot_count).max(1);
+        Some(self.hot_temp * 1000 - (count - self.hot_count) * range_mc / counts)
+    }
+
+    /// Whether the die is too hot, logging when that changes.
+    pub fn poll(&mut self) -> bool {
+        let temperature = match self.temperature() {
+            Some(temperature) => temperature,
+            None => return self.too_hot,
+        };
+        if !self.too_hot && temperature > self.limit_mc {
+            log::warn!(
+                "Die temperature {} °C is above {} °C",
+                temperature / 1000,
+                self.limit_mc / 1000
+            );
+            self.too_hot = true;
+        } else if self.too_hot && temperature < self.limit_mc - HYSTERESIS_MC {
+            log::info!("Die temperature back down to {} °C", temperature / 1000);
+            self.too_hot = false;
+        }
+        self.too_hot
+    }
+}
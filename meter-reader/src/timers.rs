@@ -0,0 +1,265 @@
+//! Software timers on top of the `Clock`, so that periodic and delayed work
+//! in the main loop doesn't each need a hardware timer, or its own
+//! bookkeeping of when it last ran.
+//!
+//! The `Clock` is the monotonic time base: the GPT counts freely, and its
+//! rollovers are counted into milliseconds since boot, which are passed in
+//! as `now`. Timers are kept in a hashed timing wheel of `WHEEL_SLOTS` slots
+//! of `TICK_MS` each, so a poll only looks at the timers due in the slots
+//! that passed since the previous one, rather than at all of them. Timers
+//! further out than one turn of the wheel stay in their slot until a later
+//! turn.
+
+/// The resolution of timers, in ms.
+pub const TICK_MS: i64 = 10;
+const WHEEL_SLOTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry<T> {
+    event: T,
+    /// In ms since boot.
+    deadline: i64,
+    period: Option<i64>,
+    // The next entry in the same slot, or in the list of expired entries.
+    next: Option<u8>,
+}
+
+/// Up to `N` timers, at most 256, each of which hands its `T` to `poll` when
+/// it expires.
+pub struct TimerWheel<T, const N: usize> {
+    entries: [Option<Entry<T>>; N],
+    // The first entry of each slot.
+    slots: [Option<u8>; WHEEL_SLOTS],
+    // The last tick that has been polled.
+    current: i64,
+}
+
+impl<T: Copy + PartialEq, const N: usize> TimerWheel<T, N> {
+    pub fn new() -> Self {
+        // Entries are linked by their index.
+        assert!(N <= u8::MAX as usize + 1);
+        Self {
+            entries: [None; N],
+            slots: [None; WHEEL_SLOTS],
+            current: 0,
+        }
+    }
+
+    /// Expires `event` once, `delay_ms` from now. Returns false if all
+    /// timers are in use.
+    pub fn after(&mut self, now: i64, delay_ms: i64, event: T) -> bool {
+        self.add(now + delay_ms, None, event)
+    }
+
+    /// Expires `event` every `period_ms`, starting `period_ms` from now.
+    /// Returns false if all timers are in use.
+    pub fn every(&mut self, now: i64, period_ms: i64, event: T) -> bool {
+        self.add(now + period_ms, Some(period_ms.max(1)), event)
+    }
+
+    /// Stops every timer of `event`.
+    pub fn cancel(&mut self, event: T) {
+        for slot in 0..WHEEL_SLOTS {
+            let mut link = self.slots[slot];
+            let mut previous: Option<u8> = None;
+            while let Some(index) = link {
+                let entry = self.entries[index as usize].unwrap();
+                link = entry.next;
+                if entry.event != event {
+                    previous = Some(index);
+                    continue;
+                }
+                match previous {
+                    Some(previous) => self.entry_mut(previous).next = entry.next,
+                    None => self.slots[slot] = entry.next,
+                }
+                self.entries[index as usize] = None;
+            }
+        }
+    }
+
+    /// Calls `on_expired` for every timer that has expired since the previous
+    /// poll. Periodic timers that were missed more than once expire once,
+    /// and continue a period from now.
+    pub fn poll<F: FnMut(T)>(&mut self, now: i64, mut on_expired: F) {
+        let tick = now / TICK_MS;
+        // Everything has come by after one turn of the wheel.
+        let passed = (tick - self.current).min(WHEEL_SLOTS as i64);
+        let mut expired = None;
+        for tick in tick - passed + 1..=tick {
+            let slot = tick as usize % WHEEL_SLOTS;
+            let mut link = self.slots[slot];
+            let mut previous: Option<u8> = None;
+            while let Some(index) = link {
+                let entry = self.entries[index as usize].unwrap();
+                link = entry.next;
+                if entry.deadline > now {
+                    previous = Some(index);
+                    continue;
+                }
+                match previous {
+                    Some(previous) => self.entry_mut(previous).next = entry.next,
+                    None => self.slots[slot] = entry.next,
+                }
+                self.entry_mut(index).next = expired;
+                expired = Some(index);
+            }
+        }
+        self.current = self.current.max(tick);
+
+        // Only once all slots have been walked, so that periodic timers can't
+        // be put back in a slot that is about to be walked.
+        while let Some(index) = expired {
+            let entry = self.entries[index as usize].unwrap();
+            expired = entry.next;
+            match entry.period {
+                // One that is merely late keeps its phase.
+                Some(period) if entry.deadline + period <= now => self.insert(index, now + period),
+                Some(period) => self.insert(index, entry.deadline + period),
+                None => self.entries[index as usize] = None,
+            }
+            on_expired(entry.event);
+        }
+    }
+
+    fn add(&mut self, deadline: i64, period: Option<i64>, event: T) -> bool {
+        let index = match self.entries.iter().position(Option::is_none) {
+            Some(index) => index as u8,
+            None => return false,
+        };
+        self.entries[index as usize] = Some(Entry {
+            event,
+            deadline,
+            period,
+            next: None,
+        });
+        self.insert(index, deadline);
+        true
+    }
+
+    // Links the entry at `index` into the slot of `deadline`.
+    fn insert(&mut self, index: u8, deadline: i64) {
+        // A slot that has already been polled is only walked again after a
+        // full turn.
+        let tick = (deadline / TICK_MS).max(self.current + 1);
+        let slot = tick as usize % WHEEL_SLOTS;
+        let next = self.slots[slot];
+        let entry = self.entry_mut(index);
+        entry.deadline = deadline;
+        entry.next = next;
+        self.slots[slot] = Some(index);
+    }
+
+    fn entry_mut(&mut self, index: u8) -> &mut Entry<T> {
+        self.entries[index as usize].as_mut().unwrap()
+    }
+}
+
+impl<T: Copy + PartialEq, const N: usize> Default for TimerWheel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Timer {
+        A,
+        B,
+    }
+
+    fn poll(timers: &mut TimerWheel<Timer, 4>, now: i64) -> Vec<Timer> {
+        let mut expired = Vec::new();
+        timers.poll(now, |timer| expired.push(timer));
+        expired
+    }
+
+    #[test]
+    fn one_shot_timer_expires_once() {
+        let mut timers = TimerWheel::new();
+        assert!(timers.after(0, 100, Timer::A));
+        assert!(poll(&mut timers, 90).is_empty());
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 100));
+        assert!(poll(&mut timers, 10_000).is_empty());
+    }
+
+    #[test]
+    fn timer_beyond_a_turn_of_the_wheel_waits_for_its_deadline() {
+        let mut timers = TimerWheel::new();
+        let delay = 3 * WHEEL_SLOTS as i64 * TICK_MS + 50;
+        timers.after(0, delay, Timer::A);
+        for now in (0..delay).step_by(TICK_MS as usize) {
+            assert!(poll(&mut timers, now).is_empty());
+        }
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, delay));
+    }
+
+    #[test]
+    fn periodic_timer_keeps_its_period() {
+        let mut timers = TimerWheel::new();
+        timers.every(0, 100, Timer::A);
+        timers.every(0, 250, Timer::B);
+        let mut expired = Vec::new();
+        for now in (0..=450).step_by(50) {
+            for timer in poll(&mut timers, now) {
+                expired.push((now, timer));
+            }
+        }
+        assert_eq!(
+            std::vec![
+                (100, Timer::A),
+                (200, Timer::A),
+                (250, Timer::B),
+                (300, Timer::A),
+                (400, Timer::A),
+            ],
+            expired
+        );
+    }
+
+    #[test]
+    fn polling_late_keeps_the_phase() {
+        let mut timers = TimerWheel::new();
+        timers.every(0, 100, Timer::A);
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 130));
+        // Still due at 200, rather than a period after the late poll.
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 200));
+    }
+
+    #[test]
+    fn missed_periods_expire_once_and_continue_from_now() {
+        let mut timers = TimerWheel::new();
+        timers.every(0, 100, Timer::A);
+        // Missed the expiries at 100 to 300, half a period ago.
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 350));
+        assert!(poll(&mut timers, 400).is_empty());
+        assert!(poll(&mut timers, 440).is_empty());
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 450));
+        assert_eq!(std::vec![Timer::A], poll(&mut timers, 550));
+    }
+
+    #[test]
+    fn cancelled_timers_dont_expire() {
+        let mut timers = TimerWheel::new();
+        timers.every(0, 100, Timer::A);
+        timers.after(0, 100, Timer::B);
+        timers.cancel(Timer::A);
+        assert_eq!(std::vec![Timer::B], poll(&mut timers, 100));
+        assert!(poll(&mut timers, 1000).is_empty());
+    }
+
+    #[test]
+    fn full_wheel_rejects_timers() {
+        let mut timers = TimerWheel::new();
+        for _ in 0..4 {
+            assert!(timers.after(0, 100, Timer::A));
+        }
+        assert!(!timers.after(0, 100, Timer::B));
+        poll(&mut timers, 100);
+        assert!(timers.after(100, 100, Timer::B));
+    }
+}
@@ -1,55 +1,792 @@
-use core::cmp;
+mod rs485;
+mod waker;
 
-use embedded_hal::serial::Read;
-use teensy4_bsp::hal::{iomuxc::prelude::consts, uart::UART};
+use core::{
+    cmp,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-const READ_BUF_SZ: usize = 1024;
+use embedded_hal::{
+    digital::v2::OutputPin,
+    serial::{Read, Write},
+};
+use embedded_io::ErrorKind;
+use smoltcp::time::Duration;
+use teensy4_bsp::hal::{
+    iomuxc::prelude::consts,
+    ral::{self, lpuart},
+    uart::{Parity, ReadError, ReadErrorFlags, UART},
+};
 
-pub struct DsmrUart {
-    uart: UART<consts::U2>,
-    read_buffer: [u8; READ_BUF_SZ],
-    read_buffer_pos: usize,
+use crate::{
+    clock::Clock,
+    dma,
+    framing::{self, Framing},
+    ring_buffer::RingBuffer,
+};
+
+use rs485::Rs485;
+pub use rs485::{Rs485Config, MAX_SEND_SZ, TX_DMA_SOURCES};
+pub use waker::on_receive_interrupt;
+
+const DEFAULT_LINE_DELIMITER: &[u8] = b"\r\n";
+const TIMESTAMP_MARKS: usize = 16;
+// Only 7-bit characters, so the test also passes with 7 data bits.
+const SELF_TEST_PATTERN: &[u8] = b"/SELF-TEST*U\r\n";
+const SELF_TEST_TIMEOUT_MS: u64 = 100;
+
+#[derive(Debug)]
+pub enum DsmrUartError {
+    /// The UART reported an error while reading a byte.
+    Read(ReadError),
+    /// The read buffer is full and incoming bytes were dropped. It must be
+    /// consumed or cleared before more data can be read.
+    BufferFull,
+    /// The UART reported an error while writing a byte.
+    Write,
+    /// The requested baud rate cannot be derived from the UART clock.
+    InvalidBaud(u32),
+    /// No complete data was received before the timeout expired.
+    Timeout,
+    /// The data received during the self test differed from what was sent.
+    SelfTestFailed,
+    /// An RS-485 send is still going, or RS-485 mode isn't enabled.
+    Busy,
+    /// More than `MAX_SEND_SZ` bytes.
+    TooLong,
+    /// Another device talked on the RS-485 bus while we were sending.
+    Collision,
+    /// The DMA failed to move an RS-485 send.
+    Dma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+impl embedded_io::Error for DsmrUartError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DsmrUartError::Read(_) => ErrorKind::InvalidData,
+            DsmrUartError::BufferFull => ErrorKind::OutOfMemory,
+            DsmrUartError::Write => ErrorKind::Other,
+            DsmrUartError::InvalidBaud(_) => ErrorKind::InvalidInput,
+            DsmrUartError::Timeout => ErrorKind::TimedOut,
+            DsmrUartError::SelfTestFailed => ErrorKind::Other,
+            DsmrUartError::Busy => ErrorKind::Other,
+            DsmrUartError::TooLong => ErrorKind::InvalidInput,
+            DsmrUartError::Collision => ErrorKind::Interrupted,
+            DsmrUartError::Dma => ErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DsmrUartStats {
+    /// Bytes that were added to the read buffer.
+    pub bytes_received: u32,
+    /// Bytes that were received but did not fit in the read buffer.
+    pub dropped_bytes: u32,
+    /// Highest number of bytes held in the read buffer.
+    pub max_fill: usize,
+    /// Number of receive interrupts handled through `on_interrupt`.
+    pub receive_interrupts: u32,
+    /// Number of times the UART FIFO overran, losing data.
+    pub overruns: u32,
+    pub framing_errors: u32,
+    pub parity_errors: u32,
+    pub noise_errors: u32,
+    /// RS-485 sends that collided with another device.
+    pub collisions: u32,
 }
 
-impl DsmrUart {
-    pub fn new(mut uart: UART<consts::U2>) -> Self {
+impl DsmrUartStats {
+    /// Returns the total number of errors reported by the UART hardware.
+    pub fn line_errors(&self) -> u32 {
+        self.overruns
+            .saturating_add(self.framing_errors)
+            .saturating_add(self.parity_errors)
+            .saturating_add(self.noise_errors)
+    }
+
+    fn record_read_error(&mut self, err: &ReadError) {
+        let counters = [
+            (ReadErrorFlags::OVERRUN, &mut self.overruns),
+            (ReadErrorFlags::FRAME_ERROR, &mut self.framing_errors),
+            (ReadErrorFlags::PARITY, &mut self.parity_errors),
+            (ReadErrorFlags::NOISY, &mut self.noise_errors),
+        ];
+        for (flag, counter) in counters.iter_mut() {
+            if err.flags.contains(*flag) {
+                **counter = counter.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Buffered reader for a UART connected to a DSMR meter.
+///
+/// `N` is the size of the read buffer, which must be able to hold at least one
+/// complete telegram.
+pub struct DsmrUart<M, const N: usize> {
+    uart: UART<M>,
+    read_buffer: RingBuffer<N>,
+    // Bytes at the start of the read buffer that have been handed out, but
+    // can only be removed once those borrows have ended.
+    pending_consume: usize,
+    line_delimiter: &'static [u8],
+    // High and low water marks for software RTS.
+    rts_watermarks: Option<(usize, usize)>,
+    rts_asserted: bool,
+    // Whether the receive interrupt was enabled when we were paused.
+    paused_rie: Option<u32>,
+    // Total number of bytes added to the read buffer, wrapping on overflow.
+    received: u32,
+    // For the most recent calls to poll_at that received data: the value of
+    // `received` before the call, and the time passed to it.
+    timestamp_marks: [Option<(u32, i64)>; TIMESTAMP_MARKS],
+    next_timestamp_mark: usize,
+    rs485: Option<Rs485>,
+    stats: DsmrUartStats,
+}
+
+impl<M: consts::Unsigned, const N: usize> DsmrUart<M, N> {
+    /// Set `rx_inverted` if the meter's inverted P1 signal is connected to the
+    /// RX pin directly, instead of through an external inverter.
+    pub fn new(mut uart: UART<M>, rx_inverted: bool) -> Self {
         uart.set_rx_fifo(true);
+        uart.set_rx_inversion(rx_inverted);
         Self {
             uart,
-            read_buffer: [0; READ_BUF_SZ],
-            read_buffer_pos: 0,
+            read_buffer: RingBuffer::new(),
+            pending_consume: 0,
+            line_delimiter: DEFAULT_LINE_DELIMITER,
+            rts_watermarks: None,
+            rts_asserted: false,
+            paused_rie: None,
+            received: 0,
+            timestamp_marks: [None; TIMESTAMP_MARKS],
+            next_timestamp_mark: 0,
+            rs485: None,
+            stats: DsmrUartStats::default(),
         }
     }
 
-    pub fn poll(&mut self) {
-        loop {
+    /// Moves all bytes currently held in the UART FIFO into the read buffer.
+    ///
+    /// Once the read buffer is full, the FIFO is still drained to prevent the
+    /// UART from overrunning, but the excess bytes are dropped and counted.
+    pub fn poll(&mut self) -> Result<(), DsmrUartError> {
+        self.consume(0);
+        let received = self.received;
+        let mut dropped = 0u32;
+        let res = loop {
             match self.uart.read() {
                 Ok(b) => {
-                    self.read_buffer[self.read_buffer_pos] = b;
-                    self.read_buffer_pos += 1;
+                    if self
+                        .rs485
+                        .as_mut()
+                        .map_or(false, |rs485| rs485.take_echo(b))
+                    {
+                        continue;
+                    }
+                    if self.read_buffer.push(b) {
+                        self.received = self.received.wrapping_add(1);
+                    } else {
+                        dropped += 1;
+                    }
                 }
-                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::WouldBlock) => break Ok(()),
                 Err(nb::Error::Other(e)) => {
-                    log::warn!("Error during polling: {:?}", e);
-                    break;
+                    self.stats.record_read_error(&e);
+                    if let Some(rs485) = self.rs485.as_mut() {
+                        rs485.on_read_error();
+                    }
+                    break Err(DsmrUartError::Read(e));
                 }
             }
+        };
+        self.stats.bytes_received = self
+            .stats
+            .bytes_received
+            .saturating_add(self.received.wrapping_sub(received));
+        self.stats.max_fill = cmp::max(self.stats.max_fill, self.read_buffer.len());
+        if dropped > 0 {
+            self.stats.dropped_bytes = self.stats.dropped_bytes.saturating_add(dropped);
+            log::trace!("Read buffer full, dropped {} bytes", dropped);
+            return Err(DsmrUartError::BufferFull);
+        }
+        res
+    }
+
+    /// Enables or disables the receive interrupt, which fires once the RX FIFO
+    /// holds more than `watermark` bytes. Returns the watermark that was
+    /// actually set, which is limited by the FIFO size.
+    ///
+    /// This is an alternative to polling from the main loop: the interrupt
+    /// handler should call `on_interrupt`.
+    pub fn set_receive_interrupt(&mut self, watermark: Option<u8>) -> u8 {
+        self.uart.set_receiver_interrupt(watermark)
+    }
+
+    /// Drains the RX FIFO and passes everything received so far to `callback`,
+    /// after which it is consumed. The callback is invoked twice if the data
+    /// wraps around the end of the read buffer.
+    pub fn on_interrupt<F: FnMut(&[u8])>(&mut self, mut callback: F) -> Result<(), DsmrUartError> {
+        self.stats.receive_interrupts = self.stats.receive_interrupts.saturating_add(1);
+        let res = self.poll();
+        let (first, second) = self.split_read();
+        for part in [first, second].iter().filter(|part| !part.is_empty()) {
+            callback(*part);
+        }
+        self.clear();
+        res
+    }
+
+    /// Polls the UART like `poll`, and records `now` as the time at which any
+    /// new data was received. See `timestamp`.
+    pub fn poll_at(&mut self, now: i64) -> Result<(), DsmrUartError> {
+        let received = self.received;
+        let res = self.poll();
+        self.mark_received(received, now);
+        res
+    }
+
+    /// Adds `bytes` to the read buffer as if they were received at `now`, for
+    /// the simulated meter. Returns how many fit.
+    #[cfg(feature = "sim")]
+    pub fn inject_at(&mut self, now: i64, bytes: &[u8]) -> usize {
+        self.consume(0);
+        let received = self.received;
+        let injected = bytes
+            .iter()
+            .take_while(|byte| self.read_buffer.push(**byte))
+            .count();
+        self.received = self.received.wrapping_add(injected as u32);
+        self.mark_received(received, now);
+        injected
+    }
+
+    /// Connects the transmitter to the receiver, as for the self test, so
+    /// that what is sent is received instead of what is on the RX pin, for
+    /// the simulated meter.
+    #[cfg(feature = "sim")]
+    pub fn set_loopback(&mut self, loopback: bool) {
+        self.while_disabled(
+            |reg| ral::modify_reg!(lpuart, reg, CTRL, LOOPS: loopback as u32, RSRC: 0),
+        );
+    }
+
+    // Records `now` as the time at which the bytes after `received` came in,
+    // if any did.
+    fn mark_received(&mut self, received: u32, now: i64) {
+        if self.received != received {
+            self.timestamp_marks[self.next_timestamp_mark] = Some((received, now));
+            self.next_timestamp_mark = (self.next_timestamp_mark + 1) % TIMESTAMP_MARKS;
+        }
+    }
+
+    /// Returns the time at which the byte at `offset` in the read buffer was
+    /// received, as passed to `poll_at`. Returns `None` if the byte was
+    /// received by `poll`, or is older than the recorded timestamps.
+    pub fn timestamp(&self, offset: usize) -> Option<i64> {
+        let buffered = (self.read_buffer.len() - self.pending_consume) as u32;
+        let index = self
+            .received
+            .wrapping_sub(buffered)
+            .wrapping_add(offset as u32);
+        // The byte was received by the most recent poll that started at or
+        // before it. Distances are wrapping, so a poll that started after the
+        // byte shows up as a huge distance.
+        self.timestamp_marks
+            .iter()
+            .flatten()
+            .map(|&(start, time)| (index.wrapping_sub(start), time))
+            .filter(|&(distance, _)| distance < u32::max_value() / 2)
+            .min_by_key(|&(distance, _)| distance)
+            .map(|(_, time)| time)
+    }
+
+    /// Stops receiving and masks the receive interrupt. Data that was already
+    /// received stays available.
+    pub fn pause(&mut self) {
+        if self.paused_rie.is_some() {
+            return;
+        }
+        let reg = self.registers();
+        self.paused_rie = Some(ral::read_reg!(lpuart, reg, CTRL, RIE));
+        ral::modify_reg!(lpuart, reg, CTRL, RE: 0, RIE: 0);
+        log::debug!("Paused UART{}", M::USIZE);
+    }
+
+    /// Starts receiving again after `pause`.
+    pub fn resume(&mut self) {
+        if let Some(rie) = self.paused_rie.take() {
+            let reg = self.registers();
+            ral::modify_reg!(lpuart, reg, CTRL, RE: 1, RIE: rie);
+            log::debug!("Resumed UART{}", M::USIZE);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_rie.is_some()
+    }
+
+    /// Releases the UART, with its receive interrupt disabled, so it can be
+    /// reconfigured or used elsewhere. Any buffered data is discarded.
+    pub fn release(mut self) -> UART<M> {
+        self.resume();
+        self.uart.set_receiver_interrupt(None);
+        self.uart
+    }
+
+    pub fn set_rx_inversion(&mut self, inverted: bool) {
+        self.uart.set_rx_inversion(inverted);
+    }
+
+    pub fn set_tx_inversion(&mut self, inverted: bool) {
+        self.uart.set_tx_inversion(inverted);
+    }
+
+    pub fn set_baud(&mut self, baud: u32) -> Result<(), DsmrUartError> {
+        self.uart.set_baud(baud).map_err(|err| {
+            log::warn!("Unable to set baud rate to {}: {:?}", baud, err);
+            DsmrUartError::InvalidBaud(baud)
+        })
+    }
+
+    pub fn set_parity(&mut self, parity: Option<Parity>) {
+        self.uart.set_parity(parity);
+    }
+
+    pub fn set_stop_bits(&mut self, stop_bits: StopBits) {
+        let sbns = match stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1,
+        };
+        // The HAL doesn't expose the stop bit configuration, so we have to
+        // access the register directly.
+        self.while_disabled(|reg| ral::modify_reg!(lpuart, reg, BAUD, SBNS: sbns));
+    }
+
+    /// Enables or disables the LPUART's own flow control. With `cts`, the
+    /// transmitter waits for CTS to be asserted. With `rts`, RTS is deasserted
+    /// while the receive FIFO is full.
+    ///
+    /// The CTS and RTS pads must be muxed to the LPUART by the caller, since
+    /// the HAL only provides pin types for TX and RX.
+    pub fn set_hardware_flow_control(&mut self, cts: bool, rts: bool) {
+        self.while_disabled(
+            |reg| ral::modify_reg!(lpuart, reg, MODIR, TXCTSE: cts as u32, RXRTSE: rts as u32),
+        );
+    }
+
+    /// Enables software flow control, in which `update_rts` deasserts RTS once
+    /// the read buffer holds `high_water` bytes, and asserts it again once it
+    /// drains below `low_water`. Unlike hardware RTS, which only protects the
+    /// FIFO, this protects the read buffer as well.
+    pub fn set_rts_watermarks(&mut self, high_water: usize, low_water: usize) {
+        assert!(
+            low_water <= high_water && high_water <= N,
+            "Invalid RTS watermarks"
+        );
+        self.rts_watermarks = Some((high_water, low_water));
+    }
+
+    /// Drives an active-low RTS pin according to the fill level of the read
+    /// buffer. Should be called after every `poll`.
+    pub fn update_rts<P: OutputPin>(&mut self, rts: &mut P) -> Result<(), P::Error> {
+        let (high_water, low_water) = match self.rts_watermarks {
+            Some(watermarks) => watermarks,
+            None => return Ok(()),
+        };
+        let fill = self.read_buffer.len() - self.pending_consume;
+        if self.rts_asserted && fill >= high_water {
+            log::trace!("Read buffer at {} bytes, deasserting RTS", fill);
+            self.rts_asserted = false;
+            rts.set_high()?;
+        } else if !self.rts_asserted && fill < low_water {
+            self.rts_asserted = true;
+            rts.set_low()?;
+        }
+        Ok(())
+    }
+
+    /// Puts the UART in RS-485 mode, in which `start_send` sends through
+    /// `dma`, a channel for the `TX_DMA_SOURCES` of this LPUART, with the
+    /// driver enabled by a GPIO.
+    pub fn set_rs485(&mut self, dma: dma::Channel, config: Rs485Config) {
+        self.rs485 = Some(Rs485::new(dma, config));
+    }
+
+    /// Asserts `de`, the driver enable pin, and starts sending `message`
+    /// once the turnaround has passed. `poll_send` moves it along.
+    pub fn start_send<P>(&mut self, message: &[u8], de: &mut P) -> Result<(), DsmrUartError>
+    where
+        P: OutputPin<Error = Infallible>,
+    {
+        match self.rs485.as_mut() {
+            Some(rs485) => rs485.start(message, de),
+            None => Err(DsmrUartError::Busy),
+        }
+    }
+
+    /// Whether the send that `start_send` started is done, in which case
+    /// `de` has been deasserted. Also `Ok` if there is none. Should be
+    /// called often while sending, since it also takes the echoes from the
+    /// receiver.
+    pub fn poll_send<P>(&mut self, de: &mut P) -> nb::Result<(), DsmrUartError>
+    where
+        P: OutputPin<Error = Infallible>,
+    {
+        if !self.rs485.as_ref().map_or(false, Rs485::is_sending) {
+            return Ok(());
+        }
+        // Errors while sending are collisions, which the send reports.
+        let _ = self.poll();
+        let reg = self.registers();
+        let result = match self.rs485.as_mut() {
+            Some(rs485) => rs485.poll(&reg, de),
+            None => Ok(()),
+        };
+        if let Err(nb::Error::Other(DsmrUartError::Collision)) = result {
+            self.stats.collisions = self.stats.collisions.saturating_add(1);
+        }
+        result
+    }
+
+    /// Sends a test pattern with the LPUART in loopback mode, and checks that
+    /// it is received intact. The read buffer is cleared before and after.
+    pub fn self_test(&mut self, clock: &mut Clock) -> Result<(), DsmrUartError> {
+        // In loopback mode the receiver is disconnected from the RX pin, so
+        // the meter can't interfere with the test.
+        self.while_disabled(|reg| ral::modify_reg!(lpuart, reg, CTRL, LOOPS: 1, RSRC: 0));
+        self.clear();
+        let res = self.run_self_test(clock);
+        self.while_disabled(|reg| ral::modify_reg!(lpuart, reg, CTRL, LOOPS: 0));
+        self.clear();
+        res
+    }
+
+    fn run_self_test(&mut self, clock: &mut Clock) -> Result<(), DsmrUartError> {
+        // Drain the receiver after every byte, since the pattern doesn't fit
+        // in the RX FIFO.
+        for &byte in SELF_TEST_PATTERN {
+            nb::block!(Write::write(self, byte))?;
+            self.poll()?;
+        }
+        nb::block!(Write::flush(self))?;
+        let deadline = clock.instant() + Duration::from_millis(SELF_TEST_TIMEOUT_MS);
+        while self.read_buffer.len() < SELF_TEST_PATTERN.len() {
+            self.poll()?;
+            if clock.instant() >= deadline {
+                log::warn!(
+                    "Self test received {} of {} bytes",
+                    self.read_buffer.len(),
+                    SELF_TEST_PATTERN.len()
+                );
+                return Err(DsmrUartError::Timeout);
+            }
+        }
+        let received = self.read_buffer.make_contiguous();
+        if received != SELF_TEST_PATTERN {
+            log::warn!("Self test received {:?}", core::str::from_utf8(received));
+            return Err(DsmrUartError::SelfTestFailed);
         }
+        Ok(())
     }
 
-    pub fn get_buffer(&self) -> &[u8] {
-        &self.read_buffer[..self.read_buffer_pos]
+    /// Runs `f` with the transmitter and receiver disabled, as is required for
+    /// modifying most of the LPUART configuration registers.
+    fn while_disabled<F: FnOnce(&lpuart::Instance)>(&self, f: F) {
+        let reg = self.registers();
+        let (te, re) = ral::read_reg!(lpuart, reg, CTRL, TE, RE);
+        ral::modify_reg!(lpuart, reg, CTRL, TE: 0, RE: 0);
+        f(&reg);
+        ral::modify_reg!(lpuart, reg, CTRL, TE: te, RE: re);
+    }
+
+    fn registers(&self) -> lpuart::Instance {
+        registers::<M>()
+    }
+
+    pub fn stats(&self) -> DsmrUartStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = DsmrUartStats::default();
+    }
+
+    /// Returns the longest contiguous slice at the start of the read buffer.
+    pub fn peek(&self) -> &[u8] {
+        self.split_read().0
+    }
+
+    /// Returns the contents of the read buffer as two slices, the second of
+    /// which is only non-empty if the contents wrap around the end of the
+    /// buffer.
+    pub fn split_read(&self) -> (&[u8], &[u8]) {
+        let (first, second) = self.read_buffer.split_read();
+        if self.pending_consume < first.len() {
+            (&first[self.pending_consume..], second)
+        } else {
+            (&second[self.pending_consume - first.len()..], &[])
+        }
     }
 
     /// Advances the read buffer by `count` bytes.
     pub fn consume(&mut self, count: usize) {
-        let count = cmp::min(count, self.read_buffer_pos);
-        self.read_buffer.copy_within(count.., 0);
-        self.read_buffer_pos -= count;
+        self.read_buffer.consume(self.pending_consume + count);
+        self.pending_consume = 0;
     }
 
     pub fn clear(&mut self) {
-        self.read_buffer = [0; READ_BUF_SZ];
-        self.read_buffer_pos = 0;
+        self.read_buffer.clear();
+        self.pending_consume = 0;
+    }
+
+    /// Sets the byte sequence that terminates a line. Defaults to `\r\n`.
+    pub fn set_line_delimiter(&mut self, delimiter: &'static [u8]) {
+        assert!(!delimiter.is_empty(), "Line delimiter may not be empty");
+        self.line_delimiter = delimiter;
+    }
+
+    /// Returns the next complete line in the read buffer, without its
+    /// delimiter. The line is consumed as soon as the buffer is modified again.
+    pub fn read_line(&mut self) -> Option<&[u8]> {
+        self.lines().next()
+    }
+
+    /// Returns an iterator over all complete lines in the read buffer, without
+    /// their delimiters. Lines are consumed as they are returned.
+    pub fn lines(&mut self) -> Lines<'_> {
+        self.consume(0);
+        Lines {
+            buffer: self.read_buffer.make_contiguous(),
+            delimiter: self.line_delimiter,
+            consumed: &mut self.pending_consume,
+        }
+    }
+
+    /// Returns the next complete frame in the read buffer, discarding any data
+    /// preceding it. The frame is consumed as soon as the buffer is modified
+    /// again.
+    pub fn read_frame(&mut self, framing: &Framing) -> Option<&[u8]> {
+        self.read_frame_timestamped(framing).map(|(frame, _)| frame)
+    }
+
+    /// Like `read_frame`, but also returns the time at which the frame started
+    /// to arrive. See `timestamp`.
+    pub fn read_frame_timestamped(&mut self, framing: &Framing) -> Option<(&[u8], Option<i64>)> {
+        self.consume(0);
+        self.read_buffer.make_contiguous();
+        let (consumed, frame) = framing.scan(self.read_buffer.peek());
+        let timestamp = frame.and_then(|frame| self.timestamp(consumed - frame.len()));
+        self.pending_consume = consumed;
+        frame.map(|frame| (frame, timestamp))
+    }
+
+    /// Polls the UART until a complete frame has been received, and returns it
+    /// like `read_frame`. Gives up once `timeout` has passed, which can be used
+    /// to detect an unresponsive meter.
+    pub fn read_frame_with_timeout(
+        &mut self,
+        framing: &Framing,
+        clock: &mut Clock,
+        timeout: Duration,
+    ) -> Result<&[u8], DsmrUartError> {
+        let deadline = clock.instant() + timeout;
+        loop {
+            match self.poll_at(clock.millis()) {
+                // Dropped bytes are counted, and the garbage will be discarded
+                // below, so we can keep waiting for a frame.
+                Ok(()) | Err(DsmrUartError::BufferFull) => {}
+                Err(err) => return Err(err),
+            }
+            self.consume(0);
+            self.read_buffer.make_contiguous();
+            match framing.scan(self.read_buffer.peek()) {
+                (_, Some(_)) => break,
+                (garbage, None) => self.consume(garbage),
+            }
+            if clock.instant() >= deadline {
+                return Err(DsmrUartError::Timeout);
+            }
+        }
+        self.read_frame(framing).ok_or(DsmrUartError::Timeout)
+    }
+
+    /// Reads at least one byte into `buf`, waiting for the receive interrupt if
+    /// no data is buffered. See `on_receive_interrupt`.
+    pub async fn read_async(&mut self, buf: &mut [u8]) -> Result<usize, DsmrUartError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.peek().is_empty() {
+            Receive { uart: &mut *self }.await?;
+        }
+        Ok(self.read_buffered(buf))
+    }
+
+    /// Waits until `delim` has been received, and returns all data up to and
+    /// including it. The data is consumed as soon as the buffer is modified
+    /// again. See `on_receive_interrupt`.
+    pub async fn read_until_async(&mut self, delim: u8) -> Result<&[u8], DsmrUartError> {
+        loop {
+            self.consume(0);
+            let contents = self.read_buffer.make_contiguous();
+            if let Some(pos) = contents.iter().position(|&b| b == delim) {
+                self.pending_consume = pos + 1;
+                return Ok(&self.read_buffer.make_contiguous()[..=pos]);
+            }
+            Receive { uart: &mut *self }.await?;
+        }
+    }
+
+    /// Copies as many buffered bytes as fit into `buf` and consumes them.
+    fn read_buffered(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        let (first, second) = self.split_read();
+        for part in [first, second].iter() {
+            let len = cmp::min(buf.len() - count, part.len());
+            buf[count..count + len].copy_from_slice(&part[..len]);
+            count += len;
+        }
+        self.consume(count);
+        count
+    }
+}
+
+/// Resolves once new data has been added to the read buffer of `uart`.
+struct Receive<'a, M, const N: usize> {
+    uart: &'a mut DsmrUart<M, N>,
+}
+
+impl<'a, M: consts::Unsigned, const N: usize> Future for Receive<'a, M, N> {
+    type Output = Result<(), DsmrUartError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let uart = &mut *self.uart;
+        uart.consume(0);
+        let len = uart.read_buffer.len();
+        let res = uart.poll();
+        if res.is_err() || uart.read_buffer.len() > len {
+            return Poll::Ready(res);
+        }
+        // If a byte arrives after we drained the FIFO, the interrupt fires as
+        // soon as it is enabled, so we can't miss a wakeup here.
+        waker::register::<M>(cx.waker());
+        Poll::Pending
+    }
+}
+
+fn registers<M: consts::Unsigned>() -> lpuart::Instance {
+    // Safety: DsmrUart owns the UART<M>, so nothing outside this module
+    // accesses these registers.
+    unsafe {
+        match M::USIZE {
+            1 => lpuart::LPUART1::steal(),
+            2 => lpuart::LPUART2::steal(),
+            3 => lpuart::LPUART3::steal(),
+            4 => lpuart::LPUART4::steal(),
+            5 => lpuart::LPUART5::steal(),
+            6 => lpuart::LPUART6::steal(),
+            7 => lpuart::LPUART7::steal(),
+            8 => lpuart::LPUART8::steal(),
+            other => unreachable!("LPUART{} does not exist", other),
+        }
+    }
+}
+
+pub struct Lines<'a> {
+    buffer: &'a [u8],
+    delimiter: &'static [u8],
+    consumed: &'a mut usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buffer = self.buffer;
+        let end = framing::find(buffer, self.delimiter)?;
+        let line_len = end + self.delimiter.len();
+        self.buffer = &buffer[line_len..];
+        *self.consumed += line_len;
+        Some(&buffer[..end])
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> Read<u8> for DsmrUart<M, N> {
+    type Error = DsmrUartError;
+
+    fn read(&mut self) -> nb::Result<u8, DsmrUartError> {
+        if self.peek().is_empty() {
+            self.poll()?;
+        }
+        let mut byte = [0];
+        match self.read_buffered(&mut byte) {
+            0 => Err(nb::Error::WouldBlock),
+            _ => Ok(byte[0]),
+        }
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> Write<u8> for DsmrUart<M, N> {
+    type Error = DsmrUartError;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), DsmrUartError> {
+        self.uart
+            .write(word)
+            .map_err(|e| e.map(|_| DsmrUartError::Write))
+    }
+
+    fn flush(&mut self) -> nb::Result<(), DsmrUartError> {
+        self.uart
+            .flush()
+            .map_err(|e| e.map(|_| DsmrUartError::Write))
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> embedded_io::ErrorType for DsmrUart<M, N> {
+    type Error = DsmrUartError;
+}
+
+impl<M: consts::Unsigned, const N: usize> embedded_io::Read for DsmrUart<M, N> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DsmrUartError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // embedded-io requires us to block until at least one byte is available.
+        while self.peek().is_empty() {
+            self.poll()?;
+        }
+        Ok(self.read_buffered(buf))
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> embedded_io::ReadReady for DsmrUart<M, N> {
+    fn read_ready(&mut self) -> Result<bool, DsmrUartError> {
+        self.poll()?;
+        Ok(!self.peek().is_empty())
+    }
+}
+
+impl<M: consts::Unsigned, const N: usize> embedded_io::Write for DsmrUart<M, N> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, DsmrUartError> {
+        for &byte in buf {
+            nb::block!(Write::write(self, byte))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), DsmrUartError> {
+        nb::block!(Write::flush(self))
     }
 }
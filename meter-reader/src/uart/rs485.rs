@@ -0,0 +1,172 @@
+//! The half-duplex transmitter of RS-485 mode: the DMA moves a message into
+//! the transmit FIFO while the driver enable pin is asserted, and the pin is
+//! only released once the last stop bit is out, and the bus has had time to
+//! settle.
+//!
+//! With a transceiver whose receiver stays on while it sends, every byte we
+//! send comes back to us. Those echoes are taken out of what is received,
+//! and compared to what was sent, so that another device talking at the
+//! same time is noticed as a collision.
+
+use core::convert::Infallible;
+
+use cortex_m::peripheral::DWT;
+use embedded_hal::digital::v2::OutputPin;
+use teensy4_bsp::hal::ral::{self, lpuart};
+
+use super::DsmrUartError;
+use crate::{cpu_clock, dma::Channel};
+
+/// The DMAMUX request sources of the transmitters of LPUART1 to 8.
+pub const TX_DMA_SOURCES: [u32; 8] = [2, 66, 4, 68, 6, 70, 8, 72];
+/// The longest message, which is that of Modbus RTU.
+pub const MAX_SEND_SZ: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rs485Config {
+    /// How long the driver is enabled before the first start bit, and after
+    /// the last stop bit, for the transceiver and the bus to settle.
+    pub turnaround_us: u32,
+    /// Whether the receiver hears our own sends, so that they can be checked.
+    pub echo: bool,
+    /// How long a send may take before it is given up, including the
+    /// turnarounds.
+    pub timeout_us: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// The driver is enabled, but nothing is sent until the turnaround.
+    Enabling,
+    Sending,
+    /// The last stop bit is out, and the driver is released after the
+    /// turnaround.
+    Draining {
+        since: u32,
+    },
+}
+
+pub(super) struct Rs485 {
+    dma: Channel,
+    config: Rs485Config,
+    state: State,
+    // For the DMA, which reads from here.
+    message: [u8; MAX_SEND_SZ],
+    len: usize,
+    started: u32,
+    // The echoes of `message` up to here have come back.
+    echoed: usize,
+    collided: bool,
+}
+
+impl Rs485 {
+    pub fn new(dma: Channel, config: Rs485Config) -> Self {
+        Self {
+            dma,
+            config,
+            state: State::Idle,
+            message: [0; MAX_SEND_SZ],
+            len: 0,
+            started: 0,
+            echoed: 0,
+            collided: false,
+        }
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.state != State::Idle
+    }
+
+    pub fn start<P>(&mut self, message: &[u8], de: &mut P) -> Result<(), DsmrUartError>
+    where
+        P: OutputPin<Error = Infallible>,
+    {
+        if self.is_sending() {
+            return Err(DsmrUartError::Busy);
+        }
+        if message.len() > MAX_SEND_SZ {
+            return Err(DsmrUartError::TooLong);
+        }
+        self.message[..message.len()].copy_from_slice(message);
+        self.len = message.len();
+        self.echoed = 0;
+        self.collided = false;
+        let _ = de.set_high();
+        self.started = DWT::get_cycle_count();
+        self.state = State::Enabling;
+        Ok(())
+    }
+
+    /// Moves the send along, and once it is done, returns whether it went
+    /// well. Echoes must have been taken from the receiver before.
+    pub fn poll<P>(&mut self, reg: &lpuart::Instance, de: &mut P) -> nb::Result<(), DsmrUartError>
+    where
+        P: OutputPin<Error = Infallible>,
+    {
+        let result = match self.state {
+            State::Idle => return Ok(()),
+            _ if elapsed_us(self.started) > self.config.timeout_us => Err(DsmrUartError::Timeout),
+            State::Enabling if elapsed_us(self.started) < self.config.turnaround_us => {
+                return Err(nb::Error::WouldBlock)
+            }
+            State::Enabling => {
+                ral::modify_reg!(lpuart, reg, BAUD, TDMAE: 1);
+                let data = &reg.DATA as *const _ as usize;
+                self.dma.write(&self.message[..self.len], data);
+                self.state = State::Sending;
+                return Err(nb::Error::WouldBlock);
+            }
+            State::Sending if self.dma.has_failed() => Err(DsmrUartError::Dma),
+            State::Sending => {
+                if self.dma.is_done() && ral::read_reg!(lpuart, reg, STAT, TC) == 1 {
+                    self.state = State::Draining {
+                        since: DWT::get_cycle_count(),
+                    };
+                }
+                return Err(nb::Error::WouldBlock);
+            }
+            State::Draining { since } if elapsed_us(since) < self.config.turnaround_us => {
+                return Err(nb::Error::WouldBlock)
+            }
+            State::Draining { .. } if self.has_collided() => Err(DsmrUartError::Collision),
+            State::Draining { .. } => Ok(()),
+        };
+        let _ = de.set_low();
+        ral::modify_reg!(lpuart, reg, BAUD, TDMAE: 0);
+        self.dma.stop();
+        self.state = State::Idle;
+        result.map_err(nb::Error::Other)
+    }
+
+    /// Whether `byte` is the echo of a byte that was sent, which isn't
+    /// received, and if it isn't what was sent, marks a collision.
+    pub fn take_echo(&mut self, byte: u8) -> bool {
+        let sent = matches!(self.state, State::Sending | State::Draining { .. });
+        if !self.config.echo || !sent || self.echoed >= self.len {
+            return false;
+        }
+        if byte != self.message[self.echoed] {
+            self.collided = true;
+        }
+        self.echoed += 1;
+        true
+    }
+
+    /// Marks a collision if an error was received while sending.
+    pub fn on_read_error(&mut self) {
+        if self.config.echo && self.is_sending() {
+            self.collided = true;
+        }
+    }
+
+    // Whether another device talked while we were sending, or the echoes
+    // didn't all come back.
+    fn has_collided(&self) -> bool {
+        self.collided || (self.config.echo && self.echoed < self.len)
+    }
+}
+
+fn elapsed_us(since: u32) -> u32 {
+    DWT::get_cycle_count().wrapping_sub(since) / (cpu_clock::hz() / 1_000_000)
+}
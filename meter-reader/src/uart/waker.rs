@@ -0,0 +1,53 @@
+use core::{cell::RefCell, task::Waker};
+
+use cortex_m::interrupt::{self, Mutex};
+use teensy4_bsp::hal::{
+    iomuxc::prelude::consts,
+    ral::{self, lpuart},
+};
+
+use super::registers;
+
+type WakerSlot = Mutex<RefCell<Option<Waker>>>;
+
+// One slot for every LPUART instance.
+static RX_WAKERS: [WakerSlot; 8] = [
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+    Mutex::new(RefCell::new(None)),
+];
+
+/// Wakes the task that is waiting for data on LPUART `M`. When using the async
+/// API, this must be called from the interrupt handler of that LPUART, which
+/// must be unmasked in the NVIC.
+pub fn on_receive_interrupt<M: consts::Unsigned>() {
+    // The receive interrupt stays asserted until the FIFO has been drained,
+    // which is up to the task we're waking, so we mask it until that task
+    // starts waiting again.
+    let reg = registers::<M>();
+    ral::modify_reg!(lpuart, reg, CTRL, RIE: 0);
+    interrupt::free(|cs| {
+        if let Some(waker) = RX_WAKERS[M::USIZE - 1].borrow(cs).borrow_mut().take() {
+            waker.wake();
+        }
+    });
+}
+
+/// Registers `waker` to be woken by the next receive interrupt on LPUART `M`,
+/// and enables that interrupt.
+pub(super) fn register<M: consts::Unsigned>(waker: &Waker) {
+    interrupt::free(|cs| {
+        let mut slot = RX_WAKERS[M::USIZE - 1].borrow(cs).borrow_mut();
+        match &*slot {
+            Some(current) if current.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    });
+    let reg = registers::<M>();
+    ral::modify_reg!(lpuart, reg, CTRL, RIE: 1);
+}
@@ -0,0 +1,149 @@
+//! A bounded queue of messages for an uplink, which the main loop hands them
+//! to without waiting on the network, so that a slow network never stalls
+//! the reception of telegrams. The uplink takes a message once it has room
+//! to send it, and while it can't keep up, what is lost depends on the
+//! class of the message:
+//!
+//! - `Alarm`: kept in order, and put back at the front when sending fails,
+//!   until it is acknowledged. Alarms are never dropped to make room for
+//!   anything, only once `MAX_ALARMS` of them are already waiting.
+//! - `Reading`: only the latest is of interest, so a new reading replaces
+//!   the one that is still queued, and a reading that failed is only put
+//!   back if no newer one has come in since.
+//! - `Diagnostics`: replaced like readings, and dropped when sending fails.
+//!
+//! Alarms are taken first, then the reading, then the diagnostics.
+
+use arrayvec::ArrayVec;
+
+pub const MAX_ALARMS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Alarm,
+    Reading,
+    Diagnostics,
+}
+
+#[derive(Clone, Debug)]
+pub enum Message<R, D, A> {
+    Alarm(A),
+    Reading(R),
+    Diagnostics(D),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    /// Readings that were replaced by newer ones before they were sent.
+    pub replaced_readings: u32,
+    /// Diagnostics that were replaced, or that could not be sent.
+    pub dropped_diagnostics: u32,
+    /// Alarms that came in while `MAX_ALARMS` were waiting.
+    pub dropped_alarms: u32,
+}
+
+pub struct UplinkQueue<R, D, A> {
+    alarms: ArrayVec<[A; MAX_ALARMS]>,
+    reading: Option<R>,
+    diagnostics: Option<D>,
+    stats: QueueStats,
+}
+
+impl<R, D, A> UplinkQueue<R, D, A> {
+    pub fn new() -> Self {
+        Self {
+            alarms: ArrayVec::new(),
+            reading: None,
+            diagnostics: None,
+            stats: QueueStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, message: Message<R, D, A>) {
+        match message {
+            Message::Alarm(alarm) => {
+                if self.alarms.try_push(alarm).is_err() {
+                    self.drop_alarm();
+                }
+            }
+            Message::Reading(reading) => {
+                if self.reading.replace(reading).is_some() {
+                    self.stats.replaced_readings = self.stats.replaced_readings.saturating_add(1);
+                }
+            }
+            Message::Diagnostics(diagnostics) => {
+                if self.diagnostics.replace(diagnostics).is_some() {
+                    self.drop_diagnostics();
+                }
+            }
+        }
+    }
+
+    /// Puts back a message that was taken, but could not be sent, to be
+    /// the next of its class, unless its class says otherwise. Returns
+    /// whether it was put back.
+    pub fn retry(&mut self, message: Message<R, D, A>) -> bool {
+        match message {
+            Message::Alarm(alarm) => {
+                if self.alarms.is_full() {
+                    self.drop_alarm();
+                    return false;
+                }
+                self.alarms.insert(0, alarm);
+                true
+            }
+            Message::Reading(reading) => {
+                if self.reading.is_some() {
+                    return false;
+                }
+                self.reading = Some(reading);
+                true
+            }
+            Message::Diagnostics(_) => {
+                self.drop_diagnostics();
+                false
+            }
+        }
+    }
+
+    /// The class of the message that `pop` would take, so that the uplink
+    /// can wait until it has room for it.
+    pub fn next_class(&self) -> Option<Class> {
+        if !self.alarms.is_empty() {
+            Some(Class::Alarm)
+        } else if self.reading.is_some() {
+            Some(Class::Reading)
+        } else if self.diagnostics.is_some() {
+            Some(Class::Diagnostics)
+        } else {
+            None
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Message<R, D, A>> {
+        if !self.alarms.is_empty() {
+            Some(Message::Alarm(self.alarms.remove(0)))
+        } else if let Some(reading) = self.reading.take() {
+            Some(Message::Reading(reading))
+        } else {
+            self.diagnostics.take().map(Message::Diagnostics)
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+
+    fn drop_alarm(&mut self) {
+        self.stats.dropped_alarms = self.stats.dropped_alarms.saturating_add(1);
+        log::warn!(
+            "Dropped an alarm, since {} are waiting to be sent ({} so far)",
+            MAX_ALARMS,
+            self.stats.dropped_alarms
+        );
+    }
+
+    fn drop_diagnostics(&mut self) {
+        self.stats.dropped_diagnostics = self.stats.dropped_diagnostics.saturating_add(1);
+    }
+}
@@ -0,0 +1,378 @@
+use crate::dsmr::{Reading, MBUS_CHANNELS, TARIFFS};
+
+// After this many implausible readings in a row, the meter is assumed to have
+// been replaced or reset, and its readings are accepted again.
+const MAX_CONSECUTIVE_ANOMALIES: u32 = 3;
+
+/// A cumulative register of the meter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// Energy delivered to the client in a tariff.
+    Delivered(u8),
+    /// Energy returned by the client in a tariff.
+    Returned(u8),
+    /// The reading of the device on an M-Bus channel.
+    Mbus(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The register is lower than in the previous reading.
+    WentBackwards { register: Register, delta: i64 },
+    /// The register increased by more than the configured maximum.
+    Jumped { register: Register, delta: i64 },
+    /// The meter's clock is `drift` ms ahead of the wall-clock time, or
+    /// behind it if negative. While the wall-clock time isn't known, it is
+    /// how many ms more the meter's clock moved than our own since the first
+    /// reading.
+    ClockDrift { drift: i64 },
+}
+
+// Register values in thousandths of their unit.
+#[derive(Clone, Copy, Default)]
+struct Registers {
+    delivered: [Option<i64>; TARIFFS],
+    returned: [Option<i64>; TARIFFS],
+    mbus: [Option<i64>; MBUS_CHANNELS],
+}
+
+impl Registers {
+    fn from_reading(reading: &Reading) -> Self {
+        let mut registers = Self::default();
+        for (register, value) in registers.delivered.iter_mut().zip(&reading.delivered) {
+            *register = value.map(|value| value.rescale(3));
+        }
+        for (register, value) in registers.returned.iter_mut().zip(&reading.returned) {
+            *register = value.map(|value| value.rescale(3));
+        }
+        for (register, mbus) in registers.mbus.iter_mut().zip(&reading.mbus) {
+            *register = mbus
+                .and_then(|mbus| mbus.reading)
+                .map(|(_, value)| value.rescale(3));
+        }
+        registers
+    }
+
+    // Calls `each` for every register that is present in both `self` and
+    // `other`, with the value in `self` and the value in `other`.
+    fn zip<F: FnMut(Register, i64, i64)>(&self, other: &Self, mut each: F) {
+        zip_values(
+            Register::Delivered,
+            &self.delivered,
+            &other.delivered,
+            &mut each,
+        );
+        zip_values(
+            Register::Returned,
+            &self.returned,
+            &other.returned,
+            &mut each,
+        );
+        zip_values(Register::Mbus, &self.mbus, &other.mbus, &mut each);
+    }
+}
+
+fn zip_values<F: FnMut(Register, i64, i64)>(
+    register: fn(u8) -> Register,
+    values: &[Option<i64>],
+    others: &[Option<i64>],
+    each: &mut F,
+) {
+    for (i, pair) in values.iter().zip(others).enumerate() {
+        if let (Some(value), Some(other)) = pair {
+            // Tariffs and channels are numbered from 1.
+            each(register(i as u8 + 1), *value, *other);
+        }
+    }
+}
+
+/// Checks readings against the previous ones, so that corrupt telegrams that
+/// happen to pass the CRC check don't end up in history or get published.
+pub struct Validator {
+    max_jump: i64,
+    max_drift: i64,
+    last: Option<Registers>,
+    // Difference between the meter's clock and ours, in ms.
+    clock_offset: Option<i64>,
+    // Difference between the meter's clock and the wall-clock time that is
+    // taken to be right, in ms. Only other than 0 once a meter whose clock is
+    // off has been accepted as the new baseline.
+    wall_offset: i64,
+    consecutive_anomalies: u32,
+    rejected: u32,
+}
+
+impl Validator {
+    /// `max_jump` is the largest increase of a register between two readings
+    /// that is considered plausible, in thousandths of its unit, so in Wh for
+    /// energy. `max_drift` is the number of ms the meter's clock may be off
+    /// from the wall-clock time.
+    ///
+    /// Until the wall-clock time is known, the meter's clock can only be
+    /// compared to our own, which counts from boot, so it is then only
+    /// checked for drifting more than `max_drift` relative to it since the
+    /// first reading.
+    pub fn new(max_jump: i64, max_drift: i64) -> Self {
+        Self {
+            max_jump,
+            max_drift,
+            last: None,
+            clock_offset: None,
+            wall_offset: 0,
+            consecutive_anomalies: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Checks a reading received at `received_at` ms, and at `wall_time` ms
+    /// since the Unix epoch if that is known, calling `report` for each
+    /// anomaly. Returns whether the reading is plausible. Implausible
+    /// readings are not used as the baseline for the next one.
+    pub fn check<F: FnMut(Anomaly)>(
+        &mut self,
+        received_at: i64,
+        wall_time: Option<i64>,
+        reading: &Reading,
+        mut report: F,
+    ) -> bool {
+        let registers = Registers::from_reading(reading);
+        let mut plausible = true;
+        if let Some(last) = self.last {
+            let max_jump = self.max_jump;
+            registers.zip(&last, |register, value, last| {
+                let delta = value - last;
+                if delta < 0 {
+                    plausible = false;
+                    report(Anomaly::WentBackwards { register, delta });
+                } else if delta > max_jump {
+                    plausible = false;
+                    report(Anomaly::Jumped { register, delta });
+                }
+            });
+        }
+        let meter_time = reading
+            .timestamp
+            .map(|timestamp| timestamp.unix_time() * 1000);
+        let clock_offset = meter_time.map(|meter_time| meter_time - received_at);
+        let wall_offset = meter_time.zip(wall_time).map(|(meter, wall)| meter - wall);
+        let drift = match (wall_offset, clock_offset, self.clock_offset) {
+            (Some(offset), _, _) => Some(offset - self.wall_offset),
+            (None, Some(offset), Some(initial)) => Some(offset - initial),
+            _ => None,
+        };
+        if let Some(drift) = drift.filter(|drift| drift.abs() > self.max_drift) {
+            plausible = false;
+            report(Anomaly::ClockDrift { drift });
+        }
+
+        if plausible {
+            self.consecutive_anomalies = 0;
+        } else {
+            self.rejected = self.rejected.saturating_add(1);
+            self.consecutive_anomalies += 1;
+            if self.consecutive_anomalies < MAX_CONSECUTIVE_ANOMALIES {
+                return false;
+            }
+            log::warn!(
+                "{} implausible readings in a row, accepting the current one as the new baseline",
+                self.consecutive_anomalies
+            );
+            self.consecutive_anomalies = 0;
+            self.clock_offset = None;
+            self.wall_offset = wall_offset.unwrap_or(self.wall_offset);
+        }
+        self.last = Some(registers);
+        self.clock_offset = self.clock_offset.or(clock_offset);
+        plausible
+    }
+
+    /// The number of readings rejected so far.
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dsmr42::{Decimal, Timestamp, Unit};
+    use std::vec::Vec;
+
+    const MAX_JUMP: i64 = 1000;
+    const MAX_DRIFT: i64 = 5000;
+
+    fn reading(delivered: i64, second: u8) -> Reading {
+        let mut reading = Reading::default();
+        reading.delivered[0] = Some(Decimal::new(delivered, 3, Some(Unit::KWh)));
+        reading.timestamp = Some(Timestamp {
+            year: 2020,
+            month: 2,
+            day: 8,
+            hour: 15,
+            minute: 35,
+            second,
+            dst: Some(false),
+        });
+        reading
+    }
+
+    // Checks `reading` as received `at` ms after it was sent, by our own
+    // clock, while the wall-clock time isn't known.
+    fn check(validator: &mut Validator, reading: &Reading, at: i64) -> (bool, Vec<Anomaly>) {
+        let received_at = reading.timestamp.unwrap().unix_time() * 1000 + at;
+        let mut anomalies = Vec::new();
+        let plausible = validator.check(received_at, None, reading, |anomaly| {
+            anomalies.push(anomaly)
+        });
+        (plausible, anomalies)
+    }
+
+    // Checks `reading` as received `at` ms after it was sent, by the
+    // wall-clock time, at 0 ms by our own clock.
+    fn check_at_wall_time(
+        validator: &mut Validator,
+        reading: &Reading,
+        at: i64,
+    ) -> (bool, Vec<Anomaly>) {
+        let wall_time = reading.timestamp.unwrap().unix_time() * 1000 + at;
+        let mut anomalies = Vec::new();
+        let plausible = validator.check(0, Some(wall_time), reading, |anomaly| {
+            anomalies.push(anomaly)
+        });
+        (plausible, anomalies)
+    }
+
+    #[test]
+    fn increasing_readings_are_plausible() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(1000, 0), 0)
+        );
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(1000, 10), 0)
+        );
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(2000, 20), 0)
+        );
+        assert_eq!(0, validator.rejected());
+    }
+
+    #[test]
+    fn register_going_backwards_is_rejected() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        check(&mut validator, &reading(1000, 0), 0);
+        let anomaly = Anomaly::WentBackwards {
+            register: Register::Delivered(1),
+            delta: -1,
+        };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check(&mut validator, &reading(999, 10), 0)
+        );
+        // Compared to the last plausible reading, not the rejected one.
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(1500, 20), 0)
+        );
+        assert_eq!(1, validator.rejected());
+    }
+
+    #[test]
+    fn register_jumping_is_rejected() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        check(&mut validator, &reading(1000, 0), 0);
+        let anomaly = Anomaly::Jumped {
+            register: Register::Delivered(1),
+            delta: 1001,
+        };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check(&mut validator, &reading(2001, 10), 0)
+        );
+        assert_eq!(1, validator.rejected());
+    }
+
+    #[test]
+    fn clock_drift_is_rejected() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        check(&mut validator, &reading(1000, 0), 0);
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(1000, 10), -5000)
+        );
+        let anomaly = Anomaly::ClockDrift { drift: 5001 };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check(&mut validator, &reading(1000, 20), -5001)
+        );
+    }
+
+    #[test]
+    fn clock_off_from_wall_time_is_rejected() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        // Even the first reading, which there is no baseline for.
+        let anomaly = Anomaly::ClockDrift { drift: 5001 };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check_at_wall_time(&mut validator, &reading(1000, 0), -5001)
+        );
+        assert_eq!(
+            (true, Vec::new()),
+            check_at_wall_time(&mut validator, &reading(1000, 10), 5000)
+        );
+        let anomaly = Anomaly::ClockDrift { drift: -5001 };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check_at_wall_time(&mut validator, &reading(1000, 20), 5001)
+        );
+    }
+
+    #[test]
+    fn wall_time_replaces_the_drift_since_the_first_reading() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        // Drifted since the first reading by our own clock, but right by the
+        // wall-clock time.
+        check(&mut validator, &reading(1000, 0), 0);
+        assert_eq!(
+            (true, Vec::new()),
+            check_at_wall_time(&mut validator, &reading(1000, 10), 0)
+        );
+    }
+
+    #[test]
+    fn clock_that_stays_off_is_accepted_as_the_new_baseline() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        let off = -10 * 60_000;
+        for second in 0..MAX_CONSECUTIVE_ANOMALIES as u8 {
+            check_at_wall_time(&mut validator, &reading(1000, second), off);
+        }
+        assert_eq!(
+            (true, Vec::new()),
+            check_at_wall_time(&mut validator, &reading(1000, 10), off)
+        );
+        let anomaly = Anomaly::ClockDrift { drift: -5001 };
+        assert_eq!(
+            (false, std::vec![anomaly]),
+            check_at_wall_time(&mut validator, &reading(1000, 20), off + 5001)
+        );
+    }
+
+    #[test]
+    fn consecutive_anomalies_reset_the_baseline() {
+        let mut validator = Validator::new(MAX_JUMP, MAX_DRIFT);
+        check(&mut validator, &reading(1000, 0), 0);
+        for second in 1..=MAX_CONSECUTIVE_ANOMALIES as u8 {
+            let (plausible, _) = check(&mut validator, &reading(500, second), 0);
+            assert!(!plausible);
+        }
+        // The meter was replaced, its readings are compared to the new one.
+        assert_eq!(
+            (true, Vec::new()),
+            check(&mut validator, &reading(600, 10), 0)
+        );
+        assert_eq!(MAX_CONSECUTIVE_ANOMALIES, validator.rejected());
+    }
+}
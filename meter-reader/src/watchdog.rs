@@ -0,0 +1,191 @@
+//! Resets the chip when the main loop stops feeding WDOG1, so that a hung
+//! poll loop recovers on its own.
+//!
+//! A `Supervisor` only feeds the watchdog once every `Task` has checked in.
+//! Shortly before the watchdog resets the chip, its interrupt records which
+//! tasks didn't, as a crash, so that it can be told where the loop hung.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use cortex_m::peripheral::{NVIC, SCB};
+use teensy4_bsp::hal::ral::interrupt;
+
+use crate::panic::{self, Registers};
+
+const WDOG1_BASE: usize = 0x400B_8000;
+const WCR: usize = WDOG1_BASE;
+const WSR: usize = WDOG1_BASE + 0x02;
+const WICR: usize = WDOG1_BASE + 0x06;
+const WMCR: usize = WDOG1_BASE + 0x08;
+// Gates the clock of WDOG1, with the bits of CG8.
+const CCM_CCGR3: usize = 0x400F_C074;
+const CG8: u32 = 0b11 << 16;
+// Why the chip was last reset.
+const SRC_SRSR: usize = 0x400F_8008;
+const SRSR_WDOG_RST: u32 = 1 << 4;
+
+// The bits of WCR that are set: suspend in debug mode, enable, don't
+// trigger a software reset, don't assert WDOG_B and extend software resets.
+const WCR_WDBG: u16 = 1 << 1;
+const WCR_WDE: u16 = 1 << 2;
+const WCR_SRS: u16 = 1 << 4;
+const WCR_WDA: u16 = 1 << 5;
+const WCR_SRE: u16 = 1 << 6;
+const FEED_SEQUENCE: [u16; 2] = [0x5555, 0xAAAA];
+// Enables the interrupt, which fires WICT steps before the timeout.
+const WICR_WIE: u16 = 1 << 15;
+const WICR_WTIS: u16 = 1 << 14;
+const WARNING_STEPS: u16 = 1;
+// The timeout is counted in steps of 0.5 s, starting at 0.5 s.
+const STEP_MS: u32 = 500;
+const MAX_STEPS: u32 = 256;
+
+pub struct Watchdog {
+    _private: (),
+}
+
+impl Watchdog {
+    /// Starts the watchdog, which resets the chip unless it is fed at least
+    /// once every `timeout_ms`, which is rounded down to a multiple of
+    /// 0.5 s, and limited to 128 s. Once started, it can't be stopped.
+    pub fn start(timeout_ms: u32) -> Self {
+        let steps = (timeout_ms / STEP_MS).max(1).min(MAX_STEPS);
+        let wcr = ((steps - 1) as u16) << 8 | WCR_SRE | WCR_WDA | WCR_SRS | WCR_WDE | WCR_WDBG;
+        unsafe {
+            let ccgr3 = ptr::read_volatile(CCM_CCGR3 as *const u32);
+            ptr::write_volatile(CCM_CCGR3 as *mut u32, ccgr3 | CG8);
+            // Otherwise the power-down counter asserts WDOG_B 16 s after
+            // reset.
+            ptr::write_volatile(WMCR as *mut u16, 0);
+            ptr::write_volatile(WCR as *mut u16, wcr);
+        }
+        log::info!("Watchdog started, timeout {} ms", steps * STEP_MS);
+        let mut watchdog = Self { _private: () };
+        watchdog.feed();
+        watchdog
+    }
+
+    pub fn feed(&mut self) {
+        for word in FEED_SEQUENCE.iter() {
+            unsafe { ptr::write_volatile(WSR as *mut u16, *word) };
+        }
+    }
+}
+
+/// Whether the watchdog caused the last reset. Only the first call after a
+/// reset can tell.
+pub fn caused_reset() -> bool {
+    unsafe {
+        let srsr = ptr::read_volatile(SRC_SRSR as *const u32);
+        // Its bits are cleared by writing ones.
+        ptr::write_volatile(SRC_SRSR as *mut u32, srsr);
+        srsr & SRSR_WDOG_RST != 0
+    }
+}
+
+/// The tasks of the main loop, which must check in with the `Supervisor`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    Uart,
+    Parser,
+    Network,
+    Logger,
+    Button,
+    Sensors,
+}
+
+impl Task {
+    pub const ALL: [Task; 6] = [
+        Task::Uart,
+        Task::Parser,
+        Task::Network,
+        Task::Logger,
+        Task::Button,
+        Task::Sensors,
+    ];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Uart => "uart",
+            Task::Parser => "parser",
+            Task::Network => "network",
+            Task::Logger => "logger",
+            Task::Button => "button",
+            Task::Sensors => "sensors",
+        }
+    }
+}
+
+// The tasks that checked in since the watchdog was last fed.
+static CHECKED_IN: AtomicU32 = AtomicU32::new(0);
+
+/// Reports that `task` is still making progress.
+pub fn check_in(task: Task) {
+    CHECKED_IN.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+pub struct Supervisor {
+    watchdog: Watchdog,
+}
+
+impl Supervisor {
+    /// Starts the watchdog with `timeout_ms`, as `Watchdog::start` does, and
+    /// its interrupt.
+    pub fn start(timeout_ms: u32) -> Self {
+        let watchdog = Watchdog::start(timeout_ms);
+        unsafe {
+            ptr::write_volatile(WICR as *mut u16, WICR_WIE | WARNING_STEPS);
+            NVIC::unmask(interrupt::WDOG1);
+        }
+        Self { watchdog }
+    }
+
+    /// Feeds the watchdog if every task has checked in since it was last
+    /// fed. Should be called well within the timeout, such as from a
+    /// periodic timer.
+    pub fn poll(&mut self) {
+        let all = Task::ALL.iter().fold(0, |bits, task| bits | task.bit());
+        if CHECKED_IN.load(Ordering::Relaxed) == all {
+            CHECKED_IN.store(0, Ordering::Relaxed);
+            self.watchdog.feed();
+        }
+    }
+}
+
+#[interrupt]
+fn WDOG1() {
+    unsafe { ptr::write_volatile(WICR as *mut u16, WICR_WIE | WICR_WTIS | WARNING_STEPS) };
+    let missing = Missing(!CHECKED_IN.load(Ordering::Relaxed));
+    log::error!("Tasks stopped checking in: {}, resetting", missing);
+    // Such as a panic, which stops every task.
+    if !panic::crash_recorded() {
+        panic::record_crash(
+            format_args!("Watchdog timeout, tasks stopped checking in: {}", missing),
+            Registers::capture(),
+        );
+    }
+    SCB::sys_reset();
+}
+
+// The tasks whose bits are set.
+struct Missing(u32);
+
+impl fmt::Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tasks = Task::ALL.iter().filter(|task| self.0 & task.bit() != 0);
+        if let Some(first) = tasks.next() {
+            f.write_str(first.name())?;
+            for task in tasks {
+                write!(f, ", {}", task.name())?;
+            }
+        }
+        Ok(())
+    }
+}